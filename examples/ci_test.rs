@@ -48,7 +48,6 @@ extern crate rustc_serialize;
 extern crate docopt;
 extern crate rust_sodium;
 extern crate routing;
-extern crate kademlia_routing_table;
 extern crate lru_time_cache;
 extern crate term;
 #[macro_use]
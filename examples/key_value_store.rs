@@ -49,7 +49,6 @@ extern crate maidsafe_utilities;
 #[macro_use]
 extern crate unwrap;
 extern crate docopt;
-extern crate kademlia_routing_table;
 extern crate rustc_serialize;
 extern crate rust_sodium;
 
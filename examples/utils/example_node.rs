@@ -15,10 +15,10 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use kademlia_routing_table::RoutingTable;
 use lru_time_cache::LruCache;
 use maidsafe_utilities::serialisation::{deserialise, serialise};
-use routing::{Authority, Data, DataIdentifier, Event, MessageId, Node, Request, Response, XorName};
+use routing::{Authority, Data, DataIdentifier, Event, GroupChange, MessageId, Node, Request,
+              Response, XorName};
 use std::collections::HashMap;
 use std::sync::mpsc;
 use std::time::Duration;
@@ -58,19 +58,21 @@ impl ExampleNode {
     pub fn run(&mut self) {
         while let Ok(event) = self.receiver.recv() {
             match event {
-                Event::Request { request, src, dst } => self.handle_request(request, src, dst),
-                Event::Response { response, src, dst } => self.handle_response(response, src, dst),
-                Event::NodeAdded(name, routing_table) => {
+                Event::Request { request, src, dst, .. } => self.handle_request(request, src, dst),
+                Event::Response { response, src, dst, .. } => {
+                    self.handle_response(response, src, dst)
+                }
+                Event::NodeAdded(name, _routing_table, group_change) => {
                     trace!("{} Received NodeAdded event {:?}",
                            self.get_debug_name(),
                            name);
-                    self.handle_node_added(name, routing_table);
+                    self.handle_node_added(name, group_change);
                 }
-                Event::NodeLost(name, routing_table) => {
+                Event::NodeLost(name, _routing_table, group_change) => {
                     trace!("{} Received NodeLost event {:?}",
                            self.get_debug_name(),
                            name);
-                    self.handle_node_lost(name, routing_table);
+                    self.handle_node_lost(name, group_change);
                 }
                 Event::Connected => {
                     trace!("{} Received connected event", self.get_debug_name());
@@ -180,19 +182,20 @@ impl ExampleNode {
 
     // While handling churn messages, we first "action" it ourselves and then
     // send the corresponding refresh messages out to our close group.
-    fn handle_node_added(&mut self, name: XorName, _routing_table: RoutingTable<XorName>) {
-        // TODO: Use the given routing table instead of repeatedly querying the routing node.
-        self.send_refresh(MessageId::from_added_node(name));
+    fn handle_node_added(&mut self, name: XorName, group_change: GroupChange<XorName>) {
+        self.send_refresh(MessageId::from_added_node(name), &group_change);
     }
 
-    fn handle_node_lost(&mut self, name: XorName, _routing_table: RoutingTable<XorName>) {
-        // TODO: Use the given routing table instead of repeatedly querying the routing node.
-        self.send_refresh(MessageId::from_lost_node(name));
+    fn handle_node_lost(&mut self, name: XorName, group_change: GroupChange<XorName>) {
+        self.send_refresh(MessageId::from_lost_node(name), &group_change);
     }
 
-    fn send_refresh(&mut self, id: MessageId) {
-        // TODO: Check whether name was actually close to client_name.
+    /// Only refreshes the data whose close group got new members, as only they are missing it.
+    fn send_refresh(&mut self, id: MessageId, group_change: &GroupChange<XorName>) {
         for (client_name, stored) in &self.client_accounts {
+            if group_change.new_members_for(client_name).is_empty() {
+                continue;
+            }
             let refresh_content = RefreshContent::Client {
                 client_name: *client_name,
                 data: *stored,
@@ -206,8 +209,10 @@ impl ExampleNode {
                                       content,
                                       id));
         }
-        // TODO: Check whether name was actually close to data_name.
         for (data_name, data) in &self.db {
+            if group_change.new_members_for(data_name).is_empty() {
+                continue;
+            }
             let refresh_content = RefreshContent::NaeManager {
                 data_name: *data_name,
                 data: data.clone(),
@@ -15,10 +15,12 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use clock::Clock;
+use crust::PeerId;
 use error::RoutingError;
 use maidsafe_utilities;
 use message_filter::MessageFilter;
-use messages::RoutingMessage;
+use messages::{RoutingMessage, SignedMessage};
 use std::collections::HashMap;
 use std::fmt;
 use std::time::Duration;
@@ -29,10 +31,15 @@ pub const ACK_TIMEOUT_SECS: u64 = 20;
 const EXPIRY_DURATION_SECS: u64 = 4 * 60;
 
 /// A copy of a message which has been sent and is pending the ack from the recipient.
+///
+/// It is kept as it was signed, so that a resend is identical and recipients and relays recognise
+/// it as a duplicate.
 #[derive(Clone, Debug)]
 pub struct UnacknowledgedMessage {
-    pub routing_msg: RoutingMessage,
+    pub signed_msg: SignedMessage,
     pub route: u8,
+    /// The peers the message was sent to.
+    pub relays: Vec<PeerId>,
     pub timer_token: u64,
 }
 
@@ -45,20 +52,23 @@ pub struct AckManager {
 pub struct Ack(u64);
 
 impl AckManager {
-    pub fn new() -> Self {
+    pub fn new(clock: Clock) -> Self {
         let expiry_duration = Duration::from_secs(EXPIRY_DURATION_SECS);
 
         AckManager {
             pending: HashMap::new(),
-            received: MessageFilter::with_expiry_duration(expiry_duration),
+            received: MessageFilter::with_expiry_duration(expiry_duration, clock),
         }
     }
 
-    // Handle received ack.
-    pub fn receive(&mut self, ack: Ack) {
-        if self.pending.remove(&ack).is_none() {
+    // Handle received ack. Returns the message it acknowledges, if it was pending, so that its
+    // timer can be cancelled.
+    pub fn receive(&mut self, ack: Ack) -> Option<UnacknowledgedMessage> {
+        let unacked_msg = self.pending.remove(&ack);
+        if unacked_msg.is_none() {
             let _ = self.received.insert(&ack);
         }
+        unacked_msg
     }
 
     pub fn did_receive(&mut self, ack: Ack) -> bool {
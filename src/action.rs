@@ -18,9 +18,13 @@
 use authority::Authority;
 use error::InterfaceError;
 use messages::{Request, UserMessage};
+use messaging::{MpidHeader, MpidMessageWrapper};
+use replay_filter::ReplayFilter;
+use rust_sodium::crypto::sign;
 use std::collections::HashSet;
 use std::fmt::{self, Debug, Formatter};
 use std::sync::mpsc::Sender;
+use std::time::Duration;
 use xor_name::XorName;
 
 /// An Action initiates a message flow < A | B > where we are (a part of) A.
@@ -44,12 +48,33 @@ pub enum Action {
         priority: u8,
         result_tx: Sender<Result<(), InterfaceError>>,
     },
+    ClientSendMpid {
+        content: MpidMessageWrapper,
+        dst: Authority,
+        result_tx: Sender<Result<(), InterfaceError>>,
+    },
+    MpidInbox { result_tx: Sender<Vec<MpidHeader>> },
+    MpidOutbox { result_tx: Sender<Vec<MpidHeader>> },
     CloseGroup {
         name: XorName,
         result_tx: Sender<Option<HashSet<XorName>>>,
     },
     Name { result_tx: Sender<XorName> },
     QuorumSize { result_tx: Sender<usize> },
+    Ban {
+        key: sign::PublicKey,
+        result_tx: Sender<Result<(), InterfaceError>>,
+    },
+    Unban {
+        key: sign::PublicKey,
+        result_tx: Sender<Result<bool, InterfaceError>>,
+    },
+    BannedKeys { result_tx: Sender<Vec<sign::PublicKey>> },
+    ReplayFilter { result_tx: Sender<ReplayFilter> },
+    Shutdown {
+        timeout: Duration,
+        result_tx: Sender<Result<(), InterfaceError>>,
+    },
     Timeout(u64),
     Terminate,
 }
@@ -68,9 +93,24 @@ impl Debug for Action {
                        content,
                        dst)
             }
+            Action::ClientSendMpid { ref content, ref dst, .. } => {
+                write!(formatter,
+                       "Action::ClientSendMpid {{ {:?}, dst: {:?}, result_tx }}",
+                       content,
+                       dst)
+            }
+            Action::MpidInbox { .. } => write!(formatter, "Action::MpidInbox"),
+            Action::MpidOutbox { .. } => write!(formatter, "Action::MpidOutbox"),
             Action::CloseGroup { .. } => write!(formatter, "Action::CloseGroup"),
             Action::Name { .. } => write!(formatter, "Action::Name"),
             Action::QuorumSize { .. } => write!(formatter, "Action::QuorumSize"),
+            Action::Ban { .. } => write!(formatter, "Action::Ban"),
+            Action::Unban { .. } => write!(formatter, "Action::Unban"),
+            Action::BannedKeys { .. } => write!(formatter, "Action::BannedKeys"),
+            Action::ReplayFilter { .. } => write!(formatter, "Action::ReplayFilter"),
+            Action::Shutdown { timeout, .. } => {
+                write!(formatter, "Action::Shutdown {{ {:?}, result_tx }}", timeout)
+            }
             Action::Timeout(token) => write!(formatter, "Action::Timeout({})", token),
             Action::Terminate => write!(formatter, "Action::Terminate"),
         }
@@ -0,0 +1,164 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use clock::Clock;
+use crust::PeerId;
+use message_filter::MessageFilter;
+use rust_sodium::crypto::sign;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The number of integrity failures after which a peer is banned temporarily.
+pub const MAX_INTEGRITY_FAILURES: usize = 3;
+/// Time (in seconds) within which integrity failures are counted towards a temporary ban.
+const INTEGRITY_FAILURE_EXPIRY_SECS: u64 = 10 * 60;
+/// Duration (in seconds) of a temporary ban.
+pub const TEMPORARY_BAN_SECS: u64 = 60 * 60;
+
+/// The peers we refuse to connect to, identified by their signing key or their crust ID.
+///
+/// Bans are either permanent, if they were requested by the user, or temporary, if a peer sent us
+/// too many messages that failed the integrity check. Only the permanent bans by key are meant to
+/// be persisted: crust IDs don't survive a restart anyway.
+pub struct BanList {
+    clock: Clock,
+    /// Banned signing keys, with the time the ban expires, or `None` if it is permanent.
+    keys: HashMap<sign::PublicKey, Option<Instant>>,
+    /// Temporarily banned crust IDs, with the time the ban expires.
+    peer_ids: HashMap<PeerId, Instant>,
+    integrity_failures: MessageFilter<PeerId>,
+}
+
+impl BanList {
+    /// Creates a ban list that permanently bans the given keys, and measures the temporary bans
+    /// with `clock`.
+    pub fn new(banned_keys: Vec<sign::PublicKey>, clock: Clock) -> BanList {
+        let expiry_duration = Duration::from_secs(INTEGRITY_FAILURE_EXPIRY_SECS);
+        BanList {
+            clock: clock.clone(),
+            keys: banned_keys.into_iter().map(|key| (key, None)).collect(),
+            peer_ids: HashMap::new(),
+            integrity_failures: MessageFilter::with_expiry_duration(expiry_duration, clock),
+        }
+    }
+
+    /// Bans the given key permanently.
+    pub fn ban(&mut self, key: sign::PublicKey) {
+        let _ = self.keys.insert(key, None);
+    }
+
+    /// Lifts any ban on the given key. Returns `true` if it was banned.
+    pub fn unban(&mut self, key: &sign::PublicKey) -> bool {
+        self.keys.remove(key).is_some()
+    }
+
+    /// Returns the permanently banned keys, e.g. to persist them across restarts.
+    pub fn banned_keys(&self) -> Vec<sign::PublicKey> {
+        self.keys.iter().filter(|&(_, expiry)| expiry.is_none()).map(|(key, _)| *key).collect()
+    }
+
+    /// Records that a message from the given peer failed the integrity check. If that happened too
+    /// often recently, the peer and its key, if known, are banned temporarily and `true` is
+    /// returned.
+    pub fn record_integrity_failure(&mut self,
+                                    peer_id: &PeerId,
+                                    key: Option<&sign::PublicKey>)
+                                    -> bool {
+        if self.integrity_failures.insert(peer_id) < MAX_INTEGRITY_FAILURES {
+            return false;
+        }
+        let expiry = self.clock.now() + Duration::from_secs(TEMPORARY_BAN_SECS);
+        let _ = self.peer_ids.insert(*peer_id, expiry);
+        if let Some(key) = key {
+            // Don't turn a permanent ban into a temporary one.
+            let _ = self.keys.entry(*key).or_insert_with(|| Some(expiry));
+        }
+        true
+    }
+
+    /// Returns `true` if the peer with the given crust ID or key is currently banned.
+    pub fn is_banned(&mut self, peer_id: &PeerId, key: &sign::PublicKey) -> bool {
+        self.remove_expired();
+        self.peer_ids.contains_key(peer_id) || self.keys.contains_key(key)
+    }
+
+    fn remove_expired(&mut self) {
+        let now = self.clock.now();
+        let expired_peer_ids = self.peer_ids
+            .iter()
+            .filter(|&(_, expiry)| *expiry <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+        for peer_id in expired_peer_ids {
+            let _ = self.peer_ids.remove(&peer_id);
+        }
+        let expired_keys = self.keys
+            .iter()
+            .filter(|&(_, expiry)| expiry.map_or(false, |expiry| expiry <= now))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in expired_keys {
+            let _ = self.keys.remove(&key);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "use-mock-crust"))]
+mod tests {
+    use clock::Clock;
+    use id::FullId;
+    use mock_crust::{self, Network};
+    use mock_crust::crust::PeerId;
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn manual_bans() {
+        let key = *FullId::new().public_id().signing_public_key();
+        let mut ban_list = BanList::new(vec![key], Clock::default());
+        assert!(ban_list.is_banned(&PeerId(0), &key));
+        assert_eq!(vec![key], ban_list.banned_keys());
+
+        assert!(ban_list.unban(&key));
+        assert!(!ban_list.is_banned(&PeerId(0), &key));
+        assert!(ban_list.banned_keys().is_empty());
+    }
+
+    #[test]
+    fn integrity_failures() {
+        let key = *FullId::new().public_id().signing_public_key();
+        let other_key = *FullId::new().public_id().signing_public_key();
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let clock = mock_crust::make_current(&handle, Clock::new);
+        let mut ban_list = BanList::new(vec![], clock);
+        for _ in 1..MAX_INTEGRITY_FAILURES {
+            assert!(!ban_list.record_integrity_failure(&PeerId(0), Some(&key)));
+        }
+        assert!(!ban_list.is_banned(&PeerId(0), &key));
+
+        assert!(ban_list.record_integrity_failure(&PeerId(0), Some(&key)));
+        assert!(ban_list.is_banned(&PeerId(0), &other_key));
+        assert!(ban_list.is_banned(&PeerId(1), &key));
+        assert!(!ban_list.is_banned(&PeerId(1), &other_key));
+        // Temporary bans are not persisted.
+        assert!(ban_list.banned_keys().is_empty());
+
+        network.advance_time(Duration::from_secs(TEMPORARY_BAN_SECS));
+        assert!(!ban_list.is_banned(&PeerId(0), &key));
+    }
+}
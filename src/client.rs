@@ -17,21 +17,30 @@
 
 use action::Action;
 use authority::Authority;
+use ban_list::BanList;
 use cache::NullCache;
 use data::{Data, DataIdentifier};
 use error::{InterfaceError, RoutingError};
 use event::Event;
+#[cfg(not(feature = "use-mock-crust"))]
+use event_loop::{Driver, Waker};
 use id::FullId;
 #[cfg(not(feature = "use-mock-crust"))]
-use maidsafe_utilities::thread::{self, Joiner};
+use maidsafe_utilities::thread;
 use messages::{CLIENT_GET_PRIORITY, DEFAULT_PRIORITY, Request};
+use messaging::{MpidHeader, MpidMessage, MpidMessageWrapper};
+use peer_manager::ProxyLimits;
+use replay_filter::ReplayFilter;
 #[cfg(not(feature = "use-mock-crust"))]
 use rust_sodium;
+use session::Sessions;
 use state_machine::{State, StateMachine};
 use states;
-#[cfg(feature = "use-mock-crust")]
 use std::cell::RefCell;
 use std::sync::mpsc::{Receiver, Sender, channel};
+#[cfg(not(feature = "use-mock-crust"))]
+use std::time::Duration;
+use tunnels::TunnelLimits;
 use types::MessageId;
 use types::RoutingActionSender;
 use xor_name::XorName;
@@ -47,12 +56,14 @@ pub struct Client {
     interface_result_tx: Sender<Result<(), InterfaceError>>,
     interface_result_rx: Receiver<Result<(), InterfaceError>>,
     action_sender: ::types::RoutingActionSender,
+    /// Our keys, to sign the MPID messages we send.
+    full_id: FullId,
 
     #[cfg(feature = "use-mock-crust")]
     machine: RefCell<StateMachine>,
 
     #[cfg(not(feature = "use-mock-crust"))]
-    _raii_joiner: Joiner,
+    driver: Driver,
 }
 
 impl Client {
@@ -71,7 +82,9 @@ impl Client {
         rust_sodium::init();  // enable shared global (i.e. safe to multithread now)
 
         // start the handler for routing with a restriction to become a full node
-        let (action_sender, mut machine) = Self::make_state_machine(event_sender, keys);
+        let full_id = keys.unwrap_or_else(FullId::new);
+        let (action_sender, mut machine) = Self::make_state_machine(event_sender,
+                                                                    full_id.clone());
         let (tx, rx) = channel();
 
         let raii_joiner = thread::named("Client thread", move || machine.run());
@@ -80,23 +93,60 @@ impl Client {
             interface_result_tx: tx,
             interface_result_rx: rx,
             action_sender: action_sender,
-            _raii_joiner: raii_joiner,
+            full_id: full_id,
+            driver: Driver::Thread(raii_joiner),
+        })
+    }
+
+    /// Create a new `Client` that is driven by the application's own event loop instead of a
+    /// dedicated thread.
+    ///
+    /// `waker` is called whenever the client has events to process. The application must then
+    /// call `Client::poll` until it returns `false`, from the thread that owns the client. None of
+    /// the client's methods block in this mode: requests are handled right away.
+    #[cfg(not(feature = "use-mock-crust"))]
+    pub fn new_embedded(event_sender: Sender<Event>,
+                        keys: Option<FullId>,
+                        waker: Box<Waker>)
+                        -> Result<Client, RoutingError> {
+        rust_sodium::init();  // enable shared global (i.e. safe to multithread now)
+
+        let full_id = keys.unwrap_or_else(FullId::new);
+        let (action_sender, mut machine) = Self::make_state_machine(event_sender,
+                                                                    full_id.clone());
+        try!(machine.set_waker(waker));
+        let (tx, rx) = channel();
+
+        Ok(Client {
+            interface_result_tx: tx,
+            interface_result_rx: rx,
+            action_sender: action_sender,
+            full_id: full_id,
+            driver: Driver::EventLoop(RefCell::new(machine)),
         })
     }
 
     fn make_state_machine(event_sender: Sender<Event>,
-                          keys: Option<FullId>)
+                          full_id: FullId)
                           -> (RoutingActionSender, StateMachine) {
         let cache = Box::new(NullCache);
-        let full_id = keys.unwrap_or_else(FullId::new);
 
-        StateMachine::new(move |crust_service, timer| {
-            State::Bootstrapping(states::Bootstrapping::new(cache,
+        StateMachine::new(move |crust_service, timer, clock| {
+            let ban_list = BanList::new(vec![], clock.clone());
+            let sessions = Sessions::new(true, clock.clone());
+            State::Bootstrapping(states::Bootstrapping::new(ban_list,
+                                                            cache,
                                                             true,
+                                                            clock,
                                                             crust_service,
                                                             event_sender,
                                                             full_id,
-                                                            timer))
+                                                            ProxyLimits::default(),
+                                                            ReplayFilter::default(),
+                                                            0,
+                                                            sessions,
+                                                            timer,
+                                                            TunnelLimits::default()))
         })
     }
 
@@ -149,11 +199,90 @@ impl Client {
     /// Returns the name of this node.
     pub fn name(&self) -> Result<XorName, InterfaceError> {
         let (result_tx, result_rx) = channel();
-        try!(self.action_sender.send(Action::Name { result_tx: result_tx }));
+        try!(self.dispatch(Action::Name { result_tx: result_tx }));
 
         self.receive_action_result(&result_rx)
     }
 
+    /// Tells our MPID managers that we are online, so that they send us the headers of the messages
+    /// we received while offline and notify us of new ones. They forget it whenever their group
+    /// changes, so this should be repeated periodically.
+    pub fn send_mpid_online(&self) -> Result<(), InterfaceError> {
+        self.send_mpid(MpidMessageWrapper::Online, self.mpid_managers())
+    }
+
+    /// Signs and sends an MPID message to the client with the given name. It is kept in our outbox
+    /// until the recipient deletes it. Returns the message's header.
+    pub fn send_mpid_message(&self,
+                             recipient: XorName,
+                             metadata: Vec<u8>,
+                             body: Vec<u8>)
+                             -> Result<MpidHeader, InterfaceError> {
+        let message = try!(MpidMessage::new(*self.full_id.public_id().name(),
+                                            metadata,
+                                            recipient,
+                                            body,
+                                            self.full_id.signing_private_key()));
+        let header = message.header().clone();
+        try!(self.send_mpid(MpidMessageWrapper::PutMessage(message), self.mpid_managers()));
+        Ok(header)
+    }
+
+    /// Requests the headers of all messages in our outbox. They are returned in an
+    /// `Event::MpidOutboxHeaders`.
+    pub fn request_mpid_outbox_headers(&self) -> Result<(), InterfaceError> {
+        self.send_mpid(MpidMessageWrapper::GetOutboxHeaders, self.mpid_managers())
+    }
+
+    /// Requests the message with the given header via our MPID managers. It is returned in an
+    /// `Event::MpidMessageReceived`.
+    pub fn get_mpid_message(&self, header: MpidHeader) -> Result<(), InterfaceError> {
+        self.send_mpid(MpidMessageWrapper::GetMessage(header), self.mpid_managers())
+    }
+
+    /// Deletes the message with the given header: if we sent it, from our outbox, otherwise from
+    /// our inbox and the sender's outbox.
+    pub fn delete_mpid_message(&self, header: &MpidHeader) -> Result<(), InterfaceError> {
+        let name = try!(header.name());
+        try!(self.send_mpid(MpidMessageWrapper::DeleteMessage(name), self.mpid_managers()));
+        if header.sender() == self.full_id.public_id().name() {
+            return Ok(());
+        }
+        let dst = Authority::ClientManager(*header.sender());
+        self.send_mpid(MpidMessageWrapper::DeleteHeader(name), dst)
+    }
+
+    /// Returns the headers of the MPID messages we were notified about and haven't deleted yet.
+    pub fn mpid_inbox(&self) -> Result<Vec<MpidHeader>, InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::MpidInbox { result_tx: result_tx }));
+
+        self.receive_action_result(&result_rx)
+    }
+
+    /// Returns the headers of the MPID messages we sent which, as far as we know, haven't been
+    /// deleted yet. `request_mpid_outbox_headers` updates this from our managers.
+    pub fn mpid_outbox(&self) -> Result<Vec<MpidHeader>, InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::MpidOutbox { result_tx: result_tx }));
+
+        self.receive_action_result(&result_rx)
+    }
+
+    fn mpid_managers(&self) -> Authority {
+        Authority::ClientManager(*self.full_id.public_id().name())
+    }
+
+    fn send_mpid(&self, content: MpidMessageWrapper, dst: Authority) -> Result<(), InterfaceError> {
+        try!(self.dispatch(Action::ClientSendMpid {
+            content: content,
+            dst: dst,
+            result_tx: self.interface_result_tx.clone(),
+        }));
+
+        try!(self.receive_action_result(&self.interface_result_rx))
+    }
+
     fn send_action(&self,
                    content: Request,
                    dst: Authority,
@@ -166,13 +295,38 @@ impl Client {
             result_tx: self.interface_result_tx.clone(),
         };
 
-        try!(self.action_sender.send(action));
+        try!(self.dispatch(action));
         try!(self.receive_action_result(&self.interface_result_rx))
     }
 
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn dispatch(&self, action: Action) -> Result<(), InterfaceError> {
+        self.driver.dispatch(&self.action_sender, action)
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    fn dispatch(&self, action: Action) -> Result<(), InterfaceError> {
+        Ok(try!(self.action_sender.send(action)))
+    }
+
     #[cfg(not(feature = "use-mock-crust"))]
     fn receive_action_result<T>(&self, rx: &Receiver<T>) -> Result<T, InterfaceError> {
-        Ok(try!(rx.recv()))
+        self.driver.receive(rx)
+    }
+}
+
+#[cfg(not(feature = "use-mock-crust"))]
+impl Client {
+    /// Processes one pending event, if the client was created with `Client::new_embedded`.
+    /// Returns `false` if there was none, or if the client has terminated. Never blocks.
+    pub fn poll(&self) -> bool {
+        self.driver.poll()
+    }
+
+    /// Like `poll`, but if no event is pending, waits up to `timeout` for one. This lets an
+    /// application that has nothing else to do sleep until the client needs attention.
+    pub fn poll_timeout(&self, timeout: Duration) -> bool {
+        self.driver.poll_timeout(timeout)
     }
 }
 
@@ -181,13 +335,15 @@ impl Client {
     /// Create a new `Client` for unit testing.
     pub fn new(event_sender: Sender<Event>, keys: Option<FullId>) -> Result<Client, RoutingError> {
         // start the handler for routing with a restriction to become a full node
-        let (action_sender, machine) = Self::make_state_machine(event_sender, keys);
+        let full_id = keys.unwrap_or_else(FullId::new);
+        let (action_sender, machine) = Self::make_state_machine(event_sender, full_id.clone());
         let (tx, rx) = channel();
 
         Ok(Client {
             interface_result_tx: tx,
             interface_result_rx: rx,
             action_sender: action_sender,
+            full_id: full_id,
             machine: RefCell::new(machine),
         })
     }
@@ -215,7 +371,7 @@ impl Client {
 
 impl Drop for Client {
     fn drop(&mut self) {
-        if let Err(err) = self.action_sender.send(Action::Terminate) {
+        if let Err(err) = self.dispatch(Action::Terminate) {
             debug!("Error {:?} sending event to Core", err);
         }
     }
@@ -0,0 +1,91 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+pub use self::implementation::Clock;
+
+#[cfg(not(feature = "use-mock-crust"))]
+mod implementation {
+    use std::time::Instant;
+    use utils;
+
+    /// The source of the current time for all time-dependent components. This reads the system
+    /// clock.
+    #[derive(Clone, Debug, Default)]
+    pub struct Clock;
+
+    impl Clock {
+        /// Creates a new clock.
+        pub fn new() -> Self {
+            Clock
+        }
+
+        /// Returns the current time.
+        pub fn now(&self) -> Instant {
+            Instant::now()
+        }
+
+        /// Returns the current time in milliseconds since the Unix epoch.
+        pub fn unix_time_millis(&self) -> u64 {
+            utils::unix_time_millis()
+        }
+    }
+}
+
+#[cfg(feature = "use-mock-crust")]
+mod implementation {
+    use mock_crust::{self, Network};
+    use std::fmt::{self, Debug, Formatter};
+    use std::time::Instant;
+    use utils;
+
+    /// Mock clock. If it was created inside `mock_crust::make_current`, it follows the virtual time
+    /// of the mock network, which only advances when the test calls `Network::advance_time`.
+    /// Otherwise, e.g. if created with `Clock::default()`, it reads the system clock.
+    #[derive(Clone, Default)]
+    pub struct Clock {
+        network: Option<Network>,
+    }
+
+    impl Clock {
+        pub fn new() -> Self {
+            Clock { network: Some(mock_crust::get_current_network()) }
+        }
+
+        pub fn now(&self) -> Instant {
+            self.network.as_ref().map_or_else(Instant::now, Network::now)
+        }
+
+        pub fn unix_time_millis(&self) -> u64 {
+            self.network.as_ref().map_or_else(utils::unix_time_millis, Network::unix_time_millis)
+        }
+
+        /// Returns the mock network whose virtual time this clock follows, if any.
+        pub fn network(&self) -> Option<&Network> {
+            self.network.as_ref()
+        }
+    }
+
+    impl Debug for Clock {
+        fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+            if self.network.is_some() {
+                write!(formatter, "Clock(virtual)")
+            } else {
+                write!(formatter, "Clock(system)")
+            }
+        }
+    }
+}
@@ -18,24 +18,34 @@
 use authority::Authority;
 use cache::{Cache, NullCache};
 use client::Client;
+use clock::Clock;
 use data::{Data, DataIdentifier, ImmutableData};
+use error::InterfaceError;
 use event::Event;
-use id::FullId;
+use id::{FullId, PublicId};
 use itertools::Itertools;
-use messages::{Request, Response};
+use maidsafe_utilities::event_sender::MaidSafeEventCategory;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use messages::{BootstrapDenyReason, ContactAddr, DirectMessage, HopMessage, Message,
+               MessageContent, Request, Response, RoutingMessage, SignedMessage,
+               client_identify_signed_bytes};
+use messaging::MpidManager;
 use mock_crust::{self, Config, Endpoint, Network, ServiceHandle};
-use mock_crust::crust::PeerId;
+use mock_crust::crust::{self, CrustEventSender, PeerId, PubConnectionInfo, Service};
 use node::Node;
-use peer_manager::{MIN_GROUP_SIZE, QUORUM_SIZE};
+use peer_manager::{DEFAULT_MAX_CLIENTS, MIN_GROUP_SIZE, QUORUM_SIZE};
 use rand::{self, Rng, SeedableRng, XorShiftRng};
 use rand::distributions::{IndependentSample, Range};
 use routing_table::{RoutingTable, Xorable};
+use rust_sodium::crypto::{box_, sign};
+use session::Sessions;
 use std::cell::RefCell;
 use std::cmp;
 use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ops;
 use std::sync::mpsc;
 use std::thread;
+use std::time::Duration;
 use types::MessageId;
 use xor_name::XorName;
 
@@ -132,6 +142,7 @@ impl TestNode {
             config: None,
             endpoint: None,
             cache: Box::new(NullCache),
+            max_clients: DEFAULT_MAX_CLIENTS,
         }
     }
 
@@ -139,12 +150,17 @@ impl TestNode {
            first_node: bool,
            config: Option<Config>,
            endpoint: Option<Endpoint>,
-           cache: Box<Cache>)
+           cache: Box<Cache>,
+           max_clients: usize)
            -> Self {
         let (event_tx, event_rx) = mpsc::channel();
         let handle = network.new_service_handle(config, endpoint);
         let node = mock_crust::make_current(&handle, || {
-            unwrap!(Node::builder().cache(cache).first(first_node).create(event_tx))
+            unwrap!(Node::builder()
+                .cache(cache)
+                .first(first_node)
+                .max_clients(max_clients)
+                .create(event_tx))
         });
 
         TestNode {
@@ -184,6 +200,7 @@ struct TestNodeBuilder<'a> {
     config: Option<Config>,
     endpoint: Option<Endpoint>,
     cache: Box<Cache>,
+    max_clients: usize,
 }
 
 impl<'a> TestNodeBuilder<'a> {
@@ -212,12 +229,18 @@ impl<'a> TestNodeBuilder<'a> {
         self
     }
 
+    fn max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
     fn create(self) -> TestNode {
         TestNode::new(self.network,
                       self.first_node,
                       self.config,
                       self.endpoint,
-                      self.cache)
+                      self.cache,
+                      self.max_clients)
     }
 }
 
@@ -360,7 +383,7 @@ fn drop_node(nodes: &mut Vec<TestNode>, index: usize) {
     for node in nodes.iter().filter(|n| close_names.contains(&n.name())) {
         loop {
             match node.event_rx.try_recv() {
-                Ok(Event::NodeLost(lost_name, _)) if lost_name == name => break,
+                Ok(Event::NodeLost(lost_name, ..)) if lost_name == name => break,
                 Ok(_) => (),
                 _ => panic!("Event::NodeLost({:?}) not received", name),
             }
@@ -483,7 +506,7 @@ fn did_receive_get_request(node: &TestNode,
                            -> bool {
     loop {
         match node.event_rx.try_recv() {
-            Ok(Event::Request { request: Request::Get(data_id, message_id), ref src, ref dst })
+            Ok(Event::Request { request: Request::Get(data_id, message_id), ref src, ref dst, .. })
                 if *src == expected_src && *dst == expected_dst && data_id == expected_data_id &&
                    message_id == expected_message_id => return true,
             Ok(_) => (),
@@ -506,13 +529,147 @@ fn did_receive_get_success(node: &TestNode,
         match node.event_rx.try_recv() {
             Ok(Event::Response { response: Response::GetSuccess(ref data, message_id),
                                  ref src,
-                                 ref dst }) if expected(src, dst, data, message_id) => return true,
+                                 ref dst,
+                                 .. }) if expected(src, dst, data, message_id) => return true,
             Ok(_) => (),
             Err(_) => return false,
         }
     }
 }
 
+// A bare crust service, to exchange raw messages with a node.
+struct RawService {
+    service: Service,
+    endpoint: Endpoint,
+    event_rx: mpsc::Receiver<crust::Event>,
+    _category_rx: mpsc::Receiver<MaidSafeEventCategory>,
+    sessions: Sessions,
+}
+
+impl RawService {
+    fn bootstrap(network: &Network, contact: Endpoint) -> Self {
+        let handle = network.new_service_handle(Some(Config::with_contacts(&[contact])), None);
+        let (event_tx, event_rx) = mpsc::channel();
+        let (category_tx, category_rx) = mpsc::channel();
+        let event_sender =
+            CrustEventSender::new(event_tx, MaidSafeEventCategory::Crust, category_tx);
+        let mut service = unwrap!(Service::with_handle(&handle, event_sender));
+        unwrap!(service.start_bootstrap(HashSet::new()));
+        RawService {
+            service: service,
+            endpoint: handle.endpoint(),
+            event_rx: event_rx,
+            _category_rx: category_rx,
+            sessions: Sessions::new(true, Clock::default()),
+        }
+    }
+
+    // Returns the next direct message we received, with its sender.
+    fn next_direct_message(&self) -> (PeerId, DirectMessage) {
+        loop {
+            if let crust::Event::NewMessage(peer_id, bytes) = unwrap!(self.event_rx.try_recv()) {
+                match unwrap!(deserialise(&bytes)) {
+                    Message::Direct(direct_message) => return (peer_id, direct_message),
+                    message => panic!("Expected a direct message, got {:?}", message),
+                }
+            }
+        }
+    }
+
+    // Returns the next `BootstrapChallenge` we received, with its sender.
+    fn next_bootstrap_challenge(&self) -> (PeerId, u64) {
+        match self.next_direct_message() {
+            (peer_id, DirectMessage::BootstrapChallenge(challenge)) => (peer_id, challenge),
+            (_, message) => panic!("Expected BootstrapChallenge, got {:?}", message),
+        }
+    }
+
+    // Returns the content of the next `BootstrapDeny` we received.
+    fn next_bootstrap_deny(&self) -> (BootstrapDenyReason, Vec<ContactAddr>) {
+        match self.next_direct_message() {
+            (_, DirectMessage::BootstrapDeny { reason, contacts }) => (reason, contacts),
+            (_, message) => panic!("Expected BootstrapDeny, got {:?}", message),
+        }
+    }
+
+    // Returns the ID of the node that accepted our `ClientIdentify`.
+    fn next_bootstrap_identify(&self) -> PublicId {
+        match self.next_direct_message() {
+            (_, DirectMessage::BootstrapIdentify { public_id, .. }) => public_id,
+            (_, message) => panic!("Expected BootstrapIdentify, got {:?}", message),
+        }
+    }
+
+    // Returns whether any of the messages we received since the last call was a `NodeIdentify`.
+    fn received_node_identify(&self) -> bool {
+        let mut result = false;
+        while let Ok(event) = self.event_rx.try_recv() {
+            if let crust::Event::NewMessage(_, bytes) = event {
+                if let Message::Direct(DirectMessage::NodeIdentify { .. }) =
+                       unwrap!(deserialise(&bytes)) {
+                    result = true;
+                }
+            }
+        }
+        result
+    }
+
+    // Returns a serialised `ClientIdentify` answering the given node's challenge.
+    fn client_identify(&mut self,
+                       full_id: &FullId,
+                       node_id: PeerId,
+                       challenge: u64,
+                       client_restriction: bool)
+                       -> Vec<u8> {
+        let serialised_public_id = unwrap!(serialise(full_id.public_id()));
+        let session_key = self.sessions.session_key(&node_id, full_id);
+        let signed_bytes = unwrap!(client_identify_signed_bytes(&serialised_public_id,
+                                                                challenge,
+                                                                &self.service.id(),
+                                                                &session_key));
+        unwrap!(serialise(&Message::Direct(DirectMessage::ClientIdentify {
+            serialised_public_id: serialised_public_id,
+            signature: sign::sign_detached(&signed_bytes, full_id.signing_private_key()),
+            client_restriction: client_restriction,
+            session_key: session_key,
+        })))
+    }
+
+    // Returns a serialised `ConnectionInfo` from the joining node `full_id` to its proxy node
+    // `proxy_id`, with the given solution to its resource proof challenge.
+    fn connection_info(&self,
+                       full_id: &FullId,
+                       proxy_id: &PublicId,
+                       resource_proof: Option<u64>,
+                       clock: &Clock)
+                       -> Vec<u8> {
+        let connection_info = PubConnectionInfo(self.service.id(), self.endpoint);
+        let nonce = box_::gen_nonce();
+        let encrypted_connection_info = box_::seal(&unwrap!(serialise(&connection_info)),
+                                                   &nonce,
+                                                   proxy_id.encrypting_public_key(),
+                                                   full_id.encrypting_private_key());
+        let routing_msg = RoutingMessage {
+            src: Authority::Client {
+                client_key: *full_id.public_id().signing_public_key(),
+                proxy_node_name: *proxy_id.name(),
+                peer_id: self.service.id(),
+            },
+            dst: Authority::ManagedNode(*proxy_id.name()),
+            content: MessageContent::ConnectionInfo {
+                encrypted_connection_info: encrypted_connection_info,
+                nonce_bytes: nonce.0,
+                public_id: *full_id.public_id(),
+                resource_proof: resource_proof,
+            },
+        };
+        let signed_msg = unwrap!(SignedMessage::new(routing_msg, full_id, clock));
+        let hop_msg =
+            unwrap!(HopMessage::new(signed_msg, 0, vec![], full_id.signing_private_key()));
+        unwrap!(serialise(&Message::Hop(hop_msg)))
+    }
+}
+
 fn test_nodes(size: usize) {
     let network = Network::new(None);
     let nodes = create_connected_nodes(&network, size);
@@ -574,6 +731,77 @@ fn disconnect_on_rebootstrap() {
     expect_next_event!(unwrap!(nodes.last()), Event::Terminate);
 }
 
+#[test]
+fn timeouts_follow_virtual_time() {
+    let network = Network::new(None);
+    let mut nodes = create_connected_nodes(&network, 3);
+    // Nothing times out before the first tick, which is due after 60 seconds.
+    network.advance_time(Duration::from_secs(59));
+    let _ = poll_all(&mut nodes, &mut []);
+    for node in &nodes {
+        assert!(node.event_rx.try_recv().is_err());
+    }
+    // The tick repeats, and the answered `GetNodeName` request doesn't time out.
+    for _ in 0..2 {
+        network.advance_time(Duration::from_secs(60));
+        let _ = poll_all(&mut nodes, &mut []);
+        for node in &nodes {
+            expect_next_event!(node, Event::Tick);
+            assert!(node.event_rx.try_recv().is_err());
+        }
+    }
+}
+
+#[test]
+fn graceful_shutdown() {
+    let network = Network::new(None);
+    let mut rng = network.new_rng();
+    let mut nodes = create_connected_nodes(&network, 4);
+    let leaving_name = nodes[3].name();
+
+    unwrap!(nodes[3].inner.shutdown(Duration::from_secs(10)));
+    let src = Authority::ManagedNode(leaving_name);
+    let dst = Authority::ManagedNode(nodes[0].name());
+    let data_id = gen_immutable_data(&mut rng, 8).identifier();
+    match nodes[3].inner.send_get_request(src, dst, data_id, MessageId::new()) {
+        Err(InterfaceError::InvalidState) => (),
+        result => panic!("Expected InvalidState, got {:?}", result),
+    }
+
+    // With nothing left to be acknowledged, the node leaves right away and its peers drop it
+    // without waiting for the connection to be lost.
+    let _ = poll_all(&mut nodes, &mut []);
+    for node in &nodes[..3] {
+        assert!(node.inner.routing_table().iter().all(|name| *name != leaving_name));
+    }
+
+    // It terminates once the `Leave` messages had time to be sent.
+    assert!(nodes[3].event_rx.try_recv().is_err());
+    network.advance_time(Duration::from_secs(1));
+    let _ = poll_all(&mut nodes, &mut []);
+    expect_next_event!(nodes[3], Event::Terminate);
+}
+
+#[test]
+fn proxy_leaves_during_join() {
+    let network = Network::new(None);
+    let mut nodes = create_connected_nodes(&network, 1);
+    let config = Config::with_contacts(&[nodes[0].handle.endpoint()]);
+    nodes.push(TestNode::builder(&network).config(config).endpoint(Endpoint(1)).create());
+
+    // Let the new node bootstrap, but not get relocated yet.
+    while !nodes[1].inner.is_node() {
+        let _ = nodes[0].inner.poll();
+        let _ = nodes[1].inner.poll();
+    }
+
+    // Once its proxy leaves, the joining node can't complete the join, so it terminates.
+    unwrap!(nodes[0].inner.shutdown(Duration::from_secs(10)));
+    let _ = nodes[1].poll();
+    expect_next_event!(nodes[1], Event::Terminate);
+    assert!(!nodes[1].inner.is_node());
+}
+
 #[test]
 #[ignore]
 fn less_than_group_size_nodes() {
@@ -838,6 +1066,56 @@ fn node_restart() {
     verify_invariant_for_all_nodes(&nodes);
 }
 
+#[test]
+#[ignore]
+fn partition_and_heal() {
+    let network = Network::new(None);
+    let size = MIN_GROUP_SIZE + 4;
+    let mut nodes = create_connected_nodes(&network, size);
+    let config = Config::with_contacts(&[nodes[0].handle.endpoint()]);
+
+    // Cut the network in half. Both halves lose too many contacts to keep running normally, but
+    // they should notice the partition and wait for it to heal instead of restarting.
+    let side = nodes[size / 2..].iter().map(|node| node.handle.endpoint()).collect_vec();
+    network.partition(&side);
+    network.poll();
+    let _ = poll_all(&mut nodes, &mut []);
+
+    for node in &nodes {
+        assert!(node.inner.is_partitioned(),
+                "{:?} didn't detect the partition.",
+                node.name());
+        assert_eq!(size / 2 - 1, node.routing_table().len());
+        while let Ok(event) = node.event_rx.try_recv() {
+            if let Event::RestartRequired = event {
+                panic!("{:?} requested a restart while partitioned.", node.name());
+            }
+        }
+    }
+
+    // Joining nodes are not relocated while the partition lasts.
+    nodes.push(TestNode::builder(&network).config(config).create());
+    let _ = poll_all(&mut nodes, &mut []);
+    assert!(unwrap!(nodes.last()).routing_table().is_empty());
+    drop(nodes.pop());
+    let _ = poll_all(&mut nodes, &mut []);
+
+    // Once healed, the halves find each other via the bootstrap contact and exchange routing
+    // tables until everyone is reconnected.
+    network.heal_partitions();
+    for node in &nodes {
+        node.inner.retry_lost_contacts();
+    }
+    let _ = poll_all(&mut nodes, &mut []);
+
+    for node in &nodes {
+        assert!(!node.inner.is_partitioned(),
+                "{:?} is still partitioned.",
+                node.name());
+        assert_eq!(size - 1, node.routing_table().len());
+    }
+}
+
 #[test]
 #[ignore]
 fn check_close_groups_for_group_size_nodes() {
@@ -870,6 +1148,120 @@ fn whitelist() {
     expect_next_event!(clients[0], Event::Connected);
 }
 
+#[test]
+fn rebootstrap_via_redirect_contacts() {
+    let network = Network::new(None);
+    let mut nodes = create_connected_nodes(&network, MIN_GROUP_SIZE);
+    let config = Config::with_contacts(&[nodes[0].handle.endpoint()]);
+    nodes.push(TestNode::builder(&network).config(config).max_clients(0).create());
+    let _ = poll_all(&mut nodes, &mut []);
+
+    // A client that only knows the node which doesn't accept clients is denied, and bootstraps
+    // against one of the contacts it suggests instead.
+    let config = Config::with_contacts(&[unwrap!(nodes.last()).handle.endpoint()]);
+    let mut clients = vec![TestClient::new(&network, Some(config), None)];
+    let _ = poll_all(&mut nodes, &mut clients);
+    expect_next_event!(clients[0],
+                       Event::BootstrapDenied(BootstrapDenyReason::ClientQuotaReached));
+    expect_next_event!(clients[0], Event::Connected);
+}
+
+// Connects a client to the node at `proxy`, then checks that another one is turned away and given
+// the addresses of other nodes to bootstrap against instead.
+fn check_second_client_redirected(network: &Network, nodes: &mut [TestNode], proxy: usize) {
+    let config = Config::with_contacts(&[nodes[proxy].handle.endpoint()]);
+    let mut clients = vec![TestClient::new(network, Some(config), None)];
+    let _ = poll_all(nodes, &mut clients);
+    expect_next_event!(clients[0], Event::Connected);
+
+    let mut raw = RawService::bootstrap(network, nodes[proxy].handle.endpoint());
+    let _ = poll_all(nodes, &mut clients);
+    let (node_id, challenge) = raw.next_bootstrap_challenge();
+    let client_identify = raw.client_identify(&FullId::new(), node_id, challenge, true);
+    unwrap!(raw.service.send(node_id, client_identify, 0));
+    let _ = poll_all(nodes, &mut clients);
+    let (reason, contacts) = raw.next_bootstrap_deny();
+    assert_eq!(reason, BootstrapDenyReason::ClientQuotaReached);
+    assert!(!contacts.is_empty());
+}
+
+#[test]
+fn client_limit_redirects_clients() {
+    let network = Network::new(None);
+    let mut nodes = create_connected_nodes(&network, MIN_GROUP_SIZE);
+    let config = Config::with_contacts(&[nodes[0].handle.endpoint()]);
+    nodes.push(TestNode::builder(&network).config(config).max_clients(1).create());
+    let _ = poll_all(&mut nodes, &mut []);
+    let proxy = nodes.len() - 1;
+    check_second_client_redirected(&network, &mut nodes, proxy);
+}
+
+// Lets a joining node that never solved a resource proof challenge send its connection info to a
+// first node with the given difficulty. Returns whether the node accepted it.
+fn accepts_connection_info_without_proof(difficulty: u8) -> bool {
+    let network = Network::new(None);
+    let handle = network.new_service_handle(None, None);
+    let (event_tx, _event_rx) = mpsc::channel();
+    let node = mock_crust::make_current(&handle, || {
+        unwrap!(Node::builder()
+            .first(true)
+            .encrypt_connections(false)
+            .resource_proof_difficulty(difficulty)
+            .create(event_tx))
+    });
+    let clock = mock_crust::make_current(&handle, Clock::new);
+    let full_id = FullId::new();
+    let mut raw = RawService::bootstrap(&network, handle.endpoint());
+    while node.poll() {}
+    let (node_id, challenge) = raw.next_bootstrap_challenge();
+    let client_identify = raw.client_identify(&full_id, node_id, challenge, false);
+    unwrap!(raw.service.send(node_id, client_identify, 0));
+    while node.poll() {}
+    let node_public_id = raw.next_bootstrap_identify();
+
+    let connection_info = raw.connection_info(&full_id, &node_public_id, None, &clock);
+    unwrap!(raw.service.send(node_id, connection_info, 0));
+    while node.poll() {}
+    raw.received_node_identify()
+}
+
+#[test]
+fn joining_node_without_resource_proof_is_refused() {
+    assert!(accepts_connection_info_without_proof(0));
+    assert!(!accepts_connection_info_without_proof(1));
+}
+
+#[test]
+fn client_identify_is_bound_to_connection() {
+    let network = Network::new(None);
+    let handle = network.new_service_handle(None, None);
+    let (event_tx, _event_rx) = mpsc::channel();
+    let node = mock_crust::make_current(&handle, || {
+        unwrap!(Node::builder().first(true).encrypt_connections(false).create(event_tx))
+    });
+    let mut raw_a = RawService::bootstrap(&network, handle.endpoint());
+    let raw_b = RawService::bootstrap(&network, handle.endpoint());
+    while node.poll() {}
+    let (node_id, challenge) = raw_a.next_bootstrap_challenge();
+    let _ = raw_b.next_bootstrap_challenge();
+    let client_identify = raw_a.client_identify(&FullId::new(), node_id, challenge, true);
+
+    // The `ClientIdentify` signed for A's connection is rejected on B's.
+    unwrap!(raw_b.service.send(node_id, client_identify.clone(), 0));
+    while node.poll() {}
+    assert!(!raw_b.service.is_connected(&node_id));
+
+    // On A's, it is accepted. The network is too small for clients yet, though.
+    unwrap!(raw_a.service.send(node_id, client_identify.clone(), 0));
+    while node.poll() {}
+    assert_eq!(raw_a.next_bootstrap_deny().0, BootstrapDenyReason::NetworkTooSmall);
+
+    // Replaying it on A's connection fails, as the challenge has been used up.
+    unwrap!(raw_a.service.send(node_id, client_identify, 0));
+    while node.poll() {}
+    assert_eq!(raw_a.next_bootstrap_deny().0, BootstrapDenyReason::ProtocolMismatch);
+}
+
 #[test]
 #[ignore]
 fn successful_put_request() {
@@ -1098,6 +1490,31 @@ fn disconnect_on_get_request() {
     }
 }
 
+#[test]
+#[ignore]
+fn key_rotation() {
+    let network = Network::new(None);
+    let mut rng = network.new_rng();
+    let mut nodes = create_connected_nodes(&network, 2 * MIN_GROUP_SIZE);
+
+    for node in &nodes {
+        node.inner.rotate_keys();
+    }
+    let _ = poll_all(&mut nodes, &mut []);
+
+    // Messages signed with the new keys are relayed and accepted.
+    let index = nodes.len() - 1;
+    let src = Authority::ManagedNode(nodes[0].name());
+    let dst = Authority::ManagedNode(nodes[index].name());
+    let data_id = gen_immutable_data(&mut rng, 8).identifier();
+    let message_id = MessageId::new();
+
+    unwrap!(nodes[0].inner.send_get_request(src.clone(), dst.clone(), data_id, message_id));
+
+    poll_and_resend(&mut nodes, &mut []);
+    assert!(did_receive_get_request(&nodes[index], src, dst, data_id, message_id));
+}
+
 const REQUEST_DURING_CHURN_ITERATIONS: usize = 10;
 
 #[test]
@@ -1343,7 +1760,8 @@ fn response_caching() {
             match node.event_rx.try_recv() {
                 Ok(Event::Request { request: Request::Get(req_data_id, req_message_id),
                                     src: req_src,
-                                    dst: req_dst }) => {
+                                    dst: req_dst,
+                                    .. }) => {
                     if req_data_id == data_id && req_message_id == message_id {
                         unwrap!(node.inner.send_get_success(req_dst,
                                                             req_src,
@@ -1406,3 +1824,66 @@ fn response_caching() {
         expect_no_event!(node);
     }
 }
+
+// Polls all nodes and clients, and lets each node's MPID manager handle the requests it receives,
+// until there is nothing left to do.
+fn poll_and_handle_mpid(nodes: &mut [TestNode],
+                        managers: &mut [MpidManager],
+                        clients: &mut [TestClient]) {
+    loop {
+        let _ = poll_all(nodes, clients);
+        let mut handled = false;
+        for (node, manager) in nodes.iter().zip(managers.iter_mut()) {
+            while let Ok(event) = node.event_rx.try_recv() {
+                if let Event::Request { request, src, dst, .. } = event {
+                    assert!(unwrap!(manager.handle_request(&node.inner, &src, &dst, &request)));
+                    handled = true;
+                }
+            }
+        }
+        if !handled {
+            return;
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn mpid_messaging() {
+    let network = Network::new(None);
+    let mut nodes = create_connected_nodes(&network, MIN_GROUP_SIZE + 1);
+    let mut managers = nodes.iter().map(|_| MpidManager::new()).collect_vec();
+    let mut clients = create_connected_clients(&network, &mut nodes, 2);
+    let recipient = clients[1].name();
+    let header =
+        unwrap!(clients[0].inner.send_mpid_message(recipient, b"subject".to_vec(), vec![1, 2, 3]));
+    poll_and_handle_mpid(&mut nodes, &mut managers, &mut clients);
+
+    // The message is in the sender's outbox.
+    unwrap!(clients[0].inner.request_mpid_outbox_headers());
+    poll_and_handle_mpid(&mut nodes, &mut managers, &mut clients);
+    expect_any_event!(clients[0],
+                      Event::MpidOutboxHeaders(ref headers) if *headers == vec![header.clone()]);
+    assert_eq!(unwrap!(clients[0].inner.mpid_outbox()), vec![header.clone()]);
+
+    // The recipient was offline, and receives the header once it comes online.
+    unwrap!(clients[1].inner.send_mpid_online());
+    poll_and_handle_mpid(&mut nodes, &mut managers, &mut clients);
+    expect_any_event!(clients[1], Event::MpidHeaderReceived(ref received) if *received == header);
+    assert_eq!(unwrap!(clients[1].inner.mpid_inbox()), vec![header.clone()]);
+
+    // The recipient can fetch it from the sender's managers.
+    unwrap!(clients[1].inner.get_mpid_message(header.clone()));
+    poll_and_handle_mpid(&mut nodes, &mut managers, &mut clients);
+    expect_any_event!(clients[1],
+                      Event::MpidMessageReceived(ref message) if *message.header() == header &&
+                                                                 message.body()[..] == [1u8, 2, 3]);
+
+    // Once the recipient deletes it, it is gone from the sender's outbox.
+    unwrap!(clients[1].inner.delete_mpid_message(&header));
+    poll_and_handle_mpid(&mut nodes, &mut managers, &mut clients);
+    unwrap!(clients[0].inner.request_mpid_outbox_headers());
+    poll_and_handle_mpid(&mut nodes, &mut managers, &mut clients);
+    expect_any_event!(clients[0], Event::MpidOutboxHeaders(ref headers) if headers.is_empty());
+    assert!(unwrap!(clients[0].inner.mpid_outbox()).is_empty());
+}
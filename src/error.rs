@@ -20,6 +20,7 @@ use crust::{self, PeerId};
 use event::Event;
 use maidsafe_utilities::event_sender::{EventSenderError, MaidSafeEventCategory};
 use maidsafe_utilities::serialisation;
+use messaging::Error as MpidError;
 use std::sync::mpsc::{RecvError, SendError};
 use super::routing_table::Error as RoutingTableError;
 
@@ -34,6 +35,8 @@ pub enum InterfaceError {
     ChannelRxError(RecvError),
     /// Error while trying to transmit an event via a channel
     EventSenderError(EventSenderError<MaidSafeEventCategory, Action>),
+    /// Error while creating or serialising an MPID message
+    Mpid(MpidError),
 }
 
 impl From<EventSenderError<MaidSafeEventCategory, Action>> for InterfaceError {
@@ -42,6 +45,12 @@ impl From<EventSenderError<MaidSafeEventCategory, Action>> for InterfaceError {
     }
 }
 
+impl From<MpidError> for InterfaceError {
+    fn from(error: MpidError) -> InterfaceError {
+        InterfaceError::Mpid(error)
+    }
+}
+
 impl From<RecvError> for InterfaceError {
     fn from(error: RecvError) -> InterfaceError {
         InterfaceError::ChannelRxError(error)
@@ -93,6 +102,10 @@ pub enum RoutingError {
     SerialisationError(serialisation::SerialisationError),
     /// Asymmetric Decryption Failure
     AsymmetricDecryptionFailure,
+    /// Failed to decrypt a message from a peer we have an encrypted session with
+    SymmetricDecryptionFailure,
+    /// A peer didn't send a session key or an encrypted message, although we require encryption
+    EncryptionRequired,
     /// Unknown Connection
     UnknownConnection(PeerId),
     /// Invalid Destination
@@ -107,6 +120,10 @@ pub enum RoutingError {
     CannotTunnelThroughTunnel,
     /// Decoded a user message with an unexpected hash.
     HashMismatch,
+    /// A joining node didn't solve its resource proof challenge.
+    FailedResourceProof,
+    /// A signed message or an encrypted frame was received twice or is outside the replay window.
+    ReplayedMessage,
 }
 
 impl From<RoutingTableError> for RoutingError {
@@ -16,8 +16,10 @@
 // relating to use of the SAFE Network Software.
 
 use authority::Authority;
-use messages::{Request, Response};
-use routing_table::RoutingTable;
+use group_proof::GroupProof;
+use messages::{BootstrapDenyReason, Request, Response};
+use messaging::{MpidHeader, MpidMessage};
+use routing_table::{GroupChange, RoutingTable};
 use std::fmt::{self, Debug, Formatter};
 use xor_name::XorName;
 
@@ -38,6 +40,8 @@ pub enum Event {
         src: Authority,
         /// The destination authority that receives the request.
         dst: Authority,
+        /// If the source is a group authority, the signatures of the members that sent it.
+        proof: Option<GroupProof>,
     },
     /// Received a response message.
     Response {
@@ -47,16 +51,35 @@ pub enum Event {
         src: Authority,
         /// The destination authority that receives the response.
         dst: Authority,
+        /// If the source is a group authority, the signatures of the members that sent it.
+        proof: Option<GroupProof>,
     },
     /// A new node joined the network and may be a member of group authorities we also belong to.
-    NodeAdded(XorName, RoutingTable<XorName>),
+    ///
+    /// The `GroupChange` tells which names we became or stopped being responsible for, and which
+    /// nodes joined our group, so that only the affected data needs to be refreshed.
+    NodeAdded(XorName, RoutingTable<XorName>, GroupChange<XorName>),
     /// A node left the network and may have been a member of group authorities we also belong to.
-    NodeLost(XorName, RoutingTable<XorName>),
+    ///
+    /// The `GroupChange` tells which names we became or stopped being responsible for, and which
+    /// nodes left our group.
+    NodeLost(XorName, RoutingTable<XorName>, GroupChange<XorName>),
     /// The client has successfully connected to a proxy node on the network.
     Connected,
+    /// The client was notified of a new MPID message addressed to it. The message can be fetched
+    /// with `Client::get_mpid_message`.
+    MpidHeaderReceived(MpidHeader),
+    /// The client received an MPID message it requested with `Client::get_mpid_message`.
+    MpidMessageReceived(MpidMessage),
+    /// The client received the headers of messages in its outbox, i.e. which have not been deleted
+    /// by their recipients yet.
+    MpidOutboxHeaders(Vec<MpidHeader>),
+    /// A node refused to act as our proxy for the given reason. We try to bootstrap against
+    /// another one.
+    BootstrapDenied(BootstrapDenyReason),
     /// Disconnected or failed to connect - restart required.
     RestartRequired,
-    /// Startup failed - terminate.
+    /// Startup failed, or the node was shut down - terminate.
     Terminate,
     // TODO: Find a better solution for periodic tasks.
     /// This event is sent periodically every time Routing sends the `Heartbeat` messages.
@@ -66,29 +89,41 @@ pub enum Event {
 impl Debug for Event {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         match *self {
-            Event::Request { ref request, ref src, ref dst } => {
+            Event::Request { ref request, ref src, ref dst, .. } => {
                 write!(formatter,
                        "Event::Request {{ request: {:?}, src: {:?}, dst: {:?} }}",
                        request,
                        src,
                        dst)
             }
-            Event::Response { ref response, ref src, ref dst } => {
+            Event::Response { ref response, ref src, ref dst, .. } => {
                 write!(formatter,
                        "Event::Response {{ response: {:?}, src: {:?}, dst: {:?} }}",
                        response,
                        src,
                        dst)
             }
-            Event::NodeAdded(ref node_name, _) => {
+            Event::NodeAdded(ref node_name, ..) => {
                 write!(formatter,
                        "Event::NodeAdded({:?}, routing_table)",
                        node_name)
             }
-            Event::NodeLost(ref node_name, _) => {
+            Event::NodeLost(ref node_name, ..) => {
                 write!(formatter, "Event::NodeLost({:?}, routing_table)", node_name)
             }
             Event::Connected => write!(formatter, "Event::Connected"),
+            Event::MpidHeaderReceived(ref header) => {
+                write!(formatter, "Event::MpidHeaderReceived({:?})", header)
+            }
+            Event::MpidMessageReceived(ref message) => {
+                write!(formatter, "Event::MpidMessageReceived({:?})", message.header())
+            }
+            Event::MpidOutboxHeaders(ref headers) => {
+                write!(formatter, "Event::MpidOutboxHeaders({} headers)", headers.len())
+            }
+            Event::BootstrapDenied(reason) => {
+                write!(formatter, "Event::BootstrapDenied({:?})", reason)
+            }
             Event::RestartRequired => write!(formatter, "Event::RestartRequired"),
             Event::Terminate => write!(formatter, "Event::Terminate"),
             Event::Tick => write!(formatter, "Event::Tick"),
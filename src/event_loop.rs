@@ -0,0 +1,116 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+#[cfg(any(test, not(feature = "use-mock-crust")))]
+pub use self::implementation::Driver;
+
+/// Notifies an application's event loop that an embedded `Node` or `Client` has pending events.
+///
+/// See `NodeBuilder::create_embedded` and `Client::new_embedded`. When `wake` is called, the
+/// application should call `poll` on the node or client until it returns `false`, from the thread
+/// that owns it. `wake` itself is called from another thread, so it should only signal the event
+/// loop, e.g. by writing to a pipe or an eventfd the loop waits on, and return quickly.
+pub trait Waker: Send {
+    /// Signals that there are events to process.
+    fn wake(&self);
+}
+
+impl<F: Fn() + Send> Waker for F {
+    fn wake(&self) {
+        self()
+    }
+}
+
+#[cfg(any(test, not(feature = "use-mock-crust")))]
+mod implementation {
+    use action::Action;
+    use error::InterfaceError;
+    #[cfg(not(feature = "use-mock-crust"))]
+    use maidsafe_utilities::thread::Joiner;
+    use state_machine::StateMachine;
+    use std::cell::RefCell;
+    use std::sync::mpsc::{Receiver, RecvError};
+    use std::time::Duration;
+    use types::RoutingActionSender;
+
+    /// How the state machine of a `Node` or `Client` is run.
+    pub enum Driver {
+        /// On a dedicated thread, which blocks waiting for events.
+        #[cfg(not(feature = "use-mock-crust"))]
+        Thread(Joiner),
+        /// From the application's own event loop, by calling `poll` when woken.
+        EventLoop(RefCell<StateMachine>),
+    }
+
+    impl Driver {
+        /// Passes an action from the application to the state machine. If the application drives
+        /// the state machine, it is handled right away, so that its result is available when this
+        /// returns. Fails if the state machine has terminated, like sending to a finished thread.
+        #[cfg_attr(feature = "use-mock-crust", allow(unused_variables))]
+        pub fn dispatch(&self,
+                        action_sender: &RoutingActionSender,
+                        action: Action)
+                        -> Result<(), InterfaceError> {
+            match *self {
+                #[cfg(not(feature = "use-mock-crust"))]
+                Driver::Thread(_) => Ok(try!(action_sender.send(action))),
+                Driver::EventLoop(ref machine) => {
+                    if machine.borrow_mut().handle_action(action) {
+                        Ok(())
+                    } else {
+                        Err(InterfaceError::InvalidState)
+                    }
+                }
+            }
+        }
+
+        /// Returns the result of an action passed to `dispatch`. This only blocks if the state
+        /// machine runs on its own thread.
+        pub fn receive<T>(&self, rx: &Receiver<T>) -> Result<T, InterfaceError> {
+            match *self {
+                #[cfg(not(feature = "use-mock-crust"))]
+                Driver::Thread(_) => Ok(try!(rx.recv())),
+                Driver::EventLoop(_) => rx.try_recv().map_err(|_| InterfaceError::from(RecvError)),
+            }
+        }
+
+        /// Processes one pending event, if the application drives the state machine. Returns
+        /// `false` if there was none, or if the state machine has terminated. Never blocks.
+        pub fn poll(&self) -> bool {
+            match *self {
+                #[cfg(not(feature = "use-mock-crust"))]
+                Driver::Thread(_) => false,
+                Driver::EventLoop(ref machine) => {
+                    let mut machine = machine.borrow_mut();
+                    machine.is_running() && machine.poll()
+                }
+            }
+        }
+
+        /// Like `poll`, but waits up to `timeout` for an event if none is pending.
+        pub fn poll_timeout(&self, timeout: Duration) -> bool {
+            match *self {
+                #[cfg(not(feature = "use-mock-crust"))]
+                Driver::Thread(_) => false,
+                Driver::EventLoop(ref machine) => {
+                    let mut machine = machine.borrow_mut();
+                    machine.is_running() && machine.poll_timeout(timeout)
+                }
+            }
+        }
+    }
+}
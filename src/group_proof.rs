@@ -0,0 +1,237 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use authority::Authority;
+use error::RoutingError;
+use id::PublicId;
+use maidsafe_utilities::serialisation::serialise;
+use messages::{Request, Response, RoutingMessage, UserMessage};
+use rust_sodium::crypto::sign;
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Formatter};
+use xor_name::XorName;
+
+/// Proof that a quorum of a group authority's members sent a request or response.
+///
+/// Group members sign each part of a user message individually, either in full or as a hash. The
+/// proof collects these signatures, so the message can be stored or forwarded together with it,
+/// and anyone can later check that enough distinct members signed every part.
+///
+/// The proof does not show that the signers belonged to the source group: the verifier needs to
+/// check the `signers()` against its own knowledge of that group.
+#[derive(Clone, Eq, PartialEq, Hash, RustcEncodable, RustcDecodable)]
+pub struct GroupProof {
+    src: Authority,
+    dst: Authority,
+    priority: u8,
+    /// For each part of the message, the members' IDs and signatures.
+    part_signatures: Vec<Vec<(PublicId, sign::Signature)>>,
+}
+
+impl GroupProof {
+    /// Creates a proof for a message with the given authorities and priority, from the signatures
+    /// collected for each of its parts.
+    pub fn new(src: Authority,
+               dst: Authority,
+               priority: u8,
+               part_signatures: Vec<Vec<(PublicId, sign::Signature)>>)
+               -> GroupProof {
+        GroupProof {
+            src: src,
+            dst: dst,
+            priority: priority,
+            part_signatures: part_signatures,
+        }
+    }
+
+    /// The group authority that sent the message.
+    pub fn src(&self) -> &Authority {
+        &self.src
+    }
+
+    /// The authority the message was sent to.
+    pub fn dst(&self) -> &Authority {
+        &self.dst
+    }
+
+    /// The names of all members that signed at least one part of the message.
+    pub fn signers(&self) -> HashSet<XorName> {
+        self.part_signatures
+            .iter()
+            .flat_map(|signatures| signatures.iter().map(|&(ref pub_id, _)| *pub_id.name()))
+            .collect()
+    }
+
+    /// Verifies that at least `quorum_size` distinct members signed every part of `request`.
+    pub fn verify_request(&self,
+                          request: &Request,
+                          quorum_size: usize)
+                          -> Result<(), RoutingError> {
+        self.verify(&UserMessage::Request(request.clone()), quorum_size)
+    }
+
+    /// Verifies that at least `quorum_size` distinct members signed every part of `response`.
+    pub fn verify_response(&self,
+                           response: &Response,
+                           quorum_size: usize)
+                           -> Result<(), RoutingError> {
+        self.verify(&UserMessage::Response(response.clone()), quorum_size)
+    }
+
+    fn verify(&self, user_msg: &UserMessage, quorum_size: usize) -> Result<(), RoutingError> {
+        let parts = try!(user_msg.to_parts(self.priority));
+        if parts.len() != self.part_signatures.len() {
+            return Err(RoutingError::FailedSignature);
+        }
+
+        for (content, signatures) in parts.into_iter().zip(&self.part_signatures) {
+            let msg = RoutingMessage {
+                src: self.src.clone(),
+                dst: self.dst.clone(),
+                content: content,
+            };
+            let hash_msg = try!(msg.to_grp_msg_hash());
+            // Members are counted by name, as a member may have signed with more than one key.
+            let signers = signatures.iter()
+                .filter(|&&(ref pub_id, ref signature)| {
+                    is_signed_by(&msg, pub_id, signature) ||
+                    is_signed_by(&hash_msg, pub_id, signature)
+                })
+                .map(|&(ref pub_id, _)| *pub_id.name())
+                .collect::<HashSet<_>>();
+            if signers.len() < quorum_size {
+                return Err(RoutingError::NotEnoughSignatures);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for GroupProof {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter,
+               "GroupProof {{ src: {:?}, dst: {:?}, signers: {}, .. }}",
+               self.src,
+               self.dst,
+               self.signers().len())
+    }
+}
+
+/// Returns `true` if `signature` is the signature of `pub_id` for `msg`, as in a `SignedMessage`.
+fn is_signed_by(msg: &RoutingMessage, pub_id: &PublicId, signature: &sign::Signature) -> bool {
+    match serialise(&(msg, pub_id)) {
+        Ok(bytes) => sign::verify_detached(signature, &bytes, pub_id.signing_public_key()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use authority::Authority;
+    use clock::Clock;
+    use data::{Data, DataIdentifier};
+    use error::RoutingError;
+    use id::FullId;
+    use immutable_data::ImmutableData;
+    use messages::{Request, RoutingMessage, SignedMessage, UserMessage};
+    use rand;
+    use super::*;
+    use types::MessageId;
+
+    // Returns a request and its proof, signed by `signer_count` members. The first member signs
+    // the full parts, the others sign their hashes.
+    fn signed_request(signer_count: usize) -> (Request, GroupProof) {
+        let data = Data::Immutable(ImmutableData::new(vec![7; 3000]));
+        let request = Request::Put(data, MessageId::new());
+        let src = Authority::NaeManager(rand::random());
+        let dst = Authority::ClientManager(rand::random());
+        let priority = 2;
+        let full_ids = (0..signer_count).map(|_| FullId::new()).collect::<Vec<_>>();
+        let clock = Clock::default();
+
+        let part_signatures = unwrap!(UserMessage::Request(request.clone()).to_parts(priority))
+            .into_iter()
+            .map(|content| {
+                let msg = RoutingMessage {
+                    src: src.clone(),
+                    dst: dst.clone(),
+                    content: content,
+                };
+                full_ids.iter()
+                    .enumerate()
+                    .map(|(i, full_id)| {
+                        let msg = if i == 0 {
+                            msg.clone()
+                        } else {
+                            unwrap!(msg.to_grp_msg_hash())
+                        };
+                        let signed_msg = unwrap!(SignedMessage::new(msg, full_id, &clock));
+                        (*signed_msg.public_id(), *signed_msg.signature())
+                    })
+                    .collect()
+            })
+            .collect();
+
+        (request, GroupProof::new(src, dst, priority, part_signatures))
+    }
+
+    #[test]
+    fn verify_quorum() {
+        let (request, proof) = signed_request(5);
+        assert_eq!(5, proof.signers().len());
+        unwrap!(proof.verify_request(&request, 5));
+
+        match proof.verify_request(&request, 6) {
+            Err(RoutingError::NotEnoughSignatures) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rotated_keys_count_once() {
+        let (request, mut proof) = signed_request(3);
+        // The first signer signs every part again with new keys.
+        let rotated_id = FullId::new();
+        let name = *proof.part_signatures[0][0].0.name();
+        let mut rotated_pub_id = *rotated_id.public_id();
+        rotated_pub_id.set_name(name);
+        let parts = unwrap!(UserMessage::Request(request.clone()).to_parts(proof.priority));
+        for (content, signatures) in parts.into_iter().zip(&mut proof.part_signatures) {
+            let msg = RoutingMessage {
+                src: proof.src.clone(),
+                dst: proof.dst.clone(),
+                content: content,
+            };
+            let bytes = unwrap!(serialise(&(&msg, &rotated_pub_id)));
+            let signature = sign::sign_detached(&bytes, rotated_id.signing_private_key());
+            signatures.push((rotated_pub_id, signature));
+        }
+        assert_eq!(3, proof.signers().len());
+        unwrap!(proof.verify_request(&request, 3));
+        assert!(proof.verify_request(&request, 4).is_err());
+    }
+
+    #[test]
+    fn tampered_message_fails() {
+        let (_, proof) = signed_request(5);
+        let data = Data::Immutable(ImmutableData::new(vec![8; 3000]));
+        let other_request = Request::Get(DataIdentifier::Immutable(*data.name()),
+                                         MessageId::new());
+        assert!(proof.verify_request(&other_request, 1).is_err());
+    }
+}
@@ -51,6 +51,13 @@ impl FullId {
         }
     }
 
+    /// Returns a new ID with the same name, but newly generated keys.
+    pub fn rotated(&self) -> FullId {
+        let mut full_id = FullId::new();
+        full_id.public_id.name = self.public_id.name;
+        full_id
+    }
+
     /// Returns public ID reference.
     pub fn public_id(&self) -> &PublicId {
         &self.public_id
@@ -17,11 +17,11 @@
 
 //! Client and node implementations for a resilient decentralised network.
 //!
-//! The network is based on the [`kademlia_routing_table`][1] and uses the XOR metric to define the
+//! The network is based on the [`routing_table`][1] and uses the XOR metric to define the
 //! "distance" between two [`XorName`][2]s. `XorName`s are used as addresses of nodes, clients as
 //! well as data.
 //!
-//! [1]: ../kademlia_routing_table/index.html
+//! [1]: routing_table/index.html
 //! [2]: ../xor_name/struct.XorName.html
 //!
 //! Messages are exchanged between _authorities_, where an `Authority` can be an
@@ -145,20 +145,27 @@ extern crate rustc_serialize;
 mod ack_manager;
 mod action;
 mod authority;
+mod ban_list;
 mod client;
+mod clock;
 mod cache;
 mod data;
 mod error;
 mod event;
+mod event_loop;
+mod group_proof;
 mod id;
 mod immutable_data;
 mod message_accumulator;
 mod message_filter;
 mod messages;
 mod node;
+mod partition;
 mod peer_manager;
 mod plain_data;
-mod routing_table;
+mod replay_filter;
+mod resource_proof;
+mod session;
 mod signed_message_filter;
 mod state_machine;
 mod states;
@@ -180,6 +187,8 @@ pub mod mock_crust;
 
 /// Messaging infrastructure
 pub mod messaging;
+/// Prefix-based routing table, generic over the `Xorable` name type
+pub mod routing_table;
 /// Error communication between vaults and core
 pub mod client_errors;
 
@@ -196,16 +205,19 @@ pub use core_tests::verify_invariant;
 pub use data::{Data, DataIdentifier};
 pub use error::{InterfaceError, RoutingError};
 pub use event::Event;
+pub use event_loop::Waker;
+pub use group_proof::GroupProof;
 pub use id::{FullId, PublicId};
 pub use immutable_data::ImmutableData;
-pub use messages::{Request, Response};
+pub use messages::{BootstrapDenyReason, Request, Response};
 #[cfg(feature = "use-mock-crust")]
 pub use mock_crust::crust;
 pub use node::{Node, NodeBuilder};
 pub use peer_manager::{MIN_GROUP_SIZE, QUORUM_SIZE};
 pub use plain_data::PlainData;
+pub use replay_filter::ReplayFilter;
+pub use routing_table::{GroupChange, MembershipChange, Xorable};
 pub use routing_table::Error as RoutingTableError;
-pub use routing_table::Xorable;
 pub use structured_data::{MAX_STRUCTURED_DATA_SIZE_IN_BYTES, StructuredData};
 pub use types::MessageId;
 pub use xor_name::{XOR_NAME_BITS, XOR_NAME_LEN, XorName, XorNameFromHexError};
@@ -16,31 +16,55 @@
 // relating to use of the SAFE Network Software.
 
 use accumulator::Accumulator;
+use clock::Clock;
 use error::RoutingError;
 use id::PublicId;
 use lru_time_cache::LruCache;
 use message_filter::MessageFilter;
-use messages::{MessageContent, RoutingMessage};
+use messages::{MessageContent, RoutingMessage, SignedMessage};
 use rust_sodium::crypto::hash::sha256;
+use routing_table::Xorable;
 use rust_sodium::crypto::sign;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use xor_name::XorName;
 
 const EXPIRY_DURATION_SECS: u64 = 60 * 20;
+/// Time (in seconds) to wait for the content of a group message whose hash has accumulated, before
+/// requesting it from one of the members that sent the hash.
+pub const CONTENT_REQUEST_DELAY_SECS: u64 = 5;
+
+/// A group message whose hash has accumulated, but whose full content hasn't arrived.
+struct MissingContent {
+    hash_msg: RoutingMessage,
+    last_request: Instant,
+    request_count: usize,
+}
 
 pub struct MessageAccumulator {
-    accumulator: Accumulator<RoutingMessage, sign::PublicKey>,
+    /// Counts the votes for each group message by the names of the senders, so that a member
+    /// which rotated its keys still only counts once.
+    accumulator: Accumulator<RoutingMessage, XorName>,
     cache: LruCache<sha256::Digest, RoutingMessage>,
+    clock: Clock,
     filter: MessageFilter<RoutingMessage>,
+    /// The signatures received so far for each group message, keyed by its hash message.
+    signatures: LruCache<RoutingMessage, Vec<(PublicId, sign::Signature)>>,
+    /// The messages whose content we still need to request from a member of the source group.
+    missing: BTreeMap<sha256::Digest, MissingContent>,
 }
 
 impl MessageAccumulator {
-    pub fn new() -> Self {
+    pub fn new(clock: Clock) -> Self {
         let expiry_duration = Duration::from_secs(EXPIRY_DURATION_SECS);
 
         MessageAccumulator {
             accumulator: Accumulator::with_duration(1, expiry_duration),
             cache: LruCache::with_expiry_duration(expiry_duration),
-            filter: MessageFilter::with_expiry_duration(expiry_duration),
+            clock: clock.clone(),
+            filter: MessageFilter::with_expiry_duration(expiry_duration, clock),
+            signatures: LruCache::with_expiry_duration(expiry_duration),
+            missing: BTreeMap::new(),
         }
     }
 
@@ -52,27 +76,99 @@ impl MessageAccumulator {
         self.accumulator.quorum()
     }
 
+    /// Adds the given signed message, returning the routing message once it has accumulated,
+    /// together with the signatures of all senders.
     pub fn add(&mut self,
-               msg: &RoutingMessage,
-               public_id: &PublicId)
-               -> Result<Option<RoutingMessage>, RoutingError> {
+               signed_msg: &SignedMessage)
+               -> Result<Option<(RoutingMessage, Vec<(PublicId, sign::Signature)>)>,
+                         RoutingError> {
+        let msg = signed_msg.routing_message();
         if !msg.src.is_group() {
-            return Ok(Some(msg.clone()));
+            let signature = (*signed_msg.public_id(), *signed_msg.signature());
+            return Ok(Some((msg.clone(), vec![signature])));
         }
 
         if self.filter.contains(msg) {
             return Err(RoutingError::FilterCheckFailed);
         }
 
-        if let Some(group_msg) = self.accumulate(msg, public_id) {
+        if let Some((group_msg, signatures)) = self.accumulate(signed_msg) {
             let _ = self.filter.insert(&group_msg);
             let _ = self.filter.insert(&try!(msg.to_grp_msg_hash()));
-            Ok(Some(group_msg))
+            Ok(Some((group_msg, signatures)))
         } else {
             Ok(None)
         }
     }
 
+    /// Adds the full content of a group message of which only the hash had accumulated, e.g. after
+    /// requesting it from a member of the source group. Returns the message together with the
+    /// signatures of all senders, or `None` if its content wasn't missing.
+    pub fn add_content(&mut self,
+                       msg: RoutingMessage)
+                       -> Option<(RoutingMessage, Vec<(PublicId, sign::Signature)>)> {
+        let hash_msg = match msg.to_grp_msg_hash() {
+            Ok(hash_msg) => hash_msg,
+            Err(_) => return None,
+        };
+        let hash = match hash_msg.content {
+            MessageContent::GroupMessageHash(hash, _) => hash,
+            _ => return None,
+        };
+        if self.missing.remove(&hash).is_none() {
+            return None;
+        }
+
+        let _ = self.filter.insert(&msg);
+        let _ = self.filter.insert(&hash_msg);
+        let signatures = self.signatures.remove(&hash_msg).unwrap_or_else(Vec::new);
+        Some((msg, signatures))
+    }
+
+    /// Returns `true` if there are group messages of which only the hash has accumulated.
+    pub fn has_missing_content(&self) -> bool {
+        !self.missing.is_empty()
+    }
+
+    /// Returns the hashes of the group messages whose content has been missing for a while, each
+    /// with the name of a member of the source group that should be asked for it.
+    ///
+    /// The members that sent the hash are asked in order of their distance to `our_name`, so that
+    /// different recipients ask different members. Each call moves on to the next member, until
+    /// all of them have been asked.
+    pub fn content_requests(&mut self, our_name: &XorName) -> Vec<(sha256::Digest, XorName)> {
+        let delay = Duration::from_secs(CONTENT_REQUEST_DELAY_SECS);
+        let now = self.clock.now();
+        let mut requests = Vec::new();
+        let mut given_up = Vec::new();
+
+        for (hash, missing) in &mut self.missing {
+            if now - missing.last_request < delay {
+                continue;
+            }
+            let mut members = match self.signatures.get(&missing.hash_msg) {
+                Some(signatures) => {
+                    signatures.iter().map(|&(ref pub_id, _)| *pub_id.name()).collect::<Vec<_>>()
+                }
+                None => vec![],
+            };
+            members.sort_by(|lhs, rhs| our_name.cmp_distance(lhs, rhs));
+            if let Some(&member) = members.get(missing.request_count) {
+                requests.push((*hash, member));
+                missing.last_request = now;
+                missing.request_count += 1;
+            } else {
+                given_up.push(*hash);
+            }
+        }
+
+        for hash in given_up {
+            error!("Failed to get content of group message {:?}", hash);
+            let _ = self.missing.remove(&hash);
+        }
+        requests
+    }
+
     pub fn contains(&mut self, msg: &RoutingMessage) -> bool {
         self.filter.contains(msg)
     }
@@ -82,10 +178,16 @@ impl MessageAccumulator {
         // self.accumulator.clear();
         self.cache.clear();
         self.filter.clear();
+        self.signatures.clear();
+        self.missing.clear();
     }
 
-    fn accumulate(&mut self, msg: &RoutingMessage, public_id: &PublicId) -> Option<RoutingMessage> {
-        let key = *public_id.signing_public_key();
+    fn accumulate(&mut self,
+                  signed_msg: &SignedMessage)
+                  -> Option<(RoutingMessage, Vec<(PublicId, sign::Signature)>)> {
+        let msg = signed_msg.routing_message();
+        let public_id = signed_msg.public_id();
+        let name = *public_id.name();
         let hash_msg = if let Ok(hash_msg) = msg.to_grp_msg_hash() {
             hash_msg
         } else {
@@ -94,17 +196,115 @@ impl MessageAccumulator {
             return None;
         };
 
-        if let MessageContent::GroupMessageHash(hash, _) = hash_msg.content {
+        {
+            let mut signatures = self.signatures.remove(&hash_msg).unwrap_or_else(Vec::new);
+            if signatures.iter().all(|&(ref signer, _)| signer.name() != public_id.name()) {
+                signatures.push((*public_id, *signed_msg.signature()));
+            }
+            let _ = self.signatures.insert(hash_msg.clone(), signatures);
+        }
+
+        let group_msg = if let MessageContent::GroupMessageHash(hash, _) = hash_msg.content {
             if hash_msg != *msg {
                 let _ = self.cache.insert(hash, msg.clone());
             }
-            if self.accumulator.add(hash_msg, key).is_some() {
-                self.cache.remove(&hash)
+            if self.accumulator.add(hash_msg.clone(), name).is_some() {
+                let group_msg = self.cache.remove(&hash);
+                if group_msg.is_some() {
+                    let _ = self.missing.remove(&hash);
+                } else if !self.missing.contains_key(&hash) {
+                    let _ = self.missing.insert(hash,
+                                                MissingContent {
+                                                    hash_msg: hash_msg.clone(),
+                                                    last_request: self.clock.now(),
+                                                    request_count: 0,
+                                                });
+                }
+                group_msg
             } else {
                 None
             }
         } else {
-            self.accumulator.add(hash_msg, key).map(|_| msg.clone())
+            self.accumulator.add(hash_msg.clone(), name).map(|_| msg.clone())
+        };
+
+        group_msg.map(|group_msg| {
+            let signatures = self.signatures.remove(&hash_msg).unwrap_or_else(Vec::new);
+            (group_msg, signatures)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use authority::Authority;
+    use clock::Clock;
+    use id::FullId;
+    use messages::{MessageContent, RoutingMessage, SignedMessage};
+    use rand;
+    use super::*;
+    use types::MessageId;
+
+    #[test]
+    fn missing_content() {
+        let msg = RoutingMessage {
+            src: Authority::NaeManager(rand::random()),
+            dst: Authority::ManagedNode(rand::random()),
+            content: MessageContent::UserMessagePart {
+                hash: 0,
+                part_count: 1,
+                part_index: 0,
+                priority: 2,
+                cacheable: false,
+                payload: vec![1, 2, 3],
+            },
+        };
+        let hash_msg = unwrap!(msg.to_grp_msg_hash());
+
+        let clock = Clock::default();
+        let mut accumulator = MessageAccumulator::new(clock.clone());
+        accumulator.set_quorum_size(3);
+        assert!(accumulator.add_content(msg.clone()).is_none());
+
+        // Only hashes arrive, so the content is missing once the quorum is reached.
+        for _ in 0..3 {
+            let signed_msg = unwrap!(SignedMessage::new(hash_msg.clone(), &FullId::new(), &clock));
+            assert!(unwrap!(accumulator.add(&signed_msg)).is_none());
         }
+        assert!(accumulator.has_missing_content());
+
+        // Nobody is asked for it before the delay has passed.
+        assert!(accumulator.content_requests(&rand::random()).is_empty());
+
+        let (group_msg, signatures) = unwrap!(accumulator.add_content(msg.clone()));
+        assert_eq!(msg, group_msg);
+        assert_eq!(3, signatures.len());
+        assert!(!accumulator.has_missing_content());
+        assert!(accumulator.contains(&msg));
+        assert!(accumulator.add_content(msg).is_none());
+    }
+
+    #[test]
+    fn rotated_keys_count_once() {
+        let msg = RoutingMessage {
+            src: Authority::NaeManager(rand::random()),
+            dst: Authority::ManagedNode(rand::random()),
+            content: MessageContent::GetCloseGroup(MessageId::new()),
+        };
+        let clock = Clock::default();
+        let mut accumulator = MessageAccumulator::new(clock.clone());
+        accumulator.set_quorum_size(2);
+
+        // The same member signing with its old and its new keys doesn't reach the quorum.
+        let full_id = FullId::new();
+        let signed_msg = unwrap!(SignedMessage::new(msg.clone(), &full_id, &clock));
+        assert!(unwrap!(accumulator.add(&signed_msg)).is_none());
+        let signed_msg = unwrap!(SignedMessage::new(msg.clone(), &full_id.rotated(), &clock));
+        assert!(unwrap!(accumulator.add(&signed_msg)).is_none());
+
+        let signed_msg = unwrap!(SignedMessage::new(msg.clone(), &FullId::new(), &clock));
+        let (group_msg, signatures) = unwrap!(unwrap!(accumulator.add(&signed_msg)));
+        assert_eq!(msg, group_msg);
+        assert_eq!(2, signatures.len());
     }
 }
@@ -19,6 +19,7 @@
 #[cfg(test)]
 extern crate rand;
 
+use clock::Clock;
 use std::hash::{Hash, Hasher};
 // TODO - Once we're at Stable v1.13.0, avoid disabling the lint check and replace `SipHasher` with
 // `std::collections::hash_map::DefaultHasher`.
@@ -26,7 +27,7 @@ use std::hash::{Hash, Hasher};
 #[allow(deprecated)]
 use std::hash::SipHasher;
 use std::marker::PhantomData;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
 #[allow(deprecated)]
 fn hash<T: Hash>(t: &T) -> u64 {
@@ -38,15 +39,17 @@ fn hash<T: Hash>(t: &T) -> u64 {
 /// A time based message filter that takes any generic type as a key and will drop keys after a
 /// time period (LRU Cache pattern).
 pub struct MessageFilter<Message> {
+    clock: Clock,
     entries: Vec<TimestampedMessage>,
     time_to_live: Duration,
     phantom: PhantomData<Message>,
 }
 
 impl<Message: Hash> MessageFilter<Message> {
-    /// Constructor for time based `MessageFilter`.
-    pub fn with_expiry_duration(time_to_live: Duration) -> MessageFilter<Message> {
+    /// Constructor for time based `MessageFilter`, measuring the time to live with `clock`.
+    pub fn with_expiry_duration(time_to_live: Duration, clock: Clock) -> MessageFilter<Message> {
         MessageFilter {
+            clock: clock,
             entries: vec![],
             time_to_live: time_to_live,
             phantom: PhantomData,
@@ -65,14 +68,15 @@ impl<Message: Hash> MessageFilter<Message> {
     pub fn insert(&mut self, message: &Message) -> usize {
         self.remove_expired();
         let hash_code = hash(message);
+        let expiry_point = self.clock.now() + self.time_to_live;
         if let Some(index) = self.entries.iter().position(|t| t.hash_code == hash_code) {
             let mut timestamped_message = self.entries.remove(index);
-            timestamped_message.update_expiry_point(self.time_to_live);
+            timestamped_message.expiry_point = expiry_point;
             let count = timestamped_message.increment_count();
             self.entries.push(timestamped_message);
             count
         } else {
-            self.entries.push(TimestampedMessage::new(hash_code, self.time_to_live));
+            self.entries.push(TimestampedMessage::new(hash_code, expiry_point));
             1
         }
     }
@@ -98,7 +102,7 @@ impl<Message: Hash> MessageFilter<Message> {
     }
 
     fn remove_expired(&mut self) {
-        let now = SystemTime::now();
+        let now = self.clock.now();
         // The entries are sorted from oldest to newest, so just split off the vector at the
         // first unexpired entry and the returned vector is the remaining unexpired values.  If
         // we don't find any unexpired value, just clear the vector.
@@ -112,25 +116,20 @@ impl<Message: Hash> MessageFilter<Message> {
 
 struct TimestampedMessage {
     pub hash_code: u64,
-    pub expiry_point: SystemTime,
+    pub expiry_point: Instant,
     /// How many copies of this message have been seen before this one.
     pub count: usize,
 }
 
 impl TimestampedMessage {
-    pub fn new(hash_code: u64, time_to_live: Duration) -> TimestampedMessage {
+    pub fn new(hash_code: u64, expiry_point: Instant) -> TimestampedMessage {
         TimestampedMessage {
             hash_code: hash_code,
-            expiry_point: SystemTime::now() + time_to_live,
+            expiry_point: expiry_point,
             count: 1,
         }
     }
 
-    /// Updates the expiry point to set the given time to live from now.
-    pub fn update_expiry_point(&mut self, time_to_live: Duration) {
-        self.expiry_point = SystemTime::now() + time_to_live;
-    }
-
     /// Increments the counter and returns its old value.
     pub fn increment_count(&mut self) -> usize {
         self.count += 1;
@@ -142,6 +141,7 @@ impl TimestampedMessage {
 
 #[cfg(test)]
 mod tests {
+    use clock::Clock;
     use rand;
     use rand::Rng;
     use std::thread;
@@ -151,7 +151,8 @@ mod tests {
     #[test]
     fn timeout() {
         let time_to_live = Duration::from_millis(rand::thread_rng().gen_range(50, 150));
-        let mut msg_filter = MessageFilter::<usize>::with_expiry_duration(time_to_live);
+        let mut msg_filter =
+            MessageFilter::<usize>::with_expiry_duration(time_to_live, Clock::default());
         assert_eq!(time_to_live, msg_filter.time_to_live);
 
         // Add 10 messages - all should be added.
@@ -192,7 +193,8 @@ mod tests {
         }
 
         let time_to_live = Duration::from_millis(rand::thread_rng().gen_range(50, 150));
-        let mut msg_filter = MessageFilter::<Temp>::with_expiry_duration(time_to_live);
+        let mut msg_filter =
+            MessageFilter::<Temp>::with_expiry_duration(time_to_live, Clock::default());
 
         let values: Vec<Temp> = (0..10).map(|_| Temp::default()).collect();
         for temp in &values {
@@ -218,7 +220,8 @@ mod tests {
     fn add_duplicate() {
         let size = 10;
         let time_to_live = Duration::from_secs(99);
-        let mut msg_filter = MessageFilter::<usize>::with_expiry_duration(time_to_live);
+        let mut msg_filter =
+            MessageFilter::<usize>::with_expiry_duration(time_to_live, Clock::default());
 
         for i in 0..size {
             assert_eq!(1, msg_filter.insert(&i));
@@ -236,7 +239,8 @@ mod tests {
         // Check re-adding a message to a filter alters its expiry time.
         let time_to_live = Duration::from_millis(3000);
         let sleep_duration = Duration::from_millis(1800); // more than half of `time_to_live`
-        let mut msg_filter = MessageFilter::<usize>::with_expiry_duration(time_to_live);
+        let mut msg_filter =
+            MessageFilter::<usize>::with_expiry_duration(time_to_live, Clock::default());
 
         // Add "0".
         assert_eq!(1, msg_filter.insert(&0));
@@ -253,4 +257,22 @@ mod tests {
         thread::sleep(sleep_duration);
         assert!(!msg_filter.contains(&0));
     }
+
+    #[cfg(feature = "use-mock-crust")]
+    #[test]
+    fn virtual_time() {
+        use mock_crust::{self, Network};
+
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let clock = mock_crust::make_current(&handle, Clock::new);
+        let time_to_live = Duration::from_secs(60);
+        let mut msg_filter = MessageFilter::<usize>::with_expiry_duration(time_to_live, clock);
+
+        assert_eq!(1, msg_filter.insert(&0));
+        network.advance_time(Duration::from_secs(59));
+        assert!(msg_filter.contains(&0));
+        network.advance_time(Duration::from_secs(1));
+        assert!(!msg_filter.contains(&0));
+    }
 }
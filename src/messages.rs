@@ -17,21 +17,27 @@
 
 use ack_manager::Ack;
 use authority::Authority;
+use clock::Clock;
 #[cfg(not(feature = "use-mock-crust"))]
 use crust::PeerId;
 use data::{Data, DataIdentifier};
 use error::RoutingError;
 use event::Event;
+use group_proof::GroupProof;
 use id::{FullId, PublicId};
 use lru_time_cache::LruCache;
 use maidsafe_utilities;
 use maidsafe_utilities::serialisation::{deserialise, serialise};
 #[cfg(feature = "use-mock-crust")]
 use mock_crust::crust::PeerId;
+use resource_proof::ResourceProofChallenge;
 use rust_sodium::crypto::{box_, sign};
 use rust_sodium::crypto::hash::sha256;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use session::SessionKey;
 use std::collections::{BTreeMap, HashSet};
 use std::fmt::{self, Debug, Formatter};
+use std::net::SocketAddr;
 use std::time::Duration;
 use types::MessageId;
 use utils;
@@ -76,6 +82,16 @@ pub enum Message {
         /// The receiver
         dst: PeerId,
     },
+    /// Any of the above, serialised and encrypted with the key of the session with the receiver.
+    Encrypted {
+        /// The number of times the sender has replaced the session key.
+        epoch: u32,
+        /// The frame's sequence number in its direction of the session, from which the nonce is
+        /// derived. It increases with every frame, so that replayed frames can be rejected.
+        seq: u64,
+        /// The encrypted message.
+        ciphertext: Vec<u8>,
+    },
 }
 
 impl Message {
@@ -85,10 +101,47 @@ impl Message {
             Message::TunnelDirect { ref content, .. } => content.priority(),
             Message::Hop(ref content) |
             Message::TunnelHop { ref content, .. } => content.content.content.priority(),
+            // The priority is determined before a message is encrypted.
+            Message::Encrypted { .. } => 0,
         }
     }
 }
 
+/// The reason a node refused to act as a client's bootstrap node.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, RustcEncodable, RustcDecodable)]
+pub enum BootstrapDenyReason {
+    /// The node's routing table is too small for it to act as a proxy, because the network is
+    /// still forming.
+    NetworkTooSmall,
+    /// The node has reached its limit of clients or joining nodes, in total or from the client's
+    /// IP address.
+    ClientQuotaReached,
+    /// The client wants to become a node, but its crust ID is not whitelisted.
+    NotWhitelisted,
+    /// The client didn't follow the bootstrap protocol, e.g. because it runs an incompatible
+    /// version.
+    ProtocolMismatch,
+}
+
+/// The socket address of a node, as sent to clients looking for a bootstrap node.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ContactAddr(pub SocketAddr);
+
+impl Encodable for ContactAddr {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), E::Error> {
+        self.0.to_string().encode(encoder)
+    }
+}
+
+impl Decodable for ContactAddr {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<ContactAddr, D::Error> {
+        let addr = try!(String::decode(decoder));
+        addr.parse()
+            .map(ContactAddr)
+            .map_err(|_| decoder.error(&format!("Invalid socket address: {}", addr)))
+    }
+}
+
 /// Messages sent via a direct connection.
 ///
 /// Allows routing to directly send specific messages between nodes.
@@ -100,25 +153,42 @@ pub enum DirectMessage {
         public_id: ::id::PublicId,
         /// The dynamically calculated quorum size the client's accumulator should use.
         current_quorum_size: usize,
+        /// The bootstrap node's key for an encrypted session, if it wants one.
+        session_key: Option<SessionKey>,
     },
     /// Sent to the client to indicate that this node is not available as a bootstrap node.
-    BootstrapDeny,
+    BootstrapDeny {
+        /// Why the node refused to act as the client's bootstrap node.
+        reason: BootstrapDenyReason,
+        /// Other nodes the client can try to bootstrap against instead.
+        contacts: Vec<ContactAddr>,
+    },
+    /// Sent from the bootstrap node to a newly connected client. The client needs to sign the
+    /// contained nonce in its `ClientIdentify`.
+    BootstrapChallenge(u64),
     /// Sent from a newly connected client to the bootstrap node to inform it about the client's
     /// public ID.
     ClientIdentify {
         /// Serialised keys and claimed name.
         serialised_public_id: Vec<u8>,
-        /// Signature of the client.
+        /// Signature of the client over the serialised public ID, the bootstrap node's challenge,
+        /// the client's crust `PeerId` and the session key, as returned by
+        /// `client_identify_signed_bytes`.
         signature: sign::Signature,
         /// Indicate whether we intend to remain a client, as opposed to becoming a routing node.
         client_restriction: bool,
+        /// The client's key for an encrypted session, if it wants one.
+        session_key: Option<SessionKey>,
     },
     /// Sent from a node to a node, to allow the latter to add the former to its routing table.
     NodeIdentify {
         /// Keys and claimed name, serialised outside routing.
         serialised_public_id: Vec<u8>,
-        /// Signature of the originator of this message.
+        /// Signature of the originator of this message over the serialised public ID and the
+        /// session key, as returned by `node_identify_signed_bytes`.
         signature: sign::Signature,
+        /// The node's key for an encrypted session, if it wants one.
+        session_key: Option<SessionKey>,
     },
     /// Sent from a node that found a new node in the network to all its contacts who might need to
     /// add the new node to their routing table.
@@ -127,12 +197,24 @@ pub enum DirectMessage {
     RoutingTable(HashSet<PublicId>),
     /// Sent from a node that needs a tunnel to be able to connect to the given peer.
     TunnelRequest(PeerId),
-    /// Sent as a response to `TunnelRequest` if the node can act as a tunnel.
-    TunnelSuccess(PeerId),
+    /// Sent as a response to `TunnelRequest` if the node can act as a tunnel, together with the
+    /// number of further client pairs it can act as a tunnel for.
+    TunnelSuccess(PeerId, usize),
+    /// Sent to a tunnel node to ask it to act as a standby tunnel to the given peer.
+    TunnelStandby(PeerId),
     /// Sent from a tunnel node to indicate that the given peer has disconnected.
     TunnelClosed(PeerId),
     /// Sent to a tunnel node to indicate the tunnel is not needed any more.
     TunnelDisconnect(PeerId),
+    /// Sent from a node to all its peers to announce that it replaced its keys.
+    RotateKeys {
+        /// The node's new keys, with its unchanged name.
+        new_public_id: PublicId,
+        /// Signature of the serialised new public ID, with the node's old signing key.
+        signature: sign::Signature,
+    },
+    /// Sent from a node that is shutting down to all its peers, so that they drop it right away.
+    Leave,
 }
 
 impl DirectMessage {
@@ -140,6 +222,19 @@ impl DirectMessage {
     pub fn priority(&self) -> u8 {
         0 // Currently all direct messages are small and should be treated with high priority.
     }
+
+    /// Returns `true` if this message is part of the handshake on a new connection, and is
+    /// therefore sent before an encrypted session is established.
+    pub fn is_handshake(&self) -> bool {
+        match *self {
+            DirectMessage::BootstrapIdentify { .. } |
+            DirectMessage::BootstrapDeny { .. } |
+            DirectMessage::BootstrapChallenge(_) |
+            DirectMessage::ClientIdentify { .. } |
+            DirectMessage::NodeIdentify { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 /// And individual hop message that represents a part of the route of a message in transit.
@@ -206,6 +301,25 @@ impl HopMessage {
     }
 }
 
+/// Returns the bytes a client signs in its `ClientIdentify`. They include the bootstrap node's
+/// challenge and the client's crust `PeerId`, so that the message can't be reused on another
+/// connection, and the session key, so that it can't be stripped to downgrade the connection.
+pub fn client_identify_signed_bytes(serialised_public_id: &[u8],
+                                    challenge: u64,
+                                    peer_id: &PeerId,
+                                    session_key: &Option<SessionKey>)
+                                    -> Result<Vec<u8>, RoutingError> {
+    Ok(try!(serialise(&(serialised_public_id, challenge, peer_id, session_key))))
+}
+
+/// Returns the bytes a node signs in its `NodeIdentify`. They include the session key, so that it
+/// can't be stripped to downgrade the connection.
+pub fn node_identify_signed_bytes(serialised_public_id: &[u8],
+                                  session_key: &Option<SessionKey>)
+                                  -> Result<Vec<u8>, RoutingError> {
+    Ok(try!(serialise(&(serialised_public_id, session_key))))
+}
+
 /// Wrapper around a routing message, signed by the originator of the message.
 #[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Hash, RustcEncodable, RustcDecodable)]
 pub struct SignedMessage {
@@ -216,26 +330,44 @@ pub struct SignedMessage {
     /// For clients this is easily verifiable since their name is computed from the ID. For nodes it
     /// needs to be confirmed by their `NodeManager`.
     public_id: PublicId,
+    /// The signature of the content and public ID. This doesn't cover the timestamp, so that group
+    /// members' signatures of the same content can be collected into a `GroupProof`.
     signature: sign::Signature,
+    /// The time the message was signed, in milliseconds since the Unix epoch.
+    timestamp: u64,
+    /// The signature of `signature` and `timestamp`, so that the message can't be replayed later
+    /// with a new timestamp.
+    timestamp_signature: sign::Signature,
 }
 
 impl SignedMessage {
-    /// Creates a `SignedMessage` with the given `content` and signed by the given `full_id`.
-    pub fn new(content: RoutingMessage, full_id: &FullId) -> Result<SignedMessage, RoutingError> {
+    /// Creates a `SignedMessage` with the given `content` and signed by the given `full_id`, with
+    /// the current time as read from `clock`.
+    pub fn new(content: RoutingMessage,
+               full_id: &FullId,
+               clock: &Clock)
+               -> Result<SignedMessage, RoutingError> {
         let bytes_to_sign = try!(serialise(&(&content, full_id.public_id())));
+        let signature = sign::sign_detached(&bytes_to_sign, full_id.signing_private_key());
+        let timestamp = clock.unix_time_millis();
+        let timestamp_bytes = try!(serialise(&(&signature, timestamp)));
         Ok(SignedMessage {
             content: content,
             public_id: *full_id.public_id(),
-            signature: sign::sign_detached(&bytes_to_sign, full_id.signing_private_key()),
+            signature: signature,
+            timestamp: timestamp,
+            timestamp_signature: sign::sign_detached(&timestamp_bytes,
+                                                     full_id.signing_private_key()),
         })
     }
 
-    /// Confirms the signature against the claimed public ID.
+    /// Confirms the signatures against the claimed public ID.
     pub fn check_integrity(&self) -> Result<(), RoutingError> {
         let signed_bytes = try!(serialise(&(&self.content, &self.public_id)));
-        if sign::verify_detached(&self.signature,
-                                 &signed_bytes,
-                                 self.public_id().signing_public_key()) {
+        let timestamp_bytes = try!(serialise(&(&self.signature, self.timestamp)));
+        let key = self.public_id().signing_public_key();
+        if sign::verify_detached(&self.signature, &signed_bytes, key) &&
+           sign::verify_detached(&self.timestamp_signature, &timestamp_bytes, key) {
             Ok(())
         } else {
             Err(RoutingError::FailedSignature)
@@ -252,6 +384,16 @@ impl SignedMessage {
         &self.public_id
     }
 
+    /// The signature of the message's content and public ID.
+    pub fn signature(&self) -> &sign::Signature {
+        &self.signature
+    }
+
+    /// The time the message was signed, in milliseconds since the Unix epoch.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
     /// The priority Crust should send this message with.
     pub fn priority(&self) -> u8 {
         self.content.priority()
@@ -340,6 +482,9 @@ pub enum MessageContent {
         // TODO: The receiver should have that in the node_id_cache.
         /// The sender's public ID.
         public_id: PublicId,
+        /// The solution to the `ResourceProofChallenge`, if the sender is a joining node that was
+        /// given one.
+        resource_proof: Option<u64>,
     },
     /// Reply with the new `PublicId` for the joining node.
     ///
@@ -349,6 +494,8 @@ pub enum MessageContent {
         relocated_id: PublicId,
         /// Our close group `PublicId`s.
         close_group_ids: Vec<PublicId>,
+        /// The challenge the joining node needs to solve before we accept it, if any.
+        resource_proof: Option<ResourceProofChallenge>,
         /// The message's unique identifier.
         message_id: MessageId,
     },
@@ -368,6 +515,11 @@ pub enum MessageContent {
     /// confirmation, so that only one of them needs to send the full message. The second field is
     /// the message priority.
     GroupMessageHash(sha256::Digest, u8),
+    /// Sent to a member of a source group authority to request the full content of a group
+    /// message, if only its `GroupMessageHash` has been received.
+    GetGroupMessage(sha256::Digest),
+    /// The full content of a group message, sent in response to `GetGroupMessage`.
+    GroupMessage(Box<RoutingMessage>),
     /// Part of a user-facing message
     UserMessagePart {
         /// The hash of this user message.
@@ -392,6 +544,7 @@ impl MessageContent {
             MessageContent::Ack(_, priority) |
             MessageContent::GroupMessageHash(_, priority) |
             MessageContent::UserMessagePart { priority, .. } => priority,
+            MessageContent::GroupMessage(ref msg) => msg.priority(),
             _ => 0,
         }
     }
@@ -400,13 +553,19 @@ impl MessageContent {
 impl Debug for DirectMessage {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         match *self {
-            DirectMessage::BootstrapIdentify { ref public_id, ref current_quorum_size } => {
+            DirectMessage::BootstrapIdentify { ref public_id, ref current_quorum_size, .. } => {
                 write!(formatter,
                        "BootstrapIdentify {{ {:?}, {:?} }}",
                        public_id,
                        current_quorum_size)
             }
-            DirectMessage::BootstrapDeny => write!(formatter, "BootstrapDeny"),
+            DirectMessage::BootstrapDeny { ref reason, ref contacts } => {
+                write!(formatter,
+                       "BootstrapDeny {{ {:?}, {} contacts }}",
+                       reason,
+                       contacts.len())
+            }
+            DirectMessage::BootstrapChallenge(_) => write!(formatter, "BootstrapChallenge"),
             DirectMessage::ClientIdentify { client_restriction: true, .. } => {
                 write!(formatter, "ClientIdentify (client only)")
             }
@@ -421,8 +580,11 @@ impl Debug for DirectMessage {
             DirectMessage::TunnelRequest(peer_id) => {
                 write!(formatter, "TunnelRequest({:?})", peer_id)
             }
-            DirectMessage::TunnelSuccess(peer_id) => {
-                write!(formatter, "TunnelSuccess({:?})", peer_id)
+            DirectMessage::TunnelSuccess(peer_id, headroom) => {
+                write!(formatter, "TunnelSuccess({:?}, {})", peer_id, headroom)
+            }
+            DirectMessage::TunnelStandby(peer_id) => {
+                write!(formatter, "TunnelStandby({:?})", peer_id)
             }
             DirectMessage::TunnelClosed(peer_id) => {
                 write!(formatter, "TunnelClosed({:?})", peer_id)
@@ -430,6 +592,10 @@ impl Debug for DirectMessage {
             DirectMessage::TunnelDisconnect(peer_id) => {
                 write!(formatter, "TunnelDisconnect({:?})", peer_id)
             }
+            DirectMessage::RotateKeys { ref new_public_id, .. } => {
+                write!(formatter, "RotateKeys {{ {:?}, .. }}", new_public_id)
+            }
+            DirectMessage::Leave => write!(formatter, "Leave"),
         }
     }
 }
@@ -446,9 +612,10 @@ impl Debug for HopMessage {
 impl Debug for SignedMessage {
     fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
         write!(formatter,
-               "SignedMessage {{ content: {:?}, public_id: {:?}, signature: .. }}",
+               "SignedMessage {{ content: {:?}, public_id: {:?}, timestamp: {}, signature: .. }}",
                self.content,
-               self.public_id)
+               self.public_id,
+               self.timestamp)
     }
 }
 
@@ -472,7 +639,8 @@ impl Debug for MessageContent {
             MessageContent::ConnectionInfo { .. } => write!(formatter, "ConnectionInfo {{ .. }}"),
             MessageContent::GetNodeNameResponse { ref relocated_id,
                                                   ref close_group_ids,
-                                                  ref message_id } => {
+                                                  ref message_id,
+                                                  .. } => {
                 write!(formatter,
                        "GetNodeNameResponse {{ {:?}, {:?}, {:?} }}",
                        close_group_ids,
@@ -492,6 +660,12 @@ impl Debug for MessageContent {
                        utils::format_binary_array(&hash.0),
                        priority)
             }
+            MessageContent::GetGroupMessage(ref hash) => {
+                write!(formatter,
+                       "GetGroupMessage({})",
+                       utils::format_binary_array(&hash.0))
+            }
+            MessageContent::GroupMessage(ref msg) => write!(formatter, "GroupMessage({:?})", msg),
             MessageContent::UserMessagePart { hash, part_count, part_index, priority, .. } => {
                 write!(formatter,
                        "UserMessagePart {{ {}/{}, priority: {}  {:x}}}",
@@ -556,13 +730,14 @@ impl UserMessage {
 
     /// Returns an event indicating that this message was received with the given source and
     /// destination authorities.
-    pub fn into_event(self, src: Authority, dst: Authority) -> Event {
+    pub fn into_event(self, src: Authority, dst: Authority, proof: Option<GroupProof>) -> Event {
         match self {
             UserMessage::Request(request) => {
                 Event::Request {
                     request: request,
                     src: src,
                     dst: dst,
+                    proof: proof,
                 }
             }
             UserMessage::Response(response) => {
@@ -570,6 +745,7 @@ impl UserMessage {
                     response: response,
                     src: src,
                     dst: dst,
+                    proof: proof,
                 }
             }
         }
@@ -795,8 +971,10 @@ impl Debug for Response {
 
 /// This assembles `UserMessage`s from `UserMessagePart`s.
 /// It maps `(hash, part_count)` of an incoming `UserMessage` to the map containing
-/// all `UserMessagePart`s that have already arrived, by `part_index`.
-pub struct UserMessageCache(LruCache<(u64, u32), BTreeMap<u32, Vec<u8>>>);
+/// all `UserMessagePart`s that have already arrived, by `part_index`, together with the
+/// signatures of the group members that sent each part.
+pub struct UserMessageCache(LruCache<(u64, u32),
+                                     BTreeMap<u32, (Vec<u8>, Vec<(PublicId, sign::Signature)>)>>);
 
 impl UserMessageCache {
     pub fn with_expiry_duration(duration: Duration) -> Self {
@@ -804,24 +982,34 @@ impl UserMessageCache {
     }
 
     /// Adds the given one to the cache of received message parts, returning a `UserMessage` if the
-    /// given part was the last missing piece of it.
+    /// given part was the last missing piece of it, together with the signatures of each part.
     pub fn add(&mut self,
                hash: u64,
                part_count: u32,
                part_index: u32,
-               payload: Vec<u8>)
-               -> Option<UserMessage> {
+               payload: Vec<u8>,
+               signatures: Vec<(PublicId, sign::Signature)>)
+               -> Option<(UserMessage, Vec<Vec<(PublicId, sign::Signature)>>)> {
         {
             let entry = self.0.entry((hash, part_count)).or_insert_with(BTreeMap::new);
-            let _ = entry.insert(part_index, payload);
+            let _ = entry.insert(part_index, (payload, signatures));
             if entry.len() != part_count as usize {
                 return None;
             }
         }
 
-        self.0
-            .remove(&(hash, part_count))
-            .and_then(|part_map| UserMessage::from_parts(hash, part_map.values()).ok())
+        let part_map = match self.0.remove(&(hash, part_count)) {
+            Some(part_map) => part_map,
+            None => return None,
+        };
+        let user_msg = match UserMessage::from_parts(hash,
+                                                     part_map.values().map(|&(ref payload, _)| {
+                                                         payload
+                                                     })) {
+            Ok(user_msg) => user_msg,
+            Err(_) => return None,
+        };
+        Some((user_msg, part_map.into_iter().map(|(_, (_, signatures))| signatures).collect()))
     }
 }
 
@@ -849,7 +1037,8 @@ mod tests {
             content: MessageContent::GetCloseGroup(MessageId::zero()),
         };
         let full_id = FullId::new();
-        let signed_message_result = SignedMessage::new(routing_message.clone(), &full_id);
+        let signed_message_result =
+            SignedMessage::new(routing_message.clone(), &full_id, &Clock::default());
 
         assert!(signed_message_result.is_ok());
 
@@ -866,11 +1055,16 @@ mod tests {
         let bytes_to_sign = unwrap!(serialise(&(&routing_message, full_id.public_id())));
         let signature = sign::sign_detached(&bytes_to_sign, full_id.signing_private_key());
 
-        signed_message.signature = signature;
+        let mut forged_message = signed_message.clone();
+        forged_message.signature = signature;
 
-        let check_integrity_result = signed_message.check_integrity();
+        let check_integrity_result = forged_message.check_integrity();
 
         assert!(check_integrity_result.is_err());
+
+        // Changing the timestamp invalidates the message, too.
+        signed_message.timestamp += 1;
+        assert!(signed_message.check_integrity().is_err());
     }
 
     #[test]
@@ -912,7 +1106,8 @@ mod tests {
             content: MessageContent::GetCloseGroup(MessageId::zero()),
         };
         let full_id = FullId::new();
-        let signed_message_result = SignedMessage::new(routing_message.clone(), &full_id);
+        let signed_message_result =
+            SignedMessage::new(routing_message.clone(), &full_id, &Clock::default());
 
         assert!(signed_message_result.is_ok());
 
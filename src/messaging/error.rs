@@ -29,6 +29,21 @@ pub enum Error {
     /// Used where the length of a [message's `body`](struct.MpidMessage.html#method.new) exceeds
     /// [`MAX_BODY_SIZE`](constant.MAX_BODY_SIZE.html).
     BodyTooLarge,
+    /// Used where a request's source or destination authority is not allowed to send or receive
+    /// it, e.g. a client asking for a message that is not addressed to it.
+    InvalidAuthority,
+    /// Used where a message's signature doesn't match the sender's key.
+    InvalidSignature,
+    /// Used where storing a header would exceed the recipient's
+    /// [`MAX_INBOX_SIZE`](constant.MAX_INBOX_SIZE.html).
+    InboxFull,
+    /// Used where storing a message would exceed the sender's
+    /// [`MAX_OUTBOX_SIZE`](constant.MAX_OUTBOX_SIZE.html).
+    OutboxFull,
+    /// Used where the requested message is not in the outbox.
+    MessageNotFound,
+    /// Used where an MPID manager receives a message that is only ever sent to clients.
+    UnexpectedMessage,
     /// Serialisation error.
     Serialisation(SerialisationError),
 }
@@ -38,6 +53,12 @@ impl Display for Error {
         match *self {
             Error::MetadataTooLarge => write!(formatter, "Message header too large"),
             Error::BodyTooLarge => write!(formatter, "Message body too large"),
+            Error::InvalidAuthority => write!(formatter, "Invalid source or destination"),
+            Error::InvalidSignature => write!(formatter, "Invalid signature"),
+            Error::InboxFull => write!(formatter, "Recipient's inbox is full"),
+            Error::OutboxFull => write!(formatter, "Sender's outbox is full"),
+            Error::MessageNotFound => write!(formatter, "Message not found in outbox"),
+            Error::UnexpectedMessage => write!(formatter, "Unexpected message type"),
             Error::Serialisation(ref error) => write!(formatter, "Serialisation error: {}", error),
         }
     }
@@ -48,6 +69,12 @@ impl StdError for Error {
         match *self {
             Error::MetadataTooLarge => "Header too large",
             Error::BodyTooLarge => "Body too large",
+            Error::InvalidAuthority => "Invalid authority",
+            Error::InvalidSignature => "Invalid signature",
+            Error::InboxFull => "Inbox full",
+            Error::OutboxFull => "Outbox full",
+            Error::MessageNotFound => "Message not found",
+            Error::UnexpectedMessage => "Unexpected message",
             Error::Serialisation(ref error) => error.description(),
         }
     }
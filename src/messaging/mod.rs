@@ -24,11 +24,13 @@ pub const MAX_OUTBOX_SIZE: usize = 1 << 27;
 
 mod error;
 mod mpid_header;
+mod mpid_manager;
 mod mpid_message;
 mod mpid_message_wrapper;
 
 pub use self::error::Error;
 pub use self::mpid_header::{MAX_HEADER_METADATA_SIZE, MpidHeader};
+pub use self::mpid_manager::MpidManager;
 pub use self::mpid_message::{MAX_BODY_SIZE, MpidMessage};
 pub use self::mpid_message_wrapper::MpidMessageWrapper;
 
@@ -0,0 +1,996 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use authority::Authority;
+use clock::Clock;
+use error::InterfaceError;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use messages::Request;
+use node::Node;
+use routing_table::{GroupChange, MembershipChange};
+use rust_sodium::crypto::hash::sha256;
+use rust_sodium::crypto::sign;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error as StdError;
+use std::time::Duration;
+use super::{Error, MAX_INBOX_SIZE, MAX_OUTBOX_SIZE, MpidHeader, MpidMessage,
+            MpidMessageWrapper};
+use types::MessageId;
+use xor_name::XorName;
+
+/// Time (in seconds) after which undeleted messages and headers expire, unless configured
+/// otherwise with `MpidManager::with_ttl`.
+const DEFAULT_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+/// Expiry times are rounded down to a multiple of the time to live divided by this, so that all
+/// managers of an account agree on them even if they stored an entry at slightly different times.
+const EXPIRY_STEPS: u64 = 720;
+
+/// A stored header or message, with its serialised size and when it expires, in milliseconds
+/// since the Unix epoch.
+struct Entry<T> {
+    item: T,
+    size: usize,
+    expires_at: u64,
+}
+
+/// The messages stored for a single client.
+#[derive(Default)]
+struct Account {
+    /// The client's authority, if it announced that it is online.
+    client: Option<Authority>,
+    /// Headers of the messages sent to the client, with their senders' keys, by name.
+    inbox: BTreeMap<XorName, Entry<(MpidHeader, sign::PublicKey)>>,
+    inbox_size: usize,
+    /// The names of the headers in the inbox which haven't been sent to the client yet.
+    undelivered: BTreeSet<XorName>,
+    /// Messages sent by the client which their recipients haven't deleted yet, by name.
+    outbox: BTreeMap<XorName, Entry<MpidMessage>>,
+    outbox_size: usize,
+}
+
+impl Account {
+    /// Inserts the header, if it is not in the inbox yet. Returns `false` if it was.
+    fn insert_header(&mut self,
+                     name: XorName,
+                     header: MpidHeader,
+                     sender_key: sign::PublicKey,
+                     size: usize,
+                     expires_at: u64)
+                     -> Result<bool, Error> {
+        if self.inbox.contains_key(&name) {
+            return Ok(false);
+        }
+        if self.inbox_size + size > MAX_INBOX_SIZE {
+            return Err(Error::InboxFull);
+        }
+        self.inbox_size += size;
+        let _ = self.undelivered.insert(name);
+        let _ = self.inbox.insert(name,
+                                  Entry {
+                                      item: (header, sender_key),
+                                      size: size,
+                                      expires_at: expires_at,
+                                  });
+        Ok(true)
+    }
+
+    /// Inserts the message, if it is not in the outbox yet. Returns `false` if it was.
+    fn insert_message(&mut self,
+                      name: XorName,
+                      message: MpidMessage,
+                      size: usize,
+                      expires_at: u64)
+                      -> Result<bool, Error> {
+        if self.outbox.contains_key(&name) {
+            return Ok(false);
+        }
+        if self.outbox_size + size > MAX_OUTBOX_SIZE {
+            return Err(Error::OutboxFull);
+        }
+        self.outbox_size += size;
+        let _ = self.outbox.insert(name,
+                                   Entry {
+                                       item: message,
+                                       size: size,
+                                       expires_at: expires_at,
+                                   });
+        Ok(true)
+    }
+
+    fn remove_header(&mut self, name: &XorName) -> bool {
+        let _ = self.undelivered.remove(name);
+        if let Some(entry) = self.inbox.remove(name) {
+            self.inbox_size -= entry.size;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn remove_message(&mut self, name: &XorName) -> bool {
+        if let Some(entry) = self.outbox.remove(name) {
+            self.outbox_size -= entry.size;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        let expired_headers = self.inbox
+            .iter()
+            .filter(|&(_, entry)| entry.expires_at <= now)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        for name in expired_headers {
+            let _ = self.remove_header(&name);
+        }
+        let expired_messages = self.outbox
+            .iter()
+            .filter(|&(_, entry)| entry.expires_at <= now)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        for name in expired_messages {
+            let _ = self.remove_message(&name);
+        }
+    }
+
+    /// Returns the headers to send to the client, if it is online, and marks them as delivered.
+    fn deliver(&mut self) -> Vec<(Authority, MpidMessageWrapper)> {
+        let client = match self.client {
+            Some(ref client) => client.clone(),
+            None => return vec![],
+        };
+        let undelivered = ::std::mem::replace(&mut self.undelivered, BTreeSet::new());
+        undelivered.into_iter()
+            .filter_map(|name| self.inbox.get(&name))
+            .map(|entry| {
+                let (ref header, sender_key) = entry.item;
+                (client.clone(), MpidMessageWrapper::PutHeader(header.clone(), sender_key))
+            })
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.client.is_none() && self.inbox.is_empty() && self.outbox.is_empty()
+    }
+}
+
+/// The content of a `Refresh` request with which a client's managers replicate a single entry of
+/// the account to new members of the group, together with the account's name and the entry's
+/// expiry time. It must be identical for all of them, so it doesn't say whether the client is
+/// online.
+#[derive(RustcEncodable, RustcDecodable)]
+enum EntryRefresh {
+    Header(XorName, MpidHeader, sign::PublicKey, u64),
+    Message(XorName, MpidMessage, u64),
+}
+
+impl EntryRefresh {
+    fn account_name(&self) -> &XorName {
+        match *self {
+            EntryRefresh::Header(ref name, _, _, _) |
+            EntryRefresh::Message(ref name, _, _) => name,
+        }
+    }
+}
+
+/// The MPID messaging logic of a client's managers, i.e. of the group `ClientManager(name)`.
+///
+/// A sender's managers keep the message in the sender's outbox and notify the recipient's managers
+/// with a `PutHeader`. They keep the header in the recipient's inbox and pass it on to the
+/// recipient as soon as it is online, i.e. has sent `Online`. The recipient fetches the message
+/// via its own managers with `GetMessage` and removes it from the sender's outbox with
+/// `DeleteHeader`. If the sender deletes it instead, its managers remove the header from the
+/// recipient's inbox. Messages and headers which are not deleted expire after a time to live.
+///
+/// All wrappers are exchanged as `Request::Post`s, see
+/// [`MpidMessageWrapper::to_data()`](enum.MpidMessageWrapper.html#method.to_data).
+pub struct MpidManager {
+    accounts: HashMap<XorName, Account>,
+    clock: Clock,
+    ttl: Duration,
+}
+
+impl MpidManager {
+    /// Creates a manager without any stored messages, which keeps them for 30 days.
+    pub fn new() -> MpidManager {
+        MpidManager::with_ttl(Duration::from_secs(DEFAULT_TTL_SECS))
+    }
+
+    /// Creates a manager without any stored messages, which keeps them for the given duration
+    /// unless they are deleted earlier.
+    pub fn with_ttl(ttl: Duration) -> MpidManager {
+        MpidManager {
+            accounts: HashMap::new(),
+            clock: Clock::default(),
+            ttl: ttl,
+        }
+    }
+
+    /// Handles a request the node received, if it is an MPID message for a `ClientManager`
+    /// authority or a `Refresh` sent by `handle_churn`, and sends the resulting messages via
+    /// `node`. If the request fails and came from a client, it is answered with a `PostFailure`.
+    ///
+    /// Returns `Ok(false)` if the request is not an MPID message, so the caller can handle it.
+    pub fn handle_request(&mut self,
+                          node: &Node,
+                          src: &Authority,
+                          dst: &Authority,
+                          request: &Request)
+                          -> Result<bool, InterfaceError> {
+        let (data, id) = match *request {
+            Request::Post(ref data, id) => (data, id),
+            Request::Refresh(ref content, id) => {
+                let refresh = match deserialise::<EntryRefresh>(content) {
+                    Ok(refresh) => refresh,
+                    Err(_) => return Ok(false),
+                };
+                if *src != Authority::ClientManager(*refresh.account_name()) {
+                    return Ok(false);
+                }
+                if *src == *dst {
+                    let messages = self.handle_refresh(refresh);
+                    try!(send_all(node, dst, messages, id));
+                }
+                return Ok(true);
+            }
+            _ => return Ok(false),
+        };
+        let wrapper = match (dst, MpidMessageWrapper::from_data(data)) {
+            (&Authority::ClientManager(_), Some(wrapper)) => wrapper,
+            _ => return Ok(false),
+        };
+
+        match self.handle(src, dst, wrapper) {
+            Ok(messages) => try!(send_all(node, dst, messages, id)),
+            Err(error) => {
+                debug!("MPID request from {:?} to {:?} failed: {:?}", src, dst, error);
+                if let Authority::Client { .. } = *src {
+                    let external_error_indicator = error.description().as_bytes().to_vec();
+                    try!(node.send_post_failure(dst.clone(),
+                                                src.clone(),
+                                                data.identifier(),
+                                                external_error_indicator,
+                                                id));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Replicates the accounts whose managers gained new members to them, entry by entry, and
+    /// drops the ones we don't manage any longer. This should be called on every `NodeAdded` and
+    /// `NodeLost` event.
+    ///
+    /// The new members don't learn whether the client is online, so we forget it, too: the client
+    /// needs to send `Online` again to be sent new headers.
+    ///
+    /// All managers of an account need to pass the same `id`, e.g. `MessageId::from_added_node`.
+    pub fn handle_churn(&mut self,
+                        node: &Node,
+                        group_change: &GroupChange<XorName>,
+                        id: MessageId)
+                        -> Result<(), InterfaceError> {
+        let now = self.clock.unix_time_millis();
+        let mut lost = vec![];
+        for (name, account) in &mut self.accounts {
+            account.expire(now);
+            if group_change.new_members_for(name).is_empty() {
+                if let MembershipChange::Lost = group_change.membership_change(name) {
+                    lost.push(*name);
+                }
+                continue;
+            }
+            account.client = None;
+            let auth = Authority::ClientManager(*name);
+            let account_id = MessageId::with_name(&id, name);
+            for (entry_name, refresh) in entry_refreshes(*name, account) {
+                let content = try!(serialise(&refresh).map_err(Error::from));
+                let entry_id = MessageId::with_name(&account_id, &entry_name);
+                try!(node.send_refresh_request(auth.clone(), auth.clone(), content, entry_id));
+            }
+        }
+        for name in lost {
+            let _ = self.accounts.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// Handles an MPID message from `src` to `dst`, which must be a `ClientManager` authority we
+    /// are part of. Returns the messages to send in response, with their destinations. Their
+    /// source is `dst`.
+    pub fn handle(&mut self,
+                  src: &Authority,
+                  dst: &Authority,
+                  wrapper: MpidMessageWrapper)
+                  -> Result<Vec<(Authority, MpidMessageWrapper)>, Error> {
+        let account_name = match *dst {
+            Authority::ClientManager(name) => name,
+            _ => return Err(Error::InvalidAuthority),
+        };
+        self.expire();
+
+        match wrapper {
+            MpidMessageWrapper::Online => {
+                let _ = try!(own_client_key(src, &account_name));
+                let account = self.accounts.entry(account_name).or_insert_with(Account::default);
+                // The client may have missed the headers we sent while it was reconnecting.
+                account.client = Some(src.clone());
+                account.undelivered = account.inbox.keys().cloned().collect();
+                Ok(account.deliver())
+            }
+            MpidMessageWrapper::PutMessage(message) => {
+                if let Authority::ClientManager(sender_name) = *src {
+                    return self.pass_on_message(account_name, sender_name, message);
+                }
+                let client_key = try!(own_client_key(src, &account_name));
+                self.put_message(account_name, &client_key, message)
+            }
+            MpidMessageWrapper::PutHeader(header, sender_key) => {
+                if *src != Authority::ClientManager(*header.sender()) {
+                    return Err(Error::InvalidAuthority);
+                }
+                self.put_header(account_name, header, sender_key)
+            }
+            MpidMessageWrapper::GetMessage(header) => {
+                let name = try!(header.name());
+                if let Authority::ClientManager(recipient_name) = *src {
+                    let message = try!(self.recipients_message(&account_name,
+                                                               &recipient_name,
+                                                               &name));
+                    return Ok(vec![(src.clone(), MpidMessageWrapper::PutMessage(message.clone()))]);
+                }
+                let _ = try!(own_client_key(src, &account_name));
+                let account = match self.accounts.get_mut(&account_name) {
+                    Some(account) => account,
+                    None => return Err(Error::MessageNotFound),
+                };
+                let header = match account.inbox.get(&name) {
+                    Some(entry) => entry.item.0.clone(),
+                    None => return Err(Error::MessageNotFound),
+                };
+                // The message is passed on to wherever the client is connected now.
+                account.client = Some(src.clone());
+                let sender = Authority::ClientManager(*header.sender());
+                Ok(vec![(sender, MpidMessageWrapper::GetMessage(header))])
+            }
+            MpidMessageWrapper::OutboxHas(names) => {
+                let _ = try!(own_client_key(src, &account_name));
+                let headers = self.accounts
+                    .get(&account_name)
+                    .map_or_else(Vec::new, |account| {
+                        names.iter()
+                            .filter_map(|name| account.outbox.get(name))
+                            .map(|entry| entry.item.header().clone())
+                            .collect()
+                    });
+                Ok(vec![(src.clone(), MpidMessageWrapper::OutboxHasResponse(headers))])
+            }
+            MpidMessageWrapper::GetOutboxHeaders => {
+                let _ = try!(own_client_key(src, &account_name));
+                let headers = self.accounts
+                    .get(&account_name)
+                    .map_or_else(Vec::new, |account| {
+                        account.outbox
+                            .values()
+                            .map(|entry| entry.item.header().clone())
+                            .collect()
+                    });
+                Ok(vec![(src.clone(), MpidMessageWrapper::GetOutboxHeadersResponse(headers))])
+            }
+            MpidMessageWrapper::DeleteMessage(name) => {
+                let _ = try!(own_client_key(src, &account_name));
+                let account = match self.accounts.get_mut(&account_name) {
+                    Some(account) => account,
+                    None => return Ok(vec![]),
+                };
+                let recipient = account.outbox.get(&name).map(|entry| *entry.item.recipient());
+                if let Some(recipient) = recipient {
+                    // The header in the recipient's inbox refers to a message that is gone now.
+                    let _ = account.remove_message(&name);
+                    let dst = Authority::ClientManager(recipient);
+                    Ok(vec![(dst, MpidMessageWrapper::DeleteHeader(name))])
+                } else {
+                    let _ = account.remove_header(&name);
+                    Ok(vec![])
+                }
+            }
+            MpidMessageWrapper::DeleteHeader(name) => {
+                if let Authority::ClientManager(sender_name) = *src {
+                    if let Some(account) = self.accounts.get_mut(&account_name) {
+                        if account.inbox
+                            .get(&name)
+                            .map_or(false, |entry| *entry.item.0.sender() == sender_name) {
+                            let _ = account.remove_header(&name);
+                        }
+                    }
+                    return Ok(vec![]);
+                }
+                let recipient_name = match *src {
+                    Authority::Client { ref client_key, .. } => client_name(client_key),
+                    _ => return Err(Error::InvalidAuthority),
+                };
+                let _ = try!(self.recipients_message(&account_name, &recipient_name, &name));
+                if let Some(account) = self.accounts.get_mut(&account_name) {
+                    let _ = account.remove_message(&name);
+                }
+                Ok(vec![])
+            }
+            MpidMessageWrapper::OutboxHasResponse(_) |
+            MpidMessageWrapper::GetOutboxHeadersResponse(_) => Err(Error::UnexpectedMessage),
+        }
+    }
+
+    /// Returns the headers in the given client's inbox.
+    pub fn inbox(&self, client_name: &XorName) -> Vec<&MpidHeader> {
+        self.accounts
+            .get(client_name)
+            .map_or_else(Vec::new,
+                         |account| account.inbox.values().map(|entry| &entry.item.0).collect())
+    }
+
+    /// Returns the messages in the given client's outbox.
+    pub fn outbox(&self, client_name: &XorName) -> Vec<&MpidMessage> {
+        self.accounts
+            .get(client_name)
+            .map_or_else(Vec::new,
+                         |account| account.outbox.values().map(|entry| &entry.item).collect())
+    }
+
+    fn expire(&mut self) {
+        let now = self.clock.unix_time_millis();
+        let mut empty = vec![];
+        for (name, account) in &mut self.accounts {
+            account.expire(now);
+            if account.is_empty() {
+                empty.push(*name);
+            }
+        }
+        for name in empty {
+            let _ = self.accounts.remove(&name);
+        }
+    }
+
+    fn put_message(&mut self,
+                   sender_name: XorName,
+                   client_key: &sign::PublicKey,
+                   message: MpidMessage)
+                   -> Result<Vec<(Authority, MpidMessageWrapper)>, Error> {
+        if *message.header().sender() != sender_name {
+            return Err(Error::InvalidAuthority);
+        }
+        if !message.verify(client_key) {
+            return Err(Error::InvalidSignature);
+        }
+        let name = try!(message.name());
+        let size = try!(serialise(&message)).len();
+        let recipient = Authority::ClientManager(*message.recipient());
+        let header = message.header().clone();
+        let expires_at = self.expiry();
+        let account = self.accounts.entry(sender_name).or_insert_with(Account::default);
+        if try!(account.insert_message(name, message, size, expires_at)) {
+            Ok(vec![(recipient, MpidMessageWrapper::PutHeader(header, *client_key))])
+        } else {
+            Ok(vec![]) // A duplicate, the recipient has already been notified.
+        }
+    }
+
+    /// Stores the header in the recipient's inbox and passes it on if the recipient is online.
+    fn put_header(&mut self,
+                  recipient_name: XorName,
+                  header: MpidHeader,
+                  sender_key: sign::PublicKey)
+                  -> Result<Vec<(Authority, MpidMessageWrapper)>, Error> {
+        if *header.sender() != client_name(&sender_key) {
+            return Err(Error::InvalidAuthority);
+        }
+        if !header.verify(&sender_key) {
+            return Err(Error::InvalidSignature);
+        }
+        let name = try!(header.name());
+        let size = try!(serialise(&header)).len();
+        let expires_at = self.expiry();
+        let account = self.accounts.entry(recipient_name).or_insert_with(Account::default);
+        let _ = try!(account.insert_header(name, header, sender_key, size, expires_at));
+        Ok(account.deliver())
+    }
+
+    /// Passes a message the recipient requested with `GetMessage` on to them, if it matches a
+    /// header in their inbox.
+    fn pass_on_message(&mut self,
+                       recipient_name: XorName,
+                       sender_name: XorName,
+                       message: MpidMessage)
+                       -> Result<Vec<(Authority, MpidMessageWrapper)>, Error> {
+        let name = try!(message.name());
+        let account = match self.accounts.get_mut(&recipient_name) {
+            Some(account) => account,
+            None => return Err(Error::MessageNotFound),
+        };
+        match account.inbox.get(&name) {
+            Some(&Entry { item: (ref header, ref sender_key), .. }) => {
+                if *header.sender() != sender_name || *message.recipient() != recipient_name {
+                    return Err(Error::InvalidAuthority);
+                }
+                if message.header() != header || !message.verify(sender_key) {
+                    return Err(Error::InvalidSignature);
+                }
+            }
+            None => return Err(Error::MessageNotFound),
+        }
+        Ok(account.client
+            .iter()
+            .map(|client| (client.clone(), MpidMessageWrapper::PutMessage(message.clone())))
+            .collect())
+    }
+
+    /// Adds the entry if we don't have it yet, keeping the expiry time of the other managers, and
+    /// retries to deliver the headers the client hasn't been sent yet.
+    fn handle_refresh(&mut self, refresh: EntryRefresh) -> Vec<(Authority, MpidMessageWrapper)> {
+        self.expire();
+        let now = self.clock.unix_time_millis();
+        match refresh {
+            EntryRefresh::Header(account_name, header, sender_key, expires_at) => {
+                let account = self.accounts.entry(account_name).or_insert_with(Account::default);
+                if let (true, Ok(name), Ok(serialised)) =
+                       (expires_at > now, header.name(), serialise(&header)) {
+                    let size = serialised.len();
+                    if let Err(error) =
+                           account.insert_header(name, header, sender_key, size, expires_at) {
+                        debug!("Failed to add refreshed MPID header: {:?}", error);
+                    }
+                }
+                account.deliver()
+            }
+            EntryRefresh::Message(account_name, message, expires_at) => {
+                if let (true, Ok(name), Ok(serialised)) =
+                       (expires_at > now, message.name(), serialise(&message)) {
+                    let account = self.accounts
+                        .entry(account_name)
+                        .or_insert_with(Account::default);
+                    if let Err(error) =
+                           account.insert_message(name, message, serialised.len(), expires_at) {
+                        debug!("Failed to add refreshed MPID message: {:?}", error);
+                    }
+                }
+                vec![]
+            }
+        }
+    }
+
+    /// Returns the expiry time of an entry stored now, in milliseconds since the Unix epoch.
+    fn expiry(&self) -> u64 {
+        let ttl = self.ttl.as_secs() * 1000 + (self.ttl.subsec_nanos() / 1_000_000) as u64;
+        let step = cmp::max(1, ttl / EXPIRY_STEPS);
+        (self.clock.unix_time_millis() + ttl) / step * step
+    }
+
+    /// Returns the named message from the sender's outbox, if it is addressed to `recipient_name`.
+    fn recipients_message(&self,
+                          sender_name: &XorName,
+                          recipient_name: &XorName,
+                          name: &XorName)
+                          -> Result<&MpidMessage, Error> {
+        let message = match self.accounts
+            .get(sender_name)
+            .and_then(|account| account.outbox.get(name)) {
+            Some(entry) => &entry.item,
+            None => return Err(Error::MessageNotFound),
+        };
+        if message.recipient() == recipient_name {
+            Ok(message)
+        } else {
+            Err(Error::InvalidAuthority)
+        }
+    }
+}
+
+impl Default for MpidManager {
+    fn default() -> MpidManager {
+        MpidManager::new()
+    }
+}
+
+/// Returns the refreshes for all entries in the account, with the entries' names.
+fn entry_refreshes(name: XorName, account: &Account) -> Vec<(XorName, EntryRefresh)> {
+    let headers = account.inbox.iter().map(|(entry_name, entry)| {
+        let (ref header, sender_key) = entry.item;
+        (*entry_name, EntryRefresh::Header(name, header.clone(), sender_key, entry.expires_at))
+    });
+    let messages = account.outbox.iter().map(|(entry_name, entry)| {
+        (*entry_name, EntryRefresh::Message(name, entry.item.clone(), entry.expires_at))
+    });
+    headers.chain(messages).collect()
+}
+
+fn send_all(node: &Node,
+            src: &Authority,
+            messages: Vec<(Authority, MpidMessageWrapper)>,
+            id: MessageId)
+            -> Result<(), InterfaceError> {
+    for (dst, wrapper) in messages {
+        match wrapper.to_data() {
+            Ok(data) => {
+                // Derived from the content, so that all members of the group agree on it.
+                let msg_id = MessageId::with_name(&id, data.name());
+                try!(node.send_post_request(src.clone(), dst, data, msg_id))
+            }
+            Err(error) => debug!("Failed to serialise {:?}: {:?}", wrapper, error),
+        }
+    }
+    Ok(())
+}
+
+fn client_name(client_key: &sign::PublicKey) -> XorName {
+    XorName(sha256::hash(&client_key[..]).0)
+}
+
+/// Returns the client's key if `src` is the client whose account is `account_name`.
+fn own_client_key(src: &Authority, account_name: &XorName) -> Result<sign::PublicKey, Error> {
+    match *src {
+        Authority::Client { client_key, .. } if client_name(&client_key) == *account_name => {
+            Ok(client_key)
+        }
+        _ => Err(Error::InvalidAuthority),
+    }
+}
+
+#[cfg(all(test, feature = "use-mock-crust"))]
+mod tests {
+    use authority::Authority;
+    use messaging::{self, Error, MAX_BODY_SIZE, MAX_OUTBOX_SIZE, MpidMessage, MpidMessageWrapper};
+    use mock_crust::crust::PeerId;
+    use rand;
+    use rust_sodium::crypto::sign;
+    use super::*;
+
+    struct TestClient {
+        auth: Authority,
+        manager_auth: Authority,
+        name: XorName,
+        public_key: sign::PublicKey,
+        secret_key: sign::SecretKey,
+    }
+
+    impl TestClient {
+        fn new() -> TestClient {
+            let (client_key, secret_key) = sign::gen_keypair();
+            let name = client_name(&client_key);
+            TestClient {
+                auth: Authority::Client {
+                    client_key: client_key,
+                    peer_id: PeerId(0),
+                    proxy_node_name: rand::random(),
+                },
+                manager_auth: Authority::ClientManager(name),
+                name: name,
+                public_key: client_key,
+                secret_key: secret_key,
+            }
+        }
+
+        fn message_to(&self, recipient: &TestClient, body_size: usize) -> MpidMessage {
+            let body = messaging::generate_random_bytes(body_size);
+            unwrap!(MpidMessage::new(self.name, vec![], recipient.name, body, &self.secret_key))
+        }
+    }
+
+    // Sends the message from `sender` to their managers and passes the resulting header on to the
+    // recipient's managers.
+    fn send(managers: &mut MpidManager,
+            sender: &TestClient,
+            message: MpidMessage)
+            -> Result<(), Error> {
+        let wrapper = MpidMessageWrapper::PutMessage(message);
+        for (dst, wrapper) in try!(managers.handle(&sender.auth, &sender.manager_auth, wrapper)) {
+            let _ = try!(managers.handle(&sender.manager_auth, &dst, wrapper));
+        }
+        Ok(())
+    }
+
+    // Passes all entries of the named account from one manager to another, and returns the
+    // messages the latter sends in response.
+    fn refresh_account(from: &MpidManager,
+                       to: &mut MpidManager,
+                       name: &XorName)
+                       -> Vec<(Authority, MpidMessageWrapper)> {
+        entry_refreshes(*name, &from.accounts[name])
+            .into_iter()
+            .flat_map(|(_, refresh)| to.handle_refresh(refresh))
+            .collect()
+    }
+
+    fn serialised_refreshes(manager: &MpidManager, name: &XorName) -> Vec<Vec<u8>> {
+        entry_refreshes(*name, &manager.accounts[name])
+            .iter()
+            .map(|&(_, ref refresh)| unwrap!(serialise(refresh)))
+            .collect()
+    }
+
+    #[test]
+    fn put_get_and_delete() {
+        let mut managers = MpidManager::new();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let message = alice.message_to(&bob, 100);
+        let name = unwrap!(message.name());
+        unwrap!(send(&mut managers, &alice, message.clone()));
+        assert_eq!(managers.outbox(&alice.name), vec![&message]);
+        assert_eq!(managers.inbox(&bob.name), vec![message.header()]);
+
+        // Alice can list her outbox.
+        let wrapper = MpidMessageWrapper::OutboxHas(vec![name, rand::random()]);
+        let response = unwrap!(managers.handle(&alice.auth, &alice.manager_auth, wrapper));
+        let headers = vec![message.header().clone()];
+        assert_eq!(response,
+                   vec![(alice.auth.clone(), MpidMessageWrapper::OutboxHasResponse(headers))]);
+
+        // Bob asks his managers for the message, and they fetch it from Alice's managers.
+        let wrapper = MpidMessageWrapper::GetMessage(message.header().clone());
+        match managers.handle(&alice.auth, &alice.manager_auth, wrapper.clone()) {
+            Err(Error::MessageNotFound) => (),
+            result => panic!("Expected MessageNotFound, got {:?}", result),
+        }
+        let response = unwrap!(managers.handle(&bob.auth, &bob.manager_auth, wrapper.clone()));
+        assert_eq!(response, vec![(alice.manager_auth.clone(), wrapper.clone())]);
+
+        // Only Bob's managers can fetch it from Alice's managers.
+        let carol = TestClient::new();
+        match managers.handle(&carol.manager_auth, &alice.manager_auth, wrapper.clone()) {
+            Err(Error::InvalidAuthority) => (),
+            result => panic!("Expected InvalidAuthority, got {:?}", result),
+        }
+        let put_message = MpidMessageWrapper::PutMessage(message.clone());
+        let response = unwrap!(managers.handle(&bob.manager_auth, &alice.manager_auth, wrapper));
+        assert_eq!(response, vec![(bob.manager_auth.clone(), put_message.clone())]);
+        let response =
+            unwrap!(managers.handle(&alice.manager_auth, &bob.manager_auth, put_message.clone()));
+        assert_eq!(response, vec![(bob.auth.clone(), put_message)]);
+
+        // Bob deletes the header from his inbox and the message from Alice's outbox.
+        let wrapper = MpidMessageWrapper::DeleteMessage(name);
+        assert!(unwrap!(managers.handle(&bob.auth, &bob.manager_auth, wrapper)).is_empty());
+        assert!(managers.inbox(&bob.name).is_empty());
+        let wrapper = MpidMessageWrapper::DeleteHeader(name);
+        assert!(unwrap!(managers.handle(&bob.auth, &alice.manager_auth, wrapper)).is_empty());
+        assert!(managers.outbox(&alice.name).is_empty());
+    }
+
+    #[test]
+    fn sender_deletes_message() {
+        let mut managers = MpidManager::new();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let message = alice.message_to(&bob, 100);
+        let name = unwrap!(message.name());
+        unwrap!(send(&mut managers, &alice, message.clone()));
+
+        // Alice's managers tell Bob's to remove the header. Only they can do that.
+        let wrapper = MpidMessageWrapper::DeleteMessage(name);
+        let response = unwrap!(managers.handle(&alice.auth, &alice.manager_auth, wrapper));
+        let delete_header = MpidMessageWrapper::DeleteHeader(name);
+        assert_eq!(response, vec![(bob.manager_auth.clone(), delete_header.clone())]);
+        assert!(managers.outbox(&alice.name).is_empty());
+        let carol = TestClient::new();
+        let wrapper = delete_header.clone();
+        let _ = unwrap!(managers.handle(&carol.manager_auth, &bob.manager_auth, wrapper));
+        assert_eq!(managers.inbox(&bob.name), vec![message.header()]);
+        let _ = unwrap!(managers.handle(&alice.manager_auth, &bob.manager_auth, delete_header));
+        assert!(managers.inbox(&bob.name).is_empty());
+    }
+
+    #[test]
+    fn invalid_requests() {
+        let mut managers = MpidManager::new();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+
+        // Bob can't store messages in Alice's outbox, not even ones signed by Alice.
+        let message = alice.message_to(&bob, 10);
+        let wrapper = MpidMessageWrapper::PutMessage(message.clone());
+        match managers.handle(&bob.auth, &alice.manager_auth, wrapper) {
+            Err(Error::InvalidAuthority) => (),
+            result => panic!("Expected InvalidAuthority, got {:?}", result),
+        }
+
+        // A message claiming to be from Alice must be signed by her.
+        let forged =
+            unwrap!(MpidMessage::new(alice.name, vec![], bob.name, vec![], &bob.secret_key));
+        match send(&mut managers, &alice, forged) {
+            Err(Error::InvalidSignature) => (),
+            result => panic!("Expected InvalidSignature, got {:?}", result),
+        }
+
+        // Headers are only accepted from the sender's managers, with the sender's key, and must be
+        // signed by the sender.
+        let wrapper = MpidMessageWrapper::PutHeader(message.header().clone(), alice.public_key);
+        match managers.handle(&bob.manager_auth, &bob.manager_auth, wrapper) {
+            Err(Error::InvalidAuthority) => (),
+            result => panic!("Expected InvalidAuthority, got {:?}", result),
+        }
+        let wrapper = MpidMessageWrapper::PutHeader(message.header().clone(), bob.public_key);
+        match managers.handle(&alice.manager_auth, &bob.manager_auth, wrapper) {
+            Err(Error::InvalidAuthority) => (),
+            result => panic!("Expected InvalidAuthority, got {:?}", result),
+        }
+        let forged =
+            unwrap!(MpidMessage::new(alice.name, vec![], bob.name, vec![], &bob.secret_key));
+        let wrapper = MpidMessageWrapper::PutHeader(forged.header().clone(), alice.public_key);
+        match managers.handle(&alice.manager_auth, &bob.manager_auth, wrapper) {
+            Err(Error::InvalidSignature) => (),
+            result => panic!("Expected InvalidSignature, got {:?}", result),
+        }
+        assert!(managers.outbox(&alice.name).is_empty());
+        assert!(managers.inbox(&bob.name).is_empty());
+    }
+
+    #[test]
+    fn outbox_limit() {
+        let mut managers = MpidManager::new();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let mut sent = 0;
+        loop {
+            match send(&mut managers, &alice, alice.message_to(&bob, MAX_BODY_SIZE)) {
+                Ok(()) => sent += 1,
+                Err(Error::OutboxFull) => break,
+                Err(error) => panic!("Unexpected error: {:?}", error),
+            }
+        }
+        assert!(sent * MAX_BODY_SIZE <= MAX_OUTBOX_SIZE);
+        assert_eq!(managers.outbox(&alice.name).len(), sent);
+        assert_eq!(managers.inbox(&bob.name).len(), sent);
+
+        // Once Bob deletes a message, there is room for another one.
+        let name = unwrap!(managers.outbox(&alice.name)[0].name());
+        let wrapper = MpidMessageWrapper::DeleteHeader(name);
+        let _ = unwrap!(managers.handle(&bob.auth, &alice.manager_auth, wrapper));
+        unwrap!(send(&mut managers, &alice, alice.message_to(&bob, MAX_BODY_SIZE)));
+        assert_eq!(managers.outbox(&alice.name).len(), sent);
+    }
+
+    #[test]
+    fn offline_delivery() {
+        let mut managers = MpidManager::new();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let message = alice.message_to(&bob, 100);
+        let header = MpidMessageWrapper::PutHeader(message.header().clone(), alice.public_key);
+
+        // Bob is offline, so the header is only stored.
+        let wrapper = MpidMessageWrapper::PutMessage(message.clone());
+        let mut response = unwrap!(managers.handle(&alice.auth, &alice.manager_auth, wrapper));
+        assert_eq!(response, vec![(bob.manager_auth.clone(), header.clone())]);
+        let (dst, wrapper) = unwrap!(response.pop());
+        assert!(unwrap!(managers.handle(&alice.manager_auth, &dst, wrapper)).is_empty());
+
+        // Only Bob himself can announce that he is online. Then he receives the stored header.
+        match managers.handle(&alice.auth, &bob.manager_auth, MpidMessageWrapper::Online) {
+            Err(Error::InvalidAuthority) => (),
+            result => panic!("Expected InvalidAuthority, got {:?}", result),
+        }
+        let wrapper = MpidMessageWrapper::Online;
+        let response = unwrap!(managers.handle(&bob.auth, &bob.manager_auth, wrapper));
+        assert_eq!(response, vec![(bob.auth.clone(), header.clone())]);
+
+        // Further headers are passed on right away.
+        let message = alice.message_to(&bob, 100);
+        let header = MpidMessageWrapper::PutHeader(message.header().clone(), alice.public_key);
+        let response =
+            unwrap!(managers.handle(&alice.manager_auth, &bob.manager_auth, header.clone()));
+        assert_eq!(response, vec![(bob.auth.clone(), header)]);
+        assert_eq!(managers.inbox(&bob.name).len(), 2);
+    }
+
+    #[test]
+    fn expiry() {
+        use mock_crust::{self, Network};
+        use std::time::Duration;
+
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let mut managers = MpidManager::with_ttl(Duration::from_secs(60));
+        managers.clock = mock_crust::make_current(&handle, Clock::new);
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let message = alice.message_to(&bob, 100);
+        unwrap!(send(&mut managers, &alice, message.clone()));
+
+        network.advance_time(Duration::from_secs(59));
+        let wrapper = MpidMessageWrapper::GetOutboxHeaders;
+        let response = unwrap!(managers.handle(&alice.auth, &alice.manager_auth, wrapper.clone()));
+        let headers = vec![message.header().clone()];
+        assert_eq!(response,
+                   vec![(alice.auth.clone(),
+                         MpidMessageWrapper::GetOutboxHeadersResponse(headers))]);
+        assert_eq!(managers.inbox(&bob.name), vec![message.header()]);
+
+        network.advance_time(Duration::from_secs(1));
+        let response = unwrap!(managers.handle(&alice.auth, &alice.manager_auth, wrapper));
+        assert_eq!(response,
+                   vec![(alice.auth.clone(),
+                         MpidMessageWrapper::GetOutboxHeadersResponse(vec![]))]);
+        assert!(managers.outbox(&alice.name).is_empty());
+        assert!(managers.inbox(&bob.name).is_empty());
+    }
+
+    #[test]
+    fn refresh() {
+        let mut old_manager = MpidManager::new();
+        let mut new_manager = MpidManager::new();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let message = alice.message_to(&bob, 100);
+        unwrap!(send(&mut old_manager, &alice, message.clone()));
+        let wrapper = MpidMessageWrapper::Online;
+        let _ = unwrap!(old_manager.handle(&bob.auth, &bob.manager_auth, wrapper));
+
+        // A new manager of Alice's account receives her outbox.
+        assert!(refresh_account(&old_manager, &mut new_manager, &alice.name).is_empty());
+        assert_eq!(new_manager.outbox(&alice.name), vec![&message]);
+
+        // A new manager of Bob's account receives his inbox, but only delivers the headers once
+        // Bob says again that he is online. Repeated refreshes don't deliver them again.
+        assert!(refresh_account(&old_manager, &mut new_manager, &bob.name).is_empty());
+        assert_eq!(new_manager.inbox(&bob.name), vec![message.header()]);
+        let wrapper = MpidMessageWrapper::Online;
+        let header = MpidMessageWrapper::PutHeader(message.header().clone(), alice.public_key);
+        assert_eq!(unwrap!(new_manager.handle(&bob.auth, &bob.manager_auth, wrapper)),
+                   vec![(bob.auth.clone(), header)]);
+        assert!(refresh_account(&old_manager, &mut new_manager, &bob.name).is_empty());
+    }
+
+    #[test]
+    fn refresh_keeps_expiry() {
+        use mock_crust::{self, Network};
+        use std::time::Duration;
+
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let mut old_manager = MpidManager::with_ttl(Duration::from_secs(60));
+        let mut new_manager = MpidManager::with_ttl(Duration::from_secs(60));
+        old_manager.clock = mock_crust::make_current(&handle, Clock::new);
+        new_manager.clock = old_manager.clock.clone();
+        let alice = TestClient::new();
+        let bob = TestClient::new();
+        let message = alice.message_to(&bob, 100);
+        unwrap!(send(&mut old_manager, &alice, message.clone()));
+
+        // The refreshes don't depend on whether the client is online.
+        let refreshes = serialised_refreshes(&old_manager, &bob.name);
+        let wrapper = MpidMessageWrapper::Online;
+        let _ = unwrap!(old_manager.handle(&bob.auth, &bob.manager_auth, wrapper));
+        assert_eq!(refreshes, serialised_refreshes(&old_manager, &bob.name));
+
+        // The new manager doesn't count the lifetime anew from when it receives the entries.
+        network.advance_time(Duration::from_secs(30));
+        let _ = refresh_account(&old_manager, &mut new_manager, &alice.name);
+        let _ = refresh_account(&old_manager, &mut new_manager, &bob.name);
+        assert_eq!(new_manager.outbox(&alice.name), vec![&message]);
+        assert_eq!(new_manager.inbox(&bob.name), vec![message.header()]);
+        network.advance_time(Duration::from_secs(30));
+        new_manager.expire();
+        assert!(new_manager.outbox(&alice.name).is_empty());
+        assert!(new_manager.inbox(&bob.name).is_empty());
+
+        // Expired entries are not refreshed.
+        let _ = refresh_account(&old_manager, &mut new_manager, &alice.name);
+        assert!(new_manager.outbox(&alice.name).is_empty());
+    }
+}
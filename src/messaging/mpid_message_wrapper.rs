@@ -15,7 +15,12 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
-use super::{MpidHeader, MpidMessage};
+use data::Data;
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use plain_data::PlainData;
+use rust_sodium::crypto::hash::sha256;
+use rust_sodium::crypto::sign::PublicKey;
+use super::{Error, MpidHeader, MpidMessage};
 use xor_name::XorName;
 
 /// A serialisable wrapper to allow multiplexing all MPID message types and actions via a single
@@ -25,11 +30,14 @@ pub enum MpidMessageWrapper {
     /// Sent by a Client to its MpidManagers to notify them that it has just connected to the
     /// network.
     Online,
-    /// Sent by a Client to its MpidManagers when storing a new `MpidMessage`.
+    /// Sent by a Client to its MpidManagers when storing a new `MpidMessage`.  Also sent by the
+    /// sender's MpidManagers to the receiver's MpidManagers in response to a `GetMessage`, and by
+    /// those to the receiver.
     PutMessage(MpidMessage),
     /// Sent by the sender's MpidManagers to the receiver's MpidManagers to alert them of a new
-    /// message.
-    PutHeader(MpidHeader),
+    /// message, and by those to the receiver.  The key is the sender's, to validate the header and
+    /// message with.
+    PutHeader(MpidHeader, PublicKey),
     /// Sent by the receiver to its MpidManagers to try to retrieve the message corresponding to the
     /// header.
     GetMessage(MpidHeader),
@@ -48,6 +56,54 @@ pub enum MpidMessageWrapper {
     /// Sent by a Client to its MpidManagers to delete the named message from its inbox or outbox.
     DeleteMessage(XorName),
     /// Sent by a receiving Client to the sender's MpidManagers to delete the named message's header
-    /// from the sender's outbox.
+    /// from the sender's outbox.  Also sent by the sender's MpidManagers to the receiver's
+    /// MpidManagers when the sender deletes the message, to delete its header from the inbox.
     DeleteHeader(XorName),
 }
+
+impl MpidMessageWrapper {
+    /// Serialises the wrapper into the `Data` of a `Request::Post`.
+    ///
+    /// The data is named after the hash of its content, so all members of a group sending the same
+    /// wrapper produce identical requests.
+    pub fn to_data(&self) -> Result<Data, Error> {
+        let value = try!(serialise(self));
+        let name = XorName(sha256::hash(&value).0);
+        Ok(Data::Plain(PlainData::new(name, value)))
+    }
+
+    /// Deserialises a wrapper created with [`to_data()`](#method.to_data). Returns `None` if `data`
+    /// is not an MPID message.
+    pub fn from_data(data: &Data) -> Option<MpidMessageWrapper> {
+        if let Data::Plain(ref plain_data) = *data {
+            if *plain_data.name() == XorName(sha256::hash(plain_data.value()).0) {
+                return deserialise(plain_data.value()).ok();
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::Data;
+    use plain_data::PlainData;
+    use rand;
+    use super::*;
+
+    #[test]
+    fn data_round_trip() {
+        let names = vec![rand::random(), rand::random()];
+        let wrapper = MpidMessageWrapper::OutboxHas(names);
+        let data = unwrap!(wrapper.to_data());
+        assert_eq!(MpidMessageWrapper::from_data(&data), Some(wrapper));
+
+        // Plain data that wasn't created from a wrapper is not mistaken for one.
+        let value = match data {
+            Data::Plain(plain_data) => plain_data.value().clone(),
+            data => panic!("Expected plain data, got {:?}", data),
+        };
+        let other_data = Data::Plain(PlainData::new(rand::random(), value));
+        assert_eq!(MpidMessageWrapper::from_data(&other_data), None);
+    }
+}
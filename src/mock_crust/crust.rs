@@ -62,6 +62,16 @@ impl Service {
         Ok(())
     }
 
+    /// Start the bootstrapping procedure against the given contacts instead of the ones in the
+    /// config and bootstrap cache.
+    pub fn start_bootstrap_with_contacts(&mut self,
+                                         contacts: Vec<SocketAddr>,
+                                         blacklist: HashSet<SocketAddr>)
+                                         -> Result<(), CrustError> {
+        self.lock_and_poll(|imp| imp.start_bootstrap_with_contacts(contacts, blacklist));
+        Ok(())
+    }
+
     /// Stops the ongoing bootstrap.
     /// Note: This currently doesn't do anything, because mock bootstrap is
     /// not interruptible. This might change in the future, if needed.
@@ -229,6 +239,11 @@ impl PubConnectionInfo {
     pub fn id(&self) -> PeerId {
         self.0
     }
+
+    /// The addresses the peer can be reached at directly, like Crust's `for_direct` field.
+    pub fn direct_addrs(&self) -> Vec<SocketAddr> {
+        vec![support::to_socket_addr(&self.1)]
+    }
 }
 
 /// The result of a `Service::prepare_contact_info` call.
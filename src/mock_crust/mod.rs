@@ -23,5 +23,5 @@ mod support;
 #[cfg(test)]
 mod tests;
 
-pub use self::support::{Config, Endpoint, Network, ServiceHandle};
-pub use self::support::make_current;
+pub use self::support::{Config, Endpoint, LinkFaults, Network, ServiceHandle};
+pub use self::support::{get_current_network, make_current};
@@ -15,14 +15,18 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use action::Action;
 use maidsafe_utilities::SeededRng;
 use rand::{Rng, XorShiftRng};
 use rust_sodium;
 use std::cell::RefCell;
 use std::cmp;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
+use types::RoutingActionSender;
+use utils;
 
 use super::crust::{ConnectionInfoResult, CrustEventSender, Event, PeerId, PrivConnectionInfo,
                    PubConnectionInfo};
@@ -34,9 +38,66 @@ pub struct Network(Rc<RefCell<NetworkImpl>>);
 pub struct NetworkImpl {
     services: HashMap<Endpoint, Weak<RefCell<ServiceImpl>>>,
     next_endpoint: usize,
-    queue: HashMap<(Endpoint, Endpoint), VecDeque<Packet>>,
+    // Queued packets per link, each with the step at which it becomes deliverable.
+    queue: HashMap<(Endpoint, Endpoint), VecDeque<(u64, Packet)>>,
     blocked_connections: HashSet<(Endpoint, Endpoint)>,
+    default_faults: LinkFaults,
+    link_faults: HashMap<(Endpoint, Endpoint), LinkFaults>,
+    partitions: Vec<Partition>,
+    // Virtual time: the number of packets delivered so far, or later if we had to skip ahead to
+    // the next delayed packet.
+    step: u64,
     rng: SeededRng,
+    // Virtual time of the mock timers and clocks. It only advances when `advance_time` is called.
+    epoch: Instant,
+    // The Unix time at `epoch`, in milliseconds.
+    unix_epoch_millis: u64,
+    elapsed: Duration,
+    timers: HashMap<usize, MockTimer>,
+    next_timer_id: usize,
+}
+
+// The timeouts scheduled with a mock timer, and the sender to raise them with.
+struct MockTimer {
+    // The period of each pending timeout, if it is repeating, by deadline and token.
+    deadlines: BTreeMap<(Duration, u64), Option<Duration>>,
+    sender: RoutingActionSender,
+}
+
+/// Faults simulated on a link between two endpoints. All random decisions are driven by the
+/// network's seeded random number generator, so a run can be reproduced from its seed.
+///
+/// Drop, duplication and reordering only affect `Message` packets; crust's own control packets
+/// (bootstrapping, connecting, disconnecting) are only ever delayed.
+#[derive(Clone, Debug, Default)]
+pub struct LinkFaults {
+    /// Probability in `[0, 1]` that a message is silently dropped.
+    pub drop_probability: f64,
+    /// Probability in `[0, 1]` that a message is delivered twice.
+    pub duplicate_probability: f64,
+    /// Probability in `[0, 1]` that a message overtakes other messages already queued on the link.
+    pub reorder_probability: f64,
+    /// Inclusive range `(min, max)` of network steps a packet is delayed by, chosen uniformly.
+    pub delay: (u64, u64),
+}
+
+// A set of endpoints cut off from the rest of the network between the steps `start` (inclusive)
+// and `end` (exclusive, `None` meaning until explicitly healed).
+struct Partition {
+    side: HashSet<Endpoint>,
+    start: u64,
+    end: Option<u64>,
+    started: bool,
+}
+
+impl Partition {
+    fn is_active(&self, step: u64) -> bool {
+        self.start <= step && self.end.map_or(true, |end| step < end)
+    }
+
+    fn separates(&self, lhs: Endpoint, rhs: Endpoint) -> bool {
+        self.side.contains(&lhs) != self.side.contains(&rhs)
+    }
 }
 
 impl Network {
@@ -53,7 +114,16 @@ impl Network {
             next_endpoint: 0,
             queue: HashMap::new(),
             blocked_connections: HashSet::new(),
+            default_faults: LinkFaults::default(),
+            link_faults: HashMap::new(),
+            partitions: Vec::new(),
+            step: 0,
             rng: SeededRng::new(),
+            epoch: Instant::now(),
+            unix_epoch_millis: utils::unix_time_millis(),
+            elapsed: Duration::from_secs(0),
+            timers: HashMap::new(),
+            next_timer_id: 0,
         })))
     }
 
@@ -99,6 +169,150 @@ impl Network {
         imp.blocked_connections.insert((sender, receiver));
     }
 
+    /// Sets the faults simulated on all links which don't have their own faults configured.
+    pub fn set_default_faults(&self, faults: LinkFaults) {
+        self.0.borrow_mut().default_faults = faults;
+    }
+
+    /// Sets the faults simulated on packets from `sender` to `receiver`.
+    pub fn set_link_faults(&self, sender: Endpoint, receiver: Endpoint, faults: LinkFaults) {
+        let _ = self.0.borrow_mut().link_faults.insert((sender, receiver), faults);
+    }
+
+    /// Removes all simulated faults, including partitions, and unblocks all connections.
+    pub fn clear_faults(&self) {
+        let mut imp = self.0.borrow_mut();
+        imp.default_faults = LinkFaults::default();
+        imp.link_faults.clear();
+        imp.partitions.clear();
+        imp.blocked_connections.clear();
+    }
+
+    /// Immediately separates the given endpoints from the rest of the network, until healed.
+    ///
+    /// Existing connections across the partition are lost, messages across it are dropped and
+    /// attempts to bootstrap or connect across it fail.
+    pub fn partition(&self, side: &[Endpoint]) {
+        self.schedule_partition(side, 0, None);
+        self.apply_partitions();
+    }
+
+    /// Schedules a partition separating the given endpoints from the rest of the network, starting
+    /// `delay` steps from now and healing `duration` steps after that (or never, if `None`).
+    pub fn schedule_partition(&self, side: &[Endpoint], delay: u64, duration: Option<u64>) {
+        let mut imp = self.0.borrow_mut();
+        let start = imp.step + delay;
+        imp.partitions.push(Partition {
+            side: side.iter().cloned().collect(),
+            start: start,
+            end: duration.map(|duration| start + duration),
+            started: false,
+        });
+    }
+
+    /// Heals all currently active partitions. Connections lost due to them are not restored.
+    pub fn heal_partitions(&self) {
+        let mut imp = self.0.borrow_mut();
+        let step = imp.step;
+        imp.partitions.retain(|partition| !partition.is_active(step));
+    }
+
+    /// Returns the current virtual time of the network, in steps.
+    pub fn step(&self) -> u64 {
+        self.0.borrow().step
+    }
+
+    /// Returns the virtual time that has passed for the mock timers.
+    pub fn elapsed(&self) -> Duration {
+        self.0.borrow().elapsed
+    }
+
+    /// Returns the current virtual time, as read by the mock clocks.
+    pub fn now(&self) -> Instant {
+        let imp = self.0.borrow();
+        imp.epoch + imp.elapsed
+    }
+
+    /// Returns the current virtual time in milliseconds since the Unix epoch.
+    pub fn unix_time_millis(&self) -> u64 {
+        let imp = self.0.borrow();
+        imp.unix_epoch_millis + imp.elapsed.as_secs() * 1000 +
+        (imp.elapsed.subsec_nanos() / 1_000_000) as u64
+    }
+
+    /// Advances the virtual time of the mock timers by `duration`, raising all timeouts that become
+    /// due, in order. Repeating timeouts can be raised several times.
+    pub fn advance_time(&self, duration: Duration) {
+        let target = self.0.borrow().elapsed + duration;
+        loop {
+            let mut imp = self.0.borrow_mut();
+            let next = imp.timers
+                .iter()
+                .filter_map(|(&id, timer)| {
+                    timer.deadlines.keys().next().map(|&(deadline, token)| (deadline, id, token))
+                })
+                .filter(|&(deadline, _, _)| deadline <= target)
+                .min();
+            let (deadline, id, token) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            imp.elapsed = deadline;
+            let timer = unwrap!(imp.timers.get_mut(&id));
+            if let Some(Some(period)) = timer.deadlines.remove(&(deadline, token)) {
+                let _ = timer.deadlines.insert((deadline + period, token), Some(period));
+            }
+            let _ = timer.sender.send(Action::Timeout(token));
+        }
+        self.0.borrow_mut().elapsed = target;
+    }
+
+    /// Registers a new mock timer that raises its timeouts using `sender`, and returns its ID.
+    pub fn add_timer(&self, sender: RoutingActionSender) -> usize {
+        let mut imp = self.0.borrow_mut();
+        let id = imp.next_timer_id;
+        imp.next_timer_id += 1;
+        let timer = MockTimer {
+            deadlines: BTreeMap::new(),
+            sender: sender,
+        };
+        let _ = imp.timers.insert(id, timer);
+        id
+    }
+
+    /// Removes the given mock timer and all its pending timeouts.
+    pub fn remove_timer(&self, id: usize) {
+        let _ = self.0.borrow_mut().timers.remove(&id);
+    }
+
+    /// Schedules a timeout with the given token after `duration`, and then every `period`, if
+    /// given.
+    pub fn schedule_timeout(&self,
+                            id: usize,
+                            token: u64,
+                            duration: Duration,
+                            period: Option<Duration>) {
+        let mut imp = self.0.borrow_mut();
+        let deadline = imp.elapsed + duration;
+        if let Some(timer) = imp.timers.get_mut(&id) {
+            let _ = timer.deadlines.insert((deadline, token), period);
+        }
+    }
+
+    /// Cancels the timeout with the given token.
+    pub fn cancel_timeout(&self, id: usize, token: u64) {
+        if let Some(timer) = self.0.borrow_mut().timers.get_mut(&id) {
+            let keys = timer.deadlines
+                .keys()
+                .filter(|&&(_, t)| t == token)
+                .cloned()
+                .collect::<Vec<_>>();
+            for key in keys {
+                let _ = timer.deadlines.remove(&key);
+            }
+        }
+    }
+
     /// Construct a new [`XorShiftRng`](https://doc.rust-lang.org/rand/rand/struct.XorShiftRng.html)
     /// using a seed generated from random data provided by `self`.
     pub fn new_rng(&self) -> XorShiftRng {
@@ -109,18 +323,85 @@ impl Network {
         self.0.borrow().blocked_connections.contains(&(sender, receiver))
     }
 
+    fn partitioned(&self, sender: Endpoint, receiver: Endpoint) -> bool {
+        let imp = self.0.borrow();
+        imp.partitions
+            .iter()
+            .any(|partition| partition.is_active(imp.step) && partition.separates(sender, receiver))
+    }
+
     fn send(&self, sender: Endpoint, receiver: Endpoint, packet: Packet) {
-        self.0
-            .borrow_mut()
-            .queue
-            .entry((sender, receiver))
-            .or_insert_with(VecDeque::new)
-            .push_back(packet);
+        let mut guard = self.0.borrow_mut();
+        let imp = &mut *guard;
+        let faults = imp.link_faults.get(&(sender, receiver)).unwrap_or(&imp.default_faults);
+        let is_message = packet.is_message();
+
+        if is_message && faults.drop_probability > 0.0 &&
+           imp.rng.gen::<f64>() < faults.drop_probability {
+            trace!("Dropping packet from {:?} to {:?}", sender, receiver);
+            return;
+        }
+        let copies = if is_message && faults.duplicate_probability > 0.0 &&
+                        imp.rng.gen::<f64>() < faults.duplicate_probability {
+            2
+        } else {
+            1
+        };
+        let reorder = is_message && faults.reorder_probability > 0.0 &&
+                      imp.rng.gen::<f64>() < faults.reorder_probability;
+        let (min_delay, max_delay) = faults.delay;
+        let delay = if max_delay > min_delay {
+            imp.rng.gen_range(min_delay, max_delay + 1)
+        } else {
+            min_delay
+        };
+        let due = imp.step + delay;
+
+        let packets = imp.queue.entry((sender, receiver)).or_insert_with(VecDeque::new);
+        for _ in 0..copies {
+            // Keep packets on a link in order unless they are explicitly reordered, in which case
+            // they may only overtake the trailing run of queued messages.
+            let deliver_at = packets.back().map_or(due, |&(last, _)| cmp::max(last, due));
+            if reorder {
+                let movable = packets.iter()
+                    .rev()
+                    .take_while(|&&(_, ref packet)| packet.is_message())
+                    .count();
+                let index = packets.len() - imp.rng.gen_range(0, movable + 1);
+                packets.insert(index, (deliver_at, packet.clone()));
+            } else {
+                packets.push_back((deliver_at, packet.clone()));
+            }
+        }
     }
 
     fn pop_packet(&self) -> Option<(Endpoint, Endpoint, Packet)> {
+        self.apply_partitions();
         let mut network_impl = self.0.borrow_mut();
-        let keys: Vec<_> = network_impl.queue.keys().cloned().collect();
+        if network_impl.queue.is_empty() {
+            return None;
+        }
+        let step = network_impl.step;
+        let mut keys: Vec<_> = network_impl.queue
+            .iter()
+            .filter(|&(_, packets)| packets.front().map_or(false, |&(at, _)| at <= step))
+            .map(|(key, _)| *key)
+            .collect();
+        if keys.is_empty() {
+            // Nothing is due yet: skip ahead to the earliest delayed packet.
+            let next = unwrap!(network_impl.queue
+                .values()
+                .filter_map(|packets| packets.front().map(|&(at, _)| at))
+                .min());
+            network_impl.step = next;
+            keys = network_impl.queue
+                .iter()
+                .filter(|&(_, packets)| packets.front().map_or(false, |&(at, _)| at <= next))
+                .map(|(key, _)| *key)
+                .collect();
+        }
+        // Sort the keys, so that the choice only depends on the seed, not on the hash map order.
+        keys.sort_by_key(|&(sender, receiver)| (sender.0, receiver.0));
         let (sender, receiver) = if let Some(key) = network_impl.rng.choose(&keys) {
             *key
         } else {
@@ -128,14 +409,48 @@ impl Network {
         };
         let result = network_impl.queue
             .get_mut(&(sender, receiver))
-            .and_then(|packets| packets.pop_front().map(|packet| (sender, receiver, packet)));
+            .and_then(|packets| packets.pop_front().map(|(_, packet)| (sender, receiver, packet)));
         if result.is_some() &&
            network_impl.queue.get(&(sender, receiver)).map_or(false, VecDeque::is_empty) {
             let _ = network_impl.queue.remove(&(sender, receiver));
         }
+        network_impl.step += 1;
         result
     }
 
+    // Starts any partitions which are due, disconnecting all connections across them, and drops
+    // partitions which have healed.
+    fn apply_partitions(&self) {
+        let mut cut = Vec::new();
+        {
+            let mut guard = self.0.borrow_mut();
+            let imp = &mut *guard;
+            let step = imp.step;
+            imp.partitions.retain(|partition| partition.end.map_or(true, |end| step < end));
+            let services = &imp.services;
+            for partition in imp.partitions.iter_mut().filter(|p| !p.started && p.start <= step) {
+                partition.started = true;
+                for endpoint in &partition.side {
+                    let service = match services.get(endpoint).and_then(|s| s.upgrade()) {
+                        Some(service) => service,
+                        None => continue,
+                    };
+                    let service = service.borrow();
+                    cut.extend(service.connections
+                        .iter()
+                        .map(|&(_, peer_endpoint)| (*endpoint, peer_endpoint))
+                        .filter(|&(endpoint, peer_endpoint)| {
+                            partition.separates(endpoint, peer_endpoint)
+                        }));
+                }
+            }
+        }
+        for (endpoint, peer_endpoint) in cut {
+            self.send(endpoint, peer_endpoint, Packet::Disconnect);
+            self.send(peer_endpoint, endpoint, Packet::Disconnect);
+        }
+    }
+
     fn process_packet(&self, sender: Endpoint, receiver: Endpoint, packet: Packet) {
         if self.connection_blocked(sender, receiver) {
             if let Some(failure) = packet.to_failure() {
@@ -144,6 +459,16 @@ impl Network {
             }
         }
 
+        if self.partitioned(sender, receiver) {
+            if let Some(failure) = packet.to_failure() {
+                self.send(receiver, sender, failure);
+                return;
+            } else if packet.is_message() {
+                trace!("Dropping packet from {:?} to {:?} across partition", sender, receiver);
+                return;
+            }
+        }
+
         if let Some(service) = self.find_service(receiver) {
             service.borrow_mut().receive_packet(sender, packet);
         } else if let Some(failure) = packet.to_failure() {
@@ -227,9 +552,21 @@ impl ServiceImpl {
     }
 
     pub fn start_bootstrap(&mut self, blacklist: HashSet<SocketAddr>) {
+        let contacts = self.config.hard_coded_contacts.clone();
+        self.bootstrap_to(contacts, blacklist);
+    }
+
+    pub fn start_bootstrap_with_contacts(&mut self,
+                                         contacts: Vec<SocketAddr>,
+                                         blacklist: HashSet<SocketAddr>) {
+        let contacts = contacts.iter().map(to_endpoint).collect();
+        self.bootstrap_to(contacts, blacklist);
+    }
+
+    fn bootstrap_to(&mut self, contacts: Vec<Endpoint>, blacklist: HashSet<SocketAddr>) {
         let mut pending_bootstraps = 0;
 
-        for endpoint in &self.config.hard_coded_contacts {
+        for endpoint in &contacts {
             if *endpoint != self.endpoint && !blacklist.contains(&to_socket_addr(endpoint)) {
                 self.send_packet(*endpoint, Packet::BootstrapRequest(self.peer_id));
                 pending_bootstraps += 1;
@@ -355,7 +692,9 @@ impl ServiceImpl {
         if let Some(peer_id) = self.find_peer_id_by_endpoint(&peer_endpoint) {
             self.send_event(Event::NewMessage(peer_id, data));
         } else {
-            unreachable!("Received message from non-connected {:?}", peer_endpoint);
+            // This can only happen with simulated faults, e.g. a delayed message arriving after
+            // the connection was cut by a partition.
+            trace!("Dropping message from non-connected {:?}", peer_endpoint);
         }
     }
 
@@ -470,11 +809,15 @@ impl Drop for ServiceImpl {
 
 /// Creates a `SocketAddr` with the endpoint as its port, so that endpoints and addresses can be
 /// easily mapped to each other during testing.
-fn to_socket_addr(endpoint: &Endpoint) -> SocketAddr {
+pub fn to_socket_addr(endpoint: &Endpoint) -> SocketAddr {
     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(123, 123, 255, 255)),
                     endpoint.0 as u16)
 }
 
+fn to_endpoint(addr: &SocketAddr) -> Endpoint {
+    Endpoint(addr.port() as usize)
+}
+
 /// Simulated crust config file.
 #[derive(Clone)]
 pub struct Config {
@@ -520,6 +863,13 @@ enum Packet {
 }
 
 impl Packet {
+    fn is_message(&self) -> bool {
+        match *self {
+            Packet::Message(_) => true,
+            _ => false,
+        }
+    }
+
     // Given a request packet, returns the corresponding failure packet.
     fn to_failure(&self) -> Option<Packet> {
         match *self {
@@ -538,15 +888,25 @@ thread_local! {
     static CURRENT: RefCell<Option<ServiceHandle>> = RefCell::new(None)
 }
 
+thread_local! {
+    static CURRENT_NETWORK: RefCell<Option<Network>> = RefCell::new(None)
+}
+
 /// Make the `ServiceHandle` current so it can be picked up by mock `Service`s created
 /// inside the passed-in lambda.
 pub fn make_current<F, R>(handle: &ServiceHandle, f: F) -> R
     where F: FnOnce() -> R
 {
-    CURRENT.with(|current| {
-        *current.borrow_mut() = Some(handle.clone());
-        let result = f();
-        *current.borrow_mut() = None;
+    let network = handle.0.borrow().network.clone();
+    CURRENT_NETWORK.with(|current_network| {
+        *current_network.borrow_mut() = Some(network);
+        let result = CURRENT.with(|current| {
+            *current.borrow_mut() = Some(handle.clone());
+            let result = f();
+            *current.borrow_mut() = None;
+            result
+        });
+        *current_network.borrow_mut() = None;
         result
     })
 }
@@ -554,3 +914,11 @@ pub fn make_current<F, R>(handle: &ServiceHandle, f: F) -> R
 pub fn get_current() -> ServiceHandle {
     CURRENT.with(|current| unwrap!(current.borrow_mut().take(), "Couldn't borrow service."))
 }
+
+/// Returns the network of the current `ServiceHandle`, for the mock clock. Unlike `get_current`,
+/// this doesn't consume the handle, and it also works after the `Service` has been created.
+pub fn get_current_network() -> Network {
+    CURRENT_NETWORK.with(|current| {
+        unwrap!(current.borrow().clone(), "Couldn't borrow network.")
+    })
+}
@@ -21,8 +21,8 @@ use maidsafe_utilities::event_sender::{MaidSafeEventCategory, MaidSafeObserver};
 use std::collections::HashSet;
 use std::sync::mpsc::{self, Receiver};
 
-use super::crust::{CrustEventSender, Event, Service};
-use super::support::{Config, Network};
+use super::crust::{CrustEventSender, Event, PeerId, Service};
+use super::support::{Config, Endpoint, LinkFaults, Network};
 
 fn get_event_sender() -> (CrustEventSender, Receiver<MaidSafeEventCategory>, Receiver<Event>) {
     let (category_tx, category_rx) = mpsc::channel();
@@ -223,3 +223,84 @@ fn drop() {
     mem::drop(service_0);
     expect_event!(event_rx_1, Event::LostPeer(id) => assert_eq!(id, id_0));
 }
+
+// Bootstraps a service with endpoint 1 off one with endpoint 0, and returns both services, their
+// event receivers and each other's peer ids.
+fn bootstrap_pair(network: &Network)
+                  -> ((Service, Receiver<Event>, PeerId), (Service, Receiver<Event>, PeerId)) {
+    let handle0 = network.new_service_handle(None, None);
+    let config = Config::with_contacts(&[handle0.endpoint()]);
+    let handle1 = network.new_service_handle(Some(config), None);
+
+    let (event_sender_0, _category_rx_0, event_rx_0) = get_event_sender();
+    let (event_sender_1, _category_rx_1, event_rx_1) = get_event_sender();
+
+    let mut service_0 = unwrap!(Service::with_handle(&handle0, event_sender_0));
+    unwrap!(service_0.start_listening_tcp());
+    expect_event!(event_rx_0, Event::ListenerStarted(_));
+
+    let mut service_1 = unwrap!(Service::with_handle(&handle1, event_sender_1));
+    unwrap!(service_1.start_bootstrap(HashSet::new()));
+
+    let id_0 = expect_event!(event_rx_1, Event::BootstrapConnect(id, _) => id);
+    let id_1 = expect_event!(event_rx_0, Event::BootstrapAccept(id) => id);
+
+    ((service_0, event_rx_0, id_1), (service_1, event_rx_1, id_0))
+}
+
+#[test]
+fn drop_and_duplicate_messages() {
+    let network = Network::new(None);
+    let ((service_0, _event_rx_0, id_1), (_service_1, event_rx_1, _)) = bootstrap_pair(&network);
+
+    network.set_default_faults(LinkFaults { drop_probability: 1.0, ..LinkFaults::default() });
+    unwrap!(service_0.send(id_1, vec![1], 0));
+    assert!(event_rx_1.try_recv().is_err());
+
+    network.set_default_faults(LinkFaults { duplicate_probability: 1.0, ..LinkFaults::default() });
+    unwrap!(service_0.send(id_1, vec![2], 0));
+    expect_event!(event_rx_1, Event::NewMessage(_, msg) => assert_eq!(msg, vec![2]));
+    expect_event!(event_rx_1, Event::NewMessage(_, msg) => assert_eq!(msg, vec![2]));
+    assert!(event_rx_1.try_recv().is_err());
+}
+
+#[test]
+fn delayed_messages_stay_in_order() {
+    let network = Network::new(None);
+    let ((service_0, _event_rx_0, id_1), (_service_1, event_rx_1, _)) = bootstrap_pair(&network);
+
+    network.set_default_faults(LinkFaults { delay: (0, 10), ..LinkFaults::default() });
+    for i in 0..10 {
+        unwrap!(service_0.send(id_1, vec![i], 0));
+    }
+    for i in 0..10 {
+        expect_event!(event_rx_1, Event::NewMessage(_, msg) => assert_eq!(msg, vec![i]));
+    }
+    assert!(network.step() > 0);
+}
+
+#[test]
+fn partition_and_heal() {
+    let network = Network::new(None);
+    let ((service_0, event_rx_0, id_1), (_service_1, event_rx_1, id_0)) =
+        bootstrap_pair(&network);
+
+    // Service 1 was created second, so it has endpoint 1.
+    network.partition(&[Endpoint(1)]);
+    network.poll();
+    expect_event!(event_rx_0, Event::LostPeer(id) => assert_eq!(id, id_1));
+    expect_event!(event_rx_1, Event::LostPeer(id) => assert_eq!(id, id_0));
+    assert!(service_0.send(id_1, vec![1], 0).is_err());
+
+    // Bootstrapping across the partition fails until it is healed.
+    let handle2 = network.new_service_handle(Some(Config::with_contacts(&[Endpoint(0)])), None);
+    let (event_sender_2, _category_rx_2, event_rx_2) = get_event_sender();
+    let mut service_2 = unwrap!(Service::with_handle(&handle2, event_sender_2));
+    network.partition(&[handle2.endpoint()]);
+    unwrap!(service_2.start_bootstrap(HashSet::new()));
+    expect_event!(event_rx_2, Event::BootstrapFailed);
+
+    network.heal_partitions();
+    unwrap!(service_2.start_bootstrap(HashSet::new()));
+    let _ = expect_event!(event_rx_2, Event::BootstrapConnect(id, _) => id);
+}
@@ -17,25 +17,33 @@
 
 use action::Action;
 use authority::Authority;
+use ban_list::BanList;
 use cache::{Cache, NullCache};
 use data::{Data, DataIdentifier};
 use error::{InterfaceError, RoutingError};
 use event::Event;
+#[cfg(not(feature = "use-mock-crust"))]
+use event_loop::{Driver, Waker};
 use id::FullId;
 #[cfg(not(feature = "use-mock-crust"))]
 use maidsafe_utilities::thread;
 use messages::{CLIENT_GET_PRIORITY, DEFAULT_PRIORITY, RELOCATE_PRIORITY, Request, Response,
                UserMessage};
+use peer_manager::ProxyLimits;
+use replay_filter::ReplayFilter;
 #[cfg(feature = "use-mock-crust")]
 use routing_table::RoutingTable;
 #[cfg(not(feature = "use-mock-crust"))]
 use rust_sodium;
+use rust_sodium::crypto::sign;
+use session::Sessions;
 use state_machine::{State, StateMachine};
 use states;
-#[cfg(feature = "use-mock-crust")]
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
+use tunnels::TunnelLimits;
 use types::{MessageId, RoutingActionSender};
 use xor_name::XorName;
 
@@ -46,6 +54,12 @@ pub struct NodeBuilder {
     cache: Box<Cache>,
     first: bool,
     deny_other_local_nodes: bool,
+    resource_proof_difficulty: u8,
+    banned_keys: Vec<sign::PublicKey>,
+    encrypt_connections: bool,
+    proxy_limits: ProxyLimits,
+    replay_filter: ReplayFilter,
+    tunnel_limits: TunnelLimits,
 }
 
 impl NodeBuilder {
@@ -64,6 +78,62 @@ impl NodeBuilder {
         NodeBuilder { deny_other_local_nodes: true, ..self }
     }
 
+    /// Configures the difficulty of the resource proof that nodes joining our group have to
+    /// provide, as the number of leading zero bits of a hash they need to find. Solving it takes
+    /// about `2 ^ difficulty` hash computations. The default `0` doesn't require any proof, and
+    /// values above 24 are treated as 24: joining nodes reject harder challenges.
+    ///
+    /// This should be the same for all nodes in the network.
+    pub fn resource_proof_difficulty(self, difficulty: u8) -> NodeBuilder {
+        NodeBuilder { resource_proof_difficulty: difficulty, ..self }
+    }
+
+    /// Configures the node to refuse connections from peers with the given signing keys, e.g. the
+    /// ones returned by `Node::banned_keys` before a restart.
+    pub fn banned_keys(self, banned_keys: Vec<sign::PublicKey>) -> NodeBuilder {
+        NodeBuilder { banned_keys: banned_keys, ..self }
+    }
+
+    /// Configures whether the node encrypts and authenticates its traffic with its peers. If
+    /// enabled (the default), an encrypted session is established with every peer, and peers that
+    /// don't support it are rejected. Disabling it saves some CPU time if the transport is already
+    /// secure.
+    pub fn encrypt_connections(self, encrypt_connections: bool) -> NodeBuilder {
+        NodeBuilder { encrypt_connections: encrypt_connections, ..self }
+    }
+
+    /// Configures the maximum number of clients the node acts as a proxy for.
+    pub fn max_clients(mut self, max_clients: usize) -> NodeBuilder {
+        self.proxy_limits.max_clients = max_clients;
+        self
+    }
+
+    /// Configures the maximum number of joining nodes the node acts as a proxy for.
+    pub fn max_joining_nodes(mut self, max_joining_nodes: usize) -> NodeBuilder {
+        self.proxy_limits.max_joining_nodes = max_joining_nodes;
+        self
+    }
+
+    /// Configures the maximum number of bytes per second the node relays between a single pair of
+    /// peers when acting as a tunnel for them. Less important messages are dropped beyond that.
+    pub fn max_tunnel_pair_bandwidth(mut self, bytes_per_sec: u64) -> NodeBuilder {
+        self.tunnel_limits.max_pair_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Configures the maximum number of bytes per second the node relays between all pairs of
+    /// peers it acts as a tunnel for. Less important messages are dropped beyond that.
+    pub fn max_tunnel_bandwidth(mut self, bytes_per_sec: u64) -> NodeBuilder {
+        self.tunnel_limits.max_total_bytes_per_sec = bytes_per_sec;
+        self
+    }
+
+    /// Configures the node to reject the signed messages already accepted by the filter, e.g. the
+    /// one returned by `Node::replay_filter` before a restart.
+    pub fn replay_filter(self, replay_filter: ReplayFilter) -> NodeBuilder {
+        NodeBuilder { replay_filter: replay_filter, ..self }
+    }
+
     /// Creates new `Node`.
     ///
     /// It will automatically connect to the network in the same way a client does, but then
@@ -85,7 +155,33 @@ impl NodeBuilder {
             interface_result_tx: tx,
             interface_result_rx: rx,
             action_sender: action_sender,
-            _raii_joiner: raii_joiner,
+            driver: Driver::Thread(raii_joiner),
+        })
+    }
+
+    /// Creates a new `Node` that is driven by the application's own event loop instead of a
+    /// dedicated thread.
+    ///
+    /// `waker` is called whenever the node has events to process. The application must then call
+    /// `Node::poll` until it returns `false`, from the thread that owns the node. None of the
+    /// node's methods block in this mode: requests are handled right away.
+    #[cfg(not(feature = "use-mock-crust"))]
+    pub fn create_embedded(self,
+                           event_sender: Sender<Event>,
+                           waker: Box<Waker>)
+                           -> Result<Node, RoutingError> {
+        rust_sodium::init();  // enable shared global (i.e. safe to multithread now)
+
+        let (action_sender, mut machine) = self.make_state_machine(event_sender);
+        try!(machine.set_waker(waker));
+
+        let (tx, rx) = channel();
+
+        Ok(Node {
+            interface_result_tx: tx,
+            interface_result_rx: rx,
+            action_sender: action_sender,
+            driver: Driver::EventLoop(RefCell::new(machine)),
         })
     }
 
@@ -109,13 +205,22 @@ impl NodeBuilder {
                           -> (RoutingActionSender, StateMachine) {
         let full_id = FullId::new();
 
-        StateMachine::new(move |crust_service, timer| {
+        StateMachine::new(move |crust_service, timer, clock| {
+            let ban_list = BanList::new(self.banned_keys, clock.clone());
+            let sessions = Sessions::new(self.encrypt_connections, clock.clone());
             if self.first {
-                if let Some(state) = states::Node::first(self.cache,
+                if let Some(state) = states::Node::first(ban_list,
+                                                         self.cache,
+                                                         clock,
                                                          crust_service,
                                                          event_sender,
                                                          full_id,
-                                                         timer) {
+                                                         self.proxy_limits,
+                                                         self.replay_filter,
+                                                         self.resource_proof_difficulty,
+                                                         sessions,
+                                                         timer,
+                                                         self.tunnel_limits) {
                     State::Node(state)
                 } else {
                     State::Terminated
@@ -128,12 +233,19 @@ impl NodeBuilder {
                 let _ = event_sender.send(Event::Terminate);
                 State::Terminated
             } else {
-                State::Bootstrapping(states::Bootstrapping::new(self.cache,
+                State::Bootstrapping(states::Bootstrapping::new(ban_list,
+                                                                self.cache,
                                                                 false,
+                                                                clock,
                                                                 crust_service,
                                                                 event_sender,
                                                                 full_id,
-                                                                timer))
+                                                                self.proxy_limits,
+                                                                self.replay_filter,
+                                                                self.resource_proof_difficulty,
+                                                                sessions,
+                                                                timer,
+                                                                self.tunnel_limits))
             }
         })
     }
@@ -156,7 +268,7 @@ pub struct Node {
     machine: RefCell<StateMachine>,
 
     #[cfg(not(feature = "use-mock-crust"))]
-    _raii_joiner: ::maidsafe_utilities::thread::Joiner,
+    driver: Driver,
 }
 
 impl Node {
@@ -166,6 +278,12 @@ impl Node {
             cache: Box::new(NullCache),
             first: false,
             deny_other_local_nodes: false,
+            resource_proof_difficulty: 0,
+            banned_keys: vec![],
+            encrypt_connections: true,
+            proxy_limits: ProxyLimits::default(),
+            replay_filter: ReplayFilter::default(),
+            tunnel_limits: TunnelLimits::default(),
         }
     }
 
@@ -375,7 +493,7 @@ impl Node {
     /// Returns the names of the nodes in the routing table which are closest to the given one.
     pub fn close_group(&self, name: XorName) -> Result<Option<HashSet<XorName>>, InterfaceError> {
         let (result_tx, result_rx) = channel();
-        try!(self.action_sender.send(Action::CloseGroup {
+        try!(self.dispatch(Action::CloseGroup {
             name: name,
             result_tx: result_tx,
         }));
@@ -386,25 +504,80 @@ impl Node {
     /// Returns the name of this node.
     pub fn name(&self) -> Result<XorName, InterfaceError> {
         let (result_tx, result_rx) = channel();
-        try!(self.action_sender.send(Action::Name { result_tx: result_tx }));
+        try!(self.dispatch(Action::Name { result_tx: result_tx }));
         self.receive_action_result(&result_rx)
     }
 
     /// Returns the name of this node.
     pub fn quorum_size(&self) -> Result<usize, InterfaceError> {
         let (result_tx, result_rx) = channel();
-        try!(self.action_sender.send(Action::QuorumSize { result_tx: result_tx }));
+        try!(self.dispatch(Action::QuorumSize { result_tx: result_tx }));
+
+        self.receive_action_result(&result_rx)
+    }
+
+    /// Bans the peer with the given signing key: it is disconnected and not accepted as a client
+    /// or node anymore.
+    pub fn ban(&self, key: sign::PublicKey) -> Result<(), InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::Ban {
+            key: key,
+            result_tx: result_tx,
+        }));
+
+        try!(self.receive_action_result(&result_rx))
+    }
+
+    /// Lifts the ban on the given signing key. Returns `true` if it was banned.
+    pub fn unban(&self, key: sign::PublicKey) -> Result<bool, InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::Unban {
+            key: key,
+            result_tx: result_tx,
+        }));
+
+        try!(self.receive_action_result(&result_rx))
+    }
+
+    /// Returns the keys banned via `ban`. To keep the bans across restarts, these can be stored
+    /// and passed to `NodeBuilder::banned_keys`.
+    pub fn banned_keys(&self) -> Result<Vec<sign::PublicKey>, InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::BannedKeys { result_tx: result_tx }));
+
+        self.receive_action_result(&result_rx)
+    }
+
+    /// Returns the filter of recently accepted signed messages. To keep rejecting replays of them
+    /// across a restart, it can be serialised, stored and passed to `NodeBuilder::replay_filter`.
+    pub fn replay_filter(&self) -> Result<ReplayFilter, InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::ReplayFilter { result_tx: result_tx }));
 
         self.receive_action_result(&result_rx)
     }
 
+    /// Leaves the network gracefully. From now on, sending messages fails with `InvalidState`. The
+    /// node waits until its messages in transit have been acknowledged, or until `timeout` has
+    /// elapsed, then tells its peers that it is leaving, so that they update their routing tables
+    /// right away. Finally, it raises `Event::Terminate`.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), InterfaceError> {
+        let (result_tx, result_rx) = channel();
+        try!(self.dispatch(Action::Shutdown {
+            timeout: timeout,
+            result_tx: result_tx,
+        }));
+
+        try!(self.receive_action_result(&result_rx))
+    }
+
     fn send_action(&self,
                    src: Authority,
                    dst: Authority,
                    user_msg: UserMessage,
                    priority: u8)
                    -> Result<(), InterfaceError> {
-        try!(self.action_sender.send(Action::NodeSendMessage {
+        try!(self.dispatch(Action::NodeSendMessage {
             src: src,
             dst: dst,
             content: user_msg,
@@ -415,9 +588,34 @@ impl Node {
         try!(self.receive_action_result(&self.interface_result_rx))
     }
 
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn dispatch(&self, action: Action) -> Result<(), InterfaceError> {
+        self.driver.dispatch(&self.action_sender, action)
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    fn dispatch(&self, action: Action) -> Result<(), InterfaceError> {
+        Ok(try!(self.action_sender.send(action)))
+    }
+
     #[cfg(not(feature = "use-mock-crust"))]
     fn receive_action_result<T>(&self, rx: &Receiver<T>) -> Result<T, InterfaceError> {
-        Ok(try!(rx.recv()))
+        self.driver.receive(rx)
+    }
+}
+
+#[cfg(not(feature = "use-mock-crust"))]
+impl Node {
+    /// Processes one pending event, if the node was created with `NodeBuilder::create_embedded`.
+    /// Returns `false` if there was none, or if the node has terminated. Never blocks.
+    pub fn poll(&self) -> bool {
+        self.driver.poll()
+    }
+
+    /// Like `poll`, but if no event is pending, waits up to `timeout` for one. This lets an
+    /// application that has nothing else to do sleep until the node needs attention.
+    pub fn poll_timeout(&self, timeout: Duration) -> bool {
+        self.driver.poll_timeout(timeout)
     }
 }
 
@@ -448,6 +646,21 @@ impl Node {
         self.machine.borrow_mut().current_mut().clear_state()
     }
 
+    /// Returns whether this node currently assumes the network to be partitioned.
+    pub fn is_partitioned(&self) -> bool {
+        self.machine.borrow().current().is_partitioned()
+    }
+
+    /// Tries to reconnect to the contacts lost in a partition, as is done periodically on `Tick`.
+    pub fn retry_lost_contacts(&self) {
+        self.machine.borrow_mut().current_mut().retry_lost_contacts()
+    }
+
+    /// Replaces this node's keys with new ones, as is done periodically.
+    pub fn rotate_keys(&self) {
+        self.machine.borrow_mut().current_mut().rotate_keys()
+    }
+
     /// Returns whether the current state is `Node`.
     pub fn is_node(&self) -> bool {
         if let State::Node(..) = *self.machine.borrow().current() {
@@ -465,8 +678,65 @@ impl Node {
 
 impl Drop for Node {
     fn drop(&mut self) {
-        if let Err(err) = self.action_sender.send(Action::Terminate) {
+        if let Err(err) = self.dispatch(Action::Terminate) {
             debug!("Error {:?} sending event Core", err);
         }
     }
 }
+
+#[cfg(all(test, feature = "use-mock-crust"))]
+mod tests {
+    use super::Node;
+    use action::Action;
+    use event_loop::Driver;
+    use mock_crust::{self, Network};
+    use state_machine::State;
+    use std::cell::RefCell;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn drive_through_event_loop() {
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let (event_tx, _event_rx) = mpsc::channel();
+        let (action_sender, mut machine) = mock_crust::make_current(&handle, || {
+            Node::builder().first(true).make_state_machine(event_tx)
+        });
+        assert!(match *machine.current() {
+            State::Node(..) => true,
+            _ => false,
+        });
+        let (wake_tx, wake_rx) = mpsc::channel();
+        unwrap!(machine.set_waker(Box::new(move || {
+            let _ = wake_tx.send(());
+        })));
+        let driver = Driver::EventLoop(RefCell::new(machine));
+
+        // Requests from the application are handled right away.
+        let (result_tx, result_rx) = mpsc::channel();
+        unwrap!(driver.dispatch(&action_sender, Action::Name { result_tx: result_tx }));
+        let name = unwrap!(driver.receive(&result_rx));
+        assert!(wake_rx.try_recv().is_err());
+
+        // Queued events, e.g. from timers, wake the event loop, which then polls to handle them.
+        let (result_tx, result_rx) = mpsc::channel();
+        unwrap!(action_sender.send(Action::Name { result_tx: result_tx }));
+        assert!(result_rx.try_recv().is_err());
+        unwrap!(wake_rx.recv_timeout(Duration::from_secs(5)));
+        assert!(driver.poll());
+        assert_eq!(name, unwrap!(result_rx.try_recv()));
+        assert!(!driver.poll());
+
+        // Once terminated, the node neither handles requests nor queued events.
+        unwrap!(driver.dispatch(&action_sender, Action::Terminate));
+        let (result_tx, result_rx) = mpsc::channel();
+        assert!(driver.dispatch(&action_sender, Action::Name { result_tx: result_tx }).is_err());
+        let (result_tx, _result_rx) = mpsc::channel();
+        unwrap!(action_sender.send(Action::Name { result_tx: result_tx }));
+        unwrap!(wake_rx.recv_timeout(Duration::from_secs(5)));
+        assert!(!driver.poll());
+        assert!(!driver.poll_timeout(Duration::from_millis(10)));
+        assert!(result_rx.try_recv().is_err());
+    }
+}
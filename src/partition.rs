@@ -0,0 +1,333 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use clock::Clock;
+use id::PublicId;
+use peer_manager::QUORUM_SIZE;
+use routing_table::{Prefix, RoutingTable};
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use xor_name::XorName;
+
+/// Time (in seconds) within which lost contacts are considered to have been lost simultaneously.
+const LOSS_WINDOW_SECS: u64 = 20;
+/// Minimum number of contacts that need to be lost within the window to suspect a partition.
+const MIN_LOST_CONTACTS: usize = QUORUM_SIZE;
+/// Time (in seconds) after which we stop waiting for contacts lost in a partition to come back.
+const HEAL_TIMEOUT_SECS: u64 = 600;
+/// Time (in seconds) after which we stop waiting for a bootstrap attempt to report its outcome.
+const BOOTSTRAP_TIMEOUT_SECS: u64 = 120;
+/// Initial delay (in seconds) between two attempts to bootstrap to the other side.
+const MIN_BOOTSTRAP_DELAY_SECS: u64 = 60;
+/// The delay between attempts doubles after each of them, but never exceeds this many seconds.
+const MAX_BOOTSTRAP_DELAY_SECS: u64 = 480;
+
+/// Detects network partitions and keeps track of the contacts lost in them.
+///
+/// A partition is suspected if a large share of the routing table, spanning more than one group
+/// where possible, is lost within a short time. While partitioned, our view of the network is
+/// incomplete, so we should neither relocate joining nodes nor split our group. The lost contacts
+/// are remembered so that we can reconnect to them and exchange routing tables once the partition
+/// heals.
+pub struct PartitionMonitor {
+    clock: Clock,
+    /// Recently lost contacts, with the time they were lost and the prefix of their group.
+    recent_losses: VecDeque<(Instant, PublicId, Option<Prefix<XorName>>)>,
+    /// When the current partition was detected, if any.
+    partitioned_since: Option<Instant>,
+    /// The contacts lost in the current partition which haven't reconnected yet.
+    lost_contacts: HashMap<XorName, PublicId>,
+    /// A split of our group that was postponed because we were partitioned, and the name of the
+    /// node whose addition made the split necessary.
+    deferred_split: Option<(Prefix<XorName>, XorName)>,
+    /// When our current attempt to bootstrap to the other side started, if there is one.
+    bootstrap_started: Option<Instant>,
+    /// The earliest time for the next attempt to bootstrap to the other side.
+    next_bootstrap: Option<Instant>,
+    /// How long to wait after the next attempt has finished.
+    bootstrap_delay: Duration,
+}
+
+impl PartitionMonitor {
+    pub fn new(clock: Clock) -> Self {
+        PartitionMonitor {
+            clock: clock,
+            recent_losses: VecDeque::new(),
+            partitioned_since: None,
+            lost_contacts: HashMap::new(),
+            deferred_split: None,
+            bootstrap_started: None,
+            next_bootstrap: None,
+            bootstrap_delay: Duration::from_secs(MIN_BOOTSTRAP_DELAY_SECS),
+        }
+    }
+
+    /// Returns `true` if we currently consider ourselves to be cut off from part of the network.
+    pub fn is_partitioned(&self) -> bool {
+        self.partitioned_since.is_some()
+    }
+
+    /// Records the loss of a routing table contact. `routing_table` is the table after the
+    /// removal. Returns `true` if this loss caused a partition to be detected.
+    pub fn record_loss(&mut self, pub_id: PublicId, routing_table: &RoutingTable<XorName>) -> bool {
+        let now = self.clock.now();
+        self.remove_old_losses(now);
+
+        let prefix = routing_table.groups()
+            .keys()
+            .find(|prefix| prefix.matches(pub_id.name()))
+            .cloned();
+        self.recent_losses.push_back((now, pub_id, prefix));
+
+        if self.is_partitioned() {
+            let _ = self.lost_contacts.insert(*pub_id.name(), pub_id);
+            return false;
+        }
+
+        let lost_count = self.recent_losses.len();
+        let lost_prefixes = self.recent_losses
+            .iter()
+            .filter_map(|&(_, _, prefix)| prefix)
+            .collect::<HashSet<_>>();
+        let required_prefixes = cmp::min(2, routing_table.groups().len());
+
+        // Require at least a third of our contacts to have gone at once.
+        if lost_count < MIN_LOST_CONTACTS || 3 * lost_count < routing_table.len() + lost_count ||
+           lost_prefixes.len() < required_prefixes {
+            return false;
+        }
+
+        self.partitioned_since = Some(now);
+        self.lost_contacts = self.recent_losses
+            .iter()
+            .map(|&(_, pub_id, _)| (*pub_id.name(), pub_id))
+            .collect();
+        true
+    }
+
+    /// Returns `true` if the given name was lost in the current partition.
+    pub fn is_lost(&self, name: &XorName) -> bool {
+        self.lost_contacts.contains_key(name)
+    }
+
+    /// Returns the contacts lost in the current partition.
+    pub fn lost_contacts(&self) -> Vec<PublicId> {
+        self.lost_contacts.values().cloned().collect()
+    }
+
+    /// Marks the given lost contact as reconnected. Returns `true` if it had been lost in the
+    /// current partition. Once all lost contacts are back, the partition is considered healed.
+    pub fn reconnected(&mut self, name: &XorName) -> bool {
+        if self.lost_contacts.remove(name).is_none() {
+            return false;
+        }
+        if self.lost_contacts.is_empty() {
+            self.heal();
+        }
+        true
+    }
+
+    /// Postpones splitting the group with the given prefix, which became necessary when the node
+    /// `name` was added, until the partition has healed.
+    pub fn defer_split(&mut self, prefix: Prefix<XorName>, name: XorName) {
+        self.deferred_split = Some((prefix, name));
+    }
+
+    /// Returns the postponed split, if the partition has healed in the meantime.
+    pub fn take_deferred_split(&mut self) -> Option<(Prefix<XorName>, XorName)> {
+        if self.is_partitioned() {
+            None
+        } else {
+            self.deferred_split.take()
+        }
+    }
+
+    /// Returns `true` if we should try to bootstrap to the other side of the partition now. That
+    /// is not the case while a previous attempt is still in progress, or if it finished too
+    /// recently: the delay between attempts grows exponentially.
+    pub fn start_bootstrap(&mut self) -> bool {
+        if !self.is_partitioned() {
+            return false;
+        }
+        let now = self.clock.now();
+        if let Some(started) = self.bootstrap_started {
+            if now - started < Duration::from_secs(BOOTSTRAP_TIMEOUT_SECS) {
+                return false;
+            }
+            // Crust doesn't report a failure if we still have other connections.
+            self.bootstrap_finished();
+        }
+        if self.next_bootstrap.map_or(false, |next| now < next) {
+            return false;
+        }
+        self.bootstrap_started = Some(now);
+        true
+    }
+
+    /// Records that our current bootstrap attempt has either connected or failed.
+    pub fn bootstrap_finished(&mut self) {
+        if self.bootstrap_started.take().is_none() {
+            return;
+        }
+        self.next_bootstrap = Some(self.clock.now() + self.bootstrap_delay);
+        self.bootstrap_delay = cmp::min(self.bootstrap_delay * 2,
+                                        Duration::from_secs(MAX_BOOTSTRAP_DELAY_SECS));
+    }
+
+    /// Gives up on the lost contacts if the partition hasn't healed in time. Returns `true` if
+    /// that was the case.
+    pub fn expire(&mut self) -> bool {
+        let timed_out = match self.partitioned_since {
+            Some(since) => self.clock.now() - since >= Duration::from_secs(HEAL_TIMEOUT_SECS),
+            None => false,
+        };
+        if timed_out {
+            self.lost_contacts.clear();
+            self.heal();
+        }
+        timed_out
+    }
+
+    fn heal(&mut self) {
+        self.partitioned_since = None;
+        self.recent_losses.clear();
+        self.bootstrap_started = None;
+        self.next_bootstrap = None;
+        self.bootstrap_delay = Duration::from_secs(MIN_BOOTSTRAP_DELAY_SECS);
+    }
+
+    fn remove_old_losses(&mut self, now: Instant) {
+        let window = Duration::from_secs(LOSS_WINDOW_SECS);
+        while self.recent_losses.front().map_or(false, |&(time, _, _)| now - time > window) {
+            let _ = self.recent_losses.pop_front();
+        }
+    }
+}
+
+impl Default for PartitionMonitor {
+    fn default() -> PartitionMonitor {
+        PartitionMonitor::new(Clock::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use id::{FullId, PublicId};
+    use peer_manager::MIN_GROUP_SIZE;
+    use routing_table::RoutingTable;
+    use super::*;
+    use xor_name::XorName;
+
+    // Returns a routing table with `count` random contacts, and their public IDs.
+    fn routing_table(count: usize) -> (RoutingTable<XorName>, Vec<PublicId>) {
+        let our_id = FullId::new();
+        let mut table = RoutingTable::new(*our_id.public_id().name(), MIN_GROUP_SIZE);
+        let pub_ids = (0..count).map(|_| *FullId::new().public_id()).collect::<Vec<_>>();
+        for pub_id in &pub_ids {
+            let _ = unwrap!(table.add(*pub_id.name()));
+        }
+        (table, pub_ids)
+    }
+
+    // Removes the first `count` contacts from the table and records their loss. Returns whether a
+    // partition was detected.
+    fn lose(monitor: &mut PartitionMonitor,
+            table: &mut RoutingTable<XorName>,
+            pub_ids: &[PublicId],
+            count: usize)
+            -> bool {
+        let mut detected = false;
+        for pub_id in &pub_ids[..count] {
+            let _ = unwrap!(table.remove(pub_id.name()));
+            detected = monitor.record_loss(*pub_id, table) || detected;
+        }
+        detected
+    }
+
+    #[test]
+    fn few_losses_are_not_a_partition() {
+        let (mut table, pub_ids) = routing_table(12);
+        let mut monitor = PartitionMonitor::default();
+        assert!(!lose(&mut monitor, &mut table, &pub_ids, MIN_LOST_CONTACTS - 1));
+        assert!(!monitor.is_partitioned());
+        assert!(monitor.lost_contacts().is_empty());
+    }
+
+    #[test]
+    fn detect_and_heal() {
+        let (mut table, pub_ids) = routing_table(12);
+        let mut monitor = PartitionMonitor::default();
+        assert!(lose(&mut monitor, &mut table, &pub_ids, 6));
+        assert!(monitor.is_partitioned());
+        assert_eq!(6, monitor.lost_contacts().len());
+        assert!(pub_ids[..6].iter().all(|pub_id| monitor.is_lost(pub_id.name())));
+
+        let prefix = *table.our_group_prefix();
+        monitor.defer_split(prefix, *pub_ids[11].name());
+        assert_eq!(None, monitor.take_deferred_split());
+
+        assert!(!monitor.reconnected(pub_ids[6].name()));
+        for pub_id in &pub_ids[..5] {
+            assert!(monitor.reconnected(pub_id.name()));
+            assert!(monitor.is_partitioned());
+        }
+        assert!(monitor.reconnected(pub_ids[5].name()));
+        assert!(!monitor.is_partitioned());
+        assert_eq!(Some((prefix, *pub_ids[11].name())),
+                   monitor.take_deferred_split());
+        assert_eq!(None, monitor.take_deferred_split());
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    #[test]
+    fn bootstrap_backoff() {
+        use mock_crust::{self, Network};
+
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let clock = mock_crust::make_current(&handle, Clock::new);
+        let (mut table, pub_ids) = routing_table(12);
+        let mut monitor = PartitionMonitor::new(clock);
+        assert!(!monitor.start_bootstrap());
+        assert!(lose(&mut monitor, &mut table, &pub_ids, 6));
+
+        // Only one attempt at a time.
+        assert!(monitor.start_bootstrap());
+        assert!(!monitor.start_bootstrap());
+
+        // After an attempt, wait for the current delay, which then doubles.
+        monitor.bootstrap_finished();
+        network.advance_time(Duration::from_secs(MIN_BOOTSTRAP_DELAY_SECS - 1));
+        assert!(!monitor.start_bootstrap());
+        network.advance_time(Duration::from_secs(1));
+        assert!(monitor.start_bootstrap());
+        monitor.bootstrap_finished();
+        network.advance_time(Duration::from_secs(2 * MIN_BOOTSTRAP_DELAY_SECS - 1));
+        assert!(!monitor.start_bootstrap());
+        network.advance_time(Duration::from_secs(1));
+        assert!(monitor.start_bootstrap());
+
+        // An attempt that never reports back is given up on eventually.
+        network.advance_time(Duration::from_secs(BOOTSTRAP_TIMEOUT_SECS - 1));
+        assert!(!monitor.start_bootstrap());
+        network.advance_time(Duration::from_secs(1));
+        assert!(!monitor.start_bootstrap());
+        network.advance_time(Duration::from_secs(4 * MIN_BOOTSTRAP_DELAY_SECS));
+        assert!(monitor.start_bootstrap());
+    }
+}
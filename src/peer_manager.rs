@@ -19,16 +19,19 @@
 #![allow(unused)]
 
 use authority::Authority;
+use ban_list::BanList;
+use clock::Clock;
 use crust::{PeerId, PrivConnectionInfo, PubConnectionInfo};
 use id::PublicId;
 use itertools::Itertools;
 use rand;
-use routing_table::{Prefix, RemovalDetails, RoutingTable};
+use routing_table::{Prefix, RemovalDetails, RoutingTable, Xorable};
 use routing_table::Error as RoutingTableError;
 use rust_sodium::crypto::sign;
-use std::{error, fmt, mem};
+use std::{cmp, error, fmt, mem};
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Values;
+use std::net::SocketAddr;
 use std::time::{Duration, Instant};
 use xor_name::XorName;
 
@@ -42,6 +45,66 @@ const JOINING_NODE_TIMEOUT_SECS: u64 = 300;
 const CONNECTION_TIMEOUT_SECS: u64 = 90;
 /// Time (in seconds) the node waits for a `NodeIdentify` message.
 const NODE_IDENTIFY_TIMEOUT_SECS: u64 = 60;
+/// Time (in seconds) after which an attempt to replace a tunnel with a direct connection is given
+/// up, so that it can be retried.
+const DIRECT_UPGRADE_TIMEOUT_SECS: u64 = 60;
+/// Time (in seconds) for which messages signed with a peer's old key are accepted after it
+/// rotated its keys.
+pub const KEY_ROTATION_GRACE_PERIOD_SECS: u64 = 5 * 60;
+/// Misbehaviour score from which a peer's votes don't count towards a group message's quorum and
+/// it isn't used as a tunnel.
+const DISTRUST_SCORE: u32 = 10;
+/// Age (in ticks) from which a peer's votes count towards a group message's quorum even if its
+/// group has enough older members to reach the quorum without it.
+const MIN_VOTING_AGE: u32 = 5;
+/// Misbehaviour score above which a peer with age zero is disconnected.
+const EVICTION_SCORE: u32 = 20;
+/// The maximum amount by which a peer's age raises its eviction score.
+const MAX_AGE_TOLERANCE: u32 = 20;
+
+/// The default maximum number of clients a node acts as a proxy for.
+pub const DEFAULT_MAX_CLIENTS: usize = 1000;
+/// The default maximum number of joining nodes a node acts as a proxy for.
+pub const DEFAULT_MAX_JOINING_NODES: usize = 16;
+
+/// Limits on the clients and joining nodes a node accepts as their proxy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ProxyLimits {
+    /// The maximum number of clients.
+    pub max_clients: usize,
+    /// The maximum number of joining nodes.
+    pub max_joining_nodes: usize,
+}
+
+impl Default for ProxyLimits {
+    fn default() -> ProxyLimits {
+        ProxyLimits {
+            max_clients: DEFAULT_MAX_CLIENTS,
+            max_joining_nodes: DEFAULT_MAX_JOINING_NODES,
+        }
+    }
+}
+
+/// Kinds of misbehaviour that count against a peer's reputation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Misbehaviour {
+    /// The peer sent a message with an invalid signature.
+    FailedSignature,
+    /// The peer sent a message that couldn't be parsed.
+    InvalidMessage,
+    /// A message we sent via the peer wasn't acknowledged.
+    UnackedRelay,
+}
+
+impl Misbehaviour {
+    fn penalty(&self) -> u32 {
+        match *self {
+            Misbehaviour::FailedSignature => 10,
+            Misbehaviour::InvalidMessage => 5,
+            Misbehaviour::UnackedRelay => 1,
+        }
+    }
+}
 
 #[derive(Debug)]
 /// Errors that occur in peer status management.
@@ -132,24 +195,92 @@ pub struct ConnectionInfoPreparedResult {
     pub infos: Option<(PrivConnectionInfo, PubConnectionInfo)>,
 }
 
+/// An attempt to replace the tunnel to a routing peer with a direct connection. The peer stays in
+/// `Routing(true)` state meanwhile, and the connection infos are kept here instead.
+struct DirectUpgrade {
+    src: Authority,
+    dst: Authority,
+    our_info: Option<PrivConnectionInfo>,
+    their_info: Option<PubConnectionInfo>,
+    timestamp: Instant,
+}
+
+impl DirectUpgrade {
+    fn new(src: Authority, dst: Authority, now: Instant) -> DirectUpgrade {
+        DirectUpgrade {
+            src: src,
+            dst: dst,
+            our_info: None,
+            their_info: None,
+            timestamp: now,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        now - self.timestamp >= Duration::from_secs(DIRECT_UPGRADE_TIMEOUT_SECS)
+    }
+}
+
 /// Represents peer we are connected or attempting connection to.
 pub struct Peer {
     pub_id: PublicId,
     peer_id: Option<PeerId>,
     state: PeerState,
     timestamp: Instant,
+    /// The number of ticks the peer has spent in our routing table.
+    age: u32,
+    /// The penalties for the peer's recent misbehaviour. This decreases with every tick.
+    misbehaviour: u32,
+    /// The signing key the peer used before it last rotated its keys, and when it did so.
+    retired_key: Option<(sign::PublicKey, Instant)>,
 }
 
 impl Peer {
-    fn new(pub_id: PublicId, peer_id: Option<PeerId>, state: PeerState) -> Self {
+    fn new(pub_id: PublicId, peer_id: Option<PeerId>, state: PeerState, now: Instant) -> Self {
         Peer {
             pub_id: pub_id,
             peer_id: peer_id,
             state: state,
-            timestamp: Instant::now(),
+            timestamp: now,
+            age: 0,
+            misbehaviour: 0,
+            retired_key: None,
         }
     }
 
+    pub fn age(&self) -> u32 {
+        self.age
+    }
+
+    pub fn misbehaviour(&self) -> u32 {
+        self.misbehaviour
+    }
+
+    /// Returns the keys that messages signed by the peer are currently accepted with: its signing
+    /// key and, for a grace period after it rotated its keys, its old one. `now` is the current
+    /// time, as given by the peer manager's clock.
+    pub fn signing_keys(&self, now: Instant) -> Vec<sign::PublicKey> {
+        let grace_period = Duration::from_secs(KEY_ROTATION_GRACE_PERIOD_SECS);
+        let mut keys = vec![*self.pub_id.signing_public_key()];
+        if let Some((key, rotated_at)) = self.retired_key {
+            if now - rotated_at < grace_period {
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    /// Returns `true` unless the peer misbehaved too much recently.
+    pub fn is_trusted(&self) -> bool {
+        self.misbehaviour < DISTRUST_SCORE
+    }
+
+    /// Returns `true` if the peer misbehaved so much that we should disconnect. Older peers are
+    /// given more leeway, as they have been behaving well for longer.
+    fn should_evict(&self) -> bool {
+        self.misbehaviour > EVICTION_SCORE + cmp::min(self.age, MAX_AGE_TOLERANCE)
+    }
+
     pub fn peer_id(&self) -> Option<&PeerId> {
         self.peer_id.as_ref()
     }
@@ -166,16 +297,15 @@ impl Peer {
         &self.state
     }
 
-    fn is_expired(&self) -> bool {
+    fn is_expired(&self, now: Instant) -> bool {
+        let age = now - self.timestamp;
         match self.state {
             PeerState::ConnectionInfoPreparing(..) |
             PeerState::ConnectionInfoReady(_) |
             PeerState::CrustConnecting |
-            PeerState::SearchingForTunnel => {
-                self.timestamp.elapsed() >= Duration::from_secs(CONNECTION_TIMEOUT_SECS)
-            }
+            PeerState::SearchingForTunnel => age >= Duration::from_secs(CONNECTION_TIMEOUT_SECS),
             PeerState::JoiningNode | PeerState::Proxy => {
-                self.timestamp.elapsed() >= Duration::from_secs(JOINING_NODE_TIMEOUT_SECS)
+                age >= Duration::from_secs(JOINING_NODE_TIMEOUT_SECS)
             }
             PeerState::Client |
             PeerState::Routing(_) |
@@ -257,7 +387,15 @@ impl PeerMap {
 /// This keeps track of which nodes we know of, which ones we have tried to connect to, which IDs
 /// we have verified, whom we are directly connected to or via a tunnel.
 pub struct PeerManager {
+    ban_list: BanList,
+    clock: Clock,
     connection_token_map: HashMap<u32, PublicId>,
+    /// The addresses the nodes we are connecting or connected to can be reached at directly.
+    contact_addrs: HashMap<XorName, Vec<SocketAddr>>,
+    /// Our attempts to directly connect to routing peers we are connected to via a tunnel.
+    direct_upgrades: HashMap<XorName, DirectUpgrade>,
+    /// The connection info tokens of the `direct_upgrades`.
+    direct_upgrade_tokens: HashMap<u32, XorName>,
     peer_map: PeerMap,
     unknown_peers: HashMap<PeerId, Instant>,
     proxy_peer_id: Option<PeerId>,
@@ -266,10 +404,16 @@ pub struct PeerManager {
 }
 
 impl PeerManager {
-    /// Returns a new peer manager with no entries.
-    pub fn new(our_public_id: PublicId) -> PeerManager {
+    /// Returns a new peer manager with no entries, except for the given ban list. All timeouts are
+    /// measured with `clock`.
+    pub fn new(our_public_id: PublicId, ban_list: BanList, clock: Clock) -> PeerManager {
         PeerManager {
+            ban_list: ban_list,
+            clock: clock,
             connection_token_map: HashMap::new(),
+            contact_addrs: HashMap::new(),
+            direct_upgrades: HashMap::new(),
+            direct_upgrade_tokens: HashMap::new(),
             peer_map: PeerMap::new(),
             unknown_peers: HashMap::new(),
             proxy_peer_id: None,
@@ -302,10 +446,11 @@ impl PeerManager {
                                 -> Result<Option<Prefix<XorName>>, RoutingTableError> {
         let _ = self.unknown_peers.remove(&peer_id);
         let split_prefix = try!(self.routing_table.add(*pub_id.name()));
-        let tunnel = match self.peer_map.remove(&peer_id).map(|peer| peer.state) {
-            Some(PeerState::SearchingForTunnel) |
-            Some(PeerState::AwaitingNodeIdentify(true)) => true,
-            Some(PeerState::Routing(tunnel)) => {
+        let old_peer = self.peer_map.remove(&peer_id);
+        let tunnel = match old_peer.as_ref().map(|peer| &peer.state) {
+            Some(&PeerState::SearchingForTunnel) |
+            Some(&PeerState::AwaitingNodeIdentify(true)) => true,
+            Some(&PeerState::Routing(tunnel)) => {
                 error!("Peer {:?} added to routing table, but already in state Routing.",
                        peer_id);
                 tunnel
@@ -313,7 +458,11 @@ impl PeerManager {
             _ => false,
         };
         let state = PeerState::Routing(tunnel);
-        let _ = self.peer_map.insert(Peer::new(pub_id, Some(peer_id), state));
+        let mut peer = Peer::new(pub_id, Some(peer_id), state, self.clock.now());
+        if let Some(old_peer) = old_peer {
+            peer.misbehaviour = old_peer.misbehaviour;
+        }
+        let _ = self.peer_map.insert(peer);
         Ok(split_prefix)
     }
 
@@ -429,10 +578,11 @@ impl PeerManager {
     /// Removes all joining nodes that have timed out, and returns their peer
     /// IDs. Also, removes our proxy if we have timed out.
     pub fn remove_expired_joining_nodes(&mut self) -> Vec<PeerId> {
+        let now = self.clock.now();
         let expired_ids = self.peer_map
             .peers()
             .filter(|peer| match peer.state {
-                PeerState::JoiningNode | PeerState::Proxy => peer.is_expired(),
+                PeerState::JoiningNode | PeerState::Proxy => peer.is_expired(now),
                 _ => false,
             })
             .filter_map(|peer| peer.peer_id)
@@ -450,12 +600,14 @@ impl PeerManager {
     /// Removes all timed out connections to unknown peers (i.e. whose public id we don't have yet)
     /// and also known peers from whom we're awaiting a `NodeIdentify`, and returns their peer IDs.
     pub fn remove_expired_connections(&mut self) -> Vec<PeerId> {
+        let now = self.clock.now();
+        let timeout = Duration::from_secs(NODE_IDENTIFY_TIMEOUT_SECS);
         let mut expired_connections = Vec::new();
 
         for (peer_id, xor_name) in &self.peer_map.names {
             if let Some(peer) = self.peer_map.peers.get(xor_name) {
                 if let PeerState::AwaitingNodeIdentify(_) = peer.state {
-                    if peer.timestamp.elapsed() >= Duration::from_secs(NODE_IDENTIFY_TIMEOUT_SECS) {
+                    if now - peer.timestamp >= timeout {
                         expired_connections.push(*peer_id);
                     }
                 }
@@ -469,7 +621,7 @@ impl PeerManager {
         let mut expired_unknown_peers = Vec::new();
 
         for (peer_id, timestamp) in &self.unknown_peers {
-            if timestamp.elapsed() >= Duration::from_secs(NODE_IDENTIFY_TIMEOUT_SECS) {
+            if now - *timestamp >= timeout {
                 expired_unknown_peers.push(*peer_id);
             }
         }
@@ -522,7 +674,18 @@ impl PeerManager {
     /// Marks the given peer as "connected and waiting for `NodeIdentify`".
     pub fn connected_to(&mut self, peer_id: &PeerId) {
         if !self.set_state(peer_id, PeerState::AwaitingNodeIdentify(false)) {
-            let _ = self.unknown_peers.insert(*peer_id, Instant::now());
+            let _ = self.unknown_peers.insert(*peer_id, self.clock.now());
+        }
+    }
+
+    /// Returns `true` if we are connected to the given peer and waiting for its `NodeIdentify`.
+    pub fn is_awaiting_node_identify(&self, peer_id: &PeerId) -> bool {
+        if self.unknown_peers.contains_key(peer_id) {
+            return true;
+        }
+        match self.get_state(peer_id) {
+            Some(&PeerState::AwaitingNodeIdentify(_)) => true,
+            _ => false,
         }
     }
 
@@ -557,6 +720,40 @@ impl PeerManager {
         })
     }
 
+    /// Returns the crust IDs of all peers in one of the connected states.
+    pub fn connected_peer_ids(&self) -> Vec<PeerId> {
+        self.peer_map
+            .peers()
+            .filter_map(|peer| match peer.state {
+                PeerState::Client |
+                PeerState::JoiningNode |
+                PeerState::Proxy |
+                PeerState::Routing(_) => peer.peer_id,
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Records the addresses the given node told us it can be reached at directly.
+    pub fn set_contact_addrs(&mut self, name: XorName, addrs: Vec<SocketAddr>) {
+        if self.peer_map.get_by_name(&name).is_some() {
+            let _ = self.contact_addrs.insert(name, addrs);
+        }
+    }
+
+    /// Returns the known addresses of the `count` routing table entries closest to `name`, out of
+    /// those whose addresses we know.
+    pub fn closest_contact_addrs(&self, name: &XorName, count: usize) -> Vec<SocketAddr> {
+        self.routing_table
+            .iter()
+            .filter(|entry| self.contact_addrs.contains_key(*entry))
+            .sorted_by(|lhs, rhs| name.cmp_distance(lhs, rhs))
+            .into_iter()
+            .take(count)
+            .flat_map(|entry| self.contact_addrs[entry].iter().cloned())
+            .collect()
+    }
+
     /// Return the PeerIds of nodes bearing the names.
     pub fn get_peer_ids(&self, names: &HashSet<XorName>) -> Vec<PeerId> {
         names.iter()
@@ -603,15 +800,141 @@ impl PeerManager {
         }
 
         let _ = self.insert_peer(pub_id, Some(peer_id), PeerState::SearchingForTunnel);
+        self.tunnel_candidates(pub_id.name())
+    }
 
-        let close_group = self.routing_table.other_close_names(pub_id.name()).unwrap_or_default();
+    /// Returns the peers that can be asked to act as a tunnel to the node with the given name:
+    /// the trusted peers in its close group, the oldest ones first.
+    pub fn tunnel_candidates(&self, name: &XorName) -> Vec<(XorName, PeerId)> {
+        let close_group = self.routing_table.other_close_names(name).unwrap_or_default();
         self.peer_map
             .peers()
+            .filter(|peer| close_group.contains(peer.name()) && peer.is_trusted())
+            .sorted_by(|lhs, rhs| rhs.age.cmp(&lhs.age))
+            .into_iter()
             .filter_map(|peer| peer.peer_id.map(|peer_id| (*peer.name(), peer_id)))
-            .filter(|&(name, _)| close_group.contains(&name))
             .collect()
     }
 
+    /// Increments the age of all routing peers and reduces the penalties for their past
+    /// misbehaviour. This should be called on every tick.
+    pub fn increment_ages(&mut self) {
+        for peer in self.peer_map.peers.values_mut() {
+            if let PeerState::Routing(_) = peer.state {
+                peer.age = peer.age.saturating_add(1);
+            }
+            peer.misbehaviour = peer.misbehaviour.saturating_sub(1);
+        }
+    }
+
+    /// Records misbehaviour of the given peer. Returns `true` if it should be evicted now.
+    pub fn report_misbehaviour(&mut self, peer_id: &PeerId, misbehaviour: Misbehaviour) -> bool {
+        match self.peer_map.get_mut(peer_id) {
+            Some(peer) => {
+                let score = peer.misbehaviour.saturating_add(misbehaviour.penalty());
+                peer.misbehaviour = match misbehaviour {
+                    // A lost message isn't necessarily the relay's fault, so that alone doesn't
+                    // get a peer distrusted or evicted.
+                    Misbehaviour::UnackedRelay => {
+                        cmp::max(peer.misbehaviour, cmp::min(score, DISTRUST_SCORE - 1))
+                    }
+                    _ => score,
+                };
+                peer.should_evict()
+            }
+            None => false,
+        }
+    }
+
+    /// Replaces the keys of the node with the given crust ID. Returns `false` if it is not a node
+    /// we are connected to, if the new ID has a different name, or if the node's previous keys are
+    /// still in their grace period: a node may rotate its keys at most once per grace period.
+    pub fn rotate_keys(&mut self, peer_id: &PeerId, new_pub_id: PublicId) -> bool {
+        let now = self.clock.now();
+        let peer = match self.peer_map.get_mut(peer_id) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        match peer.state {
+            PeerState::Proxy |
+            PeerState::Routing(_) => (),
+            _ => return false,
+        }
+        if peer.name() != new_pub_id.name() || peer.signing_keys(now).len() > 1 {
+            return false;
+        }
+        peer.retired_key = Some((*peer.pub_id.signing_public_key(), now));
+        peer.pub_id = new_pub_id;
+        true
+    }
+
+    /// Sets our own ID after we rotated our keys. The name must not change.
+    pub fn set_our_public_id(&mut self, our_public_id: PublicId) {
+        self.our_public_id = our_public_id;
+    }
+
+    /// Returns the list of banned peers.
+    pub fn ban_list_mut(&mut self) -> &mut BanList {
+        &mut self.ban_list
+    }
+
+    /// Bans the given key permanently, and returns the crust IDs of our peers that use it.
+    pub fn ban(&mut self, key: sign::PublicKey) -> Vec<PeerId> {
+        self.ban_list.ban(key);
+        self.peer_map
+            .peers()
+            .filter(|peer| *peer.pub_id().signing_public_key() == key)
+            .filter_map(|peer| peer.peer_id)
+            .collect()
+    }
+
+    /// Returns `true` if the peer with the given crust ID or public ID is currently banned.
+    pub fn is_banned(&mut self, peer_id: &PeerId, pub_id: &PublicId) -> bool {
+        self.ban_list.is_banned(peer_id, pub_id.signing_public_key())
+    }
+
+    /// Records that a message from the given peer failed the integrity check. Returns `true` if
+    /// the peer is banned now, in which case we should disconnect.
+    pub fn record_integrity_failure(&mut self, peer_id: &PeerId) -> bool {
+        let key = self.peer_map.get(peer_id).map(|peer| *peer.pub_id().signing_public_key());
+        self.ban_list.record_integrity_failure(peer_id, key.as_ref())
+    }
+
+    /// Returns `false` if the named node is a peer that misbehaved too much recently, so that
+    /// messages signed by it shouldn't count towards a group's quorum.
+    pub fn is_trusted(&self, name: &XorName) -> bool {
+        self.peer_map.get_by_name(name).map_or(true, Peer::is_trusted)
+    }
+
+    /// Returns `true` if the named node's vote counts towards the quorum of a group message. The
+    /// votes of peers that misbehaved too much recently never count. Peers younger than
+    /// `MIN_VOTING_AGE` only vote if their group has fewer than `quorum_size` older trusted
+    /// members, so that a group's newcomers can't reach the quorum on their own.
+    pub fn counts_vote(&self, name: &XorName, quorum_size: usize) -> bool {
+        let peer = match self.peer_map.get_by_name(name) {
+            Some(peer) => peer,
+            None => return true,
+        };
+        if !peer.is_trusted() {
+            return false;
+        }
+        if peer.age >= MIN_VOTING_AGE {
+            return true;
+        }
+        let group = match self.routing_table
+            .groups()
+            .iter()
+            .find(|&(prefix, _)| prefix.matches(name)) {
+            Some((_, group)) => group,
+            None => return true,
+        };
+        let elder_count = group.iter()
+            .filter_map(|member| self.peer_map.get_by_name(member))
+            .filter(|member| member.age >= MIN_VOTING_AGE && member.is_trusted())
+            .count();
+        elder_count < quorum_size
+    }
+
     /// Inserts the given connection info in the map to wait for the peer's info, or returns both
     /// if that's already present and sets the status to `CrustConnecting`. It also returns the
     /// source and destination authorities for sending the serialised connection info to the peer.
@@ -619,6 +942,9 @@ impl PeerManager {
                                     token: u32,
                                     our_info: PrivConnectionInfo)
                                     -> Result<ConnectionInfoPreparedResult, Error> {
+        if let Some(name) = self.direct_upgrade_tokens.remove(&token) {
+            return self.direct_upgrade_info_prepared(name, our_info);
+        }
         let pub_id = try!(self.connection_token_map.remove(&token).ok_or(Error::PeerNotFound));
         let (src, dst, opt_their_info) = match self.peer_map.remove_by_name(pub_id.name()) {
             Some(Peer { state: PeerState::ConnectionInfoPreparing(src, dst, info), .. }) => {
@@ -688,8 +1014,11 @@ impl PeerManager {
                 let _ = self.peer_map.insert(peer);
                 Ok(ConnectionInfoReceivedResult::IsProxy)
             }
-            Some(peer @ Peer { state: PeerState::Routing(_), .. }) => {
-                // TODO: We _should_ retry connecting if the peer is connected via tunnel.
+            Some(peer @ Peer { state: PeerState::Routing(true), .. }) => {
+                let _ = self.peer_map.insert(peer);
+                Ok(self.direct_upgrade_info_received(src, dst, pub_id, their_info))
+            }
+            Some(peer @ Peer { state: PeerState::Routing(false), .. }) => {
                 let _ = self.peer_map.insert(peer);
                 Ok(ConnectionInfoReceivedResult::IsConnected)
             }
@@ -734,6 +1063,131 @@ impl PeerManager {
         Some(token)
     }
 
+    /// Returns the public IDs of all routing peers we are connected to via a tunnel.
+    pub fn tunnelled_peers(&self) -> Vec<PublicId> {
+        self.peer_map
+            .peers()
+            .filter_map(|peer| match peer.state {
+                PeerState::Routing(true) => Some(peer.pub_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns `true` if the given node is a routing peer we are connected to via a tunnel.
+    pub fn is_tunnelled(&self, name: &XorName) -> bool {
+        match self.get_state_by_name(name) {
+            Some(&PeerState::Routing(true)) => true,
+            _ => false,
+        }
+    }
+
+    /// Starts an attempt to directly connect to the given peer, which we are connected to via a
+    /// tunnel, and returns a token for Crust's `prepare_connection_info`. Returns `None` if the
+    /// peer is not tunnelled or there already is an attempt in progress.
+    pub fn start_direct_upgrade(&mut self,
+                                src: Authority,
+                                dst: Authority,
+                                pub_id: PublicId)
+                                -> Option<u32> {
+        self.remove_expired_direct_upgrades();
+        if !self.is_tunnelled(pub_id.name()) || self.direct_upgrades.contains_key(pub_id.name()) {
+            return None;
+        }
+        let upgrade = DirectUpgrade::new(src, dst, self.clock.now());
+        let _ = self.direct_upgrades.insert(*pub_id.name(), upgrade);
+        let token = rand::random();
+        let _ = self.direct_upgrade_tokens.insert(token, *pub_id.name());
+        Some(token)
+    }
+
+    /// Marks the given tunnelled peer as directly connected, and returns `true` if it was
+    /// tunnelled before.
+    pub fn direct_connection_established(&mut self, peer_id: &PeerId) -> bool {
+        let name = match self.peer_map.get(peer_id) {
+            Some(&Peer { state: PeerState::Routing(true), ref pub_id, .. }) => *pub_id.name(),
+            _ => return false,
+        };
+        let _ = self.direct_upgrades.remove(&name);
+        self.set_state(peer_id, PeerState::Routing(false))
+    }
+
+    fn direct_upgrade_info_prepared(&mut self,
+                                    name: XorName,
+                                    our_info: PrivConnectionInfo)
+                                    -> Result<ConnectionInfoPreparedResult, Error> {
+        let pub_id = match self.peer_map.get_by_name(&name) {
+            Some(&Peer { state: PeerState::Routing(true), pub_id, .. }) => pub_id,
+            _ => {
+                let _ = self.direct_upgrades.remove(&name);
+                return Err(Error::UnexpectedState);
+            }
+        };
+        let mut upgrade = try!(self.direct_upgrades.remove(&name).ok_or(Error::PeerNotFound));
+        let (src, dst) = (upgrade.src.clone(), upgrade.dst.clone());
+        let infos = match upgrade.their_info.take() {
+            Some(their_info) => Some((our_info, their_info)),
+            None => {
+                upgrade.our_info = Some(our_info);
+                let _ = self.direct_upgrades.insert(name, upgrade);
+                None
+            }
+        };
+        Ok(ConnectionInfoPreparedResult {
+            pub_id: pub_id,
+            src: src,
+            dst: dst,
+            infos: infos,
+        })
+    }
+
+    fn direct_upgrade_info_received(&mut self,
+                                    src: Authority,
+                                    dst: Authority,
+                                    pub_id: PublicId,
+                                    their_info: PubConnectionInfo)
+                                    -> ConnectionInfoReceivedResult {
+        self.remove_expired_direct_upgrades();
+        match self.direct_upgrades.remove(pub_id.name()) {
+            Some(DirectUpgrade { our_info: Some(our_info), .. }) => {
+                ConnectionInfoReceivedResult::Ready(our_info, their_info)
+            }
+            Some(mut upgrade) => {
+                upgrade.their_info = Some(their_info);
+                let _ = self.direct_upgrades.insert(*pub_id.name(), upgrade);
+                ConnectionInfoReceivedResult::Waiting
+            }
+            None => {
+                let mut upgrade = DirectUpgrade::new(src, dst, self.clock.now());
+                upgrade.their_info = Some(their_info);
+                let _ = self.direct_upgrades.insert(*pub_id.name(), upgrade);
+                let token = rand::random();
+                let _ = self.direct_upgrade_tokens.insert(token, *pub_id.name());
+                ConnectionInfoReceivedResult::Prepare(token)
+            }
+        }
+    }
+
+    fn remove_expired_direct_upgrades(&mut self) {
+        let now = self.clock.now();
+        let expired_names = self.direct_upgrades
+            .iter()
+            .filter(|&(_, upgrade)| upgrade.is_expired(now))
+            .map(|(name, _)| *name)
+            .collect_vec();
+        for name in &expired_names {
+            let _ = self.direct_upgrades.remove(name);
+        }
+        let expired_tokens = self.direct_upgrade_tokens
+            .iter()
+            .filter(|&(_, name)| !self.direct_upgrades.contains_key(name))
+            .map(|(token, _)| *token)
+            .collect_vec();
+        for token in expired_tokens {
+            let _ = self.direct_upgrade_tokens.remove(&token);
+        }
+    }
+
     /// Returns all peers we are looking for a tunnel to.
     pub fn peers_needing_tunnel(&self) -> Vec<PeerId> {
         self.peer_map
@@ -758,6 +1212,7 @@ impl PeerManager {
                        -> Option<(Peer, Result<RemovalDetails<XorName>, RoutingTableError>)> {
         if let Some(peer) = self.peer_map.remove(peer_id) {
             self.cleanup_proxy_peer_id();
+            let _ = self.contact_addrs.remove(peer.name());
             let removal_details = self.routing_table.remove(peer.name());
             Some((peer, removal_details))
         } else {
@@ -784,7 +1239,8 @@ impl PeerManager {
     }
 
     fn insert_peer(&mut self, pub_id: PublicId, peer_id: Option<PeerId>, state: PeerState) -> bool {
-        let result = self.peer_map.insert(Peer::new(pub_id, peer_id, state)).is_some();
+        let peer = Peer::new(pub_id, peer_id, state, self.clock.now());
+        let result = self.peer_map.insert(peer).is_some();
         self.remove_expired();
         result
     }
@@ -796,9 +1252,10 @@ impl PeerManager {
     }
 
     fn remove_expired_peers(&mut self) {
+        let now = self.clock.now();
         let expired_names = self.peer_map
             .peers()
-            .filter(|peer| peer.is_expired())
+            .filter(|peer| peer.is_expired(now))
             .map(|peer| *peer.name())
             .collect_vec();
 
@@ -806,6 +1263,16 @@ impl PeerManager {
             let _ = self.peer_map.remove_by_name(&name);
         }
 
+        let unknown_names = self.contact_addrs
+            .keys()
+            .filter(|name| self.peer_map.get_by_name(name).is_none())
+            .cloned()
+            .collect_vec();
+
+        for name in unknown_names {
+            let _ = self.contact_addrs.remove(&name);
+        }
+
         self.cleanup_proxy_peer_id();
     }
 
@@ -855,12 +1322,57 @@ impl PeerManager {
     }
 }
 
+#[cfg(test)]
+mod contact_addrs_tests {
+    use ban_list::BanList;
+    use clock::Clock;
+    use id::FullId;
+    use routing_table::Xorable;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port)
+    }
+
+    #[test]
+    fn closest_contact_addrs() {
+        let our_pub_id = *FullId::new().public_id();
+        let clock = Clock::default();
+        let mut peer_mgr = PeerManager::new(our_pub_id, BanList::new(vec![], clock.clone()), clock);
+
+        let mut pub_ids = (0..3).map(|_| *FullId::new().public_id()).collect_vec();
+        let target = *FullId::new().public_id().name();
+        pub_ids.sort_by(|lhs, rhs| target.cmp_distance(lhs.name(), rhs.name()));
+        for pub_id in &pub_ids {
+            let _ = peer_mgr.insert_peer(*pub_id, None, PeerState::Routing(false));
+            let _ = unwrap!(peer_mgr.routing_table.add(*pub_id.name()));
+        }
+
+        // The closest entry didn't tell us its addresses, and the addresses of nodes we don't
+        // know are ignored.
+        peer_mgr.set_contact_addrs(*pub_ids[1].name(), vec![addr(1), addr(2)]);
+        peer_mgr.set_contact_addrs(*pub_ids[2].name(), vec![addr(3)]);
+        peer_mgr.set_contact_addrs(target, vec![addr(4)]);
+        assert_eq!(peer_mgr.closest_contact_addrs(&target, 1), vec![addr(1), addr(2)]);
+        assert_eq!(peer_mgr.closest_contact_addrs(&target, 3).len(), 3);
+
+        // Nodes that left our routing table aren't suggested anymore.
+        let _ = peer_mgr.peer_map.remove_by_name(pub_ids[1].name());
+        let _ = unwrap!(peer_mgr.routing_table.remove(pub_ids[1].name()));
+        assert_eq!(peer_mgr.closest_contact_addrs(&target, 1), vec![addr(3)]);
+    }
+}
+
 #[cfg(all(test, feature = "use-mock-crust"))]
 mod tests {
     use authority::Authority;
-    use id::FullId;
-    use mock_crust::Endpoint;
+    use ban_list::BanList;
+    use clock::Clock;
+    use id::{FullId, PublicId};
+    use mock_crust::{self, Endpoint, Network};
     use mock_crust::crust::{PeerId, PrivConnectionInfo, PubConnectionInfo};
+    use std::time::Duration;
     use super::*;
     use xor_name::{XOR_NAME_LEN, XorName};
 
@@ -868,10 +1380,30 @@ mod tests {
         Authority::ManagedNode(XorName([byte; XOR_NAME_LEN]))
     }
 
+    fn new_peer_manager(our_public_id: PublicId, clock: Clock) -> PeerManager {
+        PeerManager::new(our_public_id, BanList::new(vec![], clock.clone()), clock)
+    }
+
+    // Adds `count` new routing peers, with consecutive crust IDs, and returns their names.
+    fn add_peers(peer_mgr: &mut PeerManager, first_peer_id: usize, count: usize) -> Vec<XorName> {
+        (first_peer_id..(first_peer_id + count))
+            .map(|i| {
+                let pub_id = *FullId::new().public_id();
+                let _ = unwrap!(peer_mgr.add_to_routing_table(pub_id, PeerId(i)));
+                *pub_id.name()
+            })
+            .collect_vec()
+    }
+
+    fn virtual_clock(network: &Network) -> Clock {
+        let handle = network.new_service_handle(None, None);
+        mock_crust::make_current(&handle, Clock::new)
+    }
+
     #[test]
     pub fn connection_info_prepare_receive() {
         let orig_pub_id = *FullId::new().public_id();
-        let mut peer_mgr = PeerManager::new(orig_pub_id);
+        let mut peer_mgr = new_peer_manager(orig_pub_id, Clock::default());
 
         let our_connection_info = PrivConnectionInfo(PeerId(0), Endpoint(0));
         let their_connection_info = PubConnectionInfo(PeerId(1), Endpoint(1));
@@ -907,7 +1439,7 @@ mod tests {
     #[test]
     pub fn connection_info_receive_prepare() {
         let orig_pub_id = *FullId::new().public_id();
-        let mut peer_mgr = PeerManager::new(orig_pub_id);
+        let mut peer_mgr = new_peer_manager(orig_pub_id, Clock::default());
         let our_connection_info = PrivConnectionInfo(PeerId(0), Endpoint(0));
         let their_connection_info = PubConnectionInfo(PeerId(1), Endpoint(1));
         // We received a connection info from the peer and get a token to prepare ours.
@@ -938,4 +1470,140 @@ mod tests {
             state => panic!("Unexpected state: {:?}", state),
         }
     }
+
+    #[test]
+    pub fn direct_upgrade() {
+        let mut peer_mgr = new_peer_manager(*FullId::new().public_id(), Clock::default());
+        let pub_id = *FullId::new().public_id();
+        let peer_id = PeerId(1);
+        let our_connection_info = PrivConnectionInfo(PeerId(0), Endpoint(0));
+        let their_connection_info = PubConnectionInfo(peer_id, Endpoint(1));
+        let _ = peer_mgr.set_searching_for_tunnel(peer_id, pub_id);
+        let _ = unwrap!(peer_mgr.add_to_routing_table(pub_id, peer_id));
+        assert_eq!(vec![pub_id], peer_mgr.tunnelled_peers());
+
+        // We start an attempt to connect directly, but only one at a time.
+        let token = unwrap!(peer_mgr.start_direct_upgrade(node_auth(0), node_auth(1), pub_id));
+        assert_eq!(None,
+                   peer_mgr.start_direct_upgrade(node_auth(0), node_auth(1), pub_id));
+        match peer_mgr.connection_info_prepared(token, our_connection_info.clone()) {
+            Ok(ConnectionInfoPreparedResult { infos: None, .. }) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match peer_mgr.connection_info_received(node_auth(0),
+                                                node_auth(1),
+                                                pub_id,
+                                                their_connection_info.clone()) {
+            Ok(ConnectionInfoReceivedResult::Ready(our_info, their_info)) => {
+                assert_eq!(our_connection_info, our_info);
+                assert_eq!(their_connection_info, their_info);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        // The peer remains tunnelled until the direct connection succeeds.
+        assert!(peer_mgr.is_tunnelled(pub_id.name()));
+        assert!(peer_mgr.direct_connection_established(&peer_id));
+        assert!(!peer_mgr.is_tunnelled(pub_id.name()));
+        assert!(!peer_mgr.direct_connection_established(&peer_id));
+        assert_eq!(Some(&pub_id), peer_mgr.get_routing_peer(&peer_id));
+    }
+
+    #[test]
+    pub fn misbehaviour_and_ageing() {
+        let mut peer_mgr = new_peer_manager(*FullId::new().public_id(), Clock::default());
+        let pub_id = *FullId::new().public_id();
+        let peer_id = PeerId(1);
+        let _ = unwrap!(peer_mgr.add_to_routing_table(pub_id, peer_id));
+        assert!(peer_mgr.is_trusted(pub_id.name()));
+
+        // Unacknowledged messages alone neither make the peer distrusted nor get it evicted.
+        for _ in 0..(EVICTION_SCORE + 5) {
+            assert!(!peer_mgr.report_misbehaviour(&peer_id, Misbehaviour::UnackedRelay));
+        }
+        assert!(peer_mgr.is_trusted(pub_id.name()));
+        assert!(!peer_mgr.report_misbehaviour(&peer_id, Misbehaviour::InvalidMessage));
+        assert!(!peer_mgr.is_trusted(pub_id.name()));
+
+        // The penalties decay over time, while the peer's age grows.
+        for _ in 0..DISTRUST_SCORE {
+            peer_mgr.increment_ages();
+        }
+        assert!(peer_mgr.is_trusted(pub_id.name()));
+        assert_eq!(Some(DISTRUST_SCORE),
+                   peer_mgr.get_connected_peer(&peer_id).map(Peer::age));
+
+        // Older peers are given more leeway before they are evicted.
+        assert!(!peer_mgr.report_misbehaviour(&peer_id, Misbehaviour::FailedSignature));
+        assert!(!peer_mgr.report_misbehaviour(&peer_id, Misbehaviour::FailedSignature));
+        assert!(peer_mgr.report_misbehaviour(&peer_id, Misbehaviour::FailedSignature));
+    }
+
+    #[test]
+    pub fn young_peers_cannot_reach_quorum_alone() {
+        let mut peer_mgr = new_peer_manager(*FullId::new().public_id(), Clock::default());
+
+        // While the group has no older members, everyone's vote counts.
+        let elders = add_peers(&mut peer_mgr, 0, QUORUM_SIZE);
+        assert!(elders.iter().all(|name| peer_mgr.counts_vote(name, QUORUM_SIZE)));
+        for _ in 0..MIN_VOTING_AGE {
+            peer_mgr.increment_ages();
+        }
+
+        // Once it has enough old members to reach the quorum, the newcomers' votes don't count,
+        // so they can't reach the quorum among themselves.
+        let newcomers = add_peers(&mut peer_mgr, QUORUM_SIZE, QUORUM_SIZE);
+        assert!(newcomers.iter().all(|name| !peer_mgr.counts_vote(name, QUORUM_SIZE)));
+        assert!(elders.iter().all(|name| peer_mgr.counts_vote(name, QUORUM_SIZE)));
+
+        // If the old members alone can't reach the quorum, the newcomers vote, too.
+        assert!(newcomers.iter().all(|name| peer_mgr.counts_vote(name, QUORUM_SIZE + 1)));
+    }
+
+    #[test]
+    pub fn key_rotation() {
+        let network = Network::new(None);
+        let mut peer_mgr = new_peer_manager(*FullId::new().public_id(), virtual_clock(&network));
+        let full_id = FullId::new();
+        let peer_id = PeerId(1);
+        let _ = unwrap!(peer_mgr.add_to_routing_table(*full_id.public_id(), peer_id));
+
+        // The new ID needs to have the same name.
+        assert!(!peer_mgr.rotate_keys(&peer_id, *FullId::new().public_id()));
+        let new_full_id = full_id.rotated();
+        assert!(peer_mgr.rotate_keys(&peer_id, *new_full_id.public_id()));
+
+        // During the grace period, both keys are accepted.
+        {
+            let peer = unwrap!(peer_mgr.get_connected_peer(&peer_id));
+            assert_eq!(new_full_id.public_id(), peer.pub_id());
+            assert_eq!(vec![*new_full_id.public_id().signing_public_key(),
+                            *full_id.public_id().signing_public_key()],
+                       peer.signing_keys(network.now()));
+        }
+
+        // It can't rotate its keys again until the grace period is over.
+        assert!(!peer_mgr.rotate_keys(&peer_id, *new_full_id.rotated().public_id()));
+
+        // After the grace period, only the new one is.
+        network.advance_time(Duration::from_secs(KEY_ROTATION_GRACE_PERIOD_SECS));
+        assert_eq!(vec![*new_full_id.public_id().signing_public_key()],
+                   unwrap!(peer_mgr.get_connected_peer(&peer_id)).signing_keys(network.now()));
+        assert!(peer_mgr.rotate_keys(&peer_id, *new_full_id.rotated().public_id()));
+    }
+
+    #[test]
+    pub fn joining_node_expiry() {
+        let network = Network::new(None);
+        let mut peer_mgr = new_peer_manager(*FullId::new().public_id(), virtual_clock(&network));
+        let peer_id = PeerId(1);
+        assert!(!peer_mgr.insert_joining_node(peer_id, *FullId::new().public_id()));
+
+        network.advance_time(Duration::from_secs(JOINING_NODE_TIMEOUT_SECS - 1));
+        assert!(peer_mgr.remove_expired_joining_nodes().is_empty());
+        assert!(peer_mgr.get_joining_node(&peer_id).is_some());
+
+        network.advance_time(Duration::from_secs(1));
+        assert_eq!(vec![peer_id], peer_mgr.remove_expired_joining_nodes());
+        assert!(peer_mgr.get_joining_node(&peer_id).is_none());
+    }
 }
@@ -0,0 +1,136 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use clock::Clock;
+use error::RoutingError;
+use messages::SignedMessage;
+use rust_sodium::crypto::sign;
+use std::collections::BTreeSet;
+
+/// Time (in milliseconds) after it was signed during which a message is accepted.
+pub const REPLAY_WINDOW_MILLIS: u64 = 20 * 60 * 1000;
+/// Time (in milliseconds) by which a message's timestamp may be ahead of our own clock.
+pub const MAX_CLOCK_SKEW_MILLIS: u64 = 60 * 1000;
+
+/// Rejects signed messages that were already handled or are too old, so that they can't be
+/// replayed.
+///
+/// Messages carry the time their sender signed them. Those older than the replay window are
+/// rejected outright, and those within the window are remembered until they leave it. To protect
+/// against replays across a restart, the filter can be serialised and passed to the new node.
+#[derive(Clone, Debug, Default, Eq, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct ReplayFilter {
+    /// The timestamps and signatures of the messages we accepted within the window.
+    seen: BTreeSet<(u64, sign::Signature)>,
+}
+
+impl ReplayFilter {
+    /// Returns `Ok` if the message is within the replay window and hasn't been seen before, and
+    /// remembers it. The current time is read from `clock`.
+    pub fn check(&mut self, signed_msg: &SignedMessage, clock: &Clock) -> Result<(), RoutingError> {
+        self.check_at(signed_msg.timestamp(), signed_msg.signature(), clock.unix_time_millis())
+    }
+
+    fn check_at(&mut self,
+                timestamp: u64,
+                signature: &sign::Signature,
+                now: u64)
+                -> Result<(), RoutingError> {
+        self.remove_expired(now);
+        if timestamp + REPLAY_WINDOW_MILLIS < now || timestamp > now + MAX_CLOCK_SKEW_MILLIS {
+            return Err(RoutingError::ReplayedMessage);
+        }
+        if self.seen.insert((timestamp, *signature)) {
+            Ok(())
+        } else {
+            Err(RoutingError::ReplayedMessage)
+        }
+    }
+
+    fn remove_expired(&mut self, now: u64) {
+        let expired = self.seen
+            .iter()
+            .take_while(|&&(timestamp, _)| timestamp + REPLAY_WINDOW_MILLIS < now)
+            .cloned()
+            .collect::<Vec<_>>();
+        for entry in expired {
+            let _ = self.seen.remove(&entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_sodium::crypto::sign;
+    use super::*;
+
+    #[test]
+    fn replay_window() {
+        let signature = sign::Signature([1; sign::SIGNATUREBYTES]);
+        let other_signature = sign::Signature([2; sign::SIGNATUREBYTES]);
+        let now = 100 * REPLAY_WINDOW_MILLIS;
+        let mut filter = ReplayFilter::default();
+
+        assert!(filter.check_at(now, &signature, now).is_ok());
+        assert!(filter.check_at(now, &signature, now + 1).is_err());
+        assert!(filter.check_at(now, &other_signature, now + 1).is_ok());
+
+        // Too old or too far in the future.
+        assert!(filter.check_at(now - REPLAY_WINDOW_MILLIS - 1, &signature, now).is_err());
+        assert!(filter.check_at(now + MAX_CLOCK_SKEW_MILLIS + 1, &signature, now).is_err());
+
+        // A restarted node that was given the old filter still rejects the message.
+        let mut restored_filter = filter.clone();
+        assert!(restored_filter.check_at(now, &signature, now + 2).is_err());
+
+        // Once it has expired, the message is rejected for being too old, and forgotten.
+        let later = now + REPLAY_WINDOW_MILLIS + 1;
+        assert!(filter.check_at(now, &signature, later).is_err());
+        assert!(filter.seen.is_empty());
+    }
+
+    #[cfg(feature = "use-mock-crust")]
+    #[test]
+    fn virtual_time() {
+        use authority::Authority;
+        use clock::Clock;
+        use id::FullId;
+        use messages::{MessageContent, RoutingMessage, SignedMessage};
+        use mock_crust::{self, Network};
+        use rand;
+        use std::time::Duration;
+        use types::MessageId;
+
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let clock = mock_crust::make_current(&handle, Clock::new);
+        let msg = RoutingMessage {
+            src: Authority::ManagedNode(rand::random()),
+            dst: Authority::ManagedNode(rand::random()),
+            content: MessageContent::GetCloseGroup(MessageId::new()),
+        };
+        let signed_msg = unwrap!(SignedMessage::new(msg, &FullId::new(), &clock));
+        let mut filter = ReplayFilter::default();
+        let mut restored_filter = filter.clone();
+        unwrap!(filter.check(&signed_msg, &clock));
+        assert!(filter.check(&signed_msg, &clock).is_err());
+
+        // The message is too old once the mock network's virtual time has passed the window.
+        network.advance_time(Duration::from_millis(REPLAY_WINDOW_MILLIS + 1));
+        assert!(restored_filter.check(&signed_msg, &clock).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use error::RoutingError;
+use id::PublicId;
+use maidsafe_utilities::serialisation::serialise;
+use rust_sodium::crypto::hash::sha256;
+use std::cmp;
+use std::fmt::{self, Debug, Formatter};
+use types::MessageId;
+use utils;
+
+/// The highest difficulty of a resource proof challenge. Joining nodes reject harder challenges,
+/// which would take too long to solve.
+pub const MAX_DIFFICULTY: u8 = 24;
+
+/// A proof of work a relocated node has to provide before its new close group accepts it.
+///
+/// All members of the group compute the same challenge from the node's relocated ID and the ID of
+/// its `GetNodeName` request, so it can be sent as part of the group's `GetNodeNameResponse`. The
+/// node then has to find a nonce such that the hash of the challenge seed, its ID and the nonce
+/// starts with `difficulty` zero bits. This makes it expensive to flood a group with many nodes.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, RustcEncodable, RustcDecodable)]
+pub struct ResourceProofChallenge {
+    seed: sha256::Digest,
+    difficulty: u8,
+}
+
+impl ResourceProofChallenge {
+    /// Returns the challenge for the node with the given relocated ID, or `None` if `difficulty`
+    /// is zero, i.e. no proof is required. The difficulty is capped at `MAX_DIFFICULTY`.
+    pub fn new(relocated_id: &PublicId,
+               message_id: &MessageId,
+               difficulty: u8)
+               -> Result<Option<ResourceProofChallenge>, RoutingError> {
+        if difficulty == 0 {
+            return Ok(None);
+        }
+        let seed = sha256::hash(&try!(serialise(&(relocated_id, message_id))));
+        Ok(Some(ResourceProofChallenge {
+            seed: seed,
+            difficulty: cmp::min(difficulty, MAX_DIFFICULTY),
+        }))
+    }
+
+    /// Returns the number of leading zero bits the hash of a solution must have.
+    pub fn difficulty(&self) -> u8 {
+        self.difficulty
+    }
+
+    /// Returns `true` if `nonce` solves this challenge for the given ID.
+    pub fn verify(&self, public_id: &PublicId, nonce: u64) -> bool {
+        match serialise(public_id) {
+            Ok(id_bytes) => self.is_solution(&id_bytes, nonce),
+            Err(_) => false,
+        }
+    }
+
+    fn is_solution(&self, id_bytes: &[u8], nonce: u64) -> bool {
+        let mut bytes = self.seed.0.to_vec();
+        bytes.extend_from_slice(id_bytes);
+        bytes.extend((0..8).map(|i| (nonce >> (8 * i)) as u8));
+        leading_zero_bits(&sha256::hash(&bytes).0) >= self.difficulty as usize
+    }
+}
+
+/// The search for a solution to a `ResourceProofChallenge`, which takes about `2 ^ difficulty`
+/// hash computations. It proceeds in bounded steps, so that it doesn't block the event loop.
+pub struct ResourceProofSolver {
+    challenge: ResourceProofChallenge,
+    id_bytes: Vec<u8>,
+    next_nonce: u64,
+}
+
+impl ResourceProofSolver {
+    /// Starts searching for a solution to the challenge for the given ID.
+    pub fn new(challenge: ResourceProofChallenge,
+               public_id: &PublicId)
+               -> Result<ResourceProofSolver, RoutingError> {
+        Ok(ResourceProofSolver {
+            challenge: challenge,
+            id_bytes: try!(serialise(public_id)),
+            next_nonce: 0,
+        })
+    }
+
+    /// Tries the next `count` nonces, and returns the first one that solves the challenge, if any.
+    pub fn step(&mut self, count: u64) -> Option<u64> {
+        let end = self.next_nonce.saturating_add(count);
+        let solution = (self.next_nonce..end)
+            .find(|&nonce| self.challenge.is_solution(&self.id_bytes, nonce));
+        self.next_nonce = end;
+        solution
+    }
+}
+
+impl Debug for ResourceProofChallenge {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter,
+               "ResourceProofChallenge {{ seed: {}, difficulty: {} }}",
+               utils::format_binary_array(&self.seed.0),
+               self.difficulty)
+    }
+}
+
+fn leading_zero_bits(bytes: &[u8]) -> usize {
+    let mut count = 0;
+    for byte in bytes {
+        count += byte.leading_zeros() as usize;
+        if *byte != 0 {
+            break;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use id::FullId;
+    use super::*;
+    use types::MessageId;
+
+    #[test]
+    fn leading_zeros() {
+        assert_eq!(0, leading_zero_bits(&[0b1000_0000, 0]));
+        assert_eq!(11, leading_zero_bits(&[0, 0b0001_0000, 0]));
+        assert_eq!(16, leading_zero_bits(&[0, 0]));
+    }
+
+    #[test]
+    fn solve_and_verify() {
+        let public_id = *FullId::new().public_id();
+        let message_id = MessageId::new();
+        assert_eq!(None, unwrap!(ResourceProofChallenge::new(&public_id, &message_id, 0)));
+
+        let challenge = unwrap!(unwrap!(ResourceProofChallenge::new(&public_id, &message_id, 8)));
+        assert_eq!(Some(challenge),
+                   unwrap!(ResourceProofChallenge::new(&public_id, &message_id, 8)));
+        let mut solver = unwrap!(ResourceProofSolver::new(challenge, &public_id));
+        let nonce = unwrap!((0..).filter_map(|_| solver.step(16)).next());
+        assert!(challenge.verify(&public_id, nonce));
+
+        let other_id = *FullId::new().public_id();
+        assert!((0..64).any(|nonce| !challenge.verify(&other_id, nonce)));
+    }
+
+    #[test]
+    fn max_difficulty() {
+        let public_id = *FullId::new().public_id();
+        let message_id = MessageId::new();
+        let challenge = unwrap!(unwrap!(ResourceProofChallenge::new(&public_id, &message_id, 255)));
+        assert_eq!(MAX_DIFFICULTY, challenge.difficulty());
+    }
+}
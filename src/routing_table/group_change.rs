@@ -0,0 +1,186 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use std::collections::HashSet;
+use std::fmt::{Binary, Debug};
+use std::hash::Hash;
+use super::{Prefix, RoutingTable, Xorable};
+
+/// How our membership in the close group of a particular name was affected by a `GroupChange`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MembershipChange {
+    /// We are now a member of the name's close group, but weren't before.
+    Gained,
+    /// We were a member of the name's close group, but aren't any longer.
+    Lost,
+    /// Our membership in the name's close group didn't change.
+    Unchanged,
+}
+
+/// Describes how a routing table change affected our own group.
+///
+/// Our group is the close group for every name matching its prefix. So if the prefix changes, e.g.
+/// due to a split, we become or stop being responsible for some names; and any members added to or
+/// removed from our group are the nodes that now need, or no longer need, data for the names we
+/// manage.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GroupChange<T: Binary + Clone + Copy + Default + Hash + Xorable> {
+    old_prefix: Prefix<T>,
+    new_prefix: Prefix<T>,
+    members: HashSet<T>,
+    added_members: HashSet<T>,
+    removed_members: HashSet<T>,
+}
+
+impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> GroupChange<T> {
+    /// Computes the change from our group as it was, with the given prefix and members (excluding
+    /// ourselves), to our group in `table`.
+    pub fn new(old_prefix: Prefix<T>,
+               old_members: &HashSet<T>,
+               table: &RoutingTable<T>)
+               -> GroupChange<T> {
+        let new_members = table.our_group().clone();
+        GroupChange {
+            old_prefix: old_prefix,
+            new_prefix: *table.our_group_prefix(),
+            added_members: new_members.difference(old_members).cloned().collect(),
+            removed_members: old_members.difference(&new_members).cloned().collect(),
+            members: new_members,
+        }
+    }
+
+    /// Returns the prefix of our group before the change.
+    pub fn old_prefix(&self) -> &Prefix<T> {
+        &self.old_prefix
+    }
+
+    /// Returns the prefix of our group after the change.
+    pub fn new_prefix(&self) -> &Prefix<T> {
+        &self.new_prefix
+    }
+
+    /// Returns the members of our group after the change, excluding ourselves.
+    pub fn members(&self) -> &HashSet<T> {
+        &self.members
+    }
+
+    /// Returns the nodes which became members of our group.
+    pub fn added_members(&self) -> &HashSet<T> {
+        &self.added_members
+    }
+
+    /// Returns the nodes which are no longer members of our group.
+    pub fn removed_members(&self) -> &HashSet<T> {
+        &self.removed_members
+    }
+
+    /// Returns `true` if our group's prefix changed, i.e. we split or merged.
+    pub fn prefix_changed(&self) -> bool {
+        self.old_prefix != self.new_prefix
+    }
+
+    /// Returns whether we became or stopped being a member of the close group of `name`.
+    pub fn membership_change(&self, name: &T) -> MembershipChange {
+        match (self.old_prefix.matches(name), self.new_prefix.matches(name)) {
+            (false, true) => MembershipChange::Gained,
+            (true, false) => MembershipChange::Lost,
+            _ => MembershipChange::Unchanged,
+        }
+    }
+
+    /// Returns the nodes which joined the close group of `name`, and therefore need its data.
+    ///
+    /// This is empty unless we are a member of that close group after the change.
+    pub fn new_members_for(&self, name: &T) -> HashSet<T> {
+        match self.membership_change(name) {
+            // We managed the name before, so only the nodes added to our group are new to it.
+            MembershipChange::Unchanged if self.new_prefix.matches(name) => {
+                self.added_members.clone()
+            }
+            // The name was managed by the nodes added to our group in a merge, so the ones which
+            // were in our group already are new to it.
+            MembershipChange::Gained => {
+                self.members.difference(&self.added_members).cloned().collect()
+            }
+            _ => HashSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+    use super::super::RoutingTable;
+
+    fn snapshot(table: &RoutingTable<u8>) -> (Prefix<u8>, HashSet<u8>) {
+        (*table.our_group_prefix(), table.our_group().clone())
+    }
+
+    #[test]
+    fn node_added_and_lost() {
+        let mut table = RoutingTable::new(0b0000_0000, 8);
+        let _ = unwrap!(table.add(0b1000_0000));
+
+        let (old_prefix, old_members) = snapshot(&table);
+        let _ = unwrap!(table.add(0b0100_0000));
+        let change = GroupChange::new(old_prefix, &old_members, &table);
+        assert!(!change.prefix_changed());
+        assert_eq!(&[0b0100_0000].iter().cloned().collect::<HashSet<_>>(),
+                   change.added_members());
+        assert!(change.removed_members().is_empty());
+        assert_eq!(MembershipChange::Unchanged,
+                   change.membership_change(&0b1111_0000));
+        assert_eq!(*change.added_members(), change.new_members_for(&0b1111_0000));
+
+        let (old_prefix, old_members) = snapshot(&table);
+        let _ = unwrap!(table.remove(&0b1000_0000));
+        let change = GroupChange::new(old_prefix, &old_members, &table);
+        assert!(change.added_members().is_empty());
+        assert_eq!(&[0b1000_0000].iter().cloned().collect::<HashSet<_>>(),
+                   change.removed_members());
+        assert!(change.new_members_for(&0b1111_0000).is_empty());
+    }
+
+    #[test]
+    fn split() {
+        let mut table = RoutingTable::new(0b0000_0000, 2);
+        let names = [0b1000_0000, 0b1100_0000, 0b1010_0000, 0b0100_0000, 0b0010_0000];
+        for name in &names[..4] {
+            assert_eq!(None, unwrap!(table.add(*name)));
+        }
+
+        let (old_prefix, old_members) = snapshot(&table);
+        let prefix = unwrap!(unwrap!(table.add(names[4])));
+        let _ = table.split(prefix);
+        let change = GroupChange::new(old_prefix, &old_members, &table);
+        assert!(change.prefix_changed());
+
+        for name in &names {
+            let expected = if table.our_group_prefix().matches(name) {
+                MembershipChange::Unchanged
+            } else {
+                MembershipChange::Lost
+            };
+            assert_eq!(expected, change.membership_change(name));
+            assert_eq!(table.is_in_our_group(name) && !old_members.contains(name),
+                       change.added_members().contains(name));
+            assert_eq!(old_members.contains(name) && !table.is_in_our_group(name),
+                       change.removed_members().contains(name));
+        }
+    }
+}
@@ -16,110 +16,111 @@
 // relating to use of the SAFE Network Software.
 
 
-// A routing table to manage contacts for a node in a [Kademlia][1] distributed hash table.
-//
-// [1]: https://en.wikipedia.org/wiki/Kademlia
-//
-//
-// This uses the Kademlia mechanism for routing messages in a peer-to-peer network, and generalises
-// it to provide redundancy in every step: for senders, messages in transit and receivers.
-// It contains the routing table and the functionality to decide via which of its entries to route
-// a message, but not the networking functionality itself.
-//
-// It also provides methods to decide which other nodes to connect to, depending on a parameter
-// `bucket_size` (see below).
-//
-//
-// # Addresses and distance functions
-//
-// Nodes in the network are addressed with a [`Xorable`][2] type, an unsigned integer with `B` bits.
-// The *[XOR][3] distance* between two nodes with addresses `x` and `y` is `x ^ y`. This
-// [distance function][4] has the property that no two points ever have the same distance from a
-// given point, i. e. if `x ^ y == x ^ z`, then `y == z`. This property allows us to define the
-// `k`-*close group* of an address as the `k` closest nodes to that address, guaranteeing that the
-// close group will always have exactly `k` members (unless, of course, the whole network has less
-// than `k` nodes).
-//
-// [2]: trait.Xorable.html
-// [3]: https://en.wikipedia.org/wiki/Exclusive_or#Bitwise_operation
-// [4]: https://en.wikipedia.org/wiki/Metric_%28mathematics%29
-//
-// The routing table is associated with a node with some name `x`, and manages a number of contacts
-// to other nodes, sorting them into up to `B` *buckets*, depending on their XOR distance from `x`:
-//
-// * If 2<sup>`B`</sup> > `x ^ y` >= 2<sup>`B - 1`</sup>, then y is in bucket 0.
-// * If 2<sup>`B - 1`</sup> > `x ^ y` >= 2<sup>`B - 2`</sup>, then y is in bucket 1.
-// * If 2<sup>`B - 2`</sup> > `x ^ y` >= 2<sup>`B - 3`</sup>, then y is in bucket 2.
-// * ...
-// * If 2 > `x ^ y` >= 1, then y is in bucket `B - 1`.
-//
-// Equivalently, `y` is in bucket `n` if the longest common prefix of `x` and `y` has length `n`,
-// i. e. the first binary digit in which `x` and `y` disagree is the `(n + 1)`-th one. We call the
-// length of the remainder, without the common prefix, the *bucket distance* of `x` and `y`. Hence
-// `x` and `y` have bucket distance `B - n` if and only if `y` belongs in bucket number `n`.
-//
-// The bucket distance is coarser than the XOR distance: Whenever the bucket distance from `y` to
-// `x` is less than the bucket distance from `z` to `x`, then `y ^ x < z ^ x`. But not vice-versa:
-// Often `y ^ x < z ^ x`, even if the bucket distances are equal. The XOR distance ranges from 0
-// to 2<sup>`B`</sup> (exclusive), while the bucket distance ranges from 0 to `B` (inclusive).
-//
-//
-// # Guarantees
-//
-// The routing table provides functions to decide, for a message with a given destination, which
-// nodes in the table to pass the message on to, so that it is guaranteed that:
-//
-// * If the destination is the address of a node, the message will reach that node after at most
-//   `B - 1` hops.
-// * Otherwise, if the destination is a `k`-close group with `k <= bucket_size`, the message will
-//   reach every member of the `k`-close group of the destination address, i. e. all `k` nodes in
-//   the network that are XOR-closest to that address, and each node knows whether it belongs to
-//   that group.
-// * Each node in a given address' close group is connected to each other node in that group. In
-//   particular, every node is connected to its own close group.
-// * The number of total hop messages created for each message is at most `B`.
-// * For each node there are at most `B * bucket_size` other nodes in the network that would
-//   accept a connection, at any point in time. All other nodes do not need to disclose their IP
-//   address.
-// * There are `bucket_size` different paths along which a message can be sent, to provide
-//   redundancy.
-//
-// However, to be able to make these guarantees, the routing table must be filled with sufficiently
-// many contacts. Specifically, the following invariant must be ensured:
-//
-// > Whenever a bucket `n` has fewer than `bucket_size` entries, it contains *all* nodes in the
-// > network with bucket distance `B - n`.
-//
-// The user of this crate therefore needs to make sure that whenever a node joins or leaves, all
-// affected nodes in the network update their routing tables accordingly.
-//
-//
-// # Resilience against malfunctioning nodes
-//
-// The sender may choose to send a message via up to `bucket_size` distinct paths to provide
-// redundancy against malfunctioning hop nodes. These paths are likely, but not guaranteed, to be
-// disjoint.
-//
-// The concept of close groups exists to provide resilience even against failures of the source or
-// destination itself: If every member of a group tries to send the same message, it will arrive
-// even if some members fail. And if a message is sent to a whole group, it will arrive in most,
-// even if some of them malfunction.
-//
-// Close groups can thus be used as inherently redundant authorities in the network that messages
-// can be sent to and received from, using a consensus algorithm: A message from a group authority
-// is considered to be legitimate, if a majority of group members have sent a message with the same
-// content.
-
-// TODO - remove this
-#![allow(unused)]
+//! A routing table to manage contacts for a node in a [Kademlia][1]-like distributed hash table.
+//!
+//! [1]: https://en.wikipedia.org/wiki/Kademlia
+//!
+//!
+//! This generalises the Kademlia mechanism for routing messages in a peer-to-peer network to
+//! provide redundancy in every step: for senders, messages in transit and receivers. It contains
+//! the routing table and the functionality to decide via which of its entries to route a message,
+//! but not the networking functionality itself.
+//!
+//! The table is generic over the name type, which only needs to implement [`Xorable`][2] (and a
+//! few standard traits), so it can be used standalone, e.g. as a `RoutingTable<u64>` in simulation
+//! tools, without depending on the rest of this crate.
+//!
+//! [2]: trait.Xorable.html
+//!
+//!
+//! # Addresses and distance functions
+//!
+//! Nodes in the network are addressed with a `Xorable` type, an unsigned integer with `B` bits.
+//! The *[XOR][3] distance* between two nodes with addresses `x` and `y` is `x ^ y`. This
+//! [distance function][4] has the property that no two points ever have the same distance from a
+//! given point, i. e. if `x ^ y == x ^ z`, then `y == z`.
+//!
+//! [3]: https://en.wikipedia.org/wiki/Exclusive_or#Bitwise_operation
+//! [4]: https://en.wikipedia.org/wiki/Metric_%28mathematics%29
+//!
+//!
+//! # Groups and prefixes
+//!
+//! The name space is partitioned into *groups*, each identified by a [`Prefix`][5]: a sequence of
+//! leading bits shared by the names of all of its members. The routing table of a node with name
+//! `x` holds its own group, i.e. the one whose prefix matches `x`, and every group whose prefix
+//! differs from our own in exactly one bit (its *neighbours*).
+//!
+//! [5]: struct.Prefix.html
+//!
+//! Whenever adding a contact leaves both halves of our group with at least `min_group_size`
+//! (plus a small buffer) members, `add` returns the prefix which should be split. Conversely,
+//! when removing a contact leaves our group with fewer than `min_group_size` members, `remove`
+//! returns the details needed to merge our group with its sibling. It is up to the user to
+//! distribute those details to the affected nodes and to apply them via `split`,
+//! `merge_own_group` and `merge_other_group`.
+//!
+//!
+//! # Guarantees
+//!
+//! The routing table provides functions to decide, for a message with a given [`Destination`][6],
+//! which nodes in the table to pass the message on to, so that it is guaranteed that:
+//!
+//! * If the destination is the address of a node, the message will reach that node after at most
+//!   `B - 1` hops.
+//! * Otherwise, if the destination is a group, the message will reach every member of the group
+//!   whose prefix matches the destination address, and each node knows whether it belongs to that
+//!   group.
+//! * Each node in a group is connected to each other node in that group.
+//!
+//! [6]: enum.Destination.html
+//!
+//! However, to be able to make these guarantees, the routing table must contain all members of
+//! our own group and of each of its neighbours. The user of this module therefore needs to make
+//! sure that whenever a node joins or leaves, all affected nodes in the network update their
+//! routing tables accordingly.
+//!
+//!
+//! # Resilience against malfunctioning nodes
+//!
+//! The sender may choose to send a message via several distinct routes (see `targets`) to provide
+//! redundancy against malfunctioning hop nodes. These paths are likely, but not guaranteed, to be
+//! disjoint.
+//!
+//! The concept of groups exists to provide resilience even against failures of the source or
+//! destination itself: If every member of a group tries to send the same message, it will arrive
+//! even if some members fail. And if a message is sent to a whole group, it will arrive in most,
+//! even if some of them malfunction.
+//!
+//! Groups can thus be used as inherently redundant authorities in the network that messages can be
+//! sent to and received from, using a consensus algorithm: A message from a group authority is
+//! considered to be legitimate, if a majority of group members have sent a message with the same
+//! content.
+//!
+//!
+//! # Example
+//!
+//! ```
+//! use routing::routing_table::{Destination, RoutingTable};
+//!
+//! let mut table = RoutingTable::<u64>::new(0, 2);
+//! for name in 1..4 {
+//!     assert_eq!(None, table.add(name << 60).unwrap());
+//! }
+//! assert_eq!(3, table.len());
+//! assert!(table.is_recipient(&Destination::Group(1 << 60)));
+//! assert!(table.targets(&Destination::Node(2 << 60), 0, &[]).unwrap().contains(&(2 << 60)));
+//! ```
 
 mod error;
+mod group_change;
 mod network_tests;
 mod prefix;
 mod xorable;
 
 use itertools::Itertools;
 pub use self::error::Error;
+pub use self::group_change::{GroupChange, MembershipChange};
 pub use self::prefix::Prefix;
 pub use self::xorable::Xorable;
 use std::{iter, mem};
@@ -128,6 +129,7 @@ use std::fmt::{Binary, Debug, Formatter};
 use std::fmt::Result as FmtResult;
 use std::hash::Hash;
 
+/// The groups held in a routing table, keyed by their prefix.
 pub type Groups<T> = HashMap<Prefix<T>, HashSet<T>>;
 
 type MemberIter<'a, T> = hash_set::Iter<'a, T>;
@@ -138,7 +140,7 @@ type FlatMapFn<'a, T> = fn((&Prefix<T>, &'a HashSet<T>)) -> MemberIter<'a, T>;
 // protect against rapid splitting and merging in the face of moderate churn.
 const SPLIT_BUFFER: usize = 1;
 
-// Immutable iterator over the entries of a `RoutingTable`.
+/// Immutable iterator over the entries of a `RoutingTable`.
 pub struct Iter<'a, T: 'a + Binary + Clone + Copy + Default + Hash + Xorable> {
     inner: iter::FlatMap<GroupIter<'a, T>, MemberIter<'a, T>, FlatMapFn<'a, T>>,
 }
@@ -163,17 +165,17 @@ impl<'a, T: 'a + Binary + Clone + Copy + Default + Hash + Xorable> Iterator for
 
 
 
-// A message destination.
+/// A message destination.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Destination<N> {
-    // The group closest to the given name.
+    /// The group closest to the given name.
     Group(N),
-    // The individual node at the given name.
+    /// The individual node at the given name.
     Node(N),
 }
 
 impl<N> Destination<N> {
-    // Returns the name of the destination, i.e. the node or group name.
+    /// Returns the name of the destination, i.e. the node or group name.
     pub fn name(&self) -> &N {
         match *self {
             Destination::Group(ref name) |
@@ -181,7 +183,7 @@ impl<N> Destination<N> {
         }
     }
 
-    // Returns `true` if the destination is a group, and `false` if it is an individual node.
+    /// Returns `true` if the destination is a group, and `false` if it is an individual node.
     pub fn is_group(&self) -> bool {
         match *self {
             Destination::Group(_) => true,
@@ -189,7 +191,7 @@ impl<N> Destination<N> {
         }
     }
 
-    // Returns `true` if the destination is an individual node, and `false` if it is a group.
+    /// Returns `true` if the destination is an individual node, and `false` if it is a group.
     pub fn is_node(&self) -> bool {
         !self.is_group()
     }
@@ -197,45 +199,85 @@ impl<N> Destination<N> {
 
 
 
-// Used when removal of a contact triggers the need to merge two or more groups
+/// Used when removal of a contact triggers the need to merge two or more groups
 #[derive(Debug)]
 pub struct OwnMergeDetails<T: Binary + Clone + Copy + Default + Hash + Xorable> {
     prefix: Prefix<T>,
     groups: Groups<T>,
 }
 
+impl<T: Binary + Clone + Copy + Default + Hash + Xorable> OwnMergeDetails<T> {
+    /// Constructor, for use when the details have been received from another node.
+    pub fn new(prefix: Prefix<T>, groups: Groups<T>) -> Self {
+        OwnMergeDetails {
+            prefix: prefix,
+            groups: groups,
+        }
+    }
 
+    /// The prefix of the group resulting from the merge.
+    pub fn prefix(&self) -> &Prefix<T> {
+        &self.prefix
+    }
+
+    /// All groups held in the sender's routing table before the merge.
+    pub fn groups(&self) -> &Groups<T> {
+        &self.groups
+    }
+}
 
-// Used when merging our own group to send to peers outwith the new group
+
+
+/// Used when merging our own group to send to peers outwith the new group
 #[derive(Debug)]
 pub struct OtherMergeDetails<T: Binary + Clone + Copy + Default + Hash + Xorable> {
     prefix: Prefix<T>,
     group: HashSet<T>,
 }
 
+impl<T: Binary + Clone + Copy + Default + Hash + Xorable> OtherMergeDetails<T> {
+    /// Constructor, for use when the details have been received from another node.
+    pub fn new(prefix: Prefix<T>, group: HashSet<T>) -> Self {
+        OtherMergeDetails {
+            prefix: prefix,
+            group: group,
+        }
+    }
 
+    /// The prefix of the group resulting from the merge.
+    pub fn prefix(&self) -> &Prefix<T> {
+        &self.prefix
+    }
 
-// Details returned by a successful `RoutingTable::remove()`.
+    /// All members of the merged group.
+    pub fn group(&self) -> &HashSet<T> {
+        &self.group
+    }
+}
+
+
+
+/// Details returned by a successful `RoutingTable::remove()`.
 #[derive(Debug)]
 pub struct RemovalDetails<T: Binary + Clone + Copy + Default + Hash + Xorable> {
-    // Peer name
+    /// Peer name
     pub name: T,
-    // True if the removed peer was in our group.
+    /// True if the removed peer was in our group.
     pub was_in_our_group: bool,
-    // If, after removal, our group needs to merge, this is set to `Some`. It contains the
-    // appropriate targets (all members of the merging groups) and the merge details they each need
-    // to receive (the new prefix and all groups in the table).
+    /// If, after removal, our group needs to merge, this is set to `Some`. It contains the
+    /// appropriate targets (all members of the merging groups) and the merge details they each need
+    /// to receive (the new prefix and all groups in the table).
     pub targets_and_merge_details: Option<(Vec<T>, OwnMergeDetails<T>)>,
 }
 
 
 
-// A routing table to manage contacts for a node.
-//
-// It maintains a list of `T`s representing connected peer nodes, and provides algorithms for
-// routing messages.
-//
-// See the [crate documentation](index.html) for details.
+/// A routing table to manage contacts for a node.
+///
+/// It maintains a list of `T`s representing connected peer nodes, and provides algorithms for
+/// routing messages.
+///
+/// See the [module documentation](index.html) for details.
 #[derive(Clone, Eq, PartialEq)]
 pub struct RoutingTable<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> {
     our_name: T,
@@ -246,6 +288,9 @@ pub struct RoutingTable<T: Binary + Clone + Copy + Debug + Default + Hash + Xora
 }
 
 impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T> {
+    /// Creates a new, empty routing table for a node called `our_name`.
+    ///
+    /// `min_group_size` is the number of members below which a group merges with its sibling.
     pub fn new(our_name: T, min_group_size: usize) -> Self {
         let mut groups = HashMap::new();
         let our_group_prefix = Prefix::new(0, our_name);
@@ -259,25 +304,43 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         }
     }
 
+    /// Returns the name of the node owning this routing table.
     pub fn our_name(&self) -> &T {
         &self.our_name
     }
 
-    // Total number of entries in the routing table.
+    /// Total number of entries in the routing table.
     pub fn len(&self) -> usize {
         self.groups.values().fold(0, |acc, group| acc + group.len())
     }
 
+    /// Returns `true` if the routing table doesn't contain any entries.
     pub fn is_empty(&self) -> bool {
         self.groups.values().all(HashSet::is_empty)
     }
 
+    /// Returns an iterator over all entries in the routing table.
     pub fn iter(&self) -> Iter<T> {
         Iter { inner: self.groups.iter().flat_map(Iter::<T>::iterate) }
     }
 
-    // If our group is the closest one to `name`, returns all names in our group *including ours*,
-    // otherwise returns `None`.
+    /// Returns the prefix of our own group.
+    pub fn our_group_prefix(&self) -> &Prefix<T> {
+        &self.our_group_prefix
+    }
+
+    /// Returns all groups in the routing table, including our own (which doesn't contain our name).
+    pub fn groups(&self) -> &Groups<T> {
+        &self.groups
+    }
+
+    /// Returns the members of our own group, excluding ourselves.
+    pub fn our_group(&self) -> &HashSet<T> {
+        unwrap!(self.groups.get(&self.our_group_prefix))
+    }
+
+    /// If our group is the closest one to `name`, returns all names in our group *including ours*,
+    /// otherwise returns `None`.
     pub fn close_names(&self, name: &T) -> Option<HashSet<T>> {
         if self.our_group_prefix.matches(name) {
             let mut our_group = unwrap!(self.groups.get(&self.our_group_prefix)).clone();
@@ -288,8 +351,8 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         }
     }
 
-    // If our group is the closest one to `name`, returns all names in our group *excluding ours*,
-    // otherwise returns `None`.
+    /// If our group is the closest one to `name`, returns all names in our group *excluding ours*,
+    /// otherwise returns `None`.
     pub fn other_close_names(&self, name: &T) -> Option<HashSet<T>> {
         if self.our_group_prefix.matches(name) {
             Some(unwrap!(self.groups.get(&self.our_group_prefix)).clone())
@@ -298,6 +361,7 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         }
     }
 
+    /// Returns `true` if `name` is an entry in our own group.
     pub fn is_in_our_group(&self, name: &T) -> bool {
         if self.our_group_prefix.matches(name) {
             return unwrap!(self.groups.get(&self.our_group_prefix)).contains(name);
@@ -305,16 +369,16 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         false
     }
 
-    // Returns the list of contacts as a result of a merge to which we aren't currently connected,
-    // but should be.
+    /// Returns the list of contacts as a result of a merge to which we aren't currently connected,
+    /// but should be.
     pub fn needed(&self) -> &HashSet<T> {
         &self.needed
     }
 
-    // Returns `Ok(())` if the given contact should be added to the routing table.
-    //
-    // Returns `Err` if `name` already exists in the routing table, or it doesn't fall within any
-    // of our groups, or it's our own name.  Otherwise it returns `true`.
+    /// Returns `Ok(())` if the given contact should be added to the routing table.
+    ///
+    /// Returns `Err` if `name` already exists in the routing table, or it doesn't fall within any
+    /// of our groups, or it's our own name.
     pub fn need_to_add(&self, name: &T) -> Result<(), Error> {
         if *name == self.our_name {
             return Err(Error::OwnNameDisallowed);
@@ -330,12 +394,12 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         }
     }
 
-    // Adds a contact to the routing table.
-    //
-    // Returns `Err` if `name` already existed in the routing table, or it doesn't fall within any
-    // of our groups, or it's our own name.  Otherwise it returns `Ok(Some(prefix))` if the addition
-    // succeeded and should cause our group to split (where `prefix` is the one which should split)
-    // or `Ok(None)` if the addition succeeded and shouldn't cause a split.
+    /// Adds a contact to the routing table.
+    ///
+    /// Returns `Err` if `name` already existed in the routing table, or it doesn't fall within any
+    /// of our groups, or it's our own name.  Otherwise it returns `Ok(Some(prefix))` if the
+    /// addition succeeded and should cause our group to split (where `prefix` is the one which
+    /// should split) or `Ok(None)` if the addition succeeded and shouldn't cause a split.
     pub fn add(&mut self, name: T) -> Result<Option<Prefix<T>>, Error> {
         if name == self.our_name {
             return Err(Error::OwnNameDisallowed);
@@ -368,11 +432,11 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         })
     }
 
-    // Splits a group.
-    //
-    // If the group exists in the routing table, it is split, otherwise this function is a no-op.
-    // If one of the two new groups doesn't satisfy the invariant (i.e. only differs in one bit from
-    // our own prefix), it is removed and those contacts are returned.
+    /// Splits a group.
+    ///
+    /// If the group exists in the routing table, it is split, otherwise this function is a no-op.
+    /// If one of the two new groups doesn't satisfy the invariant (i.e. only differs in one bit
+    /// from our own prefix), it is removed and those contacts are returned.
     pub fn split(&mut self, mut prefix: Prefix<T>) -> Vec<T> {
         let mut result = vec![];
         if prefix == self.our_group_prefix {
@@ -400,11 +464,11 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         result
     }
 
-    // Removes a contact from the routing table.
-    //
-    // If no entry with that name is found, `Err(Error::NoSuchPeer)` is returned.  Otherwise, the
-    // entry is removed from the routing table and `RemovalDetails` is returned.  See that struct's
-    // docs for further info.
+    /// Removes a contact from the routing table.
+    ///
+    /// If no entry with that name is found, `Err(Error::NoSuchPeer)` is returned.  Otherwise, the
+    /// entry is removed from the routing table and `RemovalDetails` is returned.  See that struct's
+    /// docs for further info.
     pub fn remove(&mut self, name: &T) -> Result<RemovalDetails<T>, Error> {
         let mut should_merge = false;
         let mut removal_details = RemovalDetails {
@@ -443,11 +507,11 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         Ok(removal_details)
     }
 
-    // Merges our own group and all existing compatible groups into the new one defined by
-    // `merge_details.prefix`.
-    //
-    // The appropriate targets (all contacts which are not part of the merging groups) and the merge
-    // details they each need to receive (the new prefix and the new group) is returned.
+    /// Merges our own group and all existing compatible groups into the new one defined by
+    /// `merge_details.prefix`.
+    ///
+    /// The appropriate targets (all contacts which are not part of the merging groups) and the
+    /// merge details they each need to receive (the new prefix and the new group) is returned.
     pub fn merge_own_group(&mut self,
                            merge_details: &OwnMergeDetails<T>)
                            -> (Vec<T>, OtherMergeDetails<T>) {
@@ -479,16 +543,16 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
             group: unwrap!(self.groups.get(&merge_details.prefix)).clone(),
         };
         other_details.group.extend(needed.into_iter());
-        other_details.group.insert(self.our_name);
+        let _ = other_details.group.insert(self.our_name);
         (targets, other_details)
     }
 
-    // Merges all existing compatible groups into the new one defined by `merge_details.prefix`.
-    // Our own group is not included in the merge.
-    //
-    // The appropriate targets (all contacts from `merge_details.groups` which are not currently
-    // held in the routing table) are returned so the caller can establish connections to these
-    // peers and subsequently add them.
+    /// Merges all existing compatible groups into the new one defined by `merge_details.prefix`.
+    /// Our own group is not included in the merge.
+    ///
+    /// The appropriate targets (all contacts from `merge_details.groups` which are not currently
+    /// held in the routing table) are returned so the caller can establish connections to these
+    /// peers and subsequently add them.
     pub fn merge_other_group(&mut self, merge_details: &OtherMergeDetails<T>) -> HashSet<T> {
         self.merge(&merge_details.prefix);
 
@@ -499,25 +563,25 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
             .collect()
     }
 
-    // Returns a collection of nodes to which a message with the given `Destination` should be sent
-    // onwards.  In all non-error cases below, the returned collection will have the members of
-    // `exclude` removed, possibly resulting in an empty set being returned.
-    //
-    // * If the destination is a group:
-    //     - if our group is the closest on the network (i.e. our group's prefix is a prefix of the
-    //       destination), returns all other members of our group; otherwise
-    //     - if the closest group has more than `route` members, returns the `route`-th member of
-    //       this group; otherwise
-    //     - returns `Err(Error::CannotRoute)`
-    //
-    // * If the destination is an individual node:
-    //     - if our name *is* the destination, returns `Err(Error::OwnName)`; otherwise
-    //     - if the destination name is an entry in the routing table, returns it; otherwise
-    //     - if our group is the closest on the network (i.e. our group's prefix is a prefix of the
-    //       destination), this returns `Err(Error::NoSuchPeer)`; otherwise
-    //     - if the closest group has more than `route` members, returns the `route`-th member of
-    //       this group; otherwise
-    //     - returns `Err(Error::CannotRoute)`
+    /// Returns a collection of nodes to which a message with the given `Destination` should be sent
+    /// onwards.  In all non-error cases below, the returned collection will have the members of
+    /// `exclude` removed, possibly resulting in an empty set being returned.
+    ///
+    /// * If the destination is a group:
+    ///     - if our group is the closest on the network (i.e. our group's prefix is a prefix of the
+    ///       destination), returns all other members of our group; otherwise
+    ///     - if the closest group has more than `route` members, returns the `route`-th member of
+    ///       this group; otherwise
+    ///     - returns `Err(Error::CannotRoute)`
+    ///
+    /// * If the destination is an individual node:
+    ///     - if our name *is* the destination, returns `Err(Error::OwnName)`; otherwise
+    ///     - if the destination name is an entry in the routing table, returns it; otherwise
+    ///     - if our group is the closest on the network (i.e. our group's prefix is a prefix of the
+    ///       destination), this returns `Err(Error::NoSuchPeer)`; otherwise
+    ///     - if the closest group has more than `route` members, returns the `route`-th member of
+    ///       this group; otherwise
+    ///     - returns `Err(Error::CannotRoute)`
     pub fn targets(&self,
                    dst: &Destination<T>,
                    route: usize,
@@ -556,10 +620,10 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         }
     }
 
-    // Returns whether a `Destination` represents this node.
-    //
-    // Returns `true` if `dst` is a single node with name equal to `our_name`, or if `dst` is a
-    // group and the closest group is our group.
+    /// Returns whether a `Destination` represents this node.
+    ///
+    /// Returns `true` if `dst` is a single node with name equal to `our_name`, or if `dst` is a
+    /// group and the closest group is our group.
     pub fn is_recipient(&self, dst: &Destination<T>) -> bool {
         match *dst {
             Destination::Node(ref target_name) => *target_name == self.our_name,
@@ -567,14 +631,15 @@ impl<T: Binary + Clone + Copy + Debug + Default + Hash + Xorable> RoutingTable<T
         }
     }
 
-    // Returns true if our name is the `route`-th closest to `src_name` in our group.
-    //
-    // Used when sending a message from a group to decide which one of the group should send the
-    // full message (the remainder sending just a hash of the message).
-    pub fn should_route_full_message(&self, src_name: &T, route: usize) -> bool {
+    /// Returns true if our name is the `route`-th closest to `name` in our group.
+    ///
+    /// Used when sending a message from a group to decide which one of the group should send the
+    /// full message (the remainder sending just a hash of the message). Passing a name derived
+    /// from the message itself rotates that duty among the members.
+    pub fn should_route_full_message(&self, name: &T, route: usize) -> bool {
         let mut our_group = unwrap!(self.groups.get(&self.our_group_prefix)).iter().collect_vec();
         our_group.push(&self.our_name);
-        our_group.sort_by(|&lhs, &rhs| src_name.cmp_distance(lhs, rhs));
+        our_group.sort_by(|&lhs, &rhs| name.cmp_distance(lhs, rhs));
         match our_group.get(route) {
             Some(&name) => *name == self.our_name,
             None => false,
@@ -19,18 +19,11 @@
 
 use maidsafe_utilities::SeededRng;
 use rand::Rng;
-use std::cmp;
 use std::collections::{BTreeSet, HashMap, HashSet};
-use std::collections::hash_map::Entry;
-use std::fmt::{self, Binary, Debug, Formatter};
 use super::{Destination, Error, RoutingTable};
-use super::xorable::Xorable;
 
 const MIN_GROUP_SIZE: usize = 8;
 
-#[derive(Clone, Eq, PartialEq)]
-struct Contact(u64);
-
 /// A simulated network, consisting of a set of "nodes" (routing tables) and a random number
 /// generator.
 #[derive(Default)]
@@ -63,7 +56,7 @@ impl Network {
         for node in self.nodes.values_mut() {
             match node.add(name) {
                 Ok(result) => {
-                    split_prefix.insert(result);
+                    let _ = split_prefix.insert(result);
                 }
                 Err(e) => trace!("failed to add node with error {:?}", e),
             }
@@ -89,7 +82,6 @@ impl Network {
     fn drop_node(&mut self) {
         let keys = self.keys();
         let name = *unwrap!(self.rng.choose(&keys));
-        let contacts = self.known_nodes(name);
         let _ = self.nodes.remove(&name);
         // TODO: needs to verify how to broadcasting such info
         for node in self.nodes.values_mut() {
@@ -99,12 +91,9 @@ impl Network {
                 assert_eq!(name, removal_details.name);
                 assert_eq!(removed_node_is_in_our_group,
                            removal_details.was_in_our_group);
-                match removal_details.targets_and_merge_details {
-                    // TODO: shall a panic be raised in case of failure?
-                    None => {}
-                    Some((targets, own_merge_details)) => {
-                        let _ = node.merge_own_group(&own_merge_details);
-                    }
+                // TODO: shall a panic be raised in case of failure?
+                if let Some((_, own_merge_details)) = removal_details.targets_and_merge_details {
+                    let _ = node.merge_own_group(&own_merge_details);
                 }
             } else {
                 match node.remove(&name) {
@@ -133,7 +122,7 @@ impl Network {
         let mut handled = HashSet::new(); // These nodes have received and handled the message.
         received.push(src);
         while let Some(node) = received.pop() {
-            handled.insert(node); // `node` is now handling the message and relaying it.
+            let _ = handled.insert(node); // `node` is now handling the message and relaying it.
             if Destination::Node(node) != dst {
                 for target in unwrap!(self.nodes[&node].targets(&dst, route, &[])) {
                     if !handled.contains(&target) && !received.contains(&target) {
@@ -163,11 +152,6 @@ impl Network {
             .map(|(&peer, _)| peer))
     }
 
-    /// Returns the set of all entries in the given node's routing table.
-    fn known_nodes(&self, name: u64) -> HashSet<u64> {
-        self.nodes[&name].iter().cloned().collect()
-    }
-
     /// Returns all node names.
     fn keys(&self) -> Vec<u64> {
         self.nodes.keys().cloned().collect()
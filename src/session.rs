@@ -0,0 +1,418 @@
+// Copyright 2016 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement, version 1.1.  This, along with the
+// Licenses can be found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use clock::Clock;
+use crust::PeerId;
+use error::RoutingError;
+use id::{FullId, PublicId};
+use maidsafe_utilities::serialisation::{deserialise, serialise};
+use messages::Message;
+use rust_sodium::crypto::{box_, secretbox, sign};
+use rust_sodium::crypto::hash::sha256;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Formatter};
+use std::time::{Duration, Instant};
+
+/// The number of bytes after which a session's sending key is replaced.
+const REKEY_BYTES: u64 = 64 * 1024 * 1024;
+/// Time (in seconds) after which a session's sending key is replaced.
+const REKEY_INTERVAL_SECS: u64 = 60 * 60;
+
+/// An ephemeral public key for a new session, signed by the sender's signing key.
+///
+/// This is sent along with the `ClientIdentify`, `BootstrapIdentify` and `NodeIdentify` messages.
+#[derive(Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub struct SessionKey {
+    key: box_::PublicKey,
+    signature: sign::Signature,
+}
+
+impl SessionKey {
+    fn new(key: box_::PublicKey, full_id: &FullId) -> SessionKey {
+        SessionKey {
+            key: key,
+            signature: sign::sign_detached(&key.0, full_id.signing_private_key()),
+        }
+    }
+
+    fn verify(&self, public_id: &PublicId) -> bool {
+        sign::verify_detached(&self.signature, &self.key.0, public_id.signing_public_key())
+    }
+}
+
+impl Debug for SessionKey {
+    fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "SessionKey {{ .. }}")
+    }
+}
+
+/// The state of a connection whose session is not established yet.
+#[derive(Default)]
+struct PendingSession {
+    our_keys: Option<(box_::PublicKey, box_::SecretKey)>,
+    their_key: Option<box_::PublicKey>,
+}
+
+/// The symmetric keys of an established session with a peer.
+///
+/// Each direction uses its own key. They are derived from both peers' ephemeral keys and are
+/// replaced by their hash after a byte or time budget, so that past traffic can't be decrypted
+/// with a later key. The number of such replacements is the key's epoch, which is sent with every
+/// frame.
+///
+/// Every frame also carries a sequence number, which is incremented for each frame sent in that
+/// direction and used as the nonce. A frame whose sequence number isn't greater than that of the
+/// last one accepted is a replay and rejected.
+struct Session {
+    send_key: secretbox::Key,
+    send_epoch: u32,
+    send_seq: u64,
+    sent_bytes: u64,
+    rekeyed_at: Instant,
+    recv_key: secretbox::Key,
+    recv_epoch: u32,
+    /// The lowest sequence number we still accept from the peer.
+    next_recv_seq: u64,
+    /// Whether the peer has started to encrypt its messages. From then on, we reject unencrypted
+    /// ones.
+    peer_encrypts: bool,
+}
+
+impl Session {
+    fn new(our_keys: &(box_::PublicKey, box_::SecretKey),
+           their_key: &box_::PublicKey,
+           now: Instant)
+           -> Session {
+        let shared_key = box_::precompute(their_key, &our_keys.1);
+        Session {
+            send_key: derive_key(&shared_key, &our_keys.0),
+            send_epoch: 0,
+            send_seq: 0,
+            sent_bytes: 0,
+            rekeyed_at: now,
+            recv_key: derive_key(&shared_key, their_key),
+            recv_epoch: 0,
+            next_recv_seq: 0,
+            peer_encrypts: false,
+        }
+    }
+
+    fn encrypt(&mut self, bytes: &[u8], now: Instant) -> Result<Vec<u8>, RoutingError> {
+        if self.sent_bytes >= REKEY_BYTES ||
+           now - self.rekeyed_at >= Duration::from_secs(REKEY_INTERVAL_SECS) {
+            self.send_key = next_key(&self.send_key);
+            self.send_epoch += 1;
+            self.sent_bytes = 0;
+            self.rekeyed_at = now;
+        }
+        self.sent_bytes += bytes.len() as u64;
+        let seq = self.send_seq;
+        self.send_seq += 1;
+        Ok(try!(serialise(&Message::Encrypted {
+            epoch: self.send_epoch,
+            seq: seq,
+            ciphertext: secretbox::seal(bytes, &seq_nonce(seq), &self.send_key),
+        })))
+    }
+
+    fn decrypt(&mut self,
+               epoch: u32,
+               seq: u64,
+               ciphertext: &[u8])
+               -> Result<Vec<u8>, RoutingError> {
+        if seq < self.next_recv_seq {
+            return Err(RoutingError::ReplayedMessage);
+        }
+        // The peer only ever moves on to the next key, so we never need an older one.
+        let key = if epoch == self.recv_epoch {
+            self.recv_key.clone()
+        } else if epoch == self.recv_epoch + 1 {
+            next_key(&self.recv_key)
+        } else {
+            return Err(RoutingError::SymmetricDecryptionFailure);
+        };
+        let bytes = try!(secretbox::open(ciphertext, &seq_nonce(seq), &key)
+            .map_err(|()| RoutingError::SymmetricDecryptionFailure));
+        self.recv_key = key;
+        self.recv_epoch = epoch;
+        self.next_recv_seq = seq + 1;
+        self.peer_encrypts = true;
+        Ok(bytes)
+    }
+}
+
+/// The encrypted sessions with all our peers.
+///
+/// A session is established when both peers sent each other a `SessionKey`. From then on, all
+/// frames sent to that peer are encrypted and authenticated with the session's keys.
+pub struct Sessions {
+    clock: Clock,
+    enabled: bool,
+    pending: HashMap<PeerId, PendingSession>,
+    sessions: HashMap<PeerId, Session>,
+}
+
+impl Sessions {
+    /// Creates an empty session map. If `enabled` is `false`, we don't send session keys, so no
+    /// sessions are established and all traffic is unencrypted. Otherwise we require every peer to
+    /// establish a session. The rekeying interval is measured with `clock`.
+    pub fn new(enabled: bool, clock: Clock) -> Sessions {
+        Sessions {
+            clock: clock,
+            enabled: enabled,
+            pending: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Returns the session key to send to the given peer, or `None` if sessions are disabled or
+    /// one is already established. Once it is sent, `establish` must be called.
+    pub fn session_key(&mut self, peer_id: &PeerId, full_id: &FullId) -> Option<SessionKey> {
+        if !self.enabled || self.sessions.contains_key(peer_id) {
+            return None;
+        }
+        let pending = self.pending.entry(*peer_id).or_insert_with(PendingSession::default);
+        if pending.our_keys.is_none() {
+            pending.our_keys = Some(box_::gen_keypair());
+        }
+        pending.our_keys.as_ref().map(|&(ref key, _)| SessionKey::new(*key, full_id))
+    }
+
+    /// Records the session key we received from the given peer, and establishes the session if we
+    /// already sent ours.
+    ///
+    /// If sessions are enabled, encryption is required: unless we already have a session with the
+    /// peer, a missing key is an error, as it could have been stripped by a man-in-the-middle.
+    pub fn receive_session_key(&mut self,
+                               peer_id: &PeerId,
+                               public_id: &PublicId,
+                               session_key: Option<&SessionKey>)
+                               -> Result<(), RoutingError> {
+        if !self.enabled || self.sessions.contains_key(peer_id) {
+            return Ok(());
+        }
+        let session_key = match session_key {
+            Some(session_key) => session_key,
+            None => return Err(RoutingError::EncryptionRequired),
+        };
+        if !session_key.verify(public_id) {
+            return Err(RoutingError::FailedSignature);
+        }
+        self.pending.entry(*peer_id).or_insert_with(PendingSession::default).their_key =
+            Some(session_key.key);
+        self.establish(peer_id);
+        Ok(())
+    }
+
+    /// Establishes the session with the given peer if both session keys have been exchanged.
+    pub fn establish(&mut self, peer_id: &PeerId) {
+        let session = match self.pending.get(peer_id) {
+            Some(&PendingSession { our_keys: Some(ref our_keys),
+                                   their_key: Some(ref their_key) }) => {
+                Session::new(our_keys, their_key, self.clock.now())
+            }
+            _ => return,
+        };
+        let _ = self.pending.remove(peer_id);
+        let _ = self.sessions.insert(*peer_id, session);
+    }
+
+    /// Removes any session with the given peer, e.g. because it disconnected.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        let _ = self.pending.remove(peer_id);
+        let _ = self.sessions.remove(peer_id);
+    }
+
+    /// Encrypts the serialised message for the given peer, if we have a session with it.
+    pub fn encrypt(&mut self, peer_id: &PeerId, bytes: Vec<u8>) -> Result<Vec<u8>, RoutingError> {
+        match self.sessions.get_mut(peer_id) {
+            Some(session) => session.encrypt(&bytes, self.clock.now()),
+            None => Ok(bytes),
+        }
+    }
+
+    /// Decrypts a frame received from the given peer, if we have a session with it, and returns
+    /// the serialised message.
+    ///
+    /// If sessions are enabled, the only unencrypted frames accepted are handshake messages, and
+    /// only until the peer has started encrypting.
+    pub fn decrypt(&mut self, peer_id: &PeerId, bytes: Vec<u8>) -> Result<Vec<u8>, RoutingError> {
+        if !self.enabled {
+            return Ok(bytes);
+        }
+        let is_handshake = match deserialise(&bytes) {
+            Ok(Message::Encrypted { epoch, seq, ciphertext }) => {
+                return match self.sessions.get_mut(peer_id) {
+                    Some(session) => session.decrypt(epoch, seq, &ciphertext),
+                    None => Err(RoutingError::SymmetricDecryptionFailure),
+                };
+            }
+            Ok(Message::Direct(ref direct_msg)) => direct_msg.is_handshake(),
+            Ok(_) => false,
+            Err(error) => return Err(RoutingError::SerialisationError(error)),
+        };
+        let peer_encrypts = self.sessions
+            .get(peer_id)
+            .map_or(false, |session| session.peer_encrypts);
+        if is_handshake && !peer_encrypts {
+            Ok(bytes)
+        } else {
+            Err(RoutingError::EncryptionRequired)
+        }
+    }
+}
+
+/// Derives the key for the direction in which the owner of `sender_key` sends.
+fn derive_key(shared_key: &box_::PrecomputedKey, sender_key: &box_::PublicKey) -> secretbox::Key {
+    let mut bytes = shared_key.0.to_vec();
+    bytes.extend_from_slice(&sender_key.0);
+    secretbox::Key(sha256::hash(&bytes).0)
+}
+
+fn next_key(key: &secretbox::Key) -> secretbox::Key {
+    secretbox::Key(sha256::hash(&key.0).0)
+}
+
+/// Returns the nonce for the frame with the given sequence number. Each direction has its own key,
+/// so a nonce is never reused with the same key.
+fn seq_nonce(seq: u64) -> secretbox::Nonce {
+    let mut nonce = [0; secretbox::NONCEBYTES];
+    for (i, byte) in nonce.iter_mut().take(8).enumerate() {
+        *byte = (seq >> (8 * i)) as u8;
+    }
+    secretbox::Nonce(nonce)
+}
+
+#[cfg(all(test, feature = "use-mock-crust"))]
+mod tests {
+    use id::FullId;
+    use maidsafe_utilities::serialisation::{deserialise, serialise};
+    use messages::{DirectMessage, Message};
+    use clock::Clock;
+    use error::RoutingError;
+    use mock_crust::crust::PeerId;
+    use super::*;
+
+    fn serialised_message() -> Vec<u8> {
+        unwrap!(serialise(&Message::Direct(DirectMessage::BootstrapChallenge(0))))
+    }
+
+    // Exchanges session keys between two peers and returns their session maps.
+    fn established() -> (Sessions, Sessions) {
+        let (id_a, id_b) = (FullId::new(), FullId::new());
+        let mut sessions_a = Sessions::new(true, Clock::default());
+        let mut sessions_b = Sessions::new(true, Clock::default());
+        let key_a = unwrap!(sessions_a.session_key(&PeerId(1), &id_a));
+        sessions_a.establish(&PeerId(1));
+        unwrap!(sessions_b.receive_session_key(&PeerId(0), id_a.public_id(), Some(&key_a)));
+        let key_b = unwrap!(sessions_b.session_key(&PeerId(0), &id_b));
+        sessions_b.establish(&PeerId(0));
+        unwrap!(sessions_a.receive_session_key(&PeerId(1), id_b.public_id(), Some(&key_b)));
+        (sessions_a, sessions_b)
+    }
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let (mut sessions_a, mut sessions_b) = established();
+        let bytes = unwrap!(sessions_a.encrypt(&PeerId(1), serialised_message()));
+        match unwrap!(deserialise(&bytes)) {
+            Message::Encrypted { .. } => (),
+            message => panic!("Unexpected message {:?}", message),
+        }
+        assert_eq!(serialised_message(), unwrap!(sessions_b.decrypt(&PeerId(0), bytes)));
+
+        // Now that the peer encrypts, unencrypted messages are rejected.
+        assert!(sessions_b.decrypt(&PeerId(0), serialised_message()).is_err());
+        // Frames encrypted for the other direction are rejected.
+        let bytes = unwrap!(sessions_a.encrypt(&PeerId(1), serialised_message()));
+        assert!(sessions_a.decrypt(&PeerId(1), bytes).is_err());
+    }
+
+    #[test]
+    fn rekeying() {
+        let (mut sessions_a, mut sessions_b) = established();
+        unwrap!(sessions_a.sessions.get_mut(&PeerId(1))).sent_bytes = REKEY_BYTES;
+        let bytes = unwrap!(sessions_a.encrypt(&PeerId(1), serialised_message()));
+        assert_eq!(1, unwrap!(sessions_a.sessions.get(&PeerId(1))).send_epoch);
+        assert_eq!(serialised_message(), unwrap!(sessions_b.decrypt(&PeerId(0), bytes)));
+        assert_eq!(1, unwrap!(sessions_b.sessions.get(&PeerId(0))).recv_epoch);
+    }
+
+    #[test]
+    fn replayed_frames_rejected() {
+        let (mut sessions_a, mut sessions_b) = established();
+        let first = unwrap!(sessions_a.encrypt(&PeerId(1), serialised_message()));
+        let second = unwrap!(sessions_a.encrypt(&PeerId(1), serialised_message()));
+        assert_eq!(serialised_message(),
+                   unwrap!(sessions_b.decrypt(&PeerId(0), first.clone())));
+        match sessions_b.decrypt(&PeerId(0), first.clone()) {
+            Err(RoutingError::ReplayedMessage) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Once a later frame has been accepted, earlier ones are rejected, too.
+        let third = unwrap!(sessions_a.encrypt(&PeerId(1), serialised_message()));
+        assert_eq!(serialised_message(), unwrap!(sessions_b.decrypt(&PeerId(0), third)));
+        assert!(sessions_b.decrypt(&PeerId(0), second).is_err());
+
+        // The sequence number is authenticated, so a replay can't be disguised as a new frame.
+        let (epoch, ciphertext) = match unwrap!(deserialise(&first)) {
+            Message::Encrypted { epoch, ciphertext, .. } => (epoch, ciphertext),
+            message => panic!("Unexpected message {:?}", message),
+        };
+        let forged = unwrap!(serialise(&Message::Encrypted {
+            epoch: epoch,
+            seq: 3,
+            ciphertext: ciphertext,
+        }));
+        match sessions_b.decrypt(&PeerId(0), forged) {
+            Err(RoutingError::SymmetricDecryptionFailure) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn forged_session_key() {
+        let mut sessions = Sessions::new(true, Clock::default());
+        let key = unwrap!(sessions.session_key(&PeerId(0), &FullId::new()));
+        let public_id = *FullId::new().public_id();
+        assert!(sessions.receive_session_key(&PeerId(0), &public_id, Some(&key)).is_err());
+    }
+
+    #[test]
+    fn downgrade_rejected() {
+        // A peer that doesn't send a session key is rejected.
+        let mut sessions = Sessions::new(true, Clock::default());
+        let public_id = *FullId::new().public_id();
+        match sessions.receive_session_key(&PeerId(0), &public_id, None) {
+            Err(RoutingError::EncryptionRequired) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+
+        // Before the session is established, only handshake messages are accepted unencrypted.
+        assert_eq!(serialised_message(),
+                   unwrap!(sessions.decrypt(&PeerId(0), serialised_message())));
+        let leave = unwrap!(serialise(&Message::Direct(DirectMessage::Leave)));
+        assert!(sessions.decrypt(&PeerId(0), leave).is_err());
+
+        // Without encryption, everything is accepted.
+        let mut sessions = Sessions::new(false, Clock::default());
+        unwrap!(sessions.receive_session_key(&PeerId(0), &public_id, None));
+        let leave = unwrap!(serialise(&Message::Direct(DirectMessage::Leave)));
+        assert!(sessions.decrypt(&PeerId(0), leave).is_ok());
+    }
+}
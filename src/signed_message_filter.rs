@@ -15,8 +15,8 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use clock::Clock;
 use crust::PeerId;
-use lru_time_cache::LruCache;
 use maidsafe_utilities;
 
 use message_filter::MessageFilter;
@@ -29,17 +29,17 @@ const OUTGOING_EXPIRY_DURATION_SECS: u64 = 60 * 10;
 // Structure to filter (throttle) incoming and outgoing signed messages.
 pub struct SignedMessageFilter {
     incoming: MessageFilter<SignedMessage>,
-    outgoing: LruCache<(u64, PeerId, u8), ()>,
+    outgoing: MessageFilter<(u64, PeerId, u8)>,
 }
 
 impl SignedMessageFilter {
-    pub fn new() -> Self {
+    pub fn new(clock: Clock) -> Self {
         let incoming_duration = Duration::from_secs(INCOMING_EXPIRY_DURATION_SECS);
         let outgoing_duration = Duration::from_secs(OUTGOING_EXPIRY_DURATION_SECS);
 
         SignedMessageFilter {
-            incoming: MessageFilter::with_expiry_duration(incoming_duration),
-            outgoing: LruCache::with_expiry_duration(outgoing_duration),
+            incoming: MessageFilter::with_expiry_duration(incoming_duration, clock.clone()),
+            outgoing: MessageFilter::with_expiry_duration(outgoing_duration, clock),
         }
     }
 
@@ -53,7 +53,7 @@ impl SignedMessageFilter {
     // been seen recently (and thus should not be sent, due to deduplication).
     pub fn filter_outgoing(&mut self, msg: &SignedMessage, peer_id: &PeerId, route: u8) -> bool {
         let hash = maidsafe_utilities::big_endian_sip_hash(msg);
-        self.outgoing.insert((hash, *peer_id, route), ()).is_some()
+        self.outgoing.insert(&(hash, *peer_id, route)) > 1
     }
 
     #[cfg(feature = "use-mock-crust")]
@@ -16,8 +16,13 @@
 // relating to use of the SAFE Network Software.
 
 use action::Action;
+use clock::Clock;
 use crust::{CrustEventSender, PeerId, Service};
 use crust::Event as CrustEvent;
+#[cfg(any(test, not(feature = "use-mock-crust")))]
+use error::RoutingError;
+#[cfg(any(test, not(feature = "use-mock-crust")))]
+use event_loop::Waker;
 use id::PublicId;
 use maidsafe_utilities::event_sender::MaidSafeEventCategory;
 #[cfg(feature = "use-mock-crust")]
@@ -25,6 +30,10 @@ use routing_table::RoutingTable;
 use states::{Bootstrapping, Client, Node};
 use std::mem;
 use std::sync::mpsc::{self, Receiver};
+#[cfg(any(test, not(feature = "use-mock-crust")))]
+use std::thread;
+#[cfg(any(test, not(feature = "use-mock-crust")))]
+use std::time::Duration;
 use timer::Timer;
 use types::RoutingActionSender;
 #[cfg(feature = "use-mock-crust")]
@@ -168,6 +177,33 @@ impl State {
             State::Terminated => (),
         }
     }
+
+    pub fn is_partitioned(&self) -> bool {
+        match *self {
+            State::Node(ref state) => state.is_partitioned(),
+            State::Bootstrapping(_) |
+            State::Client(_) |
+            State::Terminated => false,
+        }
+    }
+
+    pub fn retry_lost_contacts(&mut self) {
+        match *self {
+            State::Node(ref mut state) => state.retry_lost_contacts(),
+            State::Bootstrapping(_) |
+            State::Client(_) |
+            State::Terminated => (),
+        }
+    }
+
+    pub fn rotate_keys(&mut self) {
+        match *self {
+            State::Node(ref mut state) => state.rotate_keys_now(),
+            State::Bootstrapping(_) |
+            State::Client(_) |
+            State::Terminated => (),
+        }
+    }
 }
 
 pub enum Transition {
@@ -187,7 +223,7 @@ impl StateMachine {
     // Construct a new StateMachine by passing a function returning the initial
     // state.
     pub fn new<F>(init_state: F) -> (RoutingActionSender, Self)
-        where F: FnOnce(Service, Timer) -> State
+        where F: FnOnce(Service, Timer, Clock) -> State
     {
         let (category_tx, category_rx) = mpsc::channel();
         let (crust_tx, crust_rx) = mpsc::channel();
@@ -206,9 +242,10 @@ impl StateMachine {
         };
         crust_service.start_service_discovery();
 
-        let timer = Timer::new(action_sender.clone());
+        let clock = Clock::new();
+        let timer = Timer::new(action_sender.clone(), &clock);
 
-        let state = init_state(crust_service, timer);
+        let state = init_state(crust_service, timer, clock);
         let is_running = match state {
             State::Terminated => false,
             _ => true,
@@ -225,6 +262,18 @@ impl StateMachine {
         (action_sender, machine)
     }
 
+    /// If there is an event in the queue, processes it and returns true.
+    /// otherwise returns false. Never blocks.
+    pub fn poll(&mut self) -> bool {
+        match self.category_rx.try_recv() {
+            Ok(category) => {
+                self.handle_event(category);
+                true
+            }
+            _ => false,
+        }
+    }
+
     fn handle_event(&mut self, category: MaidSafeEventCategory) {
         let transition = match category {
             MaidSafeEventCategory::Routing => {
@@ -242,7 +291,10 @@ impl StateMachine {
                 }
             }
         };
+        self.apply_transition(transition);
+    }
 
+    fn apply_transition(&mut self, transition: Transition) {
         match transition {
             Transition::Stay => (),
             Transition::IntoBootstrapped { proxy_peer_id, proxy_public_id, quorum_size } => {
@@ -282,12 +334,48 @@ impl StateMachine {
     }
 }
 
-#[cfg(feature = "use-mock-crust")]
+#[cfg(any(test, not(feature = "use-mock-crust")))]
 impl StateMachine {
-    /// If there is an event in the queue, processes it and returns true.
-    /// otherwise returns false. Never blocks.
-    pub fn poll(&mut self) -> bool {
-        match self.category_rx.try_recv() {
+    /// Handles an action from the application directly, without going through the action
+    /// channel. Returns `false` if the state machine has already terminated, so the action was
+    /// dropped.
+    pub fn handle_action(&mut self, action: Action) -> bool {
+        if !self.is_running {
+            return false;
+        }
+        let transition = self.state.handle_action(action);
+        self.apply_transition(transition);
+        true
+    }
+
+    /// Returns `false` once the state machine has terminated and won't handle any more events.
+    pub fn is_running(&self) -> bool {
+        self.is_running
+    }
+
+    /// Prepares the state machine to be driven by the application's event loop: from now on,
+    /// `waker` is called whenever an event is queued, so that the application can `poll`.
+    ///
+    /// Crust and the timer queue their events from their own threads, through plain channels
+    /// that can't notify anyone. So a small thread is started that waits for them, forwards them
+    /// and calls `waker`. It exits once the state machine is dropped.
+    pub fn set_waker(&mut self, waker: Box<Waker>) -> Result<(), RoutingError> {
+        let (category_tx, category_rx) = mpsc::channel();
+        let event_rx = mem::replace(&mut self.category_rx, category_rx);
+        let _ = try!(thread::Builder::new().name("Routing waker".to_owned()).spawn(move || {
+            for category in event_rx.iter() {
+                if category_tx.send(category).is_err() {
+                    break;
+                }
+                waker.wake();
+            }
+        }));
+        Ok(())
+    }
+
+    /// Like `poll`, but if the queue is empty, waits up to `timeout` for an event.
+    pub fn poll_timeout(&mut self, timeout: Duration) -> bool {
+        match self.category_rx.recv_timeout(timeout) {
             Ok(category) => {
                 self.handle_event(category);
                 true
@@ -295,7 +383,10 @@ impl StateMachine {
             _ => false,
         }
     }
+}
 
+#[cfg(feature = "use-mock-crust")]
+impl StateMachine {
     /// Get reference to the current state.
     pub fn current(&self) -> &State {
         &self.state
@@ -16,26 +16,34 @@
 // relating to use of the SAFE Network Software.
 
 use action::Action;
+use ban_list::BanList;
 use cache::Cache;
+use clock::Clock;
 use crust::{PeerId, Service};
 use crust::Event as CrustEvent;
 use error::RoutingError;
 use event::Event;
 use id::{FullId, PublicId};
 use maidsafe_utilities::serialisation;
-use messages::{DirectMessage, Message};
+use messages::{BootstrapDenyReason, ContactAddr, DirectMessage, Message,
+               client_identify_signed_bytes};
+use peer_manager::ProxyLimits;
+use replay_filter::ReplayFilter;
 use rust_sodium::crypto::hash::sha256;
 use rust_sodium::crypto::sign;
+use session::{SessionKey, Sessions};
 use state_machine::Transition;
 use stats::Stats;
 use std::collections::HashSet;
 use std::fmt::{self, Debug, Formatter};
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::mpsc::Sender;
 use std::time::Duration;
 use super::{Client, Node};
 use super::common::Base;
 use timer::Timer;
+use tunnels::TunnelLimits;
 use xor_name::XorName;
 
 // Time (in seconds) after which bootstrap is cancelled (and possibly retried).
@@ -43,43 +51,73 @@ const BOOTSTRAP_TIMEOUT_SECS: u64 = 20;
 
 // State of Client or Node while bootstrapping.
 pub struct Bootstrapping {
+    ban_list: BanList,
     bootstrap_blacklist: HashSet<SocketAddr>,
     bootstrap_connection: Option<(PeerId, u64)>,
     cache: Box<Cache>,
     client_restriction: bool,
+    clock: Clock,
     crust_service: Service,
     event_sender: Sender<Event>,
     full_id: FullId,
+    proxy_limits: ProxyLimits,
+    /// Contacts a node that denied us suggested, to try before crust's bootstrap cache. Only mock
+    /// Crust can bootstrap against given contacts, so otherwise they are ignored.
+    redirect_contacts: Vec<SocketAddr>,
+    /// Whether the current bootstrap attempt is against the redirect contacts.
+    redirected: bool,
+    replay_filter: ReplayFilter,
+    resource_proof_difficulty: u8,
+    sessions: Sessions,
     stats: Stats,
     timer: Timer,
+    tunnel_limits: TunnelLimits,
 }
 
 impl Bootstrapping {
-    pub fn new(cache: Box<Cache>,
+    #[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+    pub fn new(ban_list: BanList,
+               cache: Box<Cache>,
                client_restriction: bool,
+               clock: Clock,
                mut crust_service: Service,
                event_sender: Sender<Event>,
                full_id: FullId,
-               timer: Timer)
+               proxy_limits: ProxyLimits,
+               replay_filter: ReplayFilter,
+               resource_proof_difficulty: u8,
+               sessions: Sessions,
+               timer: Timer,
+               tunnel_limits: TunnelLimits)
                -> Self {
         let _ = crust_service.start_bootstrap(HashSet::new());
 
         Bootstrapping {
+            ban_list: ban_list,
             bootstrap_blacklist: HashSet::new(),
             bootstrap_connection: None,
             cache: cache,
             client_restriction: client_restriction,
+            clock: clock,
             crust_service: crust_service,
             event_sender: event_sender,
             full_id: full_id,
+            proxy_limits: proxy_limits,
+            redirect_contacts: vec![],
+            redirected: false,
+            replay_filter: replay_filter,
+            resource_proof_difficulty: resource_proof_difficulty,
+            sessions: sessions,
             stats: Default::default(),
             timer: timer,
+            tunnel_limits: tunnel_limits,
         }
     }
 
     pub fn handle_action(&mut self, action: Action) -> Transition {
         match action {
             Action::ClientSendRequest { ref result_tx, .. } |
+            Action::ClientSendMpid { ref result_tx, .. } |
             Action::NodeSendMessage { ref result_tx, .. } => {
                 warn!("{:?} - Cannot handle {:?} - not bootstrapped", self, action);
                 // TODO: return Err here eventually. Returning Ok for now to
@@ -89,6 +127,25 @@ impl Bootstrapping {
             Action::Name { result_tx } => {
                 let _ = result_tx.send(*self.name());
             }
+            Action::Ban { key, result_tx } => {
+                self.ban_list.ban(key);
+                let _ = result_tx.send(Ok(()));
+            }
+            Action::Unban { key, result_tx } => {
+                let _ = result_tx.send(Ok(self.ban_list.unban(&key)));
+            }
+            Action::BannedKeys { result_tx } => {
+                let _ = result_tx.send(self.ban_list.banned_keys());
+            }
+            Action::ReplayFilter { result_tx } => {
+                let _ = result_tx.send(self.replay_filter.clone());
+            }
+            Action::Shutdown { result_tx, .. } => {
+                // We have no peers yet who need to be told that we are leaving.
+                let _ = result_tx.send(Ok(()));
+                self.send_event(Event::Terminate);
+                return Transition::Terminate;
+            }
             Action::Timeout(token) => self.handle_timeout(token),
             Action::Terminate => {
                 return Transition::Terminate;
@@ -102,6 +159,10 @@ impl Bootstrapping {
             Action::QuorumSize { result_tx } => {
                 let _ = result_tx.send(0);
             }
+            Action::MpidInbox { result_tx } |
+            Action::MpidOutbox { result_tx } => {
+                let _ = result_tx.send(vec![]);
+            }
         }
 
         Transition::Stay
@@ -134,12 +195,14 @@ impl Bootstrapping {
                        proxy_public_id: PublicId,
                        quorum_size: usize)
                        -> Client {
-        Client::from_bootstrapping(self.crust_service,
+        Client::from_bootstrapping(self.clock,
+                                   self.crust_service,
                                    self.event_sender,
                                    self.full_id,
                                    proxy_peer_id,
                                    proxy_public_id,
                                    quorum_size,
+                                   self.sessions,
                                    self.stats,
                                    self.timer)
     }
@@ -149,15 +212,22 @@ impl Bootstrapping {
                      proxy_public_id: PublicId,
                      quorum_size: usize)
                      -> Option<Node> {
-        Node::from_bootstrapping(self.cache,
+        Node::from_bootstrapping(self.ban_list,
+                                 self.cache,
+                                 self.clock,
                                  self.crust_service,
                                  self.event_sender,
                                  self.full_id,
+                                 self.proxy_limits,
                                  proxy_peer_id,
                                  proxy_public_id,
                                  quorum_size,
+                                 self.replay_filter,
+                                 self.resource_proof_difficulty,
+                                 self.sessions,
                                  self.stats,
-                                 self.timer)
+                                 self.timer,
+                                 self.tunnel_limits)
     }
 
     pub fn client_restriction(&self) -> bool {
@@ -180,8 +250,9 @@ impl Bootstrapping {
         match self.bootstrap_connection {
             None => {
                 debug!("{:?} Received BootstrapConnect from {:?}.", self, peer_id);
-                // Established connection. Pending Validity checks
-                let _ = self.send_client_identify(peer_id);
+                // Established connection. Wait for the challenge to send `ClientIdentify`.
+                let token = self.timer.schedule(Duration::from_secs(BOOTSTRAP_TIMEOUT_SECS));
+                self.bootstrap_connection = Some((peer_id, token));
                 let _ = self.bootstrap_blacklist.insert(socket_addr);
             }
             Some((bootstrap_id, _)) if bootstrap_id == peer_id => {
@@ -198,6 +269,13 @@ impl Bootstrapping {
     }
 
     fn handle_bootstrap_failed(&mut self) -> Transition {
+        if self.redirected {
+            debug!("{:?} Failed to bootstrap against redirect contacts. Using crust's contacts.",
+                   self);
+            self.redirected = false;
+            let _ = self.crust_service.start_bootstrap(self.bootstrap_blacklist.clone());
+            return Transition::Stay;
+        }
         debug!("{:?} Failed to bootstrap.", self);
         self.send_event(Event::Terminate);
         Transition::Terminate
@@ -207,6 +285,7 @@ impl Bootstrapping {
                           peer_id: PeerId,
                           bytes: Vec<u8>)
                           -> Result<Transition, RoutingError> {
+        let bytes = try!(self.sessions.decrypt(&peer_id, bytes));
         match serialisation::deserialise(&bytes) {
             Ok(Message::Direct(direct_msg)) => Ok(self.handle_direct_message(direct_msg, peer_id)),
             Ok(message) => {
@@ -222,10 +301,28 @@ impl Bootstrapping {
                              peer_id: PeerId)
                              -> Transition {
         match direct_message {
-            DirectMessage::BootstrapIdentify { public_id, current_quorum_size } => {
-                self.handle_bootstrap_identify(public_id, peer_id, current_quorum_size)
+            DirectMessage::BootstrapIdentify { public_id, current_quorum_size, session_key } => {
+                self.handle_bootstrap_identify(public_id, peer_id, current_quorum_size, session_key)
+            }
+            DirectMessage::BootstrapDeny { reason, contacts } => {
+                self.handle_bootstrap_deny(peer_id, reason, contacts)
+            }
+            DirectMessage::BootstrapChallenge(challenge) => {
+                match self.bootstrap_connection {
+                    Some((bootstrap_id, _)) if bootstrap_id == peer_id => {
+                        if let Err(error) = self.send_client_identify(peer_id, challenge) {
+                            warn!("{:?} Failed to send ClientIdentify: {:?}", self, error);
+                            self.rebootstrap();
+                        }
+                    }
+                    _ => {
+                        debug!("{:?} - Unexpected BootstrapChallenge from {:?}.",
+                               self,
+                               peer_id)
+                    }
+                }
+                Transition::Stay
             }
-            DirectMessage::BootstrapDeny => self.handle_bootstrap_deny(),
             _ => {
                 debug!("{:?} - Unhandled direct message: {:?}",
                        self,
@@ -238,7 +335,8 @@ impl Bootstrapping {
     fn handle_bootstrap_identify(&mut self,
                                  public_id: PublicId,
                                  peer_id: PeerId,
-                                 current_quorum_size: usize)
+                                 current_quorum_size: usize,
+                                 session_key: Option<SessionKey>)
                                  -> Transition {
         if *public_id.name() == XorName(sha256::hash(&public_id.signing_public_key().0).0) {
             warn!("{:?} Incoming Connection not validated as a proper node - dropping",
@@ -247,6 +345,15 @@ impl Bootstrapping {
             return Transition::Stay;
         }
 
+        if let Err(error) = self.sessions
+            .receive_session_key(&peer_id, &public_id, session_key.as_ref()) {
+            warn!("{:?} Invalid session key from bootstrap node: {:?} - dropping",
+                  self,
+                  error);
+            self.rebootstrap();
+            return Transition::Stay;
+        }
+
         Transition::IntoBootstrapped {
             proxy_peer_id: peer_id,
             proxy_public_id: public_id,
@@ -254,32 +361,57 @@ impl Bootstrapping {
         }
     }
 
-    fn handle_bootstrap_deny(&mut self) -> Transition {
-        info!("{:?} Connection failed: Proxy node needs a larger routing table to accept \
-               clients.",
-              self);
+    fn handle_bootstrap_deny(&mut self,
+                             peer_id: PeerId,
+                             reason: BootstrapDenyReason,
+                             contacts: Vec<ContactAddr>)
+                             -> Transition {
+        match self.bootstrap_connection {
+            Some((bootstrap_id, _)) if bootstrap_id == peer_id => (),
+            _ => {
+                debug!("{:?} - Unexpected BootstrapDeny from {:?}.", self, peer_id);
+                return Transition::Stay;
+            }
+        }
+        info!("{:?} Connection failed: Proxy node denied us: {:?}. Trying {} suggested contacts.",
+              self,
+              reason,
+              contacts.len());
+        self.send_event(Event::BootstrapDenied(reason));
+        let redirect_contacts = contacts.into_iter()
+            .map(|contact| contact.0)
+            .filter(|addr| !self.bootstrap_blacklist.contains(addr))
+            .collect();
+        self.redirect_contacts = redirect_contacts;
         self.rebootstrap();
         Transition::Stay
     }
 
-    fn send_client_identify(&mut self, peer_id: PeerId) -> Result<(), RoutingError> {
+    fn send_client_identify(&mut self,
+                            peer_id: PeerId,
+                            challenge: u64)
+                            -> Result<(), RoutingError> {
         debug!("{:?} - Sending ClientIdentify to {:?}.", self, peer_id);
 
-        let token = self.timer.schedule(Duration::from_secs(BOOTSTRAP_TIMEOUT_SECS));
-        self.bootstrap_connection = Some((peer_id, token));
-
         let serialised_public_id = try!(serialisation::serialise(self.full_id.public_id()));
-        let signature = sign::sign_detached(&serialised_public_id,
-                                            self.full_id.signing_private_key());
+        let session_key = self.sessions.session_key(&peer_id, &self.full_id);
+        let signed_bytes = try!(client_identify_signed_bytes(&serialised_public_id,
+                                                             challenge,
+                                                             &self.crust_service.id(),
+                                                             &session_key));
+        let signature = sign::sign_detached(&signed_bytes, self.full_id.signing_private_key());
 
         let direct_message = DirectMessage::ClientIdentify {
             serialised_public_id: serialised_public_id,
             signature: signature,
             client_restriction: self.client_restriction,
+            session_key: session_key,
         };
 
         self.stats().count_direct_message(&direct_message);
-        self.send_message(&peer_id, Message::Direct(direct_message))
+        try!(self.send_message(&peer_id, Message::Direct(direct_message)));
+        self.sessions.establish(&peer_id);
+        Ok(())
     }
 
     fn disconnect_peer(&mut self, peer_id: &PeerId) {
@@ -290,14 +422,36 @@ impl Bootstrapping {
     }
 
     fn rebootstrap(&mut self) {
-        if let Some((bootstrap_id, _)) = self.bootstrap_connection.take() {
+        if let Some((bootstrap_id, token)) = self.bootstrap_connection.take() {
             debug!("{:?} Dropping bootstrap node {:?} and retrying.",
                    self,
                    bootstrap_id);
+            self.timer.cancel(token);
             self.crust_service.disconnect(bootstrap_id);
-            let _ = self.crust_service.start_bootstrap(self.bootstrap_blacklist.clone());
+            self.sessions.remove(&bootstrap_id);
+            let contacts = mem::replace(&mut self.redirect_contacts, vec![]);
+            self.start_bootstrap(contacts);
+        }
+    }
+
+    // Bootstraps against the given contacts, or against Crust's own ones if there are none.
+    #[cfg(feature = "use-mock-crust")]
+    fn start_bootstrap(&mut self, contacts: Vec<SocketAddr>) {
+        let blacklist = self.bootstrap_blacklist.clone();
+        self.redirected = !contacts.is_empty();
+        if self.redirected {
+            let _ = self.crust_service.start_bootstrap_with_contacts(contacts, blacklist);
+        } else {
+            let _ = self.crust_service.start_bootstrap(blacklist);
         }
     }
+
+    // Crust can't bootstrap against given contacts, so this always uses its own ones. As the nodes
+    // that denied us are blacklisted, we still try a different one.
+    #[cfg(not(feature = "use-mock-crust"))]
+    fn start_bootstrap(&mut self, _contacts: Vec<SocketAddr>) {
+        let _ = self.crust_service.start_bootstrap(self.bootstrap_blacklist.clone());
+    }
 }
 
 impl Base for Bootstrapping {
@@ -316,6 +470,10 @@ impl Base for Bootstrapping {
     fn stats(&mut self) -> &mut Stats {
         &mut self.stats
     }
+
+    fn sessions(&mut self) -> &mut Sessions {
+        &mut self.sessions
+    }
 }
 
 impl Debug for Bootstrapping {
@@ -18,33 +18,52 @@
 use ack_manager::{Ack, AckManager};
 use action::Action;
 use authority::Authority;
+use clock::Clock;
 use crust::{PeerId, Service};
 use crust::Event as CrustEvent;
 use error::{InterfaceError, RoutingError};
 use event::Event;
+use group_proof::GroupProof;
 use id::{FullId, PublicId};
 use maidsafe_utilities::serialisation;
-use message_accumulator::MessageAccumulator;
-use messages::{HopMessage, Message, MessageContent, RoutingMessage, SignedMessage, UserMessage,
-               UserMessageCache};
-use peer_manager::MIN_GROUP_SIZE;
+use message_accumulator::{CONTENT_REQUEST_DELAY_SECS, MessageAccumulator};
+use messages::{DEFAULT_PRIORITY, DirectMessage, HopMessage, Message, MessageContent, Request,
+               RoutingMessage, SignedMessage, UserMessage, UserMessageCache};
+use messaging::{MpidHeader, MpidMessageWrapper};
+use peer_manager::{KEY_ROTATION_GRACE_PERIOD_SECS, MIN_GROUP_SIZE};
+use replay_filter::ReplayFilter;
+use rust_sodium::crypto::sign;
+use session::Sessions;
 use signed_message_filter::SignedMessageFilter;
 use state_machine::Transition;
 use stats::Stats;
+use std::collections::HashMap;
 use std::fmt::{self, Debug, Formatter};
 use std::sync::mpsc::Sender;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use super::common::{Base, Bootstrapped, USER_MSG_CACHE_EXPIRY_DURATION_SECS};
 use timer::Timer;
+use types::MessageId;
+use xor_name::XorName;
 
 pub struct Client {
     ack_mgr: AckManager,
+    clock: Clock,
+    content_request_timer_token: Option<u64>,
     crust_service: Service,
     event_sender: Sender<Event>,
     full_id: FullId,
+    /// The headers of the MPID messages we were notified about and haven't deleted, by name.
+    mpid_inbox: HashMap<XorName, MpidHeader>,
+    /// The headers of the MPID messages we sent, as far as we know not deleted yet, by name.
+    mpid_outbox: HashMap<XorName, MpidHeader>,
     msg_accumulator: MessageAccumulator,
     proxy_peer_id: PeerId,
     proxy_public_id: PublicId,
+    replay_filter: ReplayFilter,
+    /// The proxy's signing key before it last rotated its keys, and when it did so.
+    retired_proxy_key: Option<(sign::PublicKey, Instant)>,
+    sessions: Sessions,
     signed_msg_filter: SignedMessageFilter,
     stats: Stats,
     timer: Timer,
@@ -53,27 +72,36 @@ pub struct Client {
 
 impl Client {
     #[cfg_attr(feature = "clippy", allow(too_many_arguments))]
-    pub fn from_bootstrapping(crust_service: Service,
+    pub fn from_bootstrapping(clock: Clock,
+                              crust_service: Service,
                               event_sender: Sender<Event>,
                               full_id: FullId,
                               proxy_peer_id: PeerId,
                               proxy_public_id: PublicId,
                               quorum_size: usize,
+                              sessions: Sessions,
                               stats: Stats,
                               timer: Timer)
                               -> Self {
-        let mut msg_accumulator = MessageAccumulator::new();
+        let mut msg_accumulator = MessageAccumulator::new(clock.clone());
         msg_accumulator.set_quorum_size(quorum_size);
 
         let client = Client {
-            ack_mgr: AckManager::new(),
+            ack_mgr: AckManager::new(clock.clone()),
+            clock: clock.clone(),
+            content_request_timer_token: None,
             crust_service: crust_service,
             event_sender: event_sender,
             full_id: full_id,
+            mpid_inbox: HashMap::new(),
+            mpid_outbox: HashMap::new(),
             msg_accumulator: msg_accumulator,
             proxy_peer_id: proxy_peer_id,
             proxy_public_id: proxy_public_id,
-            signed_msg_filter: SignedMessageFilter::new(),
+            replay_filter: ReplayFilter::default(),
+            retired_proxy_key: None,
+            sessions: sessions,
+            signed_msg_filter: SignedMessageFilter::new(clock),
             stats: stats,
             timer: timer,
             user_msg_cache: UserMessageCache::with_expiry_duration(
@@ -92,12 +120,7 @@ impl Client {
     pub fn handle_action(&mut self, action: Action) -> Transition {
         match action {
             Action::ClientSendRequest { content, dst, priority, result_tx } => {
-                let src = Authority::Client {
-                    client_key: *self.full_id.public_id().signing_public_key(),
-                    proxy_node_name: *self.proxy_public_id.name(),
-                    peer_id: self.crust_service.id(),
-                };
-
+                let src = self.client_auth();
                 let user_msg = UserMessage::Request(content);
                 let result = match self.send_user_message(src, dst, user_msg, priority) {
                     Err(RoutingError::Interface(err)) => Err(err),
@@ -106,9 +129,29 @@ impl Client {
 
                 let _ = result_tx.send(result);
             }
-            Action::NodeSendMessage { result_tx, .. } => {
+            Action::ClientSendMpid { content, dst, result_tx } => {
+                let _ = result_tx.send(self.send_mpid_message(content, dst));
+            }
+            Action::MpidInbox { result_tx } => {
+                let _ = result_tx.send(self.mpid_inbox.values().cloned().collect());
+            }
+            Action::MpidOutbox { result_tx } => {
+                let _ = result_tx.send(self.mpid_outbox.values().cloned().collect());
+            }
+            Action::NodeSendMessage { result_tx, .. } |
+            Action::Ban { result_tx, .. } |
+            Action::Shutdown { result_tx, .. } => {
                 let _ = result_tx.send(Err(InterfaceError::InvalidState));
             }
+            Action::Unban { result_tx, .. } => {
+                let _ = result_tx.send(Err(InterfaceError::InvalidState));
+            }
+            Action::BannedKeys { result_tx } => {
+                let _ = result_tx.send(vec![]);
+            }
+            Action::ReplayFilter { result_tx } => {
+                let _ = result_tx.send(self.replay_filter.clone());
+            }
             Action::CloseGroup { result_tx, .. } => {
                 let _ = result_tx.send(None);
             }
@@ -139,22 +182,96 @@ impl Client {
     }
 
     fn handle_ack_response(&mut self, ack: Ack) -> Transition {
-        self.ack_mgr.receive(ack);
+        if let Some(unacked_msg) = self.ack_mgr.receive(ack) {
+            self.timer.cancel(unacked_msg.timer_token);
+        }
         Transition::Stay
     }
 
     fn handle_timeout(&mut self, token: u64) {
+        if self.content_request_timer_token == Some(token) {
+            self.content_request_timer_token = None;
+            self.request_missing_content();
+            return;
+        }
+
         self.resend_unacknowledged_timed_out_msgs(token);
     }
 
+    /// Schedules a request for missing group message contents, unless one is already pending.
+    fn schedule_content_request(&mut self) {
+        if self.content_request_timer_token.is_none() &&
+           self.msg_accumulator.has_missing_content() {
+            let delay = Duration::from_secs(CONTENT_REQUEST_DELAY_SECS);
+            self.content_request_timer_token = Some(self.timer.schedule(delay));
+        }
+    }
+
+    /// Asks members of the source groups for the content of the group messages of which we only
+    /// accumulated the hash.
+    fn request_missing_content(&mut self) {
+        let src = self.client_auth();
+        let our_name = *self.name();
+        for (hash, member) in self.msg_accumulator.content_requests(&our_name) {
+            let request = RoutingMessage {
+                src: src.clone(),
+                dst: Authority::ManagedNode(member),
+                content: MessageContent::GetGroupMessage(hash),
+            };
+            if let Err(error) = self.send_routing_message(request) {
+                debug!("{:?} Failed to request group message content: {:?}",
+                       self,
+                       error);
+            }
+        }
+        self.schedule_content_request();
+    }
+
+    fn handle_rotate_keys(&mut self,
+                          peer_id: PeerId,
+                          new_public_id: PublicId,
+                          signature: &sign::Signature)
+                          -> Result<Transition, RoutingError> {
+        if self.proxy_peer_id != peer_id {
+            return Err(RoutingError::UnknownConnection(peer_id));
+        }
+        let serialised_public_id = try!(serialisation::serialise(&new_public_id));
+        if !sign::verify_detached(signature,
+                                  &serialised_public_id,
+                                  self.proxy_public_id.signing_public_key()) {
+            return Err(RoutingError::FailedSignature);
+        }
+        if new_public_id.name() != self.proxy_public_id.name() {
+            return Err(RoutingError::RejectedPublicId);
+        }
+        debug!("{:?} Proxy node rotated its keys.", self);
+        let old_key = *self.proxy_public_id.signing_public_key();
+        self.retired_proxy_key = Some((old_key, self.clock.now()));
+        self.proxy_public_id = new_public_id;
+        Ok(Transition::Stay)
+    }
+
+    fn client_auth(&self) -> Authority {
+        Authority::Client {
+            client_key: *self.full_id.public_id().signing_public_key(),
+            proxy_node_name: *self.proxy_public_id.name(),
+            peer_id: self.crust_service.id(),
+        }
+    }
+
     fn handle_new_message(&mut self, peer_id: PeerId, bytes: Vec<u8>) -> Transition {
-        let result = match serialisation::deserialise(&bytes) {
+        let result = match self.sessions
+            .decrypt(&peer_id, bytes)
+            .and_then(|bytes| serialisation::deserialise(&bytes).map_err(From::from)) {
             Ok(Message::Hop(hop_msg)) => self.handle_hop_message(hop_msg, peer_id),
+            Ok(Message::Direct(DirectMessage::RotateKeys { new_public_id, signature })) => {
+                self.handle_rotate_keys(peer_id, new_public_id, &signature)
+            }
             Ok(message) => {
                 debug!("{:?} - Unhandled new message: {:?}", self, message);
                 Ok(Transition::Stay)
             }
-            Err(error) => Err(RoutingError::SerialisationError(error)),
+            Err(error) => Err(error),
         };
 
         match result {
@@ -172,11 +289,19 @@ impl Client {
                           peer_id: PeerId)
                           -> Result<Transition, RoutingError> {
 
-        if self.proxy_peer_id == peer_id {
-            try!(hop_msg.verify(self.proxy_public_id.signing_public_key()));
-        } else {
+        if self.proxy_peer_id != peer_id {
             return Err(RoutingError::UnknownConnection(peer_id));
         }
+        if let Err(error) = hop_msg.verify(self.proxy_public_id.signing_public_key()) {
+            // Messages signed with the proxy's old key are valid during a grace period.
+            let grace_period = Duration::from_secs(KEY_ROTATION_GRACE_PERIOD_SECS);
+            match self.retired_proxy_key {
+                Some((key, rotated_at)) if self.clock.now() - rotated_at < grace_period => {
+                    try!(hop_msg.verify(&key))
+                }
+                _ => return Err(error),
+            }
+        }
 
         let signed_msg = hop_msg.content();
         try!(signed_msg.check_integrity());
@@ -192,26 +317,29 @@ impl Client {
             return Ok(Transition::Stay);
         }
 
-        self.handle_routing_message(routing_msg, signed_msg.public_id())
+        try!(self.replay_filter.check(signed_msg, &self.clock));
+
+        self.handle_routing_message(signed_msg)
     }
 
     fn handle_routing_message(&mut self,
-                              routing_msg: &RoutingMessage,
-                              public_id: &PublicId)
+                              signed_msg: &SignedMessage)
                               -> Result<Transition, RoutingError> {
-        if let Some(msg) = try!(self.accumulate(routing_msg, public_id)) {
+        if let Some((msg, signatures)) = try!(self.accumulate(signed_msg)) {
             if msg.src.is_group() {
                 self.send_ack(&msg, 0);
             }
 
-            self.dispatch_routing_message(msg)
+            self.dispatch_routing_message(msg, signatures)
         } else {
+            self.schedule_content_request();
             Ok(Transition::Stay)
         }
     }
 
     fn dispatch_routing_message(&mut self,
-                                routing_msg: RoutingMessage)
+                                routing_msg: RoutingMessage,
+                                signatures: Vec<(PublicId, sign::Signature)>)
                                 -> Result<Transition, RoutingError> {
         let msg_content = routing_msg.content.clone();
         let msg_src = routing_msg.src.clone();
@@ -231,13 +359,38 @@ impl Client {
         match (msg_content, msg_src, msg_dst) {
             // Ack
             (MessageContent::Ack(ack, _), _, _) => Ok(self.handle_ack_response(ack)),
+            // GroupMessage
+            (MessageContent::GroupMessage(msg), Authority::ManagedNode(_), _) => {
+                // The content is only accepted if its hash was accumulated from the source group.
+                if let Some((msg, signatures)) = self.msg_accumulator.add_content(*msg) {
+                    self.send_ack(&msg, 0);
+                    self.dispatch_routing_message(msg, signatures)
+                } else {
+                    Ok(Transition::Stay)
+                }
+            }
             // UserMessagePart
-            (MessageContent::UserMessagePart { hash, part_count, part_index, payload, .. },
+            (MessageContent::UserMessagePart { hash,
+                                               part_count,
+                                               part_index,
+                                               payload,
+                                               priority,
+                                               .. },
              src,
              dst) => {
-                if let Some(msg) = self.user_msg_cache.add(hash, part_count, part_index, payload) {
+                if let Some((msg, part_signatures)) =
+                       self.user_msg_cache.add(hash, part_count, part_index, payload, signatures) {
                     self.stats().count_user_message(&msg);
-                    self.send_event(msg.into_event(src, dst));
+                    if let Some(wrapper) = mpid_wrapper(&msg, &src) {
+                        self.handle_mpid_message(wrapper);
+                        return Ok(Transition::Stay);
+                    }
+                    let proof = if src.is_group() {
+                        Some(GroupProof::new(src.clone(), dst.clone(), priority, part_signatures))
+                    } else {
+                        None
+                    };
+                    self.send_event(msg.into_event(src, dst, proof));
                 }
                 Ok(Transition::Stay)
             }
@@ -267,6 +420,67 @@ impl Client {
         Ok(())
     }
 
+    fn send_mpid_message(&mut self,
+                         wrapper: MpidMessageWrapper,
+                         dst: Authority)
+                         -> Result<(), InterfaceError> {
+        let data = try!(wrapper.to_data());
+        match wrapper {
+            MpidMessageWrapper::PutMessage(ref message) => {
+                let header = message.header().clone();
+                let _ = self.mpid_outbox.insert(try!(header.name()), header);
+            }
+            MpidMessageWrapper::DeleteMessage(ref name) => {
+                let _ = self.mpid_inbox.remove(name);
+                let _ = self.mpid_outbox.remove(name);
+            }
+            _ => (),
+        }
+        let src = self.client_auth();
+        let user_msg = UserMessage::Request(Request::Post(data, MessageId::new()));
+        match self.send_user_message(src, dst, user_msg, DEFAULT_PRIORITY) {
+            Err(RoutingError::Interface(err)) => Err(err),
+            Err(_) | Ok(_) => Ok(()),
+        }
+    }
+
+    fn handle_mpid_message(&mut self, wrapper: MpidMessageWrapper) {
+        match wrapper {
+            MpidMessageWrapper::PutHeader(header, _) => {
+                // Our managers send the headers again whenever we come online.
+                if let Ok(name) = header.name() {
+                    if self.mpid_inbox.insert(name, header.clone()).is_none() {
+                        self.send_event(Event::MpidHeaderReceived(header));
+                    }
+                }
+            }
+            MpidMessageWrapper::PutMessage(message) => {
+                if message.recipient() == self.name() {
+                    self.send_event(Event::MpidMessageReceived(message));
+                } else {
+                    debug!("{:?} Received MPID message for {:?}.",
+                           self,
+                           message.recipient());
+                }
+            }
+            MpidMessageWrapper::GetOutboxHeadersResponse(headers) => {
+                self.mpid_outbox = headers.iter()
+                    .filter_map(|header| header.name().ok().map(|name| (name, header.clone())))
+                    .collect();
+                self.send_event(Event::MpidOutboxHeaders(headers));
+            }
+            MpidMessageWrapper::OutboxHasResponse(headers) => {
+                for header in &headers {
+                    if let Ok(name) = header.name() {
+                        let _ = self.mpid_outbox.insert(name, header.clone());
+                    }
+                }
+                self.send_event(Event::MpidOutboxHeaders(headers));
+            }
+            wrapper => debug!("{:?} Unexpected MPID message: {:?}", self, wrapper),
+        }
+    }
+
     fn is_recipient(&self, dst: &Authority) -> bool {
         if let Authority::Client { ref client_key, .. } = *dst {
             client_key == self.full_id.public_id().signing_public_key()
@@ -309,6 +523,10 @@ impl Base for Client {
         &mut self.stats
     }
 
+    fn sessions(&mut self) -> &mut Sessions {
+        &mut self.sessions
+    }
+
     fn send_event(&self, event: Event) {
         let _ = self.event_sender.send(event);
     }
@@ -316,10 +534,10 @@ impl Base for Client {
 
 impl Bootstrapped for Client {
     fn accumulate(&mut self,
-                  routing_msg: &RoutingMessage,
-                  public_id: &PublicId)
-                  -> Result<Option<RoutingMessage>, RoutingError> {
-        self.msg_accumulator.add(routing_msg, public_id)
+                  signed_msg: &SignedMessage)
+                  -> Result<Option<(RoutingMessage, Vec<(PublicId, sign::Signature)>)>,
+                            RoutingError> {
+        self.msg_accumulator.add(signed_msg)
     }
 
     fn ack_mgr(&self) -> &AckManager {
@@ -343,7 +561,7 @@ impl Bootstrapped for Client {
                        unacked_msg);
                 self.stats.count_unacked();
             } else if let Err(error) =
-                   self.send_routing_message_via_route(unacked_msg.routing_msg, unacked_msg.route) {
+                   self.send_signed_message_via_route(unacked_msg.signed_msg, unacked_msg.route) {
                 debug!("{:?} Failed to send message: {:?}", self, error);
             }
         }
@@ -353,17 +571,25 @@ impl Bootstrapped for Client {
                                       routing_msg: RoutingMessage,
                                       route: u8)
                                       -> Result<(), RoutingError> {
+        let signed_msg = try!(SignedMessage::new(routing_msg, &self.full_id(), &self.clock));
+        self.send_signed_message_via_route(signed_msg, route)
+    }
+
+    fn send_signed_message_via_route(&mut self,
+                                     signed_msg: SignedMessage,
+                                     route: u8)
+                                     -> Result<(), RoutingError> {
         self.stats.count_route(route);
 
-        if let Authority::Client { .. } = routing_msg.dst {
-            if self.is_recipient(&routing_msg.dst) {
+        if let Authority::Client { .. } = signed_msg.routing_message().dst {
+            if self.is_recipient(&signed_msg.routing_message().dst) {
                 return Ok(()); // Message is for us.
             }
         }
 
         // Get PeerId of the proxy node
         let proxy_peer_id = if let Authority::Client { ref proxy_node_name, .. } =
-                                   routing_msg.src {
+                                   signed_msg.routing_message().src {
             if *self.proxy_public_id.name() == *proxy_node_name {
                 self.proxy_peer_id
             } else {
@@ -377,9 +603,7 @@ impl Bootstrapped for Client {
             return Err(RoutingError::InvalidSource);
         };
 
-        let signed_msg = try!(SignedMessage::new(routing_msg, &self.full_id()));
-
-        if !self.add_to_pending_acks(&signed_msg, route) {
+        if !self.add_to_pending_acks(&signed_msg, route, &[proxy_peer_id]) {
             return Ok(());
         }
 
@@ -428,3 +652,13 @@ impl Debug for Client {
         write!(formatter, "Client({})", self.name())
     }
 }
+
+/// Returns the MPID message contained in `msg`, if it was sent by a client's managers.
+fn mpid_wrapper(msg: &UserMessage, src: &Authority) -> Option<MpidMessageWrapper> {
+    match (msg, src) {
+        (&UserMessage::Request(Request::Post(ref data, _)), &Authority::ClientManager(_)) => {
+            MpidMessageWrapper::from_data(data)
+        }
+        _ => None,
+    }
+}
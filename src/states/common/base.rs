@@ -21,6 +21,7 @@ use event::Event;
 use id::FullId;
 use maidsafe_utilities::serialisation;
 use messages::Message;
+use session::Sessions;
 use state_machine::Transition;
 use stats::Stats;
 use std::fmt::Debug;
@@ -31,6 +32,7 @@ pub trait Base: Debug {
     fn crust_service(&self) -> &Service;
     fn full_id(&self) -> &FullId;
     fn stats(&mut self) -> &mut Stats;
+    fn sessions(&mut self) -> &mut Sessions;
     fn send_event(&self, event: Event);
 
     fn handle_lost_peer(&mut self, _peer_id: PeerId) -> Transition {
@@ -65,6 +67,7 @@ pub trait Base: Debug {
                     bytes: Vec<u8>,
                     priority: u8)
                     -> Result<(), RoutingError> {
+        let bytes = try!(self.sessions().encrypt(peer_id, bytes));
         self.stats().count_bytes(bytes.len());
 
         if let Err(err) = self.crust_service().send(*peer_id, bytes.clone(), priority) {
@@ -23,6 +23,7 @@ use id::PublicId;
 use maidsafe_utilities::serialisation;
 use messages::{HopMessage, Message, MessageContent, RoutingMessage, SignedMessage};
 use peer_manager::MIN_GROUP_SIZE;
+use rust_sodium::crypto::sign;
 use signed_message_filter::SignedMessageFilter;
 use std::time::Duration;
 use super::Base;
@@ -33,9 +34,9 @@ use xor_name::XorName;
 // connection to at least one peer).
 pub trait Bootstrapped: Base {
     fn accumulate(&mut self,
-                  routing_msg: &RoutingMessage,
-                  public_id: &PublicId)
-                  -> Result<Option<RoutingMessage>, RoutingError>;
+                  signed_msg: &SignedMessage)
+                  -> Result<Option<(RoutingMessage, Vec<(PublicId, sign::Signature)>)>,
+                            RoutingError>;
 
     fn ack_mgr(&self) -> &AckManager;
     fn ack_mgr_mut(&mut self) -> &mut AckManager;
@@ -45,16 +46,30 @@ pub trait Bootstrapped: Base {
                                       route: u8)
                                       -> Result<(), RoutingError>;
 
+    /// Sends a message we have already signed, e.g. to resend it unchanged via another route.
+    fn send_signed_message_via_route(&mut self,
+                                     signed_msg: SignedMessage,
+                                     route: u8)
+                                     -> Result<(), RoutingError>;
+
     fn signed_msg_filter(&mut self) -> &mut SignedMessageFilter;
     fn timer(&mut self) -> &mut Timer;
 
-    fn add_to_pending_acks(&mut self, signed_msg: &SignedMessage, route: u8) -> bool {
+    /// Called when a message we sent via the given peers wasn't acknowledged in time.
+    fn relays_unacknowledged(&mut self, _relays: &[PeerId]) {}
+
+    fn add_to_pending_acks(&mut self,
+                           signed_msg: &SignedMessage,
+                           route: u8,
+                           relays: &[PeerId])
+                           -> bool {
         // If this is not an ack and we're the source, expect to receive an ack for this.
         if let MessageContent::Ack(..) = signed_msg.routing_message().content {
             return true;
         }
 
-        if *signed_msg.public_id() != *self.full_id().public_id() {
+        // Compare names, as we may have rotated our keys since we signed a resent message.
+        if signed_msg.public_id().name() != self.full_id().public_id().name() {
             return true;
         }
 
@@ -72,8 +87,9 @@ pub trait Bootstrapped: Base {
 
         let token = self.timer().schedule(Duration::from_secs(ACK_TIMEOUT_SECS));
         let unacked_msg = UnacknowledgedMessage {
-            routing_msg: signed_msg.routing_message().clone(),
+            signed_msg: signed_msg.clone(),
             route: route,
+            relays: relays.to_vec(),
             timer_token: token,
         };
 
@@ -113,13 +129,15 @@ pub trait Bootstrapped: Base {
                    ack,
                    unacked_msg);
 
+            self.relays_unacknowledged(&unacked_msg.relays);
+
             if unacked_msg.route as usize == MIN_GROUP_SIZE {
                 debug!("{:?} - Message unable to be acknowledged - giving up. {:?}",
                        self,
                        unacked_msg);
                 self.stats().count_unacked();
             } else if let Err(error) =
-                   self.send_routing_message_via_route(unacked_msg.routing_msg, unacked_msg.route) {
+                   self.send_signed_message_via_route(unacked_msg.signed_msg, unacked_msg.route) {
                 debug!("{:?} Failed to send message: {:?}", self, error);
             }
         }
@@ -18,36 +18,49 @@
 use ack_manager::{Ack, AckManager};
 use action::Action;
 use authority::Authority;
+use ban_list::BanList;
 use cache::Cache;
+use clock::Clock;
 use crust::{ConnectionInfoResult, CrustError, PeerId, PrivConnectionInfo, PubConnectionInfo,
             Service};
 use crust::Event as CrustEvent;
 use error::{InterfaceError, RoutingError};
 use event::Event;
+use group_proof::GroupProof;
 use id::{FullId, PublicId};
 use itertools::Itertools;
+use lru_time_cache::LruCache;
 use maidsafe_utilities::serialisation;
-use message_accumulator::MessageAccumulator;
-use messages::{DEFAULT_PRIORITY, DirectMessage, HopMessage, Message, MessageContent,
-               RoutingMessage, SignedMessage, UserMessage, UserMessageCache};
-use peer_manager::{ConnectionInfoPreparedResult, ConnectionInfoReceivedResult, MIN_GROUP_SIZE,
-                   PeerManager, PeerState, QUORUM_SIZE};
+use message_accumulator::{CONTENT_REQUEST_DELAY_SECS, MessageAccumulator};
+use messages::{BootstrapDenyReason, ContactAddr, DEFAULT_PRIORITY, DirectMessage, HopMessage,
+               Message, MessageContent, RoutingMessage, SignedMessage, UserMessage,
+               UserMessageCache, client_identify_signed_bytes, node_identify_signed_bytes};
+use partition::PartitionMonitor;
+use peer_manager::{ConnectionInfoPreparedResult, ConnectionInfoReceivedResult,
+                   KEY_ROTATION_GRACE_PERIOD_SECS, MIN_GROUP_SIZE, Misbehaviour, PeerManager,
+                   PeerState, ProxyLimits, QUORUM_SIZE};
+use rand;
+use replay_filter::ReplayFilter;
+use resource_proof::{MAX_DIFFICULTY, ResourceProofChallenge, ResourceProofSolver};
+use routing_table::{GroupChange, Prefix, RemovalDetails};
 use routing_table::Error as RoutingTableError;
-use routing_table::RemovalDetails;
 #[cfg(feature = "use-mock-crust")]
 use routing_table::RoutingTable;
 use rust_sodium::crypto::{box_, sign};
 use rust_sodium::crypto::hash::sha256;
+use session::{SessionKey, Sessions};
 use signed_message_filter::SignedMessageFilter;
 use state_machine::Transition;
 use stats::Stats;
 use std::{cmp, fmt, iter};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
+use std::net::SocketAddr;
 use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
 use super::common::{Base, Bootstrapped, USER_MSG_CACHE_EXPIRY_DURATION_SECS};
 use timer::Timer;
-use tunnels::Tunnels;
+use tunnels::{TunnelLimits, Tunnels};
 use types::MessageId;
 use utils;
 use xor_name::XorName;
@@ -58,20 +71,67 @@ const TICK_TIMEOUT_SECS: u64 = 60;
 const GET_NODE_NAME_TIMEOUT_SECS: u64 = 60;
 /// Time (in seconds) the new close group waits for a joining node it sent a network name to.
 const SENT_NETWORK_NAME_TIMEOUT_SECS: u64 = 30;
+/// Time (in seconds) for which we keep the group messages we sent, so that recipients who only
+/// received their hash can request the content from us.
+const SENT_GROUP_MSG_EXPIRY_SECS: u64 = 60 * 20;
+/// Time (in seconds) after which we replace our keys with new ones.
+const KEY_ROTATION_INTERVAL_SECS: u64 = 24 * 60 * 60;
+/// The maximum number of alternative contacts we send to a client we can't act as a proxy for.
+const MAX_REDIRECT_CONTACTS: usize = 8;
+/// Time (in seconds) we keep running after announcing that we leave, so that the `Leave` messages
+/// can be sent before the connections are closed.
+const LEAVE_TIMEOUT_SECS: u64 = 1;
+/// The number of nonces we try per step while solving a resource proof challenge. Between the
+/// steps, we handle other events.
+const RESOURCE_PROOF_STEP_NONCES: u64 = 10_000;
+/// Time (in seconds) a joining node has to send us the solution of its resource proof challenge.
+const RESOURCE_PROOF_EXPIRY_SECS: u64 = 5 * 60;
+
+/// The phase of a graceful shutdown, with the token of the timer that ends it.
+enum Shutdown {
+    /// We don't accept new messages from the user and wait for ours to be acknowledged.
+    Draining(u64),
+    /// We sent `Leave` to our peers and are about to terminate.
+    Leaving(u64),
+}
 
 pub struct Node {
     ack_mgr: AckManager,
     cacheable_user_msg_cache: UserMessageCache,
+    /// The challenges we sent to newly connected clients, by their crust ID.
+    client_challenges: HashMap<PeerId, u64>,
+    clock: Clock,
+    content_request_timer_token: Option<u64>,
     crust_service: Service,
     event_sender: Sender<Event>,
     full_id: FullId,
+    /// The challenges we gave to joining nodes we sent a network name to, by their relocated ID,
+    /// with the time we did so.
+    expected_resource_proofs: HashMap<PublicId, (ResourceProofChallenge, Instant)>,
     get_node_name_timer_token: Option<u64>,
     is_first_node: bool,
+    key_rotation_timer_token: u64,
+    /// When we last rotated our keys. Peers reject another rotation within the grace period.
+    last_key_rotation: Option<Instant>,
     msg_accumulator: MessageAccumulator,
+    partition: PartitionMonitor,
     peer_mgr: PeerManager,
+    proxy_limits: ProxyLimits,
+    replay_filter: ReplayFilter,
+    /// Our solution to the challenge our new close group gave us when we joined.
+    resource_proof: Option<u64>,
+    /// The challenge we are still solving, with the token of the timeout for the next step, and
+    /// the close group members and the source authority to send our connection info with.
+    resource_proof_solver: Option<(ResourceProofSolver, u64, Vec<PublicId>, Authority)>,
+    /// The difficulty of the challenge we give to nodes joining our group.
+    resource_proof_difficulty: u8,
     response_cache: Box<Cache>,
     /// The last joining node we have sent a `GetNodeName` response to, and when.
     sent_network_name_to: Option<(XorName, Instant)>,
+    /// The full content of the group messages we sent, by hash.
+    sent_group_msgs: LruCache<sha256::Digest, RoutingMessage>,
+    sessions: Sessions,
+    shutdown: Option<Shutdown>,
     signed_msg_filter: SignedMessageFilter,
     stats: Stats,
     tick_timer_token: u64,
@@ -81,42 +141,71 @@ pub struct Node {
 }
 
 impl Node {
-    pub fn first(cache: Box<Cache>,
+    #[cfg_attr(feature = "clippy", allow(too_many_arguments))]
+    pub fn first(ban_list: BanList,
+                 cache: Box<Cache>,
+                 clock: Clock,
                  crust_service: Service,
                  event_sender: Sender<Event>,
                  mut full_id: FullId,
-                 timer: Timer)
+                 proxy_limits: ProxyLimits,
+                 replay_filter: ReplayFilter,
+                 resource_proof_difficulty: u8,
+                 sessions: Sessions,
+                 timer: Timer,
+                 tunnel_limits: TunnelLimits)
                  -> Option<Self> {
         let name = XorName(sha256::hash(&full_id.public_id().name().0).0);
         full_id.public_id_mut().set_name(name);
 
-        Self::new(cache,
+        Self::new(ban_list,
+                  cache,
+                  clock,
                   crust_service,
                   event_sender,
                   true,
                   full_id,
+                  proxy_limits,
+                  replay_filter,
+                  resource_proof_difficulty,
+                  sessions,
                   Default::default(),
-                  timer)
+                  timer,
+                  tunnel_limits)
     }
 
     #[cfg_attr(feature = "clippy", allow(too_many_arguments))]
-    pub fn from_bootstrapping(cache: Box<Cache>,
+    pub fn from_bootstrapping(ban_list: BanList,
+                              cache: Box<Cache>,
+                              clock: Clock,
                               crust_service: Service,
                               event_sender: Sender<Event>,
                               full_id: FullId,
+                              proxy_limits: ProxyLimits,
                               proxy_peer_id: PeerId,
                               proxy_public_id: PublicId,
                               quorum_size: usize,
+                              replay_filter: ReplayFilter,
+                              resource_proof_difficulty: u8,
+                              sessions: Sessions,
                               stats: Stats,
-                              timer: Timer)
+                              timer: Timer,
+                              tunnel_limits: TunnelLimits)
                               -> Option<Self> {
-        let mut node = Self::new(cache,
+        let mut node = Self::new(ban_list,
+                                 cache,
+                                 clock,
                                  crust_service,
                                  event_sender,
                                  false,
                                  full_id,
+                                 proxy_limits,
+                                 replay_filter,
+                                 resource_proof_difficulty,
+                                 sessions,
                                  stats,
-                                 timer);
+                                 timer,
+                                 tunnel_limits);
 
         if let Some(ref mut node) = node {
             node.msg_accumulator.set_quorum_size(quorum_size);
@@ -127,37 +216,62 @@ impl Node {
     }
 
     #[cfg_attr(feature = "clippy", allow(too_many_arguments))]
-    fn new(cache: Box<Cache>,
+    fn new(ban_list: BanList,
+           cache: Box<Cache>,
+           clock: Clock,
            crust_service: Service,
            event_sender: Sender<Event>,
            first_node: bool,
            full_id: FullId,
+           proxy_limits: ProxyLimits,
+           replay_filter: ReplayFilter,
+           resource_proof_difficulty: u8,
+           sessions: Sessions,
            stats: Stats,
-           mut timer: Timer)
+           mut timer: Timer,
+           tunnel_limits: TunnelLimits)
            -> Option<Self> {
         let public_id = *full_id.public_id();
         let tick_period = Duration::from_secs(TICK_TIMEOUT_SECS);
-        let tick_timer_token = timer.schedule(tick_period);
+        let tick_timer_token = timer.schedule_repeating(tick_period);
+        let key_rotation_period = Duration::from_secs(KEY_ROTATION_INTERVAL_SECS);
+        let key_rotation_timer_token = timer.schedule_repeating(key_rotation_period);
         let user_msg_cache_duration = Duration::from_secs(USER_MSG_CACHE_EXPIRY_DURATION_SECS);
 
         let mut node = Node {
-            ack_mgr: AckManager::new(),
+            ack_mgr: AckManager::new(clock.clone()),
             cacheable_user_msg_cache:
                 UserMessageCache::with_expiry_duration(user_msg_cache_duration),
+            client_challenges: HashMap::new(),
+            clock: clock.clone(),
+            content_request_timer_token: None,
             crust_service: crust_service,
             event_sender: event_sender.clone(),
             full_id: full_id,
+            expected_resource_proofs: HashMap::new(),
             get_node_name_timer_token: None,
             is_first_node: first_node,
-            msg_accumulator: MessageAccumulator::new(),
-            peer_mgr: PeerManager::new(public_id),
+            key_rotation_timer_token: key_rotation_timer_token,
+            last_key_rotation: None,
+            msg_accumulator: MessageAccumulator::new(clock.clone()),
+            partition: PartitionMonitor::new(clock.clone()),
+            peer_mgr: PeerManager::new(public_id, ban_list, clock.clone()),
+            proxy_limits: proxy_limits,
+            replay_filter: replay_filter,
+            resource_proof: None,
+            resource_proof_solver: None,
+            resource_proof_difficulty: resource_proof_difficulty,
             response_cache: cache,
-            signed_msg_filter: SignedMessageFilter::new(),
+            signed_msg_filter: SignedMessageFilter::new(clock.clone()),
             sent_network_name_to: None,
+            sent_group_msgs:
+                LruCache::with_expiry_duration(Duration::from_secs(SENT_GROUP_MSG_EXPIRY_SECS)),
+            sessions: sessions,
+            shutdown: None,
             stats: stats,
             tick_timer_token: tick_timer_token,
             timer: timer,
-            tunnels: Default::default(),
+            tunnels: Tunnels::new(tunnel_limits, clock),
             user_msg_cache: UserMessageCache::with_expiry_duration(user_msg_cache_duration),
         };
 
@@ -182,6 +296,7 @@ impl Node {
                   self.stats.cur_client_num,
                   self.stats.cumulative_client_num);
         }
+        self.stats.tunnel_traffic = self.tunnels.total_traffic();
         if self.stats.tunnel_connections != self.tunnels.tunnel_count() ||
            self.stats.tunnel_client_pairs != self.tunnels.client_count() {
             self.stats.tunnel_connections = self.tunnels.tunnel_count();
@@ -209,7 +324,15 @@ impl Node {
 
     pub fn handle_action(&mut self, action: Action) -> Transition {
         match action {
-            Action::ClientSendRequest { result_tx, .. } => {
+            Action::ClientSendRequest { result_tx, .. } |
+            Action::ClientSendMpid { result_tx, .. } => {
+                let _ = result_tx.send(Err(InterfaceError::InvalidState));
+            }
+            Action::MpidInbox { result_tx } |
+            Action::MpidOutbox { result_tx } => {
+                let _ = result_tx.send(vec![]);
+            }
+            Action::NodeSendMessage { ref result_tx, .. } if self.shutdown.is_some() => {
                 let _ = result_tx.send(Err(InterfaceError::InvalidState));
             }
             Action::NodeSendMessage { src, dst, content, priority, result_tx } => {
@@ -229,6 +352,26 @@ impl Node {
             Action::QuorumSize { result_tx } => {
                 let _ = result_tx.send(self.dynamic_quorum_size());
             }
+            Action::Ban { key, result_tx } => {
+                let result = self.ban(key);
+                let _ = result_tx.send(Ok(()));
+                if let Err(RoutingError::Terminated) = result {
+                    return Transition::Terminate;
+                }
+            }
+            Action::Unban { key, result_tx } => {
+                let _ = result_tx.send(Ok(self.peer_mgr.ban_list_mut().unban(&key)));
+            }
+            Action::BannedKeys { result_tx } => {
+                let _ = result_tx.send(self.peer_mgr.ban_list_mut().banned_keys());
+            }
+            Action::ReplayFilter { result_tx } => {
+                let _ = result_tx.send(self.replay_filter.clone());
+            }
+            Action::Shutdown { timeout, result_tx } => {
+                self.start_shutdown(timeout);
+                let _ = result_tx.send(Ok(()));
+            }
             Action::Timeout(token) => {
                 if !self.handle_timeout(token) {
                     return Transition::Terminate;
@@ -239,6 +382,7 @@ impl Node {
             }
         }
 
+        self.leave_if_drained();
         self.update_stats();
         Transition::Stay
     }
@@ -247,6 +391,7 @@ impl Node {
         match crust_event {
             CrustEvent::BootstrapAccept(peer_id) => self.handle_bootstrap_accept(peer_id),
             CrustEvent::BootstrapConnect(peer_id, _) => self.handle_bootstrap_connect(peer_id),
+            CrustEvent::BootstrapFailed => self.partition.bootstrap_finished(),
             CrustEvent::ConnectSuccess(peer_id) => self.handle_connect_success(peer_id),
             CrustEvent::ConnectFailure(peer_id) => self.handle_connect_failure(peer_id),
             CrustEvent::LostPeer(peer_id) => {
@@ -258,6 +403,7 @@ impl Node {
                 match self.handle_new_message(peer_id, bytes) {
                     Err(RoutingError::FilterCheckFailed) |
                     Ok(_) => (),
+                    Err(RoutingError::Terminated) => return Transition::Terminate,
                     Err(err) => debug!("{:?} - {:?}", self, err),
                 }
             }
@@ -285,10 +431,43 @@ impl Node {
             }
         }
 
+        self.leave_if_drained();
         self.update_stats();
         Transition::Stay
     }
 
+    // Stops accepting new messages from the user and waits until all our messages have been
+    // acknowledged, or until `timeout` has elapsed, before we leave.
+    fn start_shutdown(&mut self, timeout: Duration) {
+        if self.shutdown.is_some() {
+            return;
+        }
+        info!("{:?} Shutting down.", self);
+        self.shutdown = Some(Shutdown::Draining(self.timer.schedule(timeout)));
+    }
+
+    fn leave_if_drained(&mut self) {
+        if let Some(Shutdown::Draining(token)) = self.shutdown {
+            if !self.ack_mgr.has_pending() {
+                self.timer.cancel(token);
+                self.leave();
+            }
+        }
+    }
+
+    // Tells all our peers that we are leaving, so that they update their routing tables right away
+    // instead of waiting for the connection to time out, then terminates after a short delay.
+    fn leave(&mut self) {
+        info!("{:?} Leaving the network.", self);
+        for peer_id in self.peer_mgr.connected_peer_ids() {
+            if let Err(error) = self.send_direct_message(&peer_id, DirectMessage::Leave) {
+                debug!("{:?} Failed to send Leave to {:?}: {:?}", self, peer_id, error);
+            }
+        }
+        let token = self.timer.schedule(Duration::from_secs(LEAVE_TIMEOUT_SECS));
+        self.shutdown = Some(Shutdown::Leaving(token));
+    }
+
     fn handle_listener_started(&mut self, port: u16) -> Transition {
         trace!("{:?} Listener started on port {}.", self, port);
         self.crust_service.set_service_discovery_listen(true);
@@ -308,9 +487,35 @@ impl Node {
     fn handle_bootstrap_accept(&mut self, peer_id: PeerId) {
         trace!("{:?} Received BootstrapAccept from {:?}.", self, peer_id);
         // TODO: Keep track of that peer to make sure we receive a message from them.
+        let challenge = rand::random();
+        let _ = self.client_challenges.insert(peer_id, challenge);
+        let direct_message = DirectMessage::BootstrapChallenge(challenge);
+        if let Err(error) = self.send_direct_message(&peer_id, direct_message) {
+            debug!("{:?} Failed to send BootstrapChallenge to {:?}: {:?}",
+                   self,
+                   peer_id,
+                   error);
+        }
     }
 
     fn handle_bootstrap_connect(&mut self, peer_id: PeerId) {
+        self.partition.bootstrap_finished();
+        if self.partition.is_partitioned() && self.peer_mgr.get_routing_peer(&peer_id).is_none() {
+            // We bootstrapped again to find a way back to the other side of a partition.
+            debug!("{:?} Received BootstrapConnect from {:?} while partitioned. Sending \
+                    NodeIdentify.",
+                   self,
+                   peer_id);
+            self.peer_mgr.connected_to(&peer_id);
+            if let Err(error) = self.send_node_identify(peer_id) {
+                warn!("{:?} Failed to send NodeIdentify to {:?}: {:?}",
+                      self,
+                      peer_id,
+                      error);
+                self.disconnect_peer(&peer_id);
+            }
+            return;
+        }
         self.disconnect_peer(&peer_id)
     }
 
@@ -328,11 +533,20 @@ impl Node {
             return;
         }
 
-        // Remove tunnel connection if we have one for this peer already
-        if let Some(tunnel_id) = self.tunnels.remove_tunnel_for(&peer_id) {
-            debug!("{:?} Removing unwanted tunnel for {:?}", self, peer_id);
-            let message = DirectMessage::TunnelDisconnect(peer_id);
-            let _ = self.send_direct_message(&tunnel_id, message);
+        // Remove tunnel connections if we have any for this peer already
+        let tunnel_ids = self.tunnels.remove_tunnel_for(&peer_id);
+        if !tunnel_ids.is_empty() {
+            debug!("{:?} Removing unwanted tunnels for {:?}", self, peer_id);
+            for tunnel_id in tunnel_ids {
+                let message = DirectMessage::TunnelDisconnect(peer_id);
+                let _ = self.send_direct_message(&tunnel_id, message);
+            }
+            if self.peer_mgr.direct_connection_established(&peer_id) {
+                debug!("{:?} Replaced tunnel to {:?} with a direct connection.",
+                       self,
+                       peer_id);
+                return;
+            }
         } else if let Some(pub_id) = self.peer_mgr.get_routing_peer(&peer_id) {
             warn!("{:?} Received ConnectSuccess from {:?}, but node {:?} is already in our \
                    routing table.",
@@ -381,33 +595,69 @@ impl Node {
     }
 
     fn handle_new_message(&mut self, peer_id: PeerId, bytes: Vec<u8>) -> Result<(), RoutingError> {
+        let bytes = match self.sessions.decrypt(&peer_id, bytes) {
+            Ok(bytes) => bytes,
+            // Not necessarily the peer's fault: the frame could have been duplicated on the way.
+            Err(RoutingError::ReplayedMessage) => return Err(RoutingError::ReplayedMessage),
+            Err(error) => {
+                try!(self.misbehaved(&peer_id, Misbehaviour::InvalidMessage));
+                return Err(error);
+            }
+        };
         match serialisation::deserialise(&bytes) {
             Ok(Message::Hop(hop_msg)) => self.handle_hop_message(hop_msg, peer_id),
             Ok(Message::Direct(direct_msg)) => self.handle_direct_message(direct_msg, peer_id),
             Ok(Message::TunnelDirect { content, src, dst }) => {
-                if dst == self.crust_service.id() &&
-                   self.tunnels.tunnel_for(&src) == Some(&peer_id) {
+                if dst == self.crust_service.id() && self.tunnels.is_tunnel(&src, &peer_id) {
                     self.handle_direct_message(content, src)
                 } else if self.tunnels.has_clients(src, dst) {
-                    self.send_or_drop(&dst, bytes, content.priority())
+                    self.relay_tunnel_message(src, dst, bytes, content.priority())
                 } else if self.tunnels.accept_clients(src, dst) {
-                    try!(self.send_direct_message(&dst, DirectMessage::TunnelSuccess(src)));
-                    self.send_or_drop(&dst, bytes, content.priority())
+                    let headroom = self.tunnels.headroom();
+                    try!(self.send_direct_message(&dst,
+                                                  DirectMessage::TunnelSuccess(src, headroom)));
+                    self.relay_tunnel_message(src, dst, bytes, content.priority())
                 } else {
                     Err(RoutingError::InvalidDestination)
                 }
             }
             Ok(Message::TunnelHop { content, src, dst }) => {
-                if dst == self.crust_service.id() &&
-                   self.tunnels.tunnel_for(&src) == Some(&peer_id) {
+                if dst == self.crust_service.id() && self.tunnels.is_tunnel(&src, &peer_id) {
                     self.handle_hop_message(content, src)
                 } else if self.tunnels.has_clients(src, dst) {
-                    self.send_or_drop(&dst, bytes, content.content().priority())
+                    self.relay_tunnel_message(src, dst, bytes, content.content().priority())
                 } else {
                     Err(RoutingError::InvalidDestination)
                 }
             }
-            Err(error) => Err(RoutingError::SerialisationError(error)),
+            Ok(Message::Encrypted { .. }) => {
+                // We have no session with this peer, or the frame was encrypted twice.
+                try!(self.misbehaved(&peer_id, Misbehaviour::InvalidMessage));
+                Err(RoutingError::SymmetricDecryptionFailure)
+            }
+            Err(error) => {
+                try!(self.misbehaved(&peer_id, Misbehaviour::InvalidMessage));
+                Err(RoutingError::SerialisationError(error))
+            }
+        }
+    }
+
+    /// Relays a message between two peers we act as a tunnel for, unless that exceeds their
+    /// bandwidth budget.
+    fn relay_tunnel_message(&mut self,
+                            src: PeerId,
+                            dst: PeerId,
+                            bytes: Vec<u8>,
+                            priority: u8)
+                            -> Result<(), RoutingError> {
+        if self.tunnels.relay(src, dst, bytes.len(), priority) {
+            self.send_or_drop(&dst, bytes, priority)
+        } else {
+            trace!("{:?} Tunnel bandwidth budget exceeded: dropping message from {:?} to {:?}.",
+                   self,
+                   src,
+                   dst);
+            Ok(())
         }
     }
 
@@ -418,9 +668,28 @@ impl Node {
         match direct_message {
             DirectMessage::ClientIdentify { ref serialised_public_id,
                                             ref signature,
-                                            client_restriction } => {
-                if let Ok(public_id) = verify_signed_public_id(serialised_public_id, signature) {
-                    self.handle_client_identify(public_id, peer_id, client_restriction)
+                                            client_restriction,
+                                            session_key } => {
+                let challenge = match self.client_challenges.remove(&peer_id) {
+                    Some(challenge) => challenge,
+                    None => {
+                        debug!("{:?} Received ClientIdentify from {:?} without a challenge.",
+                               self,
+                               peer_id);
+                        let reason = BootstrapDenyReason::ProtocolMismatch;
+                        return self.send_bootstrap_deny(peer_id, reason, vec![]);
+                    }
+                };
+                let verified = verify_client_identify(serialised_public_id,
+                                                      signature,
+                                                      challenge,
+                                                      &peer_id,
+                                                      &session_key);
+                if let Ok(public_id) = verified {
+                    self.handle_client_identify(public_id,
+                                                peer_id,
+                                                client_restriction,
+                                                session_key)
                 } else {
                     warn!("{:?} Signature check failed in ClientIdentify - Dropping connection \
                            {:?}",
@@ -430,8 +699,21 @@ impl Node {
                     Ok(())
                 }
             }
-            DirectMessage::NodeIdentify { ref serialised_public_id, ref signature } => {
-                if let Ok(public_id) = verify_signed_public_id(serialised_public_id, signature) {
+            DirectMessage::NodeIdentify { ref serialised_public_id,
+                                          ref signature,
+                                          session_key } => {
+                let verified = verify_node_identify(serialised_public_id, signature, &session_key);
+                if let Ok(public_id) = verified {
+                    if let Err(error) = self.sessions
+                        .receive_session_key(&peer_id, &public_id, session_key.as_ref()) {
+                        warn!("{:?} Invalid session key in NodeIdentify: {:?} - Dropping peer \
+                               {:?}",
+                              self,
+                              error,
+                              peer_id);
+                        self.disconnect_peer(&peer_id);
+                        return Ok(());
+                    }
                     self.handle_node_identify(public_id, peer_id);
                 } else {
                     warn!("{:?} Signature check failed in NodeIdentify - Dropping peer {:?}",
@@ -464,11 +746,18 @@ impl Node {
                 Ok(())
             }
             DirectMessage::TunnelRequest(dst_id) => self.handle_tunnel_request(peer_id, dst_id),
-            DirectMessage::TunnelSuccess(dst_id) => self.handle_tunnel_success(peer_id, dst_id),
+            DirectMessage::TunnelSuccess(dst_id, headroom) => {
+                self.handle_tunnel_success(peer_id, dst_id, headroom)
+            }
+            DirectMessage::TunnelStandby(dst_id) => self.handle_tunnel_standby(peer_id, dst_id),
             DirectMessage::TunnelClosed(dst_id) => self.handle_tunnel_closed(peer_id, dst_id),
+            DirectMessage::RotateKeys { new_public_id, ref signature } => {
+                self.handle_rotate_keys(peer_id, new_public_id, signature)
+            }
             DirectMessage::TunnelDisconnect(dst_id) => {
                 self.handle_tunnel_disconnect(peer_id, dst_id)
             }
+            DirectMessage::Leave => self.handle_leave(peer_id),
             _ => {
                 debug!("{:?} - Unhandled direct message: {:?}",
                        self,
@@ -482,9 +771,16 @@ impl Node {
                           hop_msg: HopMessage,
                           peer_id: PeerId)
                           -> Result<(), RoutingError> {
-        let hop_name = if let Some(peer) = self.peer_mgr.get_connected_peer(&peer_id) {
-            try!(hop_msg.verify(peer.pub_id().signing_public_key()));
+        let now = self.clock.now();
+        let verified = self.peer_mgr
+            .get_connected_peer(&peer_id)
+            .map(|peer| peer.signing_keys(now).iter().any(|key| hop_msg.verify(key).is_ok()));
+        if verified == Some(false) {
+            try!(self.integrity_check_failed(&peer_id));
+            return Err(RoutingError::FailedSignature);
+        }
 
+        let hop_name = if let Some(peer) = self.peer_mgr.get_connected_peer(&peer_id) {
             match *peer.state() {
                 PeerState::Client => {
                     try!(self.check_valid_client_message(hop_msg.content().routing_message()));
@@ -497,6 +793,11 @@ impl Node {
             return Err(RoutingError::UnknownConnection(peer_id));
         };
 
+        if let Err(error) = hop_msg.content().check_integrity() {
+            try!(self.integrity_check_failed(&peer_id));
+            return Err(error);
+        }
+
         self.handle_signed_message(hop_msg.content(),
                                    hop_msg.route(),
                                    &hop_name,
@@ -510,7 +811,6 @@ impl Node {
                              hop_name: &XorName,
                              sent_to: &[XorName])
                              -> Result<(), RoutingError> {
-        try!(signed_msg.check_integrity());
         let routing_msg = signed_msg.routing_message();
 
         // FIXME: This is currently only in place so acks can get delivered if the
@@ -545,34 +845,43 @@ impl Node {
         }
 
         if count == 1 && self.is_recipient(&routing_msg.dst) {
-            self.handle_routing_message(routing_msg, signed_msg.public_id())
+            try!(self.replay_filter.check(signed_msg, &self.clock));
+            self.handle_routing_message(signed_msg)
         } else {
             Ok(())
         }
     }
 
-    fn handle_routing_message(&mut self,
-                              routing_msg: &RoutingMessage,
-                              public_id: &PublicId)
-                              -> Result<(), RoutingError> {
+    fn handle_routing_message(&mut self, signed_msg: &SignedMessage) -> Result<(), RoutingError> {
         if self.is_proper() {
             let dynamic_quorum_size = self.dynamic_quorum_size();
             self.msg_accumulator.set_quorum_size(dynamic_quorum_size);
         }
 
-        if let Some(msg) = try!(self.accumulate(routing_msg, public_id)) {
+        if signed_msg.routing_message().src.is_group() &&
+           !self.peer_mgr.counts_vote(signed_msg.public_id().name(),
+                                      self.msg_accumulator.quorum_size()) {
+            debug!("{:?} Not counting the vote of distrusted or new peer {:?}.",
+                   self,
+                   signed_msg.public_id().name());
+            return Ok(());
+        }
+
+        if let Some((msg, signatures)) = try!(self.accumulate(signed_msg)) {
             if msg.src.is_group() {
                 self.send_ack(&msg, 0);
             }
 
-            self.dispatch_routing_message(msg)
+            self.dispatch_routing_message(msg, signatures)
         } else {
+            self.schedule_content_request();
             Ok(())
         }
     }
 
     fn dispatch_routing_message(&mut self,
-                                routing_msg: RoutingMessage)
+                                routing_msg: RoutingMessage,
+                                signatures: Vec<(PublicId, sign::Signature)>)
                                 -> Result<(), RoutingError> {
         let msg_content = routing_msg.content.clone();
         let msg_src = routing_msg.src.clone();
@@ -599,10 +908,16 @@ impl Node {
                                                   peer_id,
                                                   message_id)
             }
-            (MessageContent::GetNodeNameResponse { relocated_id, close_group_ids, .. },
+            (MessageContent::GetNodeNameResponse { relocated_id,
+                                                   close_group_ids,
+                                                   resource_proof,
+                                                   .. },
              Authority::NodeManager(_),
              dst) => {
-                self.handle_get_node_name_response(relocated_id, close_group_ids, dst);
+                self.handle_get_node_name_response(relocated_id,
+                                                   close_group_ids,
+                                                   resource_proof,
+                                                   dst);
                 Ok(())
             }
             (MessageContent::ExpectCloseNode { expect_id, client_auth, message_id },
@@ -615,23 +930,27 @@ impl Node {
             }
             (MessageContent::ConnectionInfo { encrypted_connection_info,
                                               nonce_bytes,
-                                              public_id },
+                                              public_id,
+                                              resource_proof },
              src @ Authority::Client { .. },
              Authority::ManagedNode(dst_name)) => {
                 self.handle_connection_info_from_client(encrypted_connection_info,
                                                         nonce_bytes,
                                                         src,
                                                         dst_name,
-                                                        public_id)
+                                                        public_id,
+                                                        resource_proof)
             }
             (MessageContent::ConnectionInfo { encrypted_connection_info,
                                               nonce_bytes,
-                                              public_id },
+                                              public_id,
+                                              .. },
              Authority::ManagedNode(src_name),
              Authority::Client { .. }) |
             (MessageContent::ConnectionInfo { encrypted_connection_info,
                                               nonce_bytes,
-                                              public_id },
+                                              public_id,
+                                              .. },
              Authority::ManagedNode(src_name),
              Authority::ManagedNode(_)) => {
                 self.handle_connection_info_from_node(encrypted_connection_info,
@@ -644,12 +963,29 @@ impl Node {
              Authority::ManagedNode(_),
              dst) => self.handle_get_close_group_response(close_group_ids, dst),
             (MessageContent::Ack(ack, _), _, _) => self.handle_ack_response(ack),
-            (MessageContent::UserMessagePart { hash, part_count, part_index, payload, .. },
+            (MessageContent::GetGroupMessage(hash), src, Authority::ManagedNode(_)) => {
+                self.handle_get_group_message(hash, src)
+            }
+            (MessageContent::GroupMessage(msg), Authority::ManagedNode(_), _) => {
+                self.handle_group_message(*msg)
+            }
+            (MessageContent::UserMessagePart { hash,
+                                               part_count,
+                                               part_index,
+                                               payload,
+                                               priority,
+                                               .. },
              src,
              dst) => {
-                if let Some(msg) = self.user_msg_cache.add(hash, part_count, part_index, payload) {
+                if let Some((msg, part_signatures)) =
+                       self.user_msg_cache.add(hash, part_count, part_index, payload, signatures) {
                     self.stats().count_user_message(&msg);
-                    self.send_event(msg.into_event(src, dst));
+                    let proof = if src.is_group() {
+                        Some(GroupProof::new(src.clone(), dst.clone(), priority, part_signatures))
+                    } else {
+                        None
+                    };
+                    self.send_event(msg.into_event(src, dst, proof));
                 }
                 Ok(())
             }
@@ -663,7 +999,8 @@ impl Node {
     /// Returns `Ok` if a client is allowed to send the given message.
     fn check_valid_client_message(&self, msg: &RoutingMessage) -> Result<(), RoutingError> {
         match msg.content {
-            MessageContent::Ack(..) => Ok(()),
+            MessageContent::Ack(..) |
+            MessageContent::GetGroupMessage(..) => Ok(()),
             MessageContent::UserMessagePart { priority, .. } if priority >= DEFAULT_PRIORITY => {
                 Ok(())
             }
@@ -690,7 +1027,9 @@ impl Node {
                 return Ok(false);
             }
 
-            match self.cacheable_user_msg_cache.add(hash, part_count, part_index, payload.clone()) {
+            match self.cacheable_user_msg_cache
+                .add(hash, part_count, part_index, payload.clone(), vec![])
+                .map(|(user_msg, _)| user_msg) {
                 Some(UserMessage::Request(request)) => {
                     if let Some(response) = self.response_cache.get(&request) {
                         debug!("{:?} Found cached response to {:?}", self, request);
@@ -781,20 +1120,37 @@ impl Node {
         let direct_message = DirectMessage::BootstrapIdentify {
             public_id: *self.full_id.public_id(),
             current_quorum_size: self.dynamic_quorum_size(),
+            session_key: self.sessions.session_key(&peer_id, &self.full_id),
         };
-        self.send_direct_message(&peer_id, direct_message)
+        try!(self.send_direct_message(&peer_id, direct_message));
+        self.sessions.establish(&peer_id);
+        Ok(())
     }
 
     fn handle_client_identify(&mut self,
                               public_id: PublicId,
                               peer_id: PeerId,
-                              client_restriction: bool)
+                              client_restriction: bool,
+                              session_key: Option<SessionKey>)
                               -> Result<(), RoutingError> {
-        if !client_restriction && !self.crust_service.is_peer_whitelisted(&peer_id) {
-            warn!("{:?} Client is not whitelisted - dropping", self);
+        if self.peer_mgr.is_banned(&peer_id, &public_id) {
+            warn!("{:?} Client {:?} is banned - dropping", self, public_id.name());
+            self.disconnect_peer(&peer_id);
+            return Ok(());
+        }
+        if let Err(error) = self.sessions
+            .receive_session_key(&peer_id, &public_id, session_key.as_ref()) {
+            warn!("{:?} Invalid session key from client: {:?} - dropping",
+                  self,
+                  error);
             self.disconnect_peer(&peer_id);
             return Ok(());
         }
+        if !client_restriction && !self.crust_service.is_peer_whitelisted(&peer_id) {
+            warn!("{:?} Client is not whitelisted - dropping", self);
+            let contacts = self.redirect_contacts(public_id.name());
+            return self.send_bootstrap_deny(peer_id, BootstrapDenyReason::NotWhitelisted, contacts);
+        }
         if *public_id.name() != XorName(sha256::hash(&public_id.signing_public_key().0).0) {
             warn!("{:?} Incoming Connection not validated as a proper client - dropping",
                   self);
@@ -816,7 +1172,17 @@ impl Node {
                    public_id.name(),
                    self.peer_mgr.routing_table().len(),
                    MIN_GROUP_SIZE - 1);
-            return self.send_direct_message(&peer_id, DirectMessage::BootstrapDeny);
+            return self.send_bootstrap_deny(peer_id, BootstrapDenyReason::NetworkTooSmall, vec![]);
+        }
+
+        if self.is_proxy_full(client_restriction) {
+            debug!("{:?} Client {:?} rejected: Too many clients or joining nodes.",
+                   self,
+                   public_id.name());
+            let contacts = self.redirect_contacts(public_id.name());
+            return self.send_bootstrap_deny(peer_id,
+                                            BootstrapDenyReason::ClientQuotaReached,
+                                            contacts);
         }
 
         let non_unique = if client_restriction {
@@ -836,21 +1202,74 @@ impl Node {
         self.send_bootstrap_identify(peer_id)
     }
 
+    fn send_bootstrap_deny(&mut self,
+                           peer_id: PeerId,
+                           reason: BootstrapDenyReason,
+                           contacts: Vec<ContactAddr>)
+                           -> Result<(), RoutingError> {
+        let direct_message = DirectMessage::BootstrapDeny {
+            reason: reason,
+            contacts: contacts,
+        };
+        self.send_direct_message(&peer_id, direct_message)
+    }
+
+    // Returns `true` if we can't act as a proxy for another client, or joining node if
+    // `client_restriction` is `false`.
+    fn is_proxy_full(&self, client_restriction: bool) -> bool {
+        if client_restriction {
+            self.peer_mgr.client_num() >= self.proxy_limits.max_clients
+        } else {
+            self.peer_mgr.joining_nodes_num() >= self.proxy_limits.max_joining_nodes
+        }
+    }
+
+    // Returns the addresses of the routing table entries closest to `name`, for a client we can't
+    // act as a proxy for to bootstrap against instead.
+    fn redirect_contacts(&self, name: &XorName) -> Vec<ContactAddr> {
+        self.peer_mgr
+            .closest_contact_addrs(name, MAX_REDIRECT_CONTACTS)
+            .into_iter()
+            .map(ContactAddr)
+            .collect()
+    }
+
     fn handle_node_identify(&mut self, public_id: PublicId, peer_id: PeerId) {
         debug!("{:?} Handling NodeIdentify from {:?}.",
                self,
                public_id.name());
 
+        if self.peer_mgr.is_banned(&peer_id, &public_id) {
+            warn!("{:?} Node {:?} is banned - dropping", self, public_id.name());
+            self.disconnect_peer(&peer_id);
+            return;
+        }
+
         if let Some((name, _)) = self.sent_network_name_to {
             if name == *public_id.name() {
                 self.sent_network_name_to = None;
             }
         }
+        let _ = self.expected_resource_proofs.remove(&public_id);
+
+        // A contact lost in a partition can reach us via a bootstrap connection once it heals. It
+        // doesn't know who we are yet, so we need to identify ourselves, too.
+        if self.partition.is_lost(public_id.name()) &&
+           !self.peer_mgr.is_awaiting_node_identify(&peer_id) {
+            if let Err(error) = self.send_node_identify(peer_id) {
+                warn!("{:?} Failed to send NodeIdentify to {:?}: {:?}",
+                      self,
+                      peer_id,
+                      error);
+            }
+        }
 
         self.add_to_routing_table(public_id, peer_id);
     }
 
     fn add_to_routing_table(&mut self, public_id: PublicId, peer_id: PeerId) {
+        let old_prefix = *self.peer_mgr.routing_table().our_group_prefix();
+        let old_members = self.peer_mgr.routing_table().our_group().clone();
         match self.peer_mgr.add_to_routing_table(public_id, peer_id) {
             Err(RoutingTableError::AlreadyExists) => return,  // already in RT
             Err(error) => {
@@ -867,10 +1286,11 @@ impl Node {
                 }
 
                 if let Some(prefix) = split_prefix {
-                    // None of these will have been in our group, so no need to notify Routing user.
-                    let peers_to_drop = self.peer_mgr.split_group(prefix);
-                    for peer_id in peers_to_drop {
-                        self.disconnect_peer(&peer_id);
+                    if self.partition.is_partitioned() {
+                        debug!("{:?} Not splitting {:?} while partitioned.", self, prefix);
+                        self.partition.defer_split(prefix, *public_id.name());
+                    } else {
+                        self.split_group(prefix);
                     }
                 }
 
@@ -886,16 +1306,27 @@ impl Node {
                     let _ = self.send_direct_message(to_notify, message);
                 }
 
-                if self.peer_mgr.routing_table().is_in_our_group(public_id.name()) {
+                // A contact lost in a partition needs our whole routing table, so that both
+                // sides can reconnect to the peers they lost.
+                let reconnected = self.partition.reconnected(public_id.name());
+                let in_our_group = self.peer_mgr.routing_table().is_in_our_group(public_id.name());
+                if reconnected || in_our_group {
                     let message = DirectMessage::RoutingTable(self.peer_mgr
                         .get_pub_ids(&all_rt_contacts));
                     let _ = self.send_direct_message(&peer_id, message);
-                    let event = Event::NodeAdded(*public_id.name(),
-                                                 self.peer_mgr.routing_table().clone());
-                    if let Err(err) = self.event_sender.send(event) {
-                        error!("{:?} Error sending event to routing user - {:?}", self, err);
+                }
+                if reconnected && !self.partition.is_partitioned() {
+                    info!("{:?} Partition healed.", self);
+                    if let Some((prefix, _)) = self.partition.take_deferred_split() {
+                        self.split_group(prefix);
                     }
                 }
+                let group_change = GroupChange::new(old_prefix,
+                                                    &old_members,
+                                                    self.peer_mgr.routing_table());
+                if in_our_group || group_change.prefix_changed() {
+                    self.send_node_added(*public_id.name(), group_change);
+                }
             }
         }
 
@@ -905,6 +1336,21 @@ impl Node {
         }
     }
 
+    fn send_node_added(&self, name: XorName, group_change: GroupChange<XorName>) {
+        let event = Event::NodeAdded(name, self.peer_mgr.routing_table().clone(), group_change);
+        if let Err(err) = self.event_sender.send(event) {
+            error!("{:?} Error sending event to routing user - {:?}", self, err);
+        }
+    }
+
+    fn split_group(&mut self, prefix: Prefix<XorName>) {
+        // None of these will have been in our group, so no need to notify Routing user.
+        let peers_to_drop = self.peer_mgr.split_group(prefix);
+        for peer_id in peers_to_drop {
+            self.disconnect_peer(&peer_id);
+        }
+    }
+
     fn handle_connection_info_prepared(&mut self,
                                        result_token: u32,
                                        result: Result<PrivConnectionInfo, CrustError>) {
@@ -958,10 +1404,16 @@ impl Node {
                                                    pub_id.encrypting_public_key(),
                                                    self.full_id().encrypting_private_key());
 
+        // Only our new close group needs the resource proof, while we are still joining.
+        let resource_proof = match src {
+            Authority::Client { .. } => self.resource_proof,
+            _ => None,
+        };
         let request_content = MessageContent::ConnectionInfo {
             encrypted_connection_info: encrypted_connection_info,
             nonce_bytes: nonce.0,
             public_id: *self.full_id().public_id(),
+            resource_proof: resource_proof,
         };
 
         let request_msg = RoutingMessage {
@@ -984,8 +1436,25 @@ impl Node {
                                           nonce_bytes: [u8; box_::NONCEBYTES],
                                           src: Authority,
                                           dst_name: XorName,
-                                          their_public_id: PublicId)
+                                          their_public_id: PublicId,
+                                          resource_proof: Option<u64>)
                                           -> Result<(), RoutingError> {
+        if self.resource_proof_difficulty > 0 {
+            self.remove_expired_resource_proofs();
+            let valid = match (self.expected_resource_proofs.get(&their_public_id),
+                               resource_proof) {
+                (Some(&(ref challenge, _)), Some(nonce)) => {
+                    challenge.verify(&their_public_id, nonce)
+                }
+                _ => false,
+            };
+            if !valid {
+                debug!("{:?} Rejecting {:?}: missing or invalid resource proof.",
+                       self,
+                       their_public_id.name());
+                return Err(RoutingError::FailedResourceProof);
+            }
+        }
         try!(self.peer_mgr.allow_connect(their_public_id.name()));
         self.connect(encrypted_connection_info,
                      nonce_bytes,
@@ -1001,7 +1470,10 @@ impl Node {
                                         dst: Authority,
                                         their_public_id: PublicId)
                                         -> Result<(), RoutingError> {
-        try!(self.peer_mgr.allow_connect(&src_name));
+        // Routing peers we are connected to via a tunnel may retry connecting directly.
+        if !self.peer_mgr.is_tunnelled(&src_name) {
+            try!(self.peer_mgr.allow_connect(&src_name));
+        }
         self.connect(encrypted_connection_info,
                      nonce_bytes,
                      their_public_id,
@@ -1020,7 +1492,8 @@ impl Node {
                        self,
                        peer_id,
                        dst_id);
-                return self.send_direct_message(&id0, DirectMessage::TunnelSuccess(id1));
+                let headroom = self.tunnels.headroom();
+                return self.send_direct_message(&id0, DirectMessage::TunnelSuccess(id1, headroom));
             }
         } else {
             debug!("{:?} Rejected tunnel request from {:?} for {:?}.",
@@ -1032,11 +1505,17 @@ impl Node {
     }
 
     /// Handle a `TunnelSuccess` response from `peer_id`: It will act as a tunnel to `dst_id`.
+    ///
+    /// If we already have a tunnel to `dst_id`, `peer_id` is considered as a standby tunnel.
     fn handle_tunnel_success(&mut self,
                              peer_id: PeerId,
-                             dst_id: PeerId)
+                             dst_id: PeerId,
+                             headroom: usize)
                              -> Result<(), RoutingError> {
-        if self.peer_mgr.tunnelling_to(&dst_id) && self.tunnels.add(dst_id, peer_id) {
+        if self.tunnels.tunnel_for(&dst_id).is_some() {
+            return self.add_standby_tunnel(peer_id, dst_id, headroom);
+        }
+        if self.peer_mgr.tunnelling_to(&dst_id) && self.tunnels.add(dst_id, peer_id, headroom) {
             debug!("{:?} Adding {:?} as a tunnel node for {:?}.",
                    self,
                    peer_id,
@@ -1046,6 +1525,57 @@ impl Node {
         Ok(())
     }
 
+    /// Offers `tunnel_id` as a standby tunnel to `dst_id` and asks it to keep the tunnel open if it
+    /// was accepted. A tunnel that was rejected or replaced is only dropped locally: sending it a
+    /// `TunnelDisconnect` would make it disconnect `dst_id`, too.
+    fn add_standby_tunnel(&mut self,
+                          tunnel_id: PeerId,
+                          dst_id: PeerId,
+                          headroom: usize)
+                          -> Result<(), RoutingError> {
+        if let Some(dropped_id) = self.tunnels.add_standby(dst_id, tunnel_id, headroom) {
+            trace!("{:?} Not using {:?} as a standby tunnel node for {:?}.",
+                   self,
+                   dropped_id,
+                   dst_id);
+        }
+        if self.tunnels.is_tunnel(&dst_id, &tunnel_id) {
+            debug!("{:?} Adding {:?} as a standby tunnel node for {:?}.",
+                   self,
+                   tunnel_id,
+                   dst_id);
+            self.send_direct_message(&tunnel_id, DirectMessage::TunnelStandby(dst_id))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Handle a `TunnelStandby` message from `peer_id`, confirming that we should keep acting as
+    /// a tunnel between it and `dst_id`.
+    fn handle_tunnel_standby(&mut self,
+                             peer_id: PeerId,
+                             dst_id: PeerId)
+                             -> Result<(), RoutingError> {
+        if self.tunnels.accept_clients(peer_id, dst_id) {
+            let headroom = self.tunnels.headroom();
+            self.send_direct_message(&dst_id, DirectMessage::TunnelSuccess(peer_id, headroom))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Asks the candidate tunnel nodes for `dst_id`, other than the ones we already use, to act
+    /// as a standby tunnel.
+    fn find_standby_tunnel_for_peer(&mut self, dst_id: PeerId, pub_id: &PublicId) {
+        let candidates = self.peer_mgr.tunnel_candidates(pub_id.name());
+        for (name, tunnel_id) in candidates {
+            if !self.tunnels.is_tunnel(&dst_id, &tunnel_id) {
+                trace!("{:?} Asking {:?} to serve as a standby tunnel.", self, name);
+                let _ = self.send_direct_message(&tunnel_id, DirectMessage::TunnelRequest(dst_id));
+            }
+        }
+    }
+
     /// Handle a `TunnelClosed` message from `peer_id`: `dst_id` disconnected.
     fn handle_tunnel_closed(&mut self,
                             peer_id: PeerId,
@@ -1056,8 +1586,14 @@ impl Node {
                    self,
                    dst_id,
                    peer_id);
-            if !self.crust_service.is_connected(&dst_id) {
-                self.dropped_peer(&dst_id);
+            if self.tunnels.tunnel_for(&dst_id).is_some() {
+                debug!("{:?} Failed over to standby tunnel for {:?}.", self, dst_id);
+                let pub_id = self.peer_mgr.get_routing_peer(&dst_id).cloned();
+                if let Some(pub_id) = pub_id {
+                    self.find_standby_tunnel_for_peer(dst_id, &pub_id);
+                }
+            } else if !self.crust_service.is_connected(&dst_id) {
+                self.dropped_peer(&dst_id, true);
             }
         }
         Ok(())
@@ -1096,16 +1632,19 @@ impl Node {
             debug!("{:?} Not disconnecting client {:?}.", self, peer_id);
         } else if self.peer_mgr.get_joining_node(peer_id).is_some() {
             debug!("{:?} Not disconnecting joining node {:?}.", self, peer_id);
-        } else if let Some(tunnel_id) = self.tunnels.remove_tunnel_for(peer_id) {
+        } else if self.tunnels.tunnel_for(peer_id).is_some() {
             debug!("{:?} Disconnecting {:?} (indirect).", self, peer_id);
-            let message = DirectMessage::TunnelDisconnect(*peer_id);
-            let _ = self.send_direct_message(&tunnel_id, message);
+            for tunnel_id in self.tunnels.remove_tunnel_for(peer_id) {
+                let message = DirectMessage::TunnelDisconnect(*peer_id);
+                let _ = self.send_direct_message(&tunnel_id, message);
+            }
         } else {
             debug!("{:?} Disconnecting {:?}. Calling crust::Service::disconnect.",
                    self,
                    peer_id);
             let _ = self.crust_service.disconnect(*peer_id);
             let _ = self.peer_mgr.remove_peer(peer_id);
+            self.sessions.remove(peer_id);
         }
     }
 
@@ -1118,6 +1657,13 @@ impl Node {
                                     peer_id: PeerId,
                                     message_id: MessageId)
                                     -> Result<(), RoutingError> {
+        if self.partition.is_partitioned() {
+            debug!("{:?} Not relocating {:?} while partitioned.",
+                   self,
+                   their_public_id.name());
+            return Ok(());
+        }
+
         let hashed_key = sha256::hash(&client_key.0);
         let close_group_to_client = XorName(hashed_key.0);
 
@@ -1157,14 +1703,77 @@ impl Node {
     fn handle_get_node_name_response(&mut self,
                                      relocated_id: PublicId,
                                      mut close_group_ids: Vec<PublicId>,
+                                     resource_proof: Option<ResourceProofChallenge>,
                                      dst: Authority) {
-        self.get_node_name_timer_token = None;
+        if let Some(challenge) = resource_proof {
+            if challenge.difficulty() > MAX_DIFFICULTY {
+                // We will time out waiting for a valid response and restart.
+                warn!("{:?} Ignoring GetNodeName response with resource proof {:?}: too difficult.",
+                      self,
+                      challenge);
+                return;
+            }
+        }
+
+        if let Some(token) = self.get_node_name_timer_token.take() {
+            self.timer.cancel(token);
+        }
 
         self.full_id.public_id_mut().set_name(*relocated_id.name());
         self.peer_mgr.reset_routing_table(*self.full_id.public_id());
 
         close_group_ids.truncate(MIN_GROUP_SIZE / 2);
 
+        if let Some(challenge) = resource_proof {
+            info!("{:?} Solving resource proof challenge {:?}.", self, challenge);
+            match ResourceProofSolver::new(challenge, self.full_id.public_id()) {
+                Ok(solver) => {
+                    let token = self.timer.schedule(Duration::from_secs(0));
+                    self.resource_proof_solver = Some((solver, token, close_group_ids, dst));
+                }
+                Err(error) => {
+                    error!("{:?} Failed to solve resource proof: {:?}", self, error);
+                }
+            }
+            return;
+        }
+
+        self.send_connection_info_to_close_group(close_group_ids, dst);
+    }
+
+    // Tries the next nonces for our resource proof. Once it is solved, sends our connection info to
+    // our new close group, otherwise schedules the next step.
+    fn solve_resource_proof_step(&mut self) {
+        let (mut solver, _, close_group_ids, dst) = match self.resource_proof_solver.take() {
+            Some(solver) => solver,
+            None => return,
+        };
+        if let Some(nonce) = solver.step(RESOURCE_PROOF_STEP_NONCES) {
+            info!("{:?} Solved resource proof challenge.", self);
+            self.resource_proof = Some(nonce);
+            self.send_connection_info_to_close_group(close_group_ids, dst);
+        } else {
+            let token = self.timer.schedule(Duration::from_secs(0));
+            self.resource_proof_solver = Some((solver, token, close_group_ids, dst));
+        }
+    }
+
+    fn remove_expired_resource_proofs(&mut self) {
+        let now = self.clock.now();
+        let expiry = Duration::from_secs(RESOURCE_PROOF_EXPIRY_SECS);
+        let expired_ids = self.expected_resource_proofs
+            .iter()
+            .filter(|&(_, &(_, time))| now - time >= expiry)
+            .map(|(public_id, _)| *public_id)
+            .collect_vec();
+        for public_id in expired_ids {
+            let _ = self.expected_resource_proofs.remove(&public_id);
+        }
+    }
+
+    fn send_connection_info_to_close_group(&mut self,
+                                           close_group_ids: Vec<PublicId>,
+                                           dst: Authority) {
         for close_node_id in close_group_ids {
             debug!("{:?} Sending connection info to {:?} on GetNodeName response.",
                    self,
@@ -1191,7 +1800,14 @@ impl Node {
             return Ok(());
         }
 
-        let now = Instant::now();
+        if self.partition.is_partitioned() {
+            debug!("{:?} Not accepting {:?} into our group while partitioned.",
+                   self,
+                   expect_id.name());
+            return Ok(());
+        }
+
+        let now = self.clock.now();
         if let Some((_, timestamp)) = self.sent_network_name_to {
             if (now - timestamp).as_secs() <= SENT_NETWORK_NAME_TIMEOUT_SECS {
                 return Ok(()); // Not sending node name, as we are already waiting for a node.
@@ -1206,11 +1822,20 @@ impl Node {
             None => return Err(RoutingError::InvalidDestination),
         };
 
+        // All of us compute the same challenge, so that the response accumulates.
+        let difficulty = self.resource_proof_difficulty;
+        let resource_proof = try!(ResourceProofChallenge::new(&expect_id, &message_id, difficulty));
+        if let Some(challenge) = resource_proof {
+            self.remove_expired_resource_proofs();
+            let _ = self.expected_resource_proofs.insert(expect_id, (challenge, now));
+        }
+
         self.sent_network_name_to = Some((*expect_id.name(), now));
         // From Y -> A (via B)
         let response_content = MessageContent::GetNodeNameResponse {
             relocated_id: expect_id,
             close_group_ids: public_ids.into_iter().collect(),
+            resource_proof: resource_proof,
             message_id: message_id,
         };
 
@@ -1279,11 +1904,100 @@ impl Node {
     }
 
     fn handle_ack_response(&mut self, ack: Ack) -> Result<(), RoutingError> {
-        self.ack_mgr.receive(ack);
+        if let Some(unacked_msg) = self.ack_mgr.receive(ack) {
+            self.timer.cancel(unacked_msg.timer_token);
+        }
         Ok(())
     }
 
+    fn handle_get_group_message(&mut self,
+                                hash: sha256::Digest,
+                                src: Authority)
+                                -> Result<(), RoutingError> {
+        let msg = match self.sent_group_msgs.get(&hash) {
+            Some(msg) => msg.clone(),
+            None => {
+                debug!("{:?} Don't have the content of requested group message {}.",
+                       self,
+                       utils::format_binary_array(&hash.0));
+                return Ok(());
+            }
+        };
+
+        let response = RoutingMessage {
+            src: Authority::ManagedNode(*self.name()),
+            dst: src,
+            content: MessageContent::GroupMessage(Box::new(msg)),
+        };
+        self.send_routing_message(response)
+    }
+
+    fn handle_group_message(&mut self, msg: RoutingMessage) -> Result<(), RoutingError> {
+        // The content is only accepted if its hash was accumulated from the source group.
+        if let Some((msg, signatures)) = self.msg_accumulator.add_content(msg) {
+            self.send_ack(&msg, 0);
+            self.dispatch_routing_message(msg, signatures)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Schedules a request for missing group message contents, unless one is already pending.
+    fn schedule_content_request(&mut self) {
+        if self.content_request_timer_token.is_none() &&
+           self.msg_accumulator.has_missing_content() {
+            let delay = Duration::from_secs(CONTENT_REQUEST_DELAY_SECS);
+            self.content_request_timer_token = Some(self.timer.schedule(delay));
+        }
+    }
+
+    /// Asks members of the source groups for the content of the group messages of which we only
+    /// accumulated the hash.
+    fn request_missing_content(&mut self) {
+        let our_name = *self.name();
+        for (hash, member) in self.msg_accumulator.content_requests(&our_name) {
+            let request = RoutingMessage {
+                src: Authority::ManagedNode(our_name),
+                dst: Authority::ManagedNode(member),
+                content: MessageContent::GetGroupMessage(hash),
+            };
+            if let Err(error) = self.send_routing_message(request) {
+                debug!("{:?} Failed to request group message content: {:?}",
+                       self,
+                       error);
+            }
+        }
+        self.schedule_content_request();
+    }
+
+    /// Remembers the content of a group message we send, so that recipients who only receive its
+    /// hash can request it from us.
+    fn remember_group_msg(&mut self, routing_msg: &RoutingMessage) {
+        if !routing_msg.src.is_group() {
+            return;
+        }
+        if let Ok(RoutingMessage { content: MessageContent::GroupMessageHash(hash, _), .. }) =
+               routing_msg.to_grp_msg_hash() {
+            let _ = self.sent_group_msgs.insert(hash, routing_msg.clone());
+        }
+    }
+
     fn handle_timeout(&mut self, token: u64) -> bool {
+        match self.shutdown {
+            Some(Shutdown::Draining(shutdown_token)) if shutdown_token == token => {
+                info!("{:?} Timed out waiting for {} messages to be acknowledged.",
+                      self,
+                      self.ack_mgr.timer_tokens().len());
+                self.leave();
+                return true;
+            }
+            Some(Shutdown::Leaving(shutdown_token)) if shutdown_token == token => {
+                self.send_event(Event::Terminate);
+                return false;
+            }
+            _ => (),
+        }
+
         if self.get_node_name_timer_token == Some(token) {
             info!("{:?} Failed to get GetNodeName response.", self);
             self.send_event(Event::RestartRequired);
@@ -1292,14 +2006,30 @@ impl Node {
 
         if self.tick_timer_token == token {
             let _ = self.event_sender.send(Event::Tick);
-            let tick_period = Duration::from_secs(TICK_TIMEOUT_SECS);
-            self.tick_timer_token = self.timer.schedule(tick_period);
+            self.peer_mgr.increment_ages();
 
             for peer_id in self.peer_mgr.remove_expired_connections() {
                 debug!("{:?} Disconnecting from timed out peer {:?}", self, peer_id);
                 let _ = self.crust_service.disconnect(peer_id);
             }
+            self.retry_direct_connections();
+
+            return self.handle_partition_tick();
+        }
+
+        if self.resource_proof_solver.as_ref().map_or(false, |&(_, t, _, _)| t == token) {
+            self.solve_resource_proof_step();
+            return true;
+        }
+
+        if self.content_request_timer_token == Some(token) {
+            self.content_request_timer_token = None;
+            self.request_missing_content();
+            return true;
+        }
 
+        if self.key_rotation_timer_token == token {
+            self.rotate_keys();
             return true;
         }
 
@@ -1308,6 +2038,121 @@ impl Node {
         true
     }
 
+    // Tries to replace the tunnels to our routing peers with direct connections, in case the
+    // network conditions changed since connecting failed. On success, the tunnels are closed.
+    fn retry_direct_connections(&mut self) {
+        let src = Authority::ManagedNode(*self.name());
+        for pub_id in self.peer_mgr.tunnelled_peers() {
+            let dst = Authority::ManagedNode(*pub_id.name());
+            if let Some(token) = self.peer_mgr.start_direct_upgrade(src.clone(), dst, pub_id) {
+                debug!("{:?} Retrying to connect directly to tunnelled peer {:?}.",
+                       self,
+                       pub_id.name());
+                self.crust_service.prepare_connection_info(token);
+            }
+        }
+    }
+
+    // Replaces our keys with new ones and announces them to all our peers, signed with the old key.
+    // They keep accepting messages signed with the old key for a grace period, so that messages
+    // still in transit don't get lost. Peers don't accept another rotation before the grace
+    // period is over.
+    fn rotate_keys(&mut self) {
+        if !self.is_proper() {
+            return; // Our name might still change.
+        }
+        let now = self.clock.now();
+        let grace_period = Duration::from_secs(KEY_ROTATION_GRACE_PERIOD_SECS);
+        if self.last_key_rotation.map_or(false, |rotated_at| now - rotated_at < grace_period) {
+            debug!("{:?} Not rotating keys again within the grace period.", self);
+            return;
+        }
+        let new_full_id = self.full_id.rotated();
+        let new_public_id = *new_full_id.public_id();
+        let serialised_public_id = match serialisation::serialise(&new_public_id) {
+            Ok(serialised_public_id) => serialised_public_id,
+            Err(error) => {
+                error!("{:?} Failed to serialise public ID: {:?}", self, error);
+                return;
+            }
+        };
+        let signature = sign::sign_detached(&serialised_public_id,
+                                            self.full_id.signing_private_key());
+        for peer_id in self.peer_mgr.connected_peer_ids() {
+            let direct_message = DirectMessage::RotateKeys {
+                new_public_id: new_public_id,
+                signature: signature,
+            };
+            if let Err(error) = self.send_direct_message(&peer_id, direct_message) {
+                debug!("{:?} Failed to announce new keys to {:?}: {:?}",
+                       self,
+                       peer_id,
+                       error);
+            }
+        }
+        self.full_id = new_full_id;
+        self.last_key_rotation = Some(now);
+        self.peer_mgr.set_our_public_id(new_public_id);
+        info!("{:?} Rotated keys.", self);
+    }
+
+    fn handle_rotate_keys(&mut self,
+                          peer_id: PeerId,
+                          new_public_id: PublicId,
+                          signature: &sign::Signature)
+                          -> Result<(), RoutingError> {
+        let old_key = match self.peer_mgr.get_connected_peer(&peer_id) {
+            Some(peer) => *peer.pub_id().signing_public_key(),
+            None => return Err(RoutingError::UnknownConnection(peer_id)),
+        };
+        let serialised_public_id = try!(serialisation::serialise(&new_public_id));
+        if !sign::verify_detached(signature, &serialised_public_id, &old_key) {
+            try!(self.integrity_check_failed(&peer_id));
+            return Err(RoutingError::FailedSignature);
+        }
+        if !self.peer_mgr.rotate_keys(&peer_id, new_public_id) {
+            return Err(RoutingError::RejectedPublicId);
+        }
+        debug!("{:?} {:?} rotated its keys.", self, new_public_id.name());
+        Ok(())
+    }
+
+    // Gives up on a partition that didn't heal in time, or otherwise tries to reach the lost
+    // contacts again. Returns true if we should keep running, false if we should terminate.
+    fn handle_partition_tick(&mut self) -> bool {
+        if self.partition.expire() {
+            info!("{:?} Partition didn't heal in time. Giving up on lost contacts.",
+                  self);
+            if let Some((prefix, name)) = self.partition.take_deferred_split() {
+                // Raise the event the split would have caused when `name` was added.
+                let old_prefix = *self.peer_mgr.routing_table().our_group_prefix();
+                let old_members = self.peer_mgr.routing_table().our_group().clone();
+                self.split_group(prefix);
+                let group_change = GroupChange::new(old_prefix,
+                                                    &old_members,
+                                                    self.peer_mgr.routing_table());
+                if group_change.prefix_changed() {
+                    self.send_node_added(name, group_change);
+                }
+            }
+            if self.peer_mgr.routing_table().len() < MIN_GROUP_SIZE - 1 && !self.is_first_node {
+                self.send_event(Event::RestartRequired);
+                return false;
+            }
+            return true;
+        }
+
+        // Our bootstrap contacts might lead us back to the other side. Once any node there is
+        // reconnected, the routing table exchange will take care of the rest.
+        if self.partition.start_bootstrap() {
+            if let Err(error) = self.crust_service.start_bootstrap(HashSet::new()) {
+                debug!("{:?} Failed to start bootstrapping: {:?}", self, error);
+                self.partition.bootstrap_finished();
+            }
+        }
+        true
+    }
+
     fn connect(&mut self,
                encrypted_connection_info: Vec<u8>,
                nonce_bytes: [u8; box_::NONCEBYTES],
@@ -1325,8 +2170,16 @@ impl Node {
         let their_connection_info: PubConnectionInfo =
             try!(serialisation::deserialise(&serialised_connection_info));
         let peer_id = their_connection_info.id();
-        match self.peer_mgr
-            .connection_info_received(src, dst, their_public_id, their_connection_info) {
+        let contact_addrs = match src {
+            Authority::ManagedNode(_) => Some(direct_addrs(&their_connection_info)),
+            _ => None,
+        };
+        let result = self.peer_mgr
+            .connection_info_received(src, dst, their_public_id, their_connection_info);
+        if let Some(addrs) = contact_addrs {
+            self.peer_mgr.set_contact_addrs(*their_public_id.name(), addrs);
+        }
+        match result {
             Ok(ConnectionInfoReceivedResult::Ready(our_info, their_info)) => {
                 debug!("{:?} Received connection info. Trying to connect to {:?} ({:?}).",
                        self,
@@ -1398,7 +2251,7 @@ impl Node {
 
         let (new_sent_to, target_peer_ids) = try!(self.get_targets(routing_msg, route, sent_to));
 
-        if !self.add_to_pending_acks(signed_msg, route) {
+        if !self.add_to_pending_acks(signed_msg, route, &target_peer_ids) {
             return Ok(());
         }
 
@@ -1476,13 +2329,21 @@ impl Node {
             return Ok(signed_msg.clone());
         }
 
-        // TODO: Better distribute the work among the group.
-        if self.peer_mgr.routing_table().should_route_full_message(src.name(), route as usize) {
+        let hash_msg = try!(signed_msg.routing_message().to_grp_msg_hash());
+        let hash = match hash_msg.content {
+            MessageContent::GroupMessageHash(hash, _) => hash,
+            _ => return Ok(signed_msg.clone()),
+        };
+
+        // The member closest to the message's hash sends the full message, so that the work is
+        // spread over the whole group. If it fails to, recipients request it from the others.
+        if self.peer_mgr
+            .routing_table()
+            .should_route_full_message(&XorName(hash.0), route as usize) {
             return Ok(signed_msg.clone());
         }
 
-        SignedMessage::new(try!(signed_msg.routing_message().to_grp_msg_hash()),
-                           &self.full_id)
+        SignedMessage::new(hash_msg, &self.full_id, &self.clock)
     }
 
     /// Returns whether we are the recipient of a message for the given authority.
@@ -1547,14 +2408,18 @@ impl Node {
 
     fn send_node_identify(&mut self, peer_id: PeerId) -> Result<(), RoutingError> {
         let serialised_public_id = try!(serialisation::serialise(self.full_id().public_id()));
-        let signature = sign::sign_detached(&serialised_public_id,
-                                            self.full_id().signing_private_key());
+        let session_key = self.sessions.session_key(&peer_id, &self.full_id);
+        let signed_bytes = try!(node_identify_signed_bytes(&serialised_public_id, &session_key));
+        let signature = sign::sign_detached(&signed_bytes, self.full_id().signing_private_key());
         let direct_message = DirectMessage::NodeIdentify {
             serialised_public_id: serialised_public_id,
             signature: signature,
+            session_key: session_key,
         };
 
-        self.send_direct_message(&peer_id, direct_message)
+        try!(self.send_direct_message(&peer_id, direct_message));
+        self.sessions.establish(&peer_id);
+        Ok(())
     }
 
     fn send_connection_info(&mut self,
@@ -1581,15 +2446,80 @@ impl Node {
         Ok(())
     }
 
-    // Handle dropped peer with the given peer id. Returns true if we should keep
-    // running, false if we should terminate.
-    fn dropped_peer(&mut self, peer_id: &PeerId) -> bool {
+    // Penalises the peer for the given misbehaviour, and evicts it if it misbehaved too often.
+    fn misbehaved(&mut self,
+                  peer_id: &PeerId,
+                  misbehaviour: Misbehaviour)
+                  -> Result<(), RoutingError> {
+        debug!("{:?} Peer {:?} misbehaved: {:?}", self, peer_id, misbehaviour);
+        if !self.peer_mgr.report_misbehaviour(peer_id, misbehaviour) {
+            return Ok(());
+        }
+        warn!("{:?} Evicting {:?} for repeated misbehaviour.", self, peer_id);
+        self.evict(peer_id)
+    }
+
+    // Handles a message from the given peer that failed the integrity check, and bans the peer
+    // temporarily if that happened too often.
+    fn integrity_check_failed(&mut self, peer_id: &PeerId) -> Result<(), RoutingError> {
+        if self.peer_mgr.record_integrity_failure(peer_id) {
+            warn!("{:?} Banning {:?} for repeated integrity failures.", self, peer_id);
+            self.evict(peer_id)
+        } else {
+            self.misbehaved(peer_id, Misbehaviour::FailedSignature)
+        }
+    }
+
+    // Bans the given key and evicts any peers using it.
+    fn ban(&mut self, key: sign::PublicKey) -> Result<(), RoutingError> {
+        for peer_id in self.peer_mgr.ban(key) {
+            info!("{:?} Disconnecting from banned peer {:?}.", self, peer_id);
+            try!(self.evict(&peer_id));
+        }
+        Ok(())
+    }
+
+    // The peer is shutting down: drop it without waiting for Crust to report the lost connection.
+    fn handle_leave(&mut self, peer_id: PeerId) -> Result<(), RoutingError> {
+        debug!("{:?} Peer {:?} is leaving the network.", self, peer_id);
+        self.evict(&peer_id)
+    }
+
+    // Disconnects from the given peer, even if it is in our routing table, and drops it the same
+    // way as a lost peer. Returns `Err(RoutingError::Terminated)` if we should terminate.
+    fn evict(&mut self, peer_id: &PeerId) -> Result<(), RoutingError> {
+        let _ = self.crust_service.disconnect(*peer_id);
+        self.dropped_tunnel_client(peer_id);
+        self.dropped_tunnel_node(peer_id);
+        for tunnel_id in self.tunnels.remove_tunnel_for(peer_id) {
+            let message = DirectMessage::TunnelDisconnect(*peer_id);
+            let _ = self.send_direct_message(&tunnel_id, message);
+        }
+        if self.dropped_peer(peer_id, false) {
+            Ok(())
+        } else {
+            Err(RoutingError::Terminated)
+        }
+    }
+
+    // Handle dropped peer with the given peer id. Only a lost connection counts as a sign of a
+    // network partition: we don't want to reconnect to peers we evicted or that left. Returns
+    // true if we should keep running, false if we should terminate.
+    fn dropped_peer(&mut self, peer_id: &PeerId, connection_lost: bool) -> bool {
+        self.sessions.remove(peer_id);
+        let _ = self.client_challenges.remove(peer_id);
         let (peer, removal_result) = match self.peer_mgr.remove_peer(peer_id) {
             Some(result) => result,
             None => return true,
         };
 
         if let Ok(removal_details) = removal_result {
+            if connection_lost &&
+               self.partition.record_loss(*peer.pub_id(), self.peer_mgr.routing_table()) {
+                warn!("{:?} Lost {} contacts at once. Assuming a network partition.",
+                      self,
+                      self.partition.lost_contacts().len());
+            }
             if !self.dropped_routing_node(removal_details) {
                 return false;
             }
@@ -1611,7 +2541,8 @@ impl Node {
                        peer.name(),
                        peer_id);
 
-                if self.peer_mgr.routing_table().len() < MIN_GROUP_SIZE - 1 {
+                if self.peer_mgr.routing_table().len() < MIN_GROUP_SIZE - 1 &&
+                   !self.partition.is_partitioned() {
                     self.send_event(Event::Terminate);
                     return false;
                 }
@@ -1630,7 +2561,14 @@ impl Node {
               details.name);
 
         if details.was_in_our_group {
-            let event = Event::NodeLost(details.name, self.peer_mgr.routing_table().clone());
+            let mut old_members = self.peer_mgr.routing_table().our_group().clone();
+            let _ = old_members.insert(details.name);
+            let group_change = GroupChange::new(*self.peer_mgr.routing_table().our_group_prefix(),
+                                                &old_members,
+                                                self.peer_mgr.routing_table());
+            let event = Event::NodeLost(details.name,
+                                        self.peer_mgr.routing_table().clone(),
+                                        group_change);
             if let Err(err) = self.event_sender.send(event) {
                 error!("{:?} Error sending event to routing user - {:?}", self, err);
             }
@@ -1645,7 +2583,10 @@ impl Node {
             debug!("{:?} Lost connection, less than {} remaining.",
                    self,
                    MIN_GROUP_SIZE - 1);
-            if !self.is_first_node {
+            if self.partition.is_partitioned() {
+                debug!("{:?} Waiting for the partition to heal instead of restarting.",
+                       self);
+            } else if !self.is_first_node {
                 self.send_event(Event::RestartRequired);
                 return false;
             }
@@ -1670,7 +2611,15 @@ impl Node {
             })
             .collect_vec();
         for (dst_id, pub_id) in peers {
-            self.dropped_peer(&dst_id);
+            if self.tunnels.tunnel_for(&dst_id).is_some() {
+                debug!("{:?} Lost tunnel for peer {:?} ({:?}). Failed over to standby tunnel.",
+                       self,
+                       dst_id,
+                       pub_id.name());
+                self.find_standby_tunnel_for_peer(dst_id, &pub_id);
+                continue;
+            }
+            self.dropped_peer(&dst_id, true);
             debug!("{:?} Lost tunnel for peer {:?} ({:?}). Requesting new tunnel.",
                    self,
                    dst_id,
@@ -1721,10 +2670,14 @@ impl Base for Node {
 
         debug!("{:?} Received LostPeer - {:?}", self, peer_id);
 
+        if let Some(Shutdown::Leaving(_)) = self.shutdown {
+            return Transition::Stay; // Our peers are disconnecting because we announced to leave.
+        }
+
         self.dropped_tunnel_client(&peer_id);
         self.dropped_tunnel_node(&peer_id);
 
-        if self.dropped_peer(&peer_id) {
+        if self.dropped_peer(&peer_id, true) {
             Transition::Stay
         } else {
             Transition::Terminate
@@ -1738,6 +2691,10 @@ impl Base for Node {
     fn stats(&mut self) -> &mut Stats {
         &mut self.stats
     }
+
+    fn sessions(&mut self) -> &mut Sessions {
+        &mut self.sessions
+    }
 }
 
 #[cfg(feature = "use-mock-crust")]
@@ -1761,6 +2718,18 @@ impl Node {
         self.ack_mgr.has_pending()
     }
 
+    pub fn is_partitioned(&self) -> bool {
+        self.partition.is_partitioned()
+    }
+
+    pub fn retry_lost_contacts(&mut self) {
+        let _ = self.handle_partition_tick();
+    }
+
+    pub fn rotate_keys_now(&mut self) {
+        self.rotate_keys();
+    }
+
     pub fn clear_state(&mut self) {
         self.ack_mgr.clear();
         self.msg_accumulator.clear();
@@ -1772,10 +2741,10 @@ impl Node {
 
 impl Bootstrapped for Node {
     fn accumulate(&mut self,
-                  routing_msg: &RoutingMessage,
-                  public_id: &PublicId)
-                  -> Result<Option<RoutingMessage>, RoutingError> {
-        self.msg_accumulator.add(routing_msg, public_id)
+                  signed_msg: &SignedMessage)
+                  -> Result<Option<(RoutingMessage, Vec<(PublicId, sign::Signature)>)>,
+                            RoutingError> {
+        self.msg_accumulator.add(signed_msg)
     }
 
     fn ack_mgr(&self) -> &AckManager {
@@ -1790,7 +2759,15 @@ impl Bootstrapped for Node {
                                       routing_msg: RoutingMessage,
                                       route: u8)
                                       -> Result<(), RoutingError> {
-        let signed_msg = try!(SignedMessage::new(routing_msg, &self.full_id));
+        self.remember_group_msg(&routing_msg);
+        let signed_msg = try!(SignedMessage::new(routing_msg, &self.full_id, &self.clock));
+        self.send_signed_message_via_route(signed_msg, route)
+    }
+
+    fn send_signed_message_via_route(&mut self,
+                                     signed_msg: SignedMessage,
+                                     route: u8)
+                                     -> Result<(), RoutingError> {
         let hop = *self.name();
         try!(self.send_signed_message(&signed_msg, route, &hop, &[hop]));
 
@@ -1798,12 +2775,20 @@ impl Bootstrapped for Node {
         let sent_msg = try!(self.message_to_send(&signed_msg, route, &hop));
         if self.is_recipient(&sent_msg.routing_message().dst) &&
            self.signed_msg_filter.filter_incoming(&sent_msg) == 1 {
-            self.handle_routing_message(sent_msg.routing_message(), sent_msg.public_id())
+            self.handle_routing_message(&sent_msg)
         } else {
             Ok(())
         }
     }
 
+    fn relays_unacknowledged(&mut self, relays: &[PeerId]) {
+        for peer_id in relays {
+            debug!("{:?} Peer {:?} misbehaved: {:?}", self, peer_id, Misbehaviour::UnackedRelay);
+            // Unacknowledged relays alone never get a peer evicted.
+            let _ = self.peer_mgr.report_misbehaviour(peer_id, Misbehaviour::UnackedRelay);
+        }
+    }
+
     fn signed_msg_filter(&mut self) -> &mut SignedMessageFilter {
         &mut self.signed_msg_filter
     }
@@ -1819,13 +2804,49 @@ impl Debug for Node {
     }
 }
 
+// Verifies the signature of a `ClientIdentify` with the given challenge and session key, sent to
+// the given peer.
+fn verify_client_identify(serialised_public_id: &[u8],
+                          signature: &sign::Signature,
+                          challenge: u64,
+                          peer_id: &PeerId,
+                          session_key: &Option<SessionKey>)
+                          -> Result<PublicId, RoutingError> {
+    let signed_bytes = try!(client_identify_signed_bytes(serialised_public_id,
+                                                         challenge,
+                                                         peer_id,
+                                                         session_key));
+    verify_signed_public_id(serialised_public_id, &signed_bytes, signature)
+}
+
+// Verifies the signature of a `NodeIdentify` with the given session key.
+fn verify_node_identify(serialised_public_id: &[u8],
+                        signature: &sign::Signature,
+                        session_key: &Option<SessionKey>)
+                        -> Result<PublicId, RoutingError> {
+    let signed_bytes = try!(node_identify_signed_bytes(serialised_public_id, session_key));
+    verify_signed_public_id(serialised_public_id, &signed_bytes, signature)
+}
+
+// Returns the addresses the peer with the given connection info can be reached at directly.
+#[cfg(not(feature = "use-mock-crust"))]
+fn direct_addrs(connection_info: &PubConnectionInfo) -> Vec<SocketAddr> {
+    connection_info.for_direct.clone()
+}
+
+#[cfg(feature = "use-mock-crust")]
+fn direct_addrs(connection_info: &PubConnectionInfo) -> Vec<SocketAddr> {
+    connection_info.direct_addrs()
+}
+
 // Verify the serialised public id against the signature.
 fn verify_signed_public_id(serialised_public_id: &[u8],
+                           signed_bytes: &[u8],
                            signature: &sign::Signature)
                            -> Result<PublicId, RoutingError> {
     let public_id: PublicId = try!(serialisation::deserialise(serialised_public_id));
     let public_key = public_id.signing_public_key();
-    if sign::verify_detached(signature, serialised_public_id, public_key) {
+    if sign::verify_detached(signature, signed_bytes, public_key) {
         Ok(public_id)
     } else {
         Err(RoutingError::FailedSignature)
@@ -17,6 +17,7 @@
 
 use messages::{DirectMessage, MessageContent, Request, Response, RoutingMessage, UserMessage};
 use peer_manager::MIN_GROUP_SIZE;
+use tunnels::TunnelTraffic;
 
 /// The number of messages after which the message statistics should be printed.
 const MSG_LOG_COUNT: usize = 1000;
@@ -30,6 +31,8 @@ pub struct Stats {
     pub cumulative_client_num: usize,
     pub tunnel_client_pairs: usize,
     pub tunnel_connections: usize,
+    /// The traffic we relayed as a tunnel node.
+    pub tunnel_traffic: TunnelTraffic,
 
     /// Messages sent by us on different routes.
     routes: [usize; MIN_GROUP_SIZE],
@@ -63,6 +66,8 @@ pub struct Stats {
     msg_get_node_name_rsp: usize,
     msg_ack: usize,
     msg_hash: usize,
+    msg_get_group_msg: usize,
+    msg_group_msg: usize,
 
     msg_other: usize,
 
@@ -128,6 +133,8 @@ impl Stats {
             MessageContent::GetNodeNameResponse { .. } => self.msg_get_node_name_rsp += 1,
             MessageContent::Ack(..) => self.msg_ack += 1,
             MessageContent::GroupMessageHash(..) => self.msg_hash += 1,
+            MessageContent::GetGroupMessage(..) => self.msg_get_group_msg += 1,
+            MessageContent::GroupMessage(..) => self.msg_group_msg += 1,
             MessageContent::UserMessagePart { .. } => return, // Counted as request/response.
         }
         self.increment_msg_total();
@@ -161,8 +168,13 @@ impl Stats {
             info!("Stats - Direct - NodeIdentify: {}, NewNode: {}",
                   self.msg_direct_node_identify,
                   self.msg_direct_new_node);
+            info!("Stats - Tunnel - Relayed messages/bytes: {}/{}, dropped messages: {}",
+                  self.tunnel_traffic.msgs,
+                  self.tunnel_traffic.bytes,
+                  self.tunnel_traffic.dropped_msgs);
             info!("Stats - Hops (Request/Response) - GetNodeName: {}/{}, ExpectCloseNode: {}, \
-                   GetCloseGroup: {}/{}, ConnectionInfo: {}, Ack: {}, GroupMessageHash: {}",
+                   GetCloseGroup: {}/{}, ConnectionInfo: {}, Ack: {}, GroupMessageHash: {}, \
+                   GetGroupMessage: {}/{}",
                   self.msg_get_node_name,
                   self.msg_get_node_name_rsp,
                   self.msg_expect_close_node,
@@ -170,7 +182,9 @@ impl Stats {
                   self.msg_get_close_group_rsp,
                   self.msg_connection_info,
                   self.msg_ack,
-                  self.msg_hash);
+                  self.msg_hash,
+                  self.msg_get_group_msg,
+                  self.msg_group_msg);
             info!("Stats - User (Request/Success/Failure) - Get: {}/{}/{}, Put: {}/{}/{}, \
                    Post: {}/{}/{}, Delete: {}/{}/{}, GetAccountInfo: {}/{}/{}, Refresh: {}",
                   self.msg_get,
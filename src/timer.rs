@@ -20,38 +20,46 @@ pub use self::implementation::Timer;
 #[cfg(not(feature = "use-mock-crust"))]
 mod implementation {
     use action::Action;
+    use clock::Clock;
     use itertools::Itertools;
     use maidsafe_utilities::thread::{self, Joiner};
-    use std::collections::BTreeMap;
+    use std::collections::{BTreeMap, HashMap};
     use std::sync::{Arc, Condvar, Mutex};
     use std::time::{Duration, Instant};
     use types::RoutingActionSender;
 
     struct Detail {
         deadlines: BTreeMap<Instant, Vec<u64>>,
+        /// The periods of the repeating timeouts, by token.
+        periods: HashMap<u64, Duration>,
         cancelled: bool,
     }
 
     /// Simple timer.
     pub struct Timer {
+        clock: Clock,
         next_token: u64,
         detail_and_cond_var: Arc<(Mutex<Detail>, Condvar)>,
         _worker: Joiner,
     }
 
     impl Timer {
-        /// Creates a new timer, passing a channel sender used to send `Timeout` events.
-        pub fn new(sender: RoutingActionSender) -> Self {
+        /// Creates a new timer, passing a channel sender used to send `Timeout` events, and the
+        /// clock the deadlines are measured with.
+        pub fn new(sender: RoutingActionSender, clock: &Clock) -> Self {
             let detail = Detail {
                 deadlines: BTreeMap::new(),
+                periods: HashMap::new(),
                 cancelled: false,
             };
             let detail_and_cond_var = Arc::new((Mutex::new(detail), Condvar::new()));
             let detail_and_cond_var_clone = detail_and_cond_var.clone();
+            let worker_clock = clock.clone();
             let worker = thread::named("Timer", move || {
-                Self::run(sender, detail_and_cond_var)
+                Self::run(sender, worker_clock, detail_and_cond_var)
             });
             Timer {
+                clock: clock.clone(),
                 next_token: 0,
                 detail_and_cond_var: detail_and_cond_var_clone,
                 _worker: worker,
@@ -61,21 +69,56 @@ mod implementation {
         /// Schedules a timeout event after `duration`. Returns a token that can be used to identify
         /// the timeout event.
         pub fn schedule(&mut self, duration: Duration) -> u64 {
+            self.schedule_with_period(duration, None)
+        }
+
+        /// Schedules a timeout event every `period`, until it is cancelled. Returns a token that
+        /// is used for all of these timeout events.
+        pub fn schedule_repeating(&mut self, period: Duration) -> u64 {
+            self.schedule_with_period(period, Some(period))
+        }
+
+        /// Cancels the timeout event with the given token. It is harmless to cancel a timeout that
+        /// has already fired.
+        pub fn cancel(&mut self, token: u64) {
+            let &(ref mutex, ref cond_var) = &*self.detail_and_cond_var;
+            let mut detail = mutex.lock().expect("Failed to lock.");
+            let _ = detail.periods.remove(&token);
+            let empty_deadlines = detail.deadlines
+                .iter_mut()
+                .filter_map(|(deadline, tokens)| {
+                    tokens.retain(|&t| t != token);
+                    if tokens.is_empty() { Some(*deadline) } else { None }
+                })
+                .collect_vec();
+            for deadline in empty_deadlines {
+                let _ = detail.deadlines.remove(&deadline);
+            }
+            cond_var.notify_one();
+        }
+
+        fn schedule_with_period(&mut self, duration: Duration, period: Option<Duration>) -> u64 {
             let token = self.next_token;
             self.next_token = token.wrapping_add(1);
             let &(ref mutex, ref cond_var) = &*self.detail_and_cond_var;
             let mut detail = mutex.lock().expect("Failed to lock.");
-            detail.deadlines.entry(Instant::now() + duration).or_insert_with(Vec::new).push(token);
+            let deadline = self.clock.now() + duration;
+            detail.deadlines.entry(deadline).or_insert_with(Vec::new).push(token);
+            if let Some(period) = period {
+                let _ = detail.periods.insert(token, period);
+            }
             cond_var.notify_one();
             token
         }
 
-        fn run(sender: RoutingActionSender, detail_and_cond_var: Arc<(Mutex<Detail>, Condvar)>) {
+        fn run(sender: RoutingActionSender,
+               clock: Clock,
+               detail_and_cond_var: Arc<(Mutex<Detail>, Condvar)>) {
             let &(ref mutex, ref cond_var) = &*detail_and_cond_var;
             let mut detail = mutex.lock().expect("Failed to lock.");
             while !detail.cancelled {
                 // Handle expired deadlines.
-                let now = Instant::now();
+                let now = clock.now();
                 let expired_list = detail.deadlines
                     .keys()
                     .take_while(|&&deadline| deadline < now)
@@ -87,6 +130,13 @@ mod implementation {
                     let tokens = detail.deadlines.remove(&expired).expect("Bug in `BTreeMap`.");
                     for token in tokens {
                         let _ = sender.send(Action::Timeout(token));
+                        let period = detail.periods.get(&token).cloned();
+                        if let Some(period) = period {
+                            detail.deadlines
+                                .entry(expired + period)
+                                .or_insert_with(Vec::new)
+                                .push(token);
+                        }
                     }
                 }
 
@@ -117,6 +167,7 @@ mod implementation {
     #[cfg(test)]
     mod tests {
         use action::Action;
+        use clock::Clock;
         use maidsafe_utilities::event_sender::MaidSafeEventCategory;
 
         use std::sync::mpsc;
@@ -146,7 +197,7 @@ mod implementation {
                         action);
             };
             {
-                let mut timer = Timer::new(sender);
+                let mut timer = Timer::new(sender, &Clock::new());
 
                 // Add deadlines, the first to time out after 2.5s, the second after 2.0s, and so on
                 // down to 500ms.
@@ -198,24 +249,105 @@ mod implementation {
 
 #[cfg(feature = "use-mock-crust")]
 mod implementation {
+    use clock::Clock;
+    use mock_crust::Network;
     use std::time::Duration;
-
     use types::RoutingActionSender;
 
-    // The mock timer currently never raises timeout events.
+    /// Mock timer, driven by the virtual time of the mock network: timeouts are only raised when
+    /// the test calls `Network::advance_time`. It must be created with a virtual `Clock`.
     pub struct Timer {
+        id: usize,
+        network: Network,
         next_token: u64,
     }
 
     impl Timer {
-        pub fn new(_: RoutingActionSender) -> Self {
-            Timer { next_token: 0 }
+        pub fn new(sender: RoutingActionSender, clock: &Clock) -> Self {
+            let network = unwrap!(clock.network().cloned(), "Mock timer needs a virtual clock.");
+            let id = network.add_timer(sender);
+            Timer {
+                id: id,
+                network: network,
+                next_token: 0,
+            }
+        }
+
+        pub fn schedule(&mut self, duration: Duration) -> u64 {
+            let token = self.next_token();
+            self.network.schedule_timeout(self.id, token, duration, None);
+            token
+        }
+
+        pub fn schedule_repeating(&mut self, period: Duration) -> u64 {
+            let token = self.next_token();
+            self.network.schedule_timeout(self.id, token, period, Some(period));
+            token
+        }
+
+        pub fn cancel(&mut self, token: u64) {
+            self.network.cancel_timeout(self.id, token);
         }
 
-        pub fn schedule(&mut self, _: Duration) -> u64 {
+        fn next_token(&mut self) -> u64 {
             let token = self.next_token;
             self.next_token = token.wrapping_add(1);
             token
         }
     }
+
+    impl Drop for Timer {
+        fn drop(&mut self) {
+            self.network.remove_timer(self.id);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use action::Action;
+        use clock::Clock;
+        use maidsafe_utilities::event_sender::MaidSafeEventCategory;
+        use mock_crust::{self, Network};
+        use std::sync::mpsc;
+        use std::time::Duration;
+        use super::*;
+        use types::RoutingActionSender;
+
+        #[test]
+        fn cancel_and_repeat() {
+            let network = Network::new(None);
+            let handle = network.new_service_handle(None, None);
+            let (action_sender, action_receiver) = mpsc::channel();
+            let (category_sender, _category_receiver) = mpsc::channel();
+            let sender = RoutingActionSender::new(action_sender,
+                                                  MaidSafeEventCategory::Routing,
+                                                  category_sender);
+            let clock = mock_crust::make_current(&handle, Clock::new);
+            let mut timer = Timer::new(sender, &clock);
+            let timeouts = || {
+                let mut tokens = vec![];
+                while let Ok(action) = action_receiver.try_recv() {
+                    match action {
+                        Action::Timeout(token) => tokens.push(token),
+                        action => panic!("Unexpected action {:?}", action),
+                    }
+                }
+                tokens
+            };
+
+            let one_shot = timer.schedule(Duration::from_secs(10));
+            let repeating = timer.schedule_repeating(Duration::from_secs(4));
+            let cancelled = timer.schedule(Duration::from_secs(5));
+            timer.cancel(cancelled);
+            network.advance_time(Duration::from_secs(3));
+            assert!(timeouts().is_empty());
+            network.advance_time(Duration::from_secs(9));
+            assert_eq!(vec![repeating, repeating, one_shot, repeating], timeouts());
+            assert_eq!(Duration::from_secs(12), network.elapsed());
+
+            timer.cancel(repeating);
+            network.advance_time(Duration::from_secs(20));
+            assert!(timeouts().is_empty());
+        }
+    }
 }
@@ -15,6 +15,7 @@
 // Please review the Licences for the specific language governing permissions and limitations
 // relating to use of the SAFE Network Software.
 
+use clock::Clock;
 #[cfg(not(feature = "use-mock-crust"))]
 use crust::PeerId;
 use itertools::Itertools;
@@ -23,11 +24,50 @@ use message_filter::MessageFilter;
 use mock_crust::crust::PeerId;
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
-use std::time::Duration;
+use std::mem;
+use std::time::{Duration, Instant};
 
 
 /// The maximum number of pairs of nodes that this node will act as a tunnel for.
 const MAX_TUNNEL_CLIENT_PAIRS: usize = 40;
+/// The maximum number of tunnels we keep to a single peer: a primary and a standby.
+const MAX_TUNNELS_PER_PEER: usize = 2;
+/// The default number of bytes per second we relay for a single client pair.
+pub const DEFAULT_MAX_PAIR_BYTES_PER_SEC: u64 = 512 * 1024;
+/// The default number of bytes per second we relay for all client pairs together.
+pub const DEFAULT_MAX_TOTAL_BYTES_PER_SEC: u64 = 4 * 1024 * 1024;
+/// The lowest priority (i. e. the highest priority number) of messages that are still relayed once
+/// a bandwidth budget is exhausted. Less important messages are dropped.
+const OVER_BUDGET_MAX_PRIORITY: u8 = 2;
+
+/// Limits on the traffic a node relays while acting as a tunnel node.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TunnelLimits {
+    /// The maximum number of bytes per second relayed for a single client pair.
+    pub max_pair_bytes_per_sec: u64,
+    /// The maximum number of bytes per second relayed for all client pairs together.
+    pub max_total_bytes_per_sec: u64,
+}
+
+impl Default for TunnelLimits {
+    fn default() -> TunnelLimits {
+        TunnelLimits {
+            max_pair_bytes_per_sec: DEFAULT_MAX_PAIR_BYTES_PER_SEC,
+            max_total_bytes_per_sec: DEFAULT_MAX_TOTAL_BYTES_PER_SEC,
+        }
+    }
+}
+
+/// Counters for the messages relayed as a tunnel node.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct TunnelTraffic {
+    /// The number of messages relayed.
+    pub msgs: u64,
+    /// The number of bytes relayed.
+    pub bytes: u64,
+    /// The number of messages dropped because the bandwidth budget was exhausted.
+    pub dropped_msgs: u64,
+}
 
 /// A container for managing tunnel connections.
 ///
@@ -35,17 +75,53 @@ const MAX_TUNNEL_CLIENT_PAIRS: usize = 40;
 /// which may not always be possible due to e. g. NAT devices. Tunnel nodes act as proxies in these
 /// cases, relaying messages between the two nodes in a way that is transparent to the rest of the
 /// routing logic.
+///
+/// For each destination we keep a primary tunnel and, if available, a standby one, so that we can
+/// fail over without losing the connection if the primary tunnel node disappears.
 pub struct Tunnels {
-    /// Maps the peer we failed to directly connect to to the one that acts as a tunnel.
-    tunnels: HashMap<PeerId, PeerId>,
+    /// Maps the peer we failed to directly connect to to the ones that act as a tunnel, together
+    /// with their remaining capacity. The first entry is the primary tunnel, the second one the
+    /// standby.
+    tunnels: HashMap<PeerId, Vec<(PeerId, usize)>>,
     /// Contains peers that are looking for a tunnel, with the lower ID first. Only once it sends
     /// a message to the latter via us, the pair is moved to `clients`.
     new_clients: MessageFilter<(PeerId, PeerId)>,
     /// Contains all pairs of names we act as a tunnel node for, with the lower ID first.
     clients: HashSet<(PeerId, PeerId)>,
+    clock: Clock,
+    limits: TunnelLimits,
+    /// The traffic we relayed for each client pair, with the lower ID first.
+    traffic: HashMap<(PeerId, PeerId), TunnelTraffic>,
+    /// The traffic we relayed for all client pairs, including the ones that were dropped.
+    total_traffic: TunnelTraffic,
+    /// The start of the current one-second budget window.
+    window_start: Instant,
+    /// The bytes relayed for each client pair in the current budget window.
+    window_bytes: HashMap<(PeerId, PeerId), u64>,
+    /// The bytes relayed for all client pairs in the current budget window.
+    window_total_bytes: u64,
 }
 
 impl Tunnels {
+    /// Creates a new container that relays at most the traffic permitted by `limits`, measuring
+    /// time with `clock`.
+    pub fn new(limits: TunnelLimits, clock: Clock) -> Tunnels {
+        let window_start = clock.now();
+        Tunnels {
+            tunnels: HashMap::new(),
+            new_clients: MessageFilter::with_expiry_duration(Duration::from_secs(60),
+                                                             clock.clone()),
+            clients: HashSet::new(),
+            clock: clock,
+            limits: limits,
+            traffic: HashMap::new(),
+            total_traffic: TunnelTraffic::default(),
+            window_start: window_start,
+            window_bytes: HashMap::new(),
+            window_total_bytes: 0,
+        }
+    }
+
     /// Returns `true` if we are acting as a tunnel for the given clients.
     pub fn has_clients(&self, src_id: PeerId, dst_id: PeerId) -> bool {
         if src_id < dst_id {
@@ -73,9 +149,13 @@ impl Tunnels {
 
     /// Returns `true` if the given client pair can be made permanent, and does so.
     ///
-    /// `consider_clients` must be called with the client pair before this.
+    /// `consider_clients` must be called with the client pair, in either order, before this.
     pub fn accept_clients(&mut self, src_id: PeerId, dst_id: PeerId) -> bool {
-        let pair = (src_id, dst_id);
+        let pair = if src_id < dst_id {
+            (src_id, dst_id)
+        } else {
+            (dst_id, src_id)
+        };
         // TODO(afck): Remove the pair from the new clients once message_filter supports that.
         if self.new_clients.contains(&pair) {
             self.clients.insert(pair);
@@ -85,6 +165,62 @@ impl Tunnels {
         }
     }
 
+    /// Accounts for a message of `len` bytes from `src_id` to `dst_id` and returns whether it
+    /// should be relayed.
+    ///
+    /// Once the pair's or the total bandwidth budget for the current second is exhausted, only
+    /// messages with a priority up to `OVER_BUDGET_MAX_PRIORITY` are relayed.
+    pub fn relay(&mut self, src_id: PeerId, dst_id: PeerId, len: usize, priority: u8) -> bool {
+        let now = self.clock.now();
+        if now - self.window_start >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_bytes.clear();
+            self.window_total_bytes = 0;
+        }
+        let pair = if src_id < dst_id {
+            (src_id, dst_id)
+        } else {
+            (dst_id, src_id)
+        };
+        let len = len as u64;
+        let pair_bytes = self.window_bytes.entry(pair).or_insert(0);
+        let over_budget = *pair_bytes + len > self.limits.max_pair_bytes_per_sec ||
+                          self.window_total_bytes + len > self.limits.max_total_bytes_per_sec;
+        let traffic = self.traffic.entry(pair).or_insert_with(TunnelTraffic::default);
+        if over_budget && priority > OVER_BUDGET_MAX_PRIORITY {
+            traffic.dropped_msgs += 1;
+            self.total_traffic.dropped_msgs += 1;
+            return false;
+        }
+        *pair_bytes += len;
+        self.window_total_bytes += len;
+        traffic.msgs += 1;
+        traffic.bytes += len;
+        self.total_traffic.msgs += 1;
+        self.total_traffic.bytes += len;
+        true
+    }
+
+    /// Returns the traffic we relayed for the given client pair.
+    pub fn traffic(&self, src_id: PeerId, dst_id: PeerId) -> TunnelTraffic {
+        let pair = if src_id < dst_id {
+            (src_id, dst_id)
+        } else {
+            (dst_id, src_id)
+        };
+        self.traffic.get(&pair).cloned().unwrap_or_else(TunnelTraffic::default)
+    }
+
+    /// Returns the traffic we relayed for all client pairs, including former ones.
+    pub fn total_traffic(&self) -> TunnelTraffic {
+        self.total_traffic
+    }
+
+    /// Returns the number of further client pairs we can act as a tunnel for.
+    pub fn headroom(&self) -> usize {
+        MAX_TUNNEL_CLIENT_PAIRS.saturating_sub(self.clients.len())
+    }
+
     /// Removes all pairs with the given client and returns a list of all clients that used us as a
     /// tunnel for them.
     pub fn drop_client(&mut self, peer_id: &PeerId) -> Vec<PeerId> {
@@ -96,6 +232,8 @@ impl Tunnels {
         pairs.into_iter()
             .map(|pair| {
                 self.clients.remove(&pair);
+                let _ = self.traffic.remove(&pair);
+                let _ = self.window_bytes.remove(&pair);
                 if pair.0 == *peer_id { pair.1 } else { pair.0 }
             })
             .collect()
@@ -109,54 +247,100 @@ impl Tunnels {
             (dst_id, src_id)
         };
 
+        let _ = self.traffic.remove(&(id0, id1));
+        let _ = self.window_bytes.remove(&(id0, id1));
         self.clients.remove(&(id0, id1))
     }
 
-    /// Adds the given `tunnel_id` as a tunnel to `dst_id` if one is needed, otherwise returns
-    /// `false`.
-    pub fn add(&mut self, dst_id: PeerId, tunnel_id: PeerId) -> bool {
+    /// Adds the given `tunnel_id` as the primary tunnel to `dst_id` if one is needed, otherwise
+    /// returns `false`.
+    pub fn add(&mut self, dst_id: PeerId, tunnel_id: PeerId, headroom: usize) -> bool {
         match self.tunnels.entry(dst_id) {
             Entry::Occupied(_) => false,
             Entry::Vacant(entry) => {
-                let _ = entry.insert(tunnel_id);
+                let _ = entry.insert(vec![(tunnel_id, headroom)]);
                 true
             }
         }
     }
 
-    /// Removes the given tunnel to the given destination, and return whether it was present.
+    /// Offers `tunnel_id` as a standby tunnel to `dst_id`, which must already have a primary one.
+    ///
+    /// If there is no standby yet, the new tunnel is added. Otherwise it replaces the standby, if
+    /// it has more headroom. The primary tunnel stays in place either way. Returns the tunnel that
+    /// was rejected or replaced, if any.
+    pub fn add_standby(&mut self, dst_id: PeerId, tunnel_id: PeerId, headroom: usize)
+                       -> Option<PeerId> {
+        let tunnels = match self.tunnels.get_mut(&dst_id) {
+            Some(tunnels) => tunnels,
+            None => return Some(tunnel_id),
+        };
+        if tunnels.iter().any(|&(id, _)| id == tunnel_id) {
+            return None;
+        }
+        if tunnels.len() < MAX_TUNNELS_PER_PEER {
+            tunnels.push((tunnel_id, headroom));
+            return None;
+        }
+        let standby = &mut tunnels[MAX_TUNNELS_PER_PEER - 1];
+        if standby.1 < headroom {
+            Some(mem::replace(standby, (tunnel_id, headroom)).0)
+        } else {
+            Some(tunnel_id)
+        }
+    }
+
+    /// Removes the given tunnel to the given destination, and return whether it was present. If it
+    /// was the primary tunnel, the standby takes its place.
     pub fn remove(&mut self, dst_id: PeerId, tunnel_id: PeerId) -> bool {
-        if let Entry::Occupied(entry) = self.tunnels.entry(dst_id) {
-            if entry.get() == &tunnel_id {
+        if let Entry::Occupied(mut entry) = self.tunnels.entry(dst_id) {
+            let len = entry.get().len();
+            entry.get_mut().retain(|&(id, _)| id != tunnel_id);
+            let removed = entry.get().len() < len;
+            if entry.get().is_empty() {
                 let _ = entry.remove();
-                return true;
             }
+            return removed;
         }
         false
     }
 
-    /// Removes and the peer that is acting as a tunnel for the given peer, if any.
-    pub fn remove_tunnel_for(&mut self, dst_id: &PeerId) -> Option<PeerId> {
-        self.tunnels.remove(dst_id)
+    /// Removes and returns the peers that are acting as a tunnel for the given peer.
+    pub fn remove_tunnel_for(&mut self, dst_id: &PeerId) -> Vec<PeerId> {
+        self.tunnels
+            .remove(dst_id)
+            .map_or_else(Vec::new, |tunnels| tunnels.into_iter().map(|(id, _)| id).collect())
     }
 
     /// Removes the given tunnel node and returns a list of all peers it was acting as a tunnel
-    /// for.
+    /// for. Peers that still have a tunnel afterwards have failed over to their standby.
     pub fn remove_tunnel(&mut self, tunnel_id: &PeerId) -> Vec<PeerId> {
         let dst_ids = self.tunnels
             .iter()
-            .filter(|&(_, id)| id == tunnel_id)
+            .filter(|&(_, tunnels)| tunnels.iter().any(|&(id, _)| id == *tunnel_id))
             .map(|(&dst_id, _)| dst_id)
             .collect_vec();
         for dst_id in &dst_ids {
-            let _ = self.tunnels.remove(dst_id);
+            let _ = self.remove(*dst_id, *tunnel_id);
         }
         dst_ids
     }
 
-    /// Returns the peer that is acting as a tunnel to the given peer, if any.
+    /// Returns the peer that is acting as the primary tunnel to the given peer, if any.
     pub fn tunnel_for(&self, dst_id: &PeerId) -> Option<&PeerId> {
-        self.tunnels.get(dst_id)
+        self.tunnels.get(dst_id).and_then(|tunnels| tunnels.first()).map(|&(ref id, _)| id)
+    }
+
+    /// Returns `true` if `tunnel_id` is the primary or standby tunnel to `dst_id`.
+    pub fn is_tunnel(&self, dst_id: &PeerId, tunnel_id: &PeerId) -> bool {
+        self.tunnels
+            .get(dst_id)
+            .map_or(false, |tunnels| tunnels.iter().any(|&(id, _)| id == *tunnel_id))
+    }
+
+    /// Returns `true` if we have a standby tunnel to the given peer.
+    pub fn has_standby(&self, dst_id: &PeerId) -> bool {
+        self.tunnels.get(dst_id).map_or(false, |tunnels| tunnels.len() >= MAX_TUNNELS_PER_PEER)
     }
 
     /// Returns the number of client pairs we are acting as a tunnel for.
@@ -172,11 +356,7 @@ impl Tunnels {
 
 impl Default for Tunnels {
     fn default() -> Tunnels {
-        Tunnels {
-            tunnels: HashMap::new(),
-            new_clients: MessageFilter::with_expiry_duration(Duration::from_secs(60)),
-            clients: HashSet::new(),
-        }
+        Tunnels::new(TunnelLimits::default(), Clock::default())
     }
 }
 
@@ -184,8 +364,11 @@ impl Default for Tunnels {
 #[cfg(feature = "use-mock-crust")]
 #[cfg(test)]
 mod tests {
+    use clock::Clock;
     use itertools::Itertools;
+    use mock_crust::{self, Network};
     use mock_crust::crust::PeerId;
+    use std::time::Duration;
     use super::*;
 
     fn id(i: usize) -> PeerId {
@@ -197,7 +380,7 @@ mod tests {
         let mut tunnels: Tunnels = Default::default();
         assert_eq!(None, tunnels.tunnel_for(&id(0)));
         // Peer 1 is acting as a tunnel for peer 0.
-        tunnels.add(id(0), id(1));
+        tunnels.add(id(0), id(1), 0);
         assert_eq!(Some(&id(1)), tunnels.tunnel_for(&id(0)));
         assert_eq!(None, tunnels.tunnel_for(&id(1)));
         tunnels.remove(id(0), id(1));
@@ -208,9 +391,9 @@ mod tests {
     fn remove_tunnel_test() {
         let mut tunnels: Tunnels = Default::default();
         // Peer 0 is acting as a tunnel for 1 and 2, but not 3.
-        tunnels.add(id(1), id(0));
-        tunnels.add(id(2), id(0));
-        tunnels.add(id(3), id(4));
+        tunnels.add(id(1), id(0), 0);
+        tunnels.add(id(2), id(0), 0);
+        tunnels.add(id(3), id(4), 0);
         let removed_peers = tunnels.remove_tunnel(&id(0))
             .into_iter()
             .sorted();
@@ -220,11 +403,70 @@ mod tests {
         assert_eq!(Some(&id(4)), tunnels.tunnel_for(&id(3)));
     }
 
+    #[test]
+    fn standby_tunnel_test() {
+        let mut tunnels: Tunnels = Default::default();
+        // A standby tunnel is only accepted once there is a primary one.
+        assert_eq!(Some(id(2)), tunnels.add_standby(id(0), id(2), 10));
+        assert!(tunnels.add(id(0), id(1), 0));
+        assert!(!tunnels.has_standby(&id(0)));
+        // Even though peer 1 has no headroom left, it remains the primary tunnel to peer 0.
+        assert_eq!(None, tunnels.add_standby(id(0), id(2), 5));
+        assert!(tunnels.has_standby(&id(0)));
+        assert_eq!(Some(&id(1)), tunnels.tunnel_for(&id(0)));
+        assert!(tunnels.is_tunnel(&id(0), &id(2)));
+        // Peer 3 has more headroom than the standby 2 and replaces it, but 4 is rejected.
+        assert_eq!(Some(id(2)), tunnels.add_standby(id(0), id(3), 10));
+        assert_eq!(Some(id(4)), tunnels.add_standby(id(0), id(4), 10));
+        assert!(!tunnels.is_tunnel(&id(0), &id(2)));
+        assert_eq!(Some(&id(1)), tunnels.tunnel_for(&id(0)));
+        // If the primary tunnel disappears, we fail over to the standby.
+        assert_eq!(vec![id(0)], tunnels.remove_tunnel(&id(1)));
+        assert_eq!(Some(&id(3)), tunnels.tunnel_for(&id(0)));
+        assert!(!tunnels.has_standby(&id(0)));
+        assert!(tunnels.remove(id(0), id(3)));
+        assert_eq!(None, tunnels.tunnel_for(&id(0)));
+    }
+
+    #[test]
+    fn bandwidth_budget_test() {
+        let limits = TunnelLimits {
+            max_pair_bytes_per_sec: 100,
+            max_total_bytes_per_sec: 150,
+        };
+        let network = Network::new(None);
+        let handle = network.new_service_handle(None, None);
+        let clock = mock_crust::make_current(&handle, Clock::new);
+        let mut tunnels = Tunnels::new(limits, clock);
+        assert!(tunnels.relay(id(1), id(0), 80, 5));
+        // Over the pair's budget: only high priority messages are relayed.
+        assert!(!tunnels.relay(id(0), id(1), 30, 5));
+        assert!(tunnels.relay(id(0), id(1), 30, 0));
+        assert!(tunnels.relay(id(2), id(3), 30, 5));
+        // Over the total budget.
+        assert!(!tunnels.relay(id(2), id(3), 30, 3));
+        assert!(tunnels.relay(id(2), id(3), 30, OVER_BUDGET_MAX_PRIORITY));
+        // The budgets are renewed every second.
+        network.advance_time(Duration::from_secs(1));
+        assert!(tunnels.relay(id(2), id(3), 30, 3));
+        let expected = TunnelTraffic {
+            msgs: 2,
+            bytes: 110,
+            dropped_msgs: 1,
+        };
+        assert_eq!(expected, tunnels.traffic(id(0), id(1)));
+        assert_eq!(5, tunnels.total_traffic().msgs);
+        assert_eq!(2, tunnels.total_traffic().dropped_msgs);
+        let _ = tunnels.drop_client_pair(id(1), id(0));
+        assert_eq!(TunnelTraffic::default(), tunnels.traffic(id(0), id(1)));
+        assert_eq!(5, tunnels.total_traffic().msgs);
+    }
+
     #[test]
     fn clients_test() {
         let mut tunnels: Tunnels = Default::default();
         // We are directly connected to 1, but not 0.
-        tunnels.add(id(0), id(1));
+        tunnels.add(id(0), id(1), 0);
         // consider_clients has not been called yet.
         assert!(!tunnels.accept_clients(id(1), id(2)));
         assert!(!tunnels.accept_clients(id(3), id(4)));
@@ -236,5 +478,9 @@ mod tests {
         assert!(tunnels.accept_clients(id(3), id(4)));
         assert!(tunnels.has_clients(id(2), id(1)));
         assert!(tunnels.has_clients(id(3), id(4)));
+        // The pair is accepted no matter which of the two asks for it, e.g. for a standby tunnel.
+        assert_eq!(Some((id(5), id(6))), tunnels.consider_clients(id(5), id(6)));
+        assert!(tunnels.accept_clients(id(6), id(5)));
+        assert!(tunnels.has_clients(id(5), id(6)));
     }
 }
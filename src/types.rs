@@ -17,6 +17,7 @@
 
 use maidsafe_utilities::event_sender::MaidSafeObserver;
 use rand::random;
+use rust_sodium::crypto::hash::sha256;
 use xor_name::XorName;
 
 pub type RoutingActionSender = MaidSafeObserver<::action::Action>;
@@ -73,6 +74,15 @@ impl MessageId {
         vec_mut[0] = vec_mut[0].wrapping_sub(1);
         MessageId(XorName(vec_mut))
     }
+
+    /// Generate a `MessageId` from the given one and a name, e.g. to give each of several messages
+    /// sent in response to the same event its own ID.
+    pub fn with_name(message_id: &MessageId, name: &XorName) -> MessageId {
+        let MessageId(XorName(ref id)) = *message_id;
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&name.0);
+        MessageId(XorName(sha256::hash(&bytes).0))
+    }
 }
 
 impl Default for MessageId {
@@ -100,4 +110,16 @@ mod tests {
         let MessageId(XorName(vec_bytes)) = message_id;
         assert_eq!(vec_bytes[0], 255);
     }
+
+    #[test]
+    fn with_name() {
+        let message_id = MessageId::new();
+        let name0 = XorName([0; XOR_NAME_LEN]);
+        let name1 = XorName([1; XOR_NAME_LEN]);
+        assert_eq!(MessageId::with_name(&message_id, &name0),
+                   MessageId::with_name(&message_id, &name0));
+        assert!(MessageId::with_name(&message_id, &name0) !=
+                MessageId::with_name(&message_id, &name1));
+        assert!(MessageId::with_name(&message_id, &name0) != message_id);
+    }
 }
@@ -18,6 +18,7 @@
 use routing_table::Xorable;
 use rust_sodium::crypto::hash::sha256;
 use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
 use xor_name::XorName;
 
 /// Format a vector of bytes as a hexadecimal number, ellipsising all but the first and last three.
@@ -42,6 +43,15 @@ pub fn format_binary_array<V: AsRef<[u8]>>(input: V) -> String {
             input_ref[input_ref.len() - 1])
 }
 
+/// Returns the current time in milliseconds since the Unix epoch, or `0` if the system clock is
+/// set to an earlier time.
+pub fn unix_time_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64,
+        Err(_) => 0,
+    }
+}
+
 /// Compute the relocated name of a client with the given original name.
 ///
 /// This is used by each member of the client's `ClientManager` group to choose a new name for the
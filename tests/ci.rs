@@ -269,7 +269,7 @@ fn core() {
                         assert!(result.is_ok());
                     }
 
-                    TestEvent(index, Event::Request { request, src, dst }) => {
+                    TestEvent(index, Event::Request { request, src, dst, .. }) => {
                         // A node received request from the client. Reply with a success.
                         if let Request::Put(_, ref id) = request {
                             let node = &nodes[index].node;
@@ -351,14 +351,15 @@ fn core() {
                     TestEvent(index,
                               Event::Request { request: Request::Put(data, id),
                                                src: Authority::Client { .. },
-                                               dst: Authority::ClientManager(name) }) => {
+                                               dst: Authority::ClientManager(name),
+                                               .. }) => {
                         let src = Authority::ClientManager(name);
                         let dst = Authority::NaeManager(*data.name());
                         unwrap!(nodes[index]
                             .node
                             .send_put_request(src, dst, data.clone(), id.clone()));
                     }
-                    TestEvent(index, Event::Request { request, src, dst }) => {
+                    TestEvent(index, Event::Request { request, src, dst, .. }) => {
                         if let Request::Put(data, id) = request {
                             unwrap!(nodes[index]
                                 .node
@@ -394,7 +395,7 @@ fn core() {
         loop {
             if let Ok(test_event) = recv_with_timeout(&event_receiver, Duration::from_secs(20)) {
                 match test_event {
-                    TestEvent(index, Event::NodeLost(lost_name, _)) if index < nodes.len() &&
+                    TestEvent(index, Event::NodeLost(lost_name, ..)) if index < nodes.len() &&
                                                                        lost_name == name => {
                         churns[index] = true;
                         if churns.iter().all(|b| *b) {
@@ -452,14 +453,15 @@ fn core() {
                 TestEvent(index,
                           Event::Request { request: Request::Put(data, id),
                                            src: Authority::Client { .. },
-                                           dst: Authority::ClientManager(name) }) => {
+                                           dst: Authority::ClientManager(name),
+                                           .. }) => {
                     let src = Authority::ClientManager(name);
                     let dst = Authority::NaeManager(*data.name());
                     unwrap!(nodes[index]
                         .node
                         .send_put_request(src, dst, data.clone(), id.clone()));
                 }
-                TestEvent(index, Event::Request { request, src, dst }) => {
+                TestEvent(index, Event::Request { request, src, dst, .. }) => {
                     if let Request::Put(data, id) = request {
                         if index < QUORUM_SIZE - 1 {
                             unwrap!(nodes[index]
@@ -495,7 +497,7 @@ fn core() {
                         assert!(result.is_ok());
                         sent_ids.insert(message_id);
                     }
-                    TestEvent(index, Event::Request { request, src, dst }) => {
+                    TestEvent(index, Event::Request { request, src, dst, .. }) => {
                         // A node received request from the client. Reply with a success.
                         let data_id = DataIdentifier::Plain(*data.name());
                         if let Request::Put(_, id) = request {